@@ -4,15 +4,17 @@ use futures::prelude::*;
 fn load_player(c: &mut Criterion) {
     let runtime = tokio::runtime::Runtime::new().unwrap();
     let http_client = reqwest::Client::new();
+    let config = rating_update::rater::Config::load().unwrap();
+    let db_path = config.db_path.clone();
 
     runtime.block_on(async {
-        tokio::spawn(rating_update::website::run());
+        tokio::spawn(rating_update::website::run(config));
     });
 
     c.bench_function("load_player", |b| {
         // Find a number of players to run the benchmark on
         let players = {
-            let db_connection = rusqlite::Connection::open(rating_update::rater::DB_NAME).unwrap();
+            let db_connection = rusqlite::Connection::open(&db_path).unwrap();
             let mut stmt = db_connection
                 .prepare("SELECT id, char_id FROM ranking_global LIMIT 50")
                 .unwrap();
@@ -35,7 +37,7 @@ fn load_player(c: &mut Criterion) {
                         .get(format!(
                             "http://localhost/player/{:X}/{}",
                             id,
-                            rating_update::website::CHAR_NAMES[char_id].0
+                            rating_update::characters::all()[char_id].0
                         ))
                         .send()
                         .await
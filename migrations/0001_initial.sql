@@ -0,0 +1,472 @@
+-- The schema as it was when migrations were introduced. Written so it can also run over a
+-- database made before then, which only gets the tables and rows it's missing.
+
+CREATE TABLE IF NOT EXISTS games (
+    timestamp INTEGER NOT NULL,
+    id_a INTEGER NOT NULL,
+    name_a TEXT NOT NULL,
+    char_a INTEGER NOT NULL,
+    platform_a INTEGER NOT NULL,
+    id_b INTEGER NOT NULL,
+    name_b TEXT NOT NULL,
+    char_b INTEGER NOT NULL,
+    platform_b INTEGER NOT NULL,
+    winner INTEGER NOT NULL,
+    game_floor INTEGER NOT NULL,
+    -- Name of the configured source the game was first pulled from
+    source TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (timestamp, id_a, id_b)
+);
+
+CREATE INDEX IF NOT EXISTS games_char_a ON games(char_a);
+CREATE INDEX IF NOT EXISTS games_char_b ON games(char_b);
+
+-- Indices for speeding up player character match history lookup
+CREATE INDEX IF NOT EXISTS games_id_char_a ON games (
+	id_a,
+	char_a
+);
+CREATE INDEX IF NOT EXISTS games_id_char_b ON games (
+	id_b,
+	char_b
+);
+
+CREATE TABLE IF NOT EXISTS game_ratings (
+    timestamp INTEGER NOT NULL,
+    id_a INTEGER NOT NULL,
+    value_a REAL NOT NULL,
+    deviation_a REAL NOT NULL,
+    id_b INTEGER NOT NULL,
+    value_b REAL NOT NULL,
+    deviation_b REAL NOT NULL,
+    winner INTEGER NOT NULL,
+    valid BOOLEAN NOT NULL, 
+    -- Ratings right after the game, the columns above are from before it
+    new_value_a REAL,
+    new_deviation_a REAL,
+    new_value_b REAL,
+    new_deviation_b REAL,
+    PRIMARY KEY (timestamp, id_a, id_b, winner)
+);
+
+CREATE INDEX IF NOT EXISTS ratings_value_a ON game_ratings(value_a);
+CREATE INDEX IF NOT EXISTS ratings_value_b ON game_ratings(value_b);
+CREATE INDEX IF NOT EXISTS ratings_dev_a ON game_ratings(deviation_a);
+CREATE INDEX IF NOT EXISTS ratings_dev_b ON game_ratings(deviation_b);
+
+CREATE TABLE IF NOT EXISTS players  (
+    id INTEGER NOT NULL PRIMARY KEY,
+    floor INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    platform INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS player_names (
+    id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY(id, name)
+);
+
+-- Trigram index over current and old names for substring search, kept in sync by the triggers
+CREATE VIRTUAL TABLE IF NOT EXISTS player_names_fts USING fts5(
+    name,
+    content = 'player_names',
+    tokenize = 'trigram'
+);
+
+CREATE TRIGGER IF NOT EXISTS player_names_ai AFTER INSERT ON player_names BEGIN
+    INSERT INTO player_names_fts(rowid, name) VALUES (new.rowid, new.name);
+END;
+
+CREATE TRIGGER IF NOT EXISTS player_names_ad AFTER DELETE ON player_names BEGIN
+    INSERT INTO player_names_fts(player_names_fts, rowid, name) VALUES ('delete', old.rowid, old.name);
+END;
+
+CREATE TRIGGER IF NOT EXISTS player_names_au AFTER UPDATE ON player_names BEGIN
+    INSERT INTO player_names_fts(player_names_fts, rowid, name) VALUES ('delete', old.rowid, old.name);
+    INSERT INTO player_names_fts(rowid, name) VALUES (new.rowid, new.name);
+END;
+
+CREATE TABLE IF NOT EXISTS player_ratings (
+    id INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    value REAL NOT NULL,
+    deviation REAL NOT NULL,
+    last_decay INTEGER NOT NULL,
+
+    top_rating_value REAL,
+    top_rating_deviation REAL,
+    top_rating_timestamp INTEGER,
+
+    top_defeated_id INTEGER,
+    top_defeated_char_id INTEGER,
+    top_defeated_name TEXT,
+    top_defeated_value REAL,
+    top_defeated_deviation REAL,
+    top_defeated_floor INTEGER,
+    top_defeated_timestamp INTEGER,
+
+    current_streak INTEGER NOT NULL DEFAULT 0,
+    longest_streak INTEGER NOT NULL DEFAULT 0,
+
+    PRIMARY KEY(id, char_id)
+);
+
+CREATE INDEX IF NOT EXISTS player_current_streak ON player_ratings(current_streak);
+
+CREATE TABLE IF NOT EXISTS daily_ratings (
+    id INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    value REAL NOT NULL,
+    deviation REAL NOT NULL,
+    PRIMARY KEY(id, char_id, timestamp)
+);
+
+CREATE TABLE IF NOT EXISTS player_rating_history (
+    id INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    value REAL NOT NULL,
+    deviation REAL NOT NULL,
+    PRIMARY KEY(id, char_id, timestamp)
+);
+
+CREATE INDEX IF NOT EXISTS player_value ON player_ratings(value);
+CREATE INDEX IF NOT EXISTS player_dev ON player_ratings(deviation);
+
+CREATE TABLE IF NOT EXISTS player_matchups (
+    id INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    rating_timestamp INTEGER NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(id, char_id, opp_char_id)
+);
+
+CREATE TABLE IF NOT EXISTS player_floor_matchups (
+    id INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    floor INTEGER NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(id, char_id, opp_char_id, floor)
+);
+
+CREATE TABLE IF NOT EXISTS global_matchups(
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(char_id, opp_char_id)
+);
+
+CREATE TABLE IF NOT EXISTS top_1000_matchups(
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(char_id, opp_char_id)
+);
+
+CREATE TABLE IF NOT EXISTS top_100_matchups(
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(char_id, opp_char_id)
+);
+
+CREATE TABLE IF NOT EXISTS proportional_matchups(
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(char_id, opp_char_id)
+);
+
+CREATE TABLE IF NOT EXISTS floor_matchups(
+    floor INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(floor, char_id, opp_char_id)
+);
+
+CREATE TABLE IF NOT EXISTS floor_band_matchups(
+    band TEXT NOT NULL,
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(band, char_id, opp_char_id)
+);
+
+-- Running totals behind floor_band_matchups, including pairs with too few games to show
+CREATE TABLE IF NOT EXISTS floor_band_matchup_totals(
+    band TEXT NOT NULL,
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    rating_value REAL NOT NULL,
+    rating_deviation REAL NOT NULL,
+    wins INTEGER NOT NULL,
+    losses INTEGER NOT NULL,
+    PRIMARY KEY(band, char_id, opp_char_id)
+);
+
+-- First game_ratings rowid that hasn't been folded into floor_band_matchup_totals yet
+CREATE TABLE IF NOT EXISTS floor_band_progress(
+    next_rowid INTEGER NOT NULL
+);
+INSERT INTO floor_band_progress SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM floor_band_progress);
+
+-- Biggest upsets of the last few days, rebuilt every ranking period
+CREATE TABLE IF NOT EXISTS top_upsets(
+    timestamp INTEGER NOT NULL,
+    winner_id INTEGER NOT NULL,
+    winner_char INTEGER NOT NULL,
+    winner_name TEXT NOT NULL,
+    winner_value REAL NOT NULL,
+    winner_deviation REAL NOT NULL,
+    loser_id INTEGER NOT NULL,
+    loser_char INTEGER NOT NULL,
+    loser_name TEXT NOT NULL,
+    loser_value REAL NOT NULL,
+    loser_deviation REAL NOT NULL,
+    expected REAL NOT NULL,
+    game_floor INTEGER NOT NULL,
+    PRIMARY KEY(timestamp, winner_id, loser_id)
+);
+
+CREATE TABLE IF NOT EXISTS player_floor_distribution(
+    floor INTEGER NOT NULL,
+    player_count INTEGER NOT NULL,
+    game_count INTEGER NOT NULL,
+    PRIMARY KEY(floor)
+);
+
+-- Where each active, settled player-character falls among the others, 0.1 is the top 0.1%
+CREATE TABLE IF NOT EXISTS player_percentiles(
+    id INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    top_percent REAL NOT NULL,
+    PRIMARY KEY(id, char_id)
+);
+
+CREATE TABLE IF NOT EXISTS player_rating_distribution(
+    min_rating INTEGER NOT NULL,
+    max_rating INTEGER NOT NULL,
+    player_count INTEGER NOT NULL,
+    player_count_cum INTEGER NOT NULL,
+    PRIMARY KEY(min_rating, max_rating)
+);
+
+CREATE TABLE IF NOT EXISTS char_rating_distribution(
+    char_id INTEGER NOT NULL,
+    min_rating INTEGER NOT NULL,
+    max_rating INTEGER NOT NULL,
+    player_count INTEGER NOT NULL,
+    player_count_cum INTEGER NOT NULL,
+    PRIMARY KEY(char_id, min_rating)
+);
+
+CREATE TABLE IF NOT EXISTS ranking_global (
+    global_rank INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    PRIMARY KEY(global_rank)
+);
+
+CREATE TABLE IF NOT EXISTS ranking_character (
+    character_rank INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    PRIMARY KEY(character_rank, char_id)
+);
+
+-- One rating per player combined from their settled characters, rebuilt with the rankings
+CREATE TABLE IF NOT EXISTS player_overall_ratings (
+    id INTEGER NOT NULL,
+    value REAL NOT NULL,
+    deviation REAL NOT NULL,
+    char_count INTEGER NOT NULL,
+    best_char_id INTEGER NOT NULL,
+    PRIMARY KEY(id)
+);
+
+CREATE INDEX IF NOT EXISTS player_overall_value ON player_overall_ratings(value);
+
+CREATE TABLE IF NOT EXISTS character_popularity_global (
+    char_id INTEGER NOT NULL,
+    popularity REAL NOT NULL,
+    PRIMARY KEY(char_id)
+);
+
+CREATE TABLE IF NOT EXISTS character_popularity_rating (
+    char_id INTEGER NOT NULL,
+    rating_bracket INTEGER NOT NULL,
+    popularity REAL NOT NULL,
+    PRIMARY KEY(char_id, rating_bracket)
+);
+
+-- Games played by each character per rating period, both sides of a game count
+CREATE TABLE IF NOT EXISTS character_popularity (
+    period INTEGER NOT NULL,
+    char_id INTEGER NOT NULL,
+    game_count INTEGER NOT NULL,
+    PRIMARY KEY(period, char_id)
+);
+
+CREATE TABLE IF NOT EXISTS fraud_index (
+    char_id INTEGER NOT NULL,
+    player_count INTEGER NOT NULL,
+    avg_delta REAL NOT NULL,
+    PRIMARY KEY(char_id)
+);
+
+CREATE TABLE IF NOT EXISTS fraud_index_higher_rated (
+    char_id INTEGER NOT NULL,
+    player_count INTEGER NOT NULL,
+    avg_delta REAL NOT NULL,
+    PRIMARY KEY(char_id)
+);
+
+CREATE TABLE IF NOT EXISTS fraud_index_highest_rated (
+    char_id INTEGER NOT NULL,
+    player_count INTEGER NOT NULL,
+    avg_delta REAL NOT NULL,
+    PRIMARY KEY(char_id)
+);
+
+CREATE TABLE IF NOT EXISTS vip_status (
+    id INTEGER NOT NULL,
+    vip_status TEXT NOT NULL,
+    notes TEXT NOT NULL,
+    PRIMARY KEY(id)
+);
+
+CREATE TABLE IF NOT EXISTS cheater_status (
+    id INTEGER NOT NULL,
+    cheater_status TEXT NOT NULL,
+    notes TEXT NOT NULL,
+    PRIMARY KEY(id)
+);
+
+CREATE TABLE IF NOT EXISTS hidden_status (
+    id INTEGER NOT NULL,
+    hidden_status TEXT NOT NULL,
+    notes TEXT NOT NULL,
+    PRIMARY KEY(id)
+);
+
+
+CREATE TABLE IF NOT EXISTS claimed_players (
+    id INTEGER NOT NULL,
+    code TEXT NOT NULL,
+    requested INTEGER NOT NULL,
+    token TEXT,
+    display_name TEXT,
+    pronouns TEXT,
+    links TEXT,
+    PRIMARY KEY(id)
+);
+
+-- Replay pages that failed after retrying. Resolved once a full depth pull gets through.
+CREATE TABLE IF NOT EXISTS fetch_failures (
+    timestamp INTEGER NOT NULL,
+    page INTEGER NOT NULL,
+    min_floor INTEGER NOT NULL,
+    max_floor INTEGER NOT NULL,
+    error TEXT NOT NULL,
+    resolved INTEGER,
+    source TEXT NOT NULL DEFAULT ''
+);
+
+-- Time span of the replays each pull saw, used to spot pulls that didn't overlap
+CREATE TABLE IF NOT EXISTS pull_coverage (
+    timestamp INTEGER NOT NULL,
+    first_page INTEGER NOT NULL,
+    last_page INTEGER NOT NULL,
+    oldest INTEGER NOT NULL,
+    newest INTEGER NOT NULL,
+    source TEXT NOT NULL DEFAULT ''
+);
+
+-- Discord webhooks and the events they get. Peak subscriptions follow player_id,
+-- char_id narrows peak and matchup subscriptions down to one character.
+CREATE TABLE IF NOT EXISTS notifications (
+    id INTEGER PRIMARY KEY,
+    webhook_url TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    player_id INTEGER,
+    char_id INTEGER
+);
+
+-- Players followed by website visitors, keyed by the id kept in their cookie
+CREATE TABLE IF NOT EXISTS watchlists (
+    watchlist_id TEXT NOT NULL,
+    player_id INTEGER NOT NULL,
+    added INTEGER NOT NULL,
+    PRIMARY KEY(watchlist_id, player_id)
+);
+
+-- Suspicious patterns found by the anomaly scan, one row per player and kind of finding.
+-- Excluded players are left out of leaderboards and matchup stats.
+CREATE TABLE IF NOT EXISTS flagged_players (
+    id INTEGER NOT NULL,
+    reason TEXT NOT NULL,
+    details TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    excluded BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY(id, reason)
+);
+
+-- Audit log of player ids folded into another, rows is a JSON object of rows touched per table
+CREATE TABLE IF NOT EXISTS player_merges (
+    timestamp INTEGER NOT NULL,
+    keep_id INTEGER NOT NULL,
+    merge_id INTEGER NOT NULL,
+    source TEXT NOT NULL,
+    rows TEXT NOT NULL
+);
+
+-- Which side of even each global matchup was on at the last check
+CREATE TABLE IF NOT EXISTS matchup_signs (
+    char_id INTEGER NOT NULL,
+    opp_char_id INTEGER NOT NULL,
+    favored BOOLEAN NOT NULL,
+    PRIMARY KEY(char_id, opp_char_id)
+);
+
+CREATE TABLE IF NOT EXISTS config (
+    last_update INTEGER NOT NULL,
+    rating_system TEXT
+);
+
+CREATE TABLE IF NOT EXISTS hits (
+    page TEXT NOT NULL,
+    hit_count INTEGER NOT NULL,
+    PRIMARY KEY(page)
+);
+
+INSERT INTO config SELECT 1675132574, NULL WHERE NOT EXISTS (SELECT 1 FROM config);
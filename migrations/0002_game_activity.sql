@@ -0,0 +1,9 @@
+-- Games per floor by UTC day of week (0 is Sunday) and hour of day over the last two weeks,
+-- rebuilt with the player distribution
+CREATE TABLE game_activity (
+    floor INTEGER NOT NULL,
+    day_of_week INTEGER NOT NULL,
+    hour INTEGER NOT NULL,
+    game_count INTEGER NOT NULL,
+    PRIMARY KEY(floor, day_of_week, hour)
+);
@@ -0,0 +1,4 @@
+-- Everything the opponent breakdown reads from games, per side, so it never touches the table
+-- itself and gets each opponent's games together
+CREATE INDEX games_opponents_a ON games(id_a, id_b, timestamp, winner);
+CREATE INDEX games_opponents_b ON games(id_b, id_a, timestamp, winner);
@@ -0,0 +1,2 @@
+-- For the all time peaks leaderboard, which reads every character ever rated
+CREATE INDEX player_top_rating ON player_ratings(top_rating_value);
@@ -0,0 +1,8 @@
+-- Keys handed out to API users who need more than the per IP rate limit
+CREATE TABLE api_keys (
+    key TEXT NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    requests_per_minute INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    revoked INTEGER
+);
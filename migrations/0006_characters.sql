@@ -0,0 +1,6 @@
+-- Character codes and names as the rater knew them, rewritten from the config on startup
+CREATE TABLE characters (
+    id INTEGER NOT NULL PRIMARY KEY,
+    short TEXT NOT NULL,
+    name TEXT NOT NULL
+);
@@ -0,0 +1,79 @@
+-- The tables the rater keeps in Postgres when storage is set to it, the same columns as in the
+-- SQLite schema. Safe to run again, it only creates what's missing.
+
+CREATE TABLE IF NOT EXISTS config (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    rating_system TEXT
+);
+
+INSERT INTO config(id) VALUES (TRUE) ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS games (
+    timestamp BIGINT NOT NULL,
+    id_a BIGINT NOT NULL,
+    name_a TEXT NOT NULL,
+    char_a BIGINT NOT NULL,
+    platform_a BIGINT NOT NULL,
+    id_b BIGINT NOT NULL,
+    name_b TEXT NOT NULL,
+    char_b BIGINT NOT NULL,
+    platform_b BIGINT NOT NULL,
+    winner BIGINT NOT NULL,
+    game_floor BIGINT NOT NULL,
+    source TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (timestamp, id_a, id_b)
+);
+
+CREATE INDEX IF NOT EXISTS games_id_char_a ON games(id_a, char_a);
+CREATE INDEX IF NOT EXISTS games_id_char_b ON games(id_b, char_b);
+
+CREATE TABLE IF NOT EXISTS game_ratings (
+    timestamp BIGINT NOT NULL,
+    id_a BIGINT NOT NULL,
+    value_a DOUBLE PRECISION NOT NULL,
+    deviation_a DOUBLE PRECISION NOT NULL,
+    id_b BIGINT NOT NULL,
+    value_b DOUBLE PRECISION NOT NULL,
+    deviation_b DOUBLE PRECISION NOT NULL,
+    winner BIGINT NOT NULL,
+    valid BOOLEAN NOT NULL,
+    new_value_a DOUBLE PRECISION,
+    new_deviation_a DOUBLE PRECISION,
+    new_value_b DOUBLE PRECISION,
+    new_deviation_b DOUBLE PRECISION,
+    PRIMARY KEY (timestamp, id_a, id_b, winner)
+);
+
+CREATE TABLE IF NOT EXISTS players (
+    id BIGINT NOT NULL PRIMARY KEY,
+    floor BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    platform BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS player_ratings (
+    id BIGINT NOT NULL,
+    char_id BIGINT NOT NULL,
+    wins BIGINT NOT NULL,
+    losses BIGINT NOT NULL,
+    value DOUBLE PRECISION NOT NULL,
+    deviation DOUBLE PRECISION NOT NULL,
+    last_decay BIGINT NOT NULL,
+
+    top_rating_value DOUBLE PRECISION,
+    top_rating_deviation DOUBLE PRECISION,
+    top_rating_timestamp BIGINT,
+
+    top_defeated_id BIGINT,
+    top_defeated_char_id BIGINT,
+    top_defeated_name TEXT,
+    top_defeated_value DOUBLE PRECISION,
+    top_defeated_deviation DOUBLE PRECISION,
+    top_defeated_floor BIGINT,
+    top_defeated_timestamp BIGINT,
+
+    current_streak BIGINT NOT NULL DEFAULT 0,
+    longest_streak BIGINT NOT NULL DEFAULT 0,
+
+    PRIMARY KEY(id, char_id)
+);
@@ -2,16 +2,28 @@ DELETE FROM game_ratings;
 DELETE FROM players;
 DELETE FROM player_ratings;
 DELETE FROM daily_ratings;
+DELETE FROM player_rating_history;
 DELETE FROM player_matchups;
+DELETE FROM player_floor_matchups;
 DELETE FROM global_matchups;
 DELETE FROM top_1000_matchups;
 DELETE FROM proportional_matchups;
 DELETE FROM top_100_matchups;
+DELETE FROM floor_matchups;
+DELETE FROM floor_band_matchups;
+DELETE FROM floor_band_matchup_totals;
+UPDATE floor_band_progress SET next_rowid = 0;
+DELETE FROM matchup_signs;
+DELETE FROM top_upsets;
+DELETE FROM character_popularity;
 DELETE FROM player_names;
 DELETE FROM ranking_character;
 DELETE FROM ranking_global;
+DELETE FROM player_overall_ratings;
 DELETE FROM player_rating_distribution;
+DELETE FROM char_rating_distribution;
 DELETE FROM player_floor_distribution;
+DELETE FROM player_percentiles;
 
 DELETE FROM config;
-INSERT INTO config VALUES(1635717600, 0);
+INSERT INTO config VALUES(1635717600, NULL);
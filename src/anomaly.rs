@@ -0,0 +1,286 @@
+use anyhow::Result;
+use fxhash::FxHashMap;
+use rocket::serde::Serialize;
+use rusqlite::{params, Connection};
+
+use crate::cache;
+
+//Games looked at by every scan, long enough that farming and trading sessions show up whole
+pub const SCAN_WINDOW: i64 = 24 * 60 * 60;
+
+//Games against one opponent rated this far below, on average, before it counts as farming
+const FARM_GAMES: usize = 100;
+const FARM_RATING_GAP: f64 = 300.0;
+//Nobody finishes a game in less time than this, a few close pairs are upload jitter
+const MIN_GAME_GAP: i64 = 30;
+const FAST_GAMES: usize = 10;
+//A pair trading wins back and forth, the winner flipping after nearly every game
+const TRADE_GAMES: usize = 40;
+const TRADE_FLIP_SHARE: f64 = 0.9;
+
+//Players admins excluded from leaderboards and matchup stats, for `id NOT IN (...)`
+pub const EXCLUDED_IDS: &str = "SELECT id FROM flagged_players WHERE excluded";
+
+#[derive(Debug)]
+pub struct ScanGame {
+    pub timestamp: i64,
+    pub id_a: i64,
+    pub id_b: i64,
+    pub winner: i64,
+    pub value_a: f64,
+    pub value_b: f64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub id: i64,
+    pub reason: &'static str,
+    pub details: String,
+}
+
+//Flags whatever looks off in the last SCAN_WINDOW of rated games. Findings that come up again
+//only move last_seen along, so rescanning overlapping windows is harmless.
+pub fn scan(conn: &mut Connection, now: i64) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    let games = {
+        let mut stmt = tx.prepare(
+            "SELECT games.timestamp, games.id_a, games.id_b, games.winner, value_a, value_b
+            FROM games JOIN game_ratings ON
+                games.timestamp = game_ratings.timestamp
+                AND games.id_a = game_ratings.id_a
+                AND games.id_b = game_ratings.id_b
+            WHERE games.timestamp >= ?
+            ORDER BY games.timestamp ASC",
+        )?;
+        let games = stmt
+            .query_map(params![now - SCAN_WINDOW], |r| {
+                Ok(ScanGame {
+                    timestamp: r.get(0)?,
+                    id_a: r.get(1)?,
+                    id_b: r.get(2)?,
+                    winner: r.get(3)?,
+                    value_a: r.get(4)?,
+                    value_b: r.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        games
+    };
+
+    let findings = find_anomalies(&games);
+    for f in &findings {
+        //New findings for an already excluded player stay excluded
+        tx.execute(
+            "INSERT OR IGNORE INTO flagged_players
+            VALUES(?1, ?2, ?3, ?4, ?4,
+                EXISTS(SELECT 1 FROM flagged_players WHERE id = ?1 AND excluded))",
+            params![f.id, f.reason, f.details, now],
+        )?;
+        tx.execute(
+            "UPDATE flagged_players SET details = ?, last_seen = ? WHERE id = ? AND reason = ?",
+            params![f.details, now, f.id, f.reason],
+        )?;
+    }
+    tx.commit()?;
+
+    if !findings.is_empty() {
+        info!(
+            "Flagged {} anomalies in {} games",
+            findings.len(),
+            games.len()
+        );
+    }
+    Ok(findings.len())
+}
+
+//Games are expected in timestamp order
+pub fn find_anomalies(games: &[ScanGame]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    //Both directions of a pair are kept under the lower id first
+    let mut pairs = FxHashMap::<(i64, i64), Vec<&ScanGame>>::default();
+    let mut last_game = FxHashMap::<i64, i64>::default();
+    let mut fast_games = FxHashMap::<i64, usize>::default();
+    for g in games {
+        pairs
+            .entry((g.id_a.min(g.id_b), g.id_a.max(g.id_b)))
+            .or_default()
+            .push(g);
+
+        for id in [g.id_a, g.id_b] {
+            if let Some(last) = last_game.insert(id, g.timestamp) {
+                if g.timestamp - last < MIN_GAME_GAP {
+                    *fast_games.entry(id).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    for (id, count) in fast_games {
+        if count >= FAST_GAMES {
+            findings.push(Finding {
+                id,
+                reason: "impossible_frequency",
+                details: format!(
+                    "{} games less than {}s after the previous one",
+                    count, MIN_GAME_GAP
+                ),
+            });
+        }
+    }
+
+    for ((low_id, high_id), games) in pairs {
+        let rating_gap = games
+            .iter()
+            .map(|g| {
+                if g.id_a == low_id {
+                    g.value_a - g.value_b
+                } else {
+                    g.value_b - g.value_a
+                }
+            })
+            .sum::<f64>()
+            / games.len() as f64;
+
+        if games.len() >= FARM_GAMES && rating_gap.abs() >= FARM_RATING_GAP {
+            let (farmer, victim) = if rating_gap > 0.0 {
+                (low_id, high_id)
+            } else {
+                (high_id, low_id)
+            };
+            findings.push(Finding {
+                id: farmer,
+                reason: "farming",
+                details: format!(
+                    "{} games against {:X}, rated {:.0} lower on average",
+                    games.len(),
+                    victim,
+                    rating_gap.abs()
+                ),
+            });
+        }
+
+        if games.len() >= TRADE_GAMES {
+            let winner = |g: &ScanGame| if g.winner == 1 { g.id_a } else { g.id_b };
+            let flips = games
+                .windows(2)
+                .filter(|w| winner(w[0]) != winner(w[1]))
+                .count();
+            if flips as f64 >= (games.len() - 1) as f64 * TRADE_FLIP_SHARE {
+                for (id, other) in [(low_id, high_id), (high_id, low_id)] {
+                    findings.push(Finding {
+                        id,
+                        reason: "win_trading",
+                        details: format!(
+                            "Traded wins with {:X}, the winner changed {} times in {} games",
+                            other,
+                            flips,
+                            games.len()
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    findings.sort_by_key(|f| (f.id, f.reason));
+    findings
+}
+
+//Returns false if the player has never been flagged
+pub fn set_excluded(conn: &Connection, id: i64, excluded: bool) -> Result<bool> {
+    let changed = conn.execute(
+        "UPDATE flagged_players SET excluded = ? WHERE id = ?",
+        params![excluded, id],
+    )?;
+    cache::invalidate();
+    Ok(changed > 0)
+}
+
+pub fn flagged_players(conn: &Connection) -> Result<Vec<FlaggedPlayer>> {
+    let mut stmt = conn.prepare(
+        "SELECT flagged_players.id, players.name, reason, details, first_seen, last_seen, excluded
+        FROM flagged_players LEFT JOIN players ON players.id = flagged_players.id
+        ORDER BY last_seen DESC",
+    )?;
+    let flagged = stmt
+        .query_map([], |r| {
+            Ok(FlaggedPlayer {
+                id: format!("{:X}", r.get::<_, i64>(0)?),
+                name: r.get(1)?,
+                reason: r.get(2)?,
+                details: r.get(3)?,
+                first_seen: r.get(4)?,
+                last_seen: r.get(5)?,
+                excluded: r.get(6)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(flagged)
+}
+
+#[derive(Debug, Serialize)]
+pub struct FlaggedPlayer {
+    pub id: String,
+    pub name: Option<String>,
+    pub reason: String,
+    pub details: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub excluded: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn game(timestamp: i64, id_a: i64, id_b: i64, winner: i64, value_b: f64) -> ScanGame {
+        ScanGame {
+            timestamp,
+            id_a,
+            id_b,
+            winner,
+            value_a: 1500.0,
+            value_b,
+        }
+    }
+
+    #[test]
+    fn normal_play() {
+        //Two players running a long set with a few minutes per game and streaky results
+        let games = (0..60)
+            .map(|i| game(i * 300, 1, 2, 1 + (i / 3) % 2, 1450.0))
+            .collect::<Vec<_>>();
+        assert!(find_anomalies(&games).is_empty());
+    }
+
+    #[test]
+    fn farming_and_frequency() {
+        let games = (0..120)
+            .map(|i| game(i * 10, 1, 2, 1, 1000.0))
+            .collect::<Vec<_>>();
+        let reasons = find_anomalies(&games)
+            .into_iter()
+            .map(|f| (f.id, f.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                (1, "farming"),
+                (1, "impossible_frequency"),
+                (2, "impossible_frequency")
+            ]
+        );
+    }
+
+    #[test]
+    fn win_trading() {
+        let games = (0..50)
+            .map(|i| game(i * 120, 1, 2, 1 + i % 2, 1500.0))
+            .collect::<Vec<_>>();
+        let findings = find_anomalies(&games);
+        assert_eq!(findings.len(), 2);
+        assert!(findings.iter().all(|f| f.reason == "win_trading"));
+    }
+}
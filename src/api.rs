@@ -1,13 +1,23 @@
-use chrono::{Duration, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use fxhash::FxHashMap;
-use rocket::serde::{json::Json, Serialize};
+use rocket::{
+    http::{ContentType, Status},
+    request::{self, FromRequest, Request},
+    response::stream::{ByteStream, Event, EventStream},
+    serde::{json::Json, Serialize},
+    Shutdown, State,
+};
 use rusqlite::{named_params, params, Connection, OptionalExtension};
+use std::io::{self, Write};
+use tokio::sync::{broadcast, mpsc};
 
 use crate::{
-    glicko,
+    anomaly, cache, characters, claims, db, export, glicko,
     glicko::Rating,
+    live,
     rater::{self, RatedPlayer},
-    website::{self, RatingsDbConn},
+    watchlists,
+    website::RatingsDbConn,
 };
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
@@ -21,6 +31,17 @@ pub struct Stats {
     activity_1h: Activity,
 }
 
+#[derive(Serialize)]
+pub struct Health {
+    healthy: bool,
+    last_pull: Option<i64>,
+    last_rating_update: Option<i64>,
+    game_count: i64,
+    rating_lag_seconds: i64,
+    rating_period: i64,
+    schema_version: i64,
+}
+
 #[derive(Serialize)]
 pub struct Activity {
     players: i64,
@@ -41,6 +62,15 @@ fn to_platform_string(i: i64) -> &'static str {
     }
 }
 
+//For the leaderboards' ?platform= filter, anything unknown shows every platform
+pub fn from_platform_string(s: &str) -> Option<i64> {
+    match s.to_lowercase().as_str() {
+        "ps" => Some(1),
+        "pc" => Some(3),
+        _ => None,
+    }
+}
+
 impl Activity {
     fn calculate(conn: &mut Connection, time_offset: i64) -> Self {
         let t = Utc::now().timestamp() - time_offset;
@@ -157,6 +187,229 @@ pub async fn stats_inner(conn: &RatingsDbConn) -> Stats {
     .await
 }
 
+//Pulls run every minute, so this many seconds without one means the loop is stuck
+pub const PULL_STALE_SECONDS: i64 = 10 * 60;
+
+#[get("/health")]
+pub async fn health(conn: RatingsDbConn, config: &State<rater::Config>) -> (Status, Json<Health>) {
+    let runtime = rater::get_runtime_stats();
+    let rating_period = config.rating_period;
+    let health = conn
+        .run(move |conn| {
+            let game_count: i64 = conn
+                .query_row("SELECT COUNT(*) FROM games", [], |r| r.get(0))
+                .unwrap();
+            //The website can run on its own, so fall back to what the database knows
+            let last_pull = runtime.last_pull_time.or_else(|| {
+                conn.query_row("SELECT MAX(timestamp) FROM pull_coverage", [], |r| r.get(0))
+                    .unwrap()
+            });
+            let rating_lag_seconds = rater::rating_lag_seconds(conn);
+            let schema_version = db::schema_version(conn).unwrap();
+            let pulling = last_pull
+                .map(|t| Utc::now().timestamp() - t <= PULL_STALE_SECONDS)
+                .unwrap_or(false);
+
+            Health {
+                healthy: pulling && rater::is_caught_up(conn),
+                last_pull,
+                last_rating_update: runtime.last_rating_update_time,
+                game_count,
+                rating_lag_seconds,
+                rating_period,
+                schema_version,
+            }
+        })
+        .await;
+
+    let status = if health.healthy {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(health))
+}
+
+//Where the rate limiter sends requests that went over their limit
+#[get("/api/rate_limited")]
+pub async fn rate_limited() -> Status {
+    Status::TooManyRequests
+}
+
+//Requests carrying the configured admin token as `Authorization: Bearer <token>`
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let token = req
+            .rocket()
+            .state::<rater::Config>()
+            .and_then(|c| c.admin_token.as_deref());
+        let given = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+        match (token, given) {
+            (Some(token), Some(given)) if constant_time_eq(token.as_bytes(), given.as_bytes()) => {
+                request::Outcome::Success(Admin)
+            }
+            _ => request::Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+//Takes as long for every token of the same length, so the time a guess takes doesn't tell
+//how much of it was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+//Chunks of an export waiting to be sent before the database side has to wait
+const EXPORT_CHANNEL_CHUNKS: usize = 16;
+
+//Hands what the exporter writes over to the response stream
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(buf.to_vec()).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "Export download was dropped")
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[get("/api/admin/export/<table>?<format>&<from>&<to>")]
+pub async fn admin_export(
+    _admin: Admin,
+    config: &State<rater::Config>,
+    table: &str,
+    format: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> std::result::Result<(ContentType, ByteStream![Vec<u8>]), Status> {
+    let table: export::ExportTable = table.parse().map_err(|_| Status::NotFound)?;
+    let format: export::ExportFormat = format
+        .unwrap_or("csv")
+        .parse()
+        .map_err(|_| Status::BadRequest)?;
+    let from = from
+        .map(export::parse_date)
+        .transpose()
+        .map_err(|_| Status::BadRequest)?;
+    //The last day is included
+    let to = to
+        .map(|d| export::parse_date(d).map(|t| t + 24 * 60 * 60))
+        .transpose()
+        .map_err(|_| Status::BadRequest)?;
+
+    //The export waits on the client for as long as the download takes, so it gets its own
+    //connection instead of holding one of the pool's, and its read transaction, that long
+    let (tx, mut rx) = mpsc::channel(EXPORT_CHANNEL_CHUNKS);
+    let db_path = config.db_path.clone();
+    tokio::task::spawn_blocking(move || {
+        let result = db::open(&db_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|c| {
+                let out = io::BufWriter::new(ChannelWriter(tx));
+                export::export(&c, table, format, from, to, out)
+            });
+        if let Err(e) = result {
+            error!("Export of {:?} failed: {}", table, e);
+        }
+    });
+
+    let content_type = match format {
+        export::ExportFormat::Csv => ContentType::CSV,
+        export::ExportFormat::Parquet => ContentType::Binary,
+    };
+    Ok((
+        content_type,
+        ByteStream! {
+            while let Some(chunk) = rx.recv().await {
+                yield chunk;
+            }
+        },
+    ))
+}
+
+//Same as the merge-players command, with the ids in hex
+#[post("/api/admin/merge/<keep>/<merge>?<dry_run>")]
+pub async fn admin_merge(
+    _admin: Admin,
+    conn: RatingsDbConn,
+    keep: &str,
+    merge: &str,
+    dry_run: Option<bool>,
+) -> std::result::Result<Json<rater::MergeReport>, Status> {
+    let keep = claims::parse_id(keep).ok_or(Status::BadRequest)?;
+    let merge = claims::parse_id(merge).ok_or(Status::BadRequest)?;
+    if keep == merge {
+        return Err(Status::BadRequest);
+    }
+    let dry_run = dry_run.unwrap_or(false);
+
+    conn.run(move |c| rater::merge_players(c, keep, merge, dry_run, "api"))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Merging {:X} into {:X} failed: {}", merge, keep, e);
+            Status::InternalServerError
+        })
+}
+
+#[get("/api/admin/flags")]
+pub async fn admin_flags(
+    _admin: Admin,
+    conn: RatingsDbConn,
+) -> std::result::Result<Json<Vec<anomaly::FlaggedPlayer>>, Status> {
+    conn.run(|c| anomaly::flagged_players(c))
+        .await
+        .map(Json)
+        .map_err(|e| {
+            error!("Listing flagged players failed: {}", e);
+            Status::InternalServerError
+        })
+}
+
+//Leaves the player out of leaderboards and matchup stats, or puts them back with `include`
+#[post("/api/admin/flags/<player>/<action>")]
+pub async fn admin_flag_exclude(
+    _admin: Admin,
+    conn: RatingsDbConn,
+    player: &str,
+    action: &str,
+) -> Status {
+    let id = match claims::parse_id(player) {
+        Some(id) => id,
+        None => return Status::BadRequest,
+    };
+    let excluded = match action {
+        "exclude" => true,
+        "include" => false,
+        _ => return Status::NotFound,
+    };
+
+    match conn
+        .run(move |c| anomaly::set_excluded(c, id, excluded))
+        .await
+    {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::NotFound,
+        Err(e) => {
+            error!("Updating flags of {:X} failed: {}", id, e);
+            Status::InternalServerError
+        }
+    }
+}
+
 #[get("/api/daily_games?<length>")]
 pub async fn daily_games(
     conn: RatingsDbConn,
@@ -285,8 +538,8 @@ pub async fn daily_character_games(
                     .take(length as usize)
                     .map(|date| date.format("%Y-%m-%d").to_string())
                     .collect(),
-                website::CHAR_NAMES.iter().map(|c| c.0.to_owned()).collect(),
-                (0..website::CHAR_NAMES.len())
+                characters::all().iter().map(|c| c.0.to_owned()).collect(),
+                (0..characters::all().len())
                     .map(|c| {
                         then.iter_days()
                             .take(length as usize)
@@ -326,7 +579,7 @@ pub async fn add_hit(_conn: &RatingsDbConn, _page: String) {
     //.await;
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct RankingPlayer {
     pos: i32,
     id: String,
@@ -357,10 +610,10 @@ impl RankingPlayer {
             name,
             platform: to_platform_string(platform),
             id: format!("{:X}", rated_player.id),
-            character: website::CHAR_NAMES[rated_player.char_id as usize]
+            character: characters::all()[rated_player.char_id as usize]
                 .1
                 .to_owned(),
-            character_short: website::CHAR_NAMES[rated_player.char_id as usize]
+            character_short: characters::all()[rated_player.char_id as usize]
                 .0
                 .to_owned(),
             game_count: (rated_player.win_count + rated_player.loss_count) as i32,
@@ -372,37 +625,39 @@ impl RankingPlayer {
         }
     }
 }
-#[get("/api/top/all")]
-pub async fn top_all(conn: RatingsDbConn) -> Json<Vec<RankingPlayer>> {
-    Json(top_all_inner(&conn).await)
+#[get("/api/top/all?<platform>")]
+pub async fn top_all(conn: RatingsDbConn, platform: Option<&str>) -> Json<Vec<RankingPlayer>> {
+    Json(top_all_inner(&conn, platform.and_then(from_platform_string)).await)
 }
 
 #[get("/api/player_rating/<player>")]
-pub async fn player_rating_all(conn: RatingsDbConn, player: &str) -> Json<Vec<Rating>> {
+pub async fn player_rating_all(conn: RatingsDbConn, player: &str) -> Option<Json<Vec<Rating>>> {
     let id = i64::from_str_radix(&player, 16).unwrap();
-    let mut res = vec![Rating::default(); website::CHAR_NAMES.len()];
-    Json(
-        conn.run(move |conn| {
-            let mut stmt = conn
-                .prepare(
-                    "SELECT char_id, value, deviation 
+    let mut res = vec![Rating::default(); characters::all().len()];
+    conn.run(move |conn| {
+        if rater::is_hidden(conn, id) {
+            return None;
+        }
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT char_id, value, deviation 
         FROM player_ratings
         WHERE id = ?",
-                )
-                .unwrap();
+            )
+            .unwrap();
 
-            let mut rows = stmt.query(params![id]).unwrap();
-            while let Some(row) = rows.next().unwrap() {
-                let char_id: usize = row.get(0).unwrap();
-                res[char_id] = Rating::new(row.get(1).unwrap(), row.get(2).unwrap());
-            }
+        let mut rows = stmt.query(params![id]).unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            let char_id: usize = row.get(0).unwrap();
+            res[char_id] = Rating::new(row.get(1).unwrap(), row.get(2).unwrap());
+        }
 
-            res
-        })
-        .await,
-    )
+        Some(Json(res))
+    })
+    .await
 
-    //for char_id in 0..website::CHAR_NAMES.len() {
+    //for char_id in 0..characters::all().len() {
     //    let conn.run(move |conn| {
     //        conn
     //            .query_row(
@@ -423,11 +678,15 @@ pub async fn player_rating(
     character_short: &str,
 ) -> Option<Json<Rating>> {
     let id = i64::from_str_radix(&player, 16).unwrap();
-    if let Some(char_id) = website::CHAR_NAMES
+    if let Some(char_id) = characters::all()
         .iter()
         .position(|(c, _)| *c == character_short)
     {
         conn.run(move |conn| {
+            if rater::is_hidden(conn, id) {
+                return None;
+            }
+
             if let Some((value, deviation)) = conn
                 .query_row(
                     "SELECT value, deviation
@@ -450,14 +709,216 @@ pub async fn player_rating(
     }
 }
 
+#[derive(Serialize)]
+pub struct PlayerV1 {
+    id: String,
+    name: String,
+    floor: i64,
+    characters: Vec<rater::PlayerProfileCharacter>,
+    recent_games: Vec<rater::RecentGame>,
+}
+
+#[get("/api/v1/player/<player>")]
+pub async fn player_v1(
+    conn: RatingsDbConn,
+    config: &State<rater::Config>,
+    player: &str,
+) -> Option<Json<PlayerV1>> {
+    let id = i64::from_str_radix(player, 16).ok()?;
+    let low_deviation = config.low_deviation;
+    conn.run(move |conn| {
+        let profile = rater::get_player_profile(conn, id, low_deviation)?;
+        let recent_games = rater::get_recent_games(conn, id, 100);
+
+        Some(Json(PlayerV1 {
+            id: format!("{:X}", profile.id),
+            name: profile.name,
+            floor: profile.floor,
+            characters: profile.characters,
+            recent_games,
+        }))
+    })
+    .await
+}
+
+const RECENT_GAMES_MAX_COUNT: usize = 500;
+
+#[get("/api/v1/player/<player>/recent?<count>")]
+pub async fn player_recent_v1(
+    conn: RatingsDbConn,
+    player: &str,
+    count: Option<usize>,
+) -> Option<Json<Vec<rater::RecentGame>>> {
+    let id = i64::from_str_radix(player, 16).ok()?;
+    let count = count.unwrap_or(20).min(RECENT_GAMES_MAX_COUNT);
+    conn.run(move |conn| {
+        if rater::is_hidden(conn, id) {
+            None
+        } else {
+            Some(Json(rater::get_recent_games(conn, id, count)))
+        }
+    })
+    .await
+}
+
+const OPPONENTS_MAX_COUNT: usize = 100;
+
+#[get("/api/v1/player/<player>/opponents?<count>")]
+pub async fn player_opponents_v1(
+    conn: RatingsDbConn,
+    player: &str,
+    count: Option<usize>,
+) -> Option<Json<Vec<rater::OpponentBreakdown>>> {
+    let id = i64::from_str_radix(player, 16).ok()?;
+    let count = count.unwrap_or(20).min(OPPONENTS_MAX_COUNT);
+    conn.run(move |conn| {
+        if rater::is_hidden(conn, id) {
+            None
+        } else {
+            Some(Json(rater::get_opponent_breakdown(conn, id, count)))
+        }
+    })
+    .await
+}
+
+#[get("/api/v1/player/<player>/<character_short>/history")]
+pub async fn player_history_v1(
+    conn: RatingsDbConn,
+    player: &str,
+    character_short: &str,
+) -> Option<Json<Vec<rater::RatingSnapshot>>> {
+    let id = i64::from_str_radix(player, 16).ok()?;
+    let char_id = characters::by_short(character_short)?;
+    conn.run(move |conn| {
+        if rater::is_hidden(conn, id) {
+            None
+        } else {
+            Some(Json(rater::get_rating_history(conn, id, char_id)))
+        }
+    })
+    .await
+}
+
+#[get("/api/v1/watchlist/<watchlist>/feed?<count>")]
+pub async fn watchlist_feed_v1(
+    conn: RatingsDbConn,
+    watchlist: &str,
+    count: Option<usize>,
+) -> Option<Json<Vec<rater::WatchlistGame>>> {
+    if !watchlists::is_valid_id(watchlist) {
+        return None;
+    }
+    let watchlist = watchlist.to_owned();
+    let count = count.unwrap_or(50).min(RECENT_GAMES_MAX_COUNT);
+    Some(Json(
+        conn.run(move |conn| rater::get_watchlist_feed(conn, &watchlist, count))
+            .await,
+    ))
+}
+
+//Server-sent events for each game as it's pulled and each finished rating period. Only
+//carries anything when the website runs in the same process as the rater.
+#[get("/api/v1/live")]
+pub fn live_feed(mut shutdown: Shutdown) -> EventStream![] {
+    let mut events = live::subscribe();
+    EventStream! {
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&event).event(event.name());
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FeedGame {
+    timestamp: String,
+    floor: String,
+    player_id: String,
+    player_name: String,
+    character: &'static str,
+    character_short: &'static str,
+    opponent_id: Option<String>,
+    opponent_name: String,
+    opponent_character: &'static str,
+    opponent_character_short: &'static str,
+    won: bool,
+    rating_value: i64,
+    rating_change: Option<String>,
+    rating_change_class: &'static str,
+}
+
+//The watchlist feed as the website shows it
+pub async fn watchlist_feed_inner(
+    conn: &RatingsDbConn,
+    watchlist: String,
+    count: usize,
+) -> Vec<FeedGame> {
+    conn.run(move |conn| rater::get_watchlist_feed(conn, &watchlist, count))
+        .await
+        .into_iter()
+        .map(|g| {
+            let game = g.game;
+            FeedGame {
+                timestamp: DateTime::from_timestamp(game.timestamp, 0)
+                    .unwrap()
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string(),
+                floor: stringify_floor(game.floor),
+                player_id: format!("{:X}", g.player_id),
+                player_name: g.player_name,
+                character: characters::all()[game.char_id as usize].1,
+                character_short: characters::all()[game.char_id as usize].0,
+                opponent_id: game.opponent_id.map(|id| format!("{:X}", id)),
+                opponent_name: game.opponent_name,
+                opponent_character: characters::all()[game.opponent_char_id as usize].1,
+                opponent_character_short: characters::all()[game.opponent_char_id as usize].0,
+                won: game.won,
+                rating_value: game.value.round() as i64,
+                rating_change: game.rating_change.map(|c| format!("{:+.1}", c)),
+                rating_change_class: match game.rating_change {
+                    Some(c) if c > 0.0 => "rating-up",
+                    Some(c) if c < 0.0 => "rating-down",
+                    _ => "rating-same",
+                },
+            }
+        })
+        .collect()
+}
+
+const POPULARITY_MAX_DAYS: i64 = 365;
+
+#[get("/api/v1/popularity/<character_short>?<days>")]
+pub async fn popularity_history_v1(
+    conn: RatingsDbConn,
+    character_short: &str,
+    days: Option<i64>,
+) -> Option<Json<Vec<rater::PopularitySnapshot>>> {
+    let char_id = characters::by_short(character_short)?;
+    let days = days.unwrap_or(30).clamp(1, POPULARITY_MAX_DAYS);
+    let since = (Utc::now() - Duration::days(days)).timestamp();
+    let history = conn
+        .run(move |conn| rater::get_popularity_history(conn, char_id, since))
+        .await;
+    Some(Json(history))
+}
+
 #[get("/api/accuracy/<player>/<character_short>")]
 pub async fn player_rating_accuracy(
     conn: RatingsDbConn,
+    config: &State<rater::Config>,
     player: &str,
     character_short: &str,
 ) -> Option<Json<Vec<f64>>> {
     let id = i64::from_str_radix(&player, 16).unwrap();
-    if let Some(char_id) = website::CHAR_NAMES
+    let low_deviation = config.low_deviation;
+    if let Some(char_id) = characters::all()
         .iter()
         .position(|(c, _)| *c == character_short)
     {
@@ -477,8 +938,8 @@ pub async fn player_rating_accuracy(
                 WHERE 
                     games.id_a = :id 
                     AND games.char_a = :char_id 
-                    AND game_ratings.deviation_a< 75.0
-                    AND game_ratings.deviation_b < 75.0
+                    AND game_ratings.deviation_a < :deviation
+                    AND game_ratings.deviation_b < :deviation
 
                 UNION
 
@@ -501,6 +962,7 @@ pub async fn player_rating_accuracy(
                 .query(named_params! {
                     ":id" : id,
                     ":char_id": char_id,
+                    ":deviation": low_deviation,
                 })
                 .unwrap();
 
@@ -533,48 +995,361 @@ pub async fn player_rating_accuracy(
     }
 }
 
-pub async fn top_all_inner(conn: &RatingsDbConn) -> Vec<RankingPlayer> {
-    conn.run(|c| {
-        let mut stmt = c
-            .prepare(
-                "SELECT 
-                    player_ratings.id as id, char_id, 
-                    wins, losses, 
-                    value, deviation, last_decay, 
-                    name, platform, vip_status, cheater_status, hidden_status
-                 FROM ranking_global
-                 NATURAL JOIN player_ratings
-                 NATURAL JOIN players
-                 LEFT JOIN vip_status ON vip_status.id = player_ratings.id
-                 LEFT JOIN cheater_status ON cheater_status.id = player_ratings.id
-                 LEFT JOIN hidden_status ON hidden_status.id = player_ratings.id
-                 LIMIT 100",
-            )
-            .unwrap();
-        let mut rows = stmt.query(params![]).unwrap();
+pub async fn top_all_inner(conn: &RatingsDbConn, platform: Option<i64>) -> Vec<RankingPlayer> {
+    cache::get_or_insert(format!("top_all:{:?}", platform), || {
+        conn.run(move |c| {
+            let mut stmt = c
+                .prepare(
+                    "SELECT 
+                        player_ratings.id as id, char_id, 
+                        wins, losses, 
+                        value, deviation, last_decay, 
+                        name, platform, vip_status, cheater_status, hidden_status
+                     FROM ranking_global
+                     NATURAL JOIN player_ratings
+                     NATURAL JOIN players
+                     LEFT JOIN vip_status ON vip_status.id = player_ratings.id
+                     LEFT JOIN cheater_status ON cheater_status.id = player_ratings.id
+                     LEFT JOIN hidden_status ON hidden_status.id = player_ratings.id
+                     WHERE ?1 IS NULL OR platform = ?1
+                     ORDER BY global_rank
+                     LIMIT 100",
+                )
+                .unwrap();
+            let mut rows = stmt.query(params![platform]).unwrap();
 
-        let mut res = Vec::with_capacity(100);
-        let mut i = 1;
+            let mut res = Vec::with_capacity(100);
+            let mut i = 1;
 
-        while let Some(row) = rows.next().unwrap() {
-            let name = row.get("name").unwrap();
-            let platform = row.get("platform").unwrap();
-            let vip_status = row.get("vip_status").unwrap();
-            let cheater_status = row.get("cheater_status").unwrap();
-            let hidden_status = row.get("hidden_status").unwrap();
-            res.push(RankingPlayer::from_db(
-                i,
-                name,
-                platform,
-                vip_status,
-                cheater_status,
-                hidden_status,
-                RatedPlayer::from_row(row),
-            ));
-            i += 1;
-        }
+            while let Some(row) = rows.next().unwrap() {
+                let name = row.get("name").unwrap();
+                let platform = row.get("platform").unwrap();
+                let vip_status = row.get("vip_status").unwrap();
+                let cheater_status = row.get("cheater_status").unwrap();
+                let hidden_status = row.get("hidden_status").unwrap();
+                res.push(RankingPlayer::from_db(
+                    i,
+                    name,
+                    platform,
+                    vip_status,
+                    cheater_status,
+                    hidden_status,
+                    RatedPlayer::from_row(row),
+                ));
+                i += 1;
+            }
 
-        res
+            res
+        })
+    })
+    .await
+}
+
+#[derive(Clone, Serialize)]
+pub struct StreakPlayer {
+    pos: i32,
+    id: String,
+    name: String,
+    platform: &'static str,
+    character: &'static str,
+    character_short: &'static str,
+    vip_status: Option<String>,
+    rating_value: i64,
+    rating_deviation: i64,
+    current_streak: i64,
+    longest_streak: i64,
+}
+
+#[get("/api/top/streaks")]
+pub async fn top_streaks(conn: RatingsDbConn) -> Json<Vec<StreakPlayer>> {
+    Json(top_streaks_inner(&conn).await)
+}
+
+//Longest running win streaks, leaving out flagged and hidden players
+pub async fn top_streaks_inner(conn: &RatingsDbConn) -> Vec<StreakPlayer> {
+    cache::get_or_insert("top_streaks".to_owned(), || {
+        conn.run(|c| {
+            let mut stmt = c
+                .prepare(&format!(
+                    "SELECT
+                        player_ratings.id, char_id, value, deviation,
+                        current_streak, longest_streak,
+                        name, platform, vip_status
+                    FROM player_ratings
+                    NATURAL JOIN players
+                    LEFT JOIN vip_status ON vip_status.id = player_ratings.id
+                    LEFT JOIN cheater_status ON cheater_status.id = player_ratings.id
+                    LEFT JOIN hidden_status ON hidden_status.id = player_ratings.id
+                    WHERE current_streak > 0 AND cheater_status IS NULL AND hidden_status IS NULL
+                        AND player_ratings.id NOT IN ({})
+                    ORDER BY current_streak DESC
+                    LIMIT 100",
+                    anomaly::EXCLUDED_IDS
+                ))
+                .unwrap();
+            let mut rows = stmt.query([]).unwrap();
+
+            let mut res = Vec::with_capacity(100);
+            let mut pos = 1;
+            while let Some(row) = rows.next().unwrap() {
+                let char_id: usize = row.get(1).unwrap();
+                let value: f64 = row.get(2).unwrap();
+                let deviation: f64 = row.get(3).unwrap();
+                res.push(StreakPlayer {
+                    pos,
+                    id: format!("{:X}", row.get::<_, i64>(0).unwrap()),
+                    name: row.get(6).unwrap(),
+                    platform: to_platform_string(row.get(7).unwrap()),
+                    character: characters::all()[char_id].1,
+                    character_short: characters::all()[char_id].0,
+                    vip_status: row.get(8).unwrap(),
+                    rating_value: value.round() as i64,
+                    rating_deviation: (deviation * 2.0).round() as i64,
+                    current_streak: row.get(4).unwrap(),
+                    longest_streak: row.get(5).unwrap(),
+                });
+                pos += 1;
+            }
+
+            res
+        })
+    })
+    .await
+}
+
+#[derive(Clone, Serialize)]
+pub struct OverallPlayer {
+    pos: i32,
+    id: String,
+    name: String,
+    platform: &'static str,
+    best_character: &'static str,
+    best_character_short: &'static str,
+    vip_status: Option<String>,
+    rating_value: i64,
+    rating_deviation: i64,
+    char_count: i64,
+}
+
+#[get("/api/top/overall")]
+pub async fn top_overall(conn: RatingsDbConn) -> Json<Vec<OverallPlayer>> {
+    Json(top_overall_inner(&conn).await)
+}
+
+//Players by their rating combined over every settled character
+pub async fn top_overall_inner(conn: &RatingsDbConn) -> Vec<OverallPlayer> {
+    cache::get_or_insert("top_overall".to_owned(), || {
+        conn.run(|c| {
+            let mut stmt = c
+                .prepare(
+                    "SELECT
+                        player_overall_ratings.id, value, deviation, char_count, best_char_id,
+                        name, platform, vip_status
+                    FROM player_overall_ratings
+                    NATURAL JOIN players
+                    LEFT JOIN vip_status ON vip_status.id = player_overall_ratings.id
+                    LEFT JOIN cheater_status ON cheater_status.id = player_overall_ratings.id
+                    LEFT JOIN hidden_status ON hidden_status.id = player_overall_ratings.id
+                    WHERE cheater_status IS NULL AND hidden_status IS NULL
+                    ORDER BY value DESC
+                    LIMIT 100",
+                )
+                .unwrap();
+            let mut rows = stmt.query([]).unwrap();
+
+            let mut res = Vec::with_capacity(100);
+            let mut pos = 1;
+            while let Some(row) = rows.next().unwrap() {
+                let value: f64 = row.get(1).unwrap();
+                let deviation: f64 = row.get(2).unwrap();
+                let char_id: usize = row.get(4).unwrap();
+                res.push(OverallPlayer {
+                    pos,
+                    id: format!("{:X}", row.get::<_, i64>(0).unwrap()),
+                    name: row.get(5).unwrap(),
+                    platform: to_platform_string(row.get(6).unwrap()),
+                    best_character: characters::all()[char_id].1,
+                    best_character_short: characters::all()[char_id].0,
+                    vip_status: row.get(7).unwrap(),
+                    rating_value: value.round() as i64,
+                    rating_deviation: (deviation * 2.0).round() as i64,
+                    char_count: row.get(3).unwrap(),
+                });
+                pos += 1;
+            }
+
+            res
+        })
+    })
+    .await
+}
+
+#[derive(Clone, Serialize)]
+pub struct PeakPlayer {
+    pos: i32,
+    id: String,
+    name: String,
+    platform: &'static str,
+    character: &'static str,
+    character_short: &'static str,
+    vip_status: Option<String>,
+    peak_value: i64,
+    peak_deviation: i64,
+    peak_date: String,
+    rating_value: i64,
+    rating_deviation: i64,
+}
+
+#[get("/api/top/peaks")]
+pub async fn top_peaks(conn: RatingsDbConn) -> Json<Vec<PeakPlayer>> {
+    Json(top_peaks_inner(&conn).await)
+}
+
+//Highest ratings ever reached, whether or not the player is still around to defend them
+pub async fn top_peaks_inner(conn: &RatingsDbConn) -> Vec<PeakPlayer> {
+    cache::get_or_insert("top_peaks".to_owned(), || {
+        conn.run(|c| {
+            let mut stmt = c
+                .prepare(&format!(
+                    "SELECT
+                        player_ratings.id, char_id, top_rating_value, top_rating_deviation,
+                        top_rating_timestamp, value, deviation, name, platform, vip_status
+                    FROM player_ratings
+                    NATURAL JOIN players
+                    LEFT JOIN vip_status ON vip_status.id = player_ratings.id
+                    WHERE top_rating_value IS NOT NULL
+                        AND player_ratings.id NOT IN (SELECT id FROM cheater_status)
+                        AND player_ratings.id NOT IN (SELECT id FROM hidden_status)
+                        AND player_ratings.id NOT IN ({})
+                    ORDER BY top_rating_value DESC
+                    LIMIT 100",
+                    anomaly::EXCLUDED_IDS
+                ))
+                .unwrap();
+            let mut rows = stmt.query([]).unwrap();
+
+            let mut res = Vec::with_capacity(100);
+            let mut pos = 1;
+            while let Some(row) = rows.next().unwrap() {
+                let char_id: usize = row.get(1).unwrap();
+                let peak_value: f64 = row.get(2).unwrap();
+                let peak_deviation: f64 = row.get(3).unwrap();
+                let value: f64 = row.get(5).unwrap();
+                let deviation: f64 = row.get(6).unwrap();
+                res.push(PeakPlayer {
+                    pos,
+                    id: format!("{:X}", row.get::<_, i64>(0).unwrap()),
+                    name: row.get(7).unwrap(),
+                    platform: to_platform_string(row.get(8).unwrap()),
+                    character: characters::all()[char_id].1,
+                    character_short: characters::all()[char_id].0,
+                    vip_status: row.get(9).unwrap(),
+                    peak_value: peak_value.round() as i64,
+                    peak_deviation: (peak_deviation * 2.0).round() as i64,
+                    peak_date: DateTime::from_timestamp(row.get(4).unwrap(), 0)
+                        .unwrap()
+                        .format("%Y-%m-%d")
+                        .to_string(),
+                    rating_value: value.round() as i64,
+                    rating_deviation: (deviation * 2.0).round() as i64,
+                });
+                pos += 1;
+            }
+
+            res
+        })
+    })
+    .await
+}
+
+#[derive(Clone, Serialize)]
+pub struct UpsetPlayer {
+    id: String,
+    name: String,
+    platform: &'static str,
+    character: &'static str,
+    character_short: &'static str,
+    vip_status: Option<String>,
+    rating_value: i64,
+    rating_deviation: i64,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Upset {
+    timestamp: String,
+    floor: String,
+    winner: UpsetPlayer,
+    loser: UpsetPlayer,
+    win_chance: f64,
+}
+
+const UPSETS_MAX_COUNT: usize = 100;
+
+#[get("/api/v1/upsets?<count>")]
+pub async fn upsets_v1(conn: RatingsDbConn, count: Option<usize>) -> Json<Vec<Upset>> {
+    let count = count.unwrap_or(UPSETS_MAX_COUNT).min(UPSETS_MAX_COUNT);
+    Json(top_upsets_inner(&conn, count).await)
+}
+
+//Most unlikely wins from the last calc_top_upsets run, skipping anyone hidden since
+pub async fn top_upsets_inner(conn: &RatingsDbConn, count: usize) -> Vec<Upset> {
+    cache::get_or_insert(format!("top_upsets:{}", count), || {
+        conn.run(move |c| {
+            let mut stmt = c
+                .prepare(
+                    "SELECT
+                        timestamp, game_floor, expected,
+                        winner_id, winner_char, winner_name, winner_value, winner_deviation,
+                        winner_players.platform, winner_vip.vip_status,
+                        loser_id, loser_char, loser_name, loser_value, loser_deviation,
+                        loser_players.platform, loser_vip.vip_status
+                    FROM top_upsets
+                    JOIN players AS winner_players ON winner_players.id = winner_id
+                    JOIN players AS loser_players ON loser_players.id = loser_id
+                    LEFT JOIN vip_status AS winner_vip ON winner_vip.id = winner_id
+                    LEFT JOIN vip_status AS loser_vip ON loser_vip.id = loser_id
+                    LEFT JOIN hidden_status AS winner_hidden ON winner_hidden.id = winner_id
+                    LEFT JOIN hidden_status AS loser_hidden ON loser_hidden.id = loser_id
+                    WHERE winner_hidden.id IS NULL AND loser_hidden.id IS NULL
+                    ORDER BY expected ASC
+                    LIMIT ?",
+                )
+                .unwrap();
+            let mut rows = stmt.query(params![count as i64]).unwrap();
+
+            let upset_player = |row: &rusqlite::Row, offset: usize| -> UpsetPlayer {
+                let char_id: usize = row.get(offset + 1).unwrap();
+                let value: f64 = row.get(offset + 3).unwrap();
+                let deviation: f64 = row.get(offset + 4).unwrap();
+                UpsetPlayer {
+                    id: format!("{:X}", row.get::<_, i64>(offset).unwrap()),
+                    name: row.get(offset + 2).unwrap(),
+                    platform: to_platform_string(row.get(offset + 5).unwrap()),
+                    character: characters::all()[char_id].1,
+                    character_short: characters::all()[char_id].0,
+                    vip_status: row.get(offset + 6).unwrap(),
+                    rating_value: value.round() as i64,
+                    rating_deviation: (deviation * 2.0).round() as i64,
+                }
+            };
+
+            let mut res = Vec::with_capacity(count);
+            while let Some(row) = rows.next().unwrap() {
+                let expected: f64 = row.get(2).unwrap();
+                res.push(Upset {
+                    timestamp: DateTime::from_timestamp(row.get(0).unwrap(), 0)
+                        .unwrap()
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string(),
+                    floor: stringify_floor(row.get(1).unwrap()),
+                    winner: upset_player(row, 3),
+                    loser: upset_player(row, 10),
+                    win_chance: (expected * 1000.0).round() / 10.0,
+                });
+            }
+
+            res
+        })
     })
     .await
 }
@@ -664,7 +1439,7 @@ pub async fn player_lookup(conn: RatingsDbConn, name: String) -> Json<Vec<Player
                 let mut characters = Vec::new();
                 while let Some(row) = rows.next().unwrap() {
                     characters.push(PlayerLookupCharacter {
-                        shortname: website::CHAR_NAMES[row.get::<_, usize>(0).unwrap()].0,
+                        shortname: characters::all()[row.get::<_, usize>(0).unwrap()].0,
                         rating: row.get::<_, f64>(1).unwrap().round() as i64,
                         deviation: (row.get::<_, f64>(2).unwrap() * 2.0).round() as i64,
                         game_count: row.get(3).unwrap(),
@@ -709,6 +1484,32 @@ pub async fn search_exact(conn: RatingsDbConn, name: String) -> Json<Vec<SearchR
     Json(search_inner(&conn, name, true).await)
 }
 
+#[derive(Serialize)]
+pub struct SearchResultV1 {
+    id: String,
+    name: String,
+    matched_alias: Option<String>,
+    game_count: i64,
+}
+
+#[get("/api/v1/search?<name>")]
+pub async fn search_v1(conn: RatingsDbConn, name: String) -> Json<Vec<SearchResultV1>> {
+    Json(
+        conn.run(move |conn| {
+            rater::search_players_by_name(conn, &name, 100)
+                .into_iter()
+                .map(|p| SearchResultV1 {
+                    id: format!("{:X}", p.id),
+                    name: p.name,
+                    matched_alias: p.matched_alias,
+                    game_count: p.game_count,
+                })
+                .collect()
+        })
+        .await,
+    )
+}
+
 pub async fn search_inner(
     conn: &RatingsDbConn,
     search: String,
@@ -750,10 +1551,10 @@ pub async fn search_inner(
                 name: row.get("name").unwrap(),
                 platform: to_platform_string(platform),
                 id: format!("{:X}", row.get::<_, i64>("id").unwrap()),
-                character: website::CHAR_NAMES[row.get::<_, usize>("char_id").unwrap()]
+                character: characters::all()[row.get::<_, usize>("char_id").unwrap()]
                     .1
                     .to_owned(),
-                character_short: website::CHAR_NAMES[row.get::<_, usize>("char_id").unwrap()]
+                character_short: characters::all()[row.get::<_, usize>("char_id").unwrap()]
                     .0
                     .to_owned(),
                 rating_value: rating.value.round() as i64,
@@ -773,55 +1574,94 @@ pub async fn search_inner(
     .await
 }
 
-#[get("/api/top/<char_id>")]
-pub async fn top_char(conn: RatingsDbConn, char_id: i64) -> Json<Vec<RankingPlayer>> {
-    Json(top_char_inner(&conn, char_id).await)
+//ranking_character holds the top 1000 for each character
+pub const TOP_CHAR_MAX_PAGE_SIZE: i64 = 500;
+
+#[get("/api/top/<char_id>?<page>&<page_size>&<platform>")]
+pub async fn top_char(
+    conn: RatingsDbConn,
+    char_id: i64,
+    page: Option<i64>,
+    page_size: Option<i64>,
+    platform: Option<&str>,
+) -> Json<Vec<RankingPlayer>> {
+    Json(
+        top_char_inner(
+            &conn,
+            char_id,
+            page.unwrap_or(0),
+            page_size.unwrap_or(100),
+            platform.and_then(from_platform_string),
+        )
+        .await,
+    )
 }
 
-pub async fn top_char_inner(conn: &RatingsDbConn, char_id: i64) -> Vec<RankingPlayer> {
-    conn.run(move |c| {
-        let mut stmt = c
-            .prepare(
-                "SELECT 
-                    player_ratings.id as id, char_id, 
-                    wins, losses, 
-                    value, deviation, last_decay, 
-                    name, platform, vip_status, cheater_status, hidden_status
-                 FROM ranking_character
-                 NATURAL JOIN player_ratings
-                 NATURAL JOIN players
-                 LEFT JOIN vip_status ON vip_status.id = player_ratings.id
-                 LEFT JOIN cheater_status ON cheater_status.id = player_ratings.id
-                 LEFT JOIN hidden_status ON hidden_status.id = player_ratings.id
-                 WHERE char_id = ?
-                 LIMIT 100
-                 ",
-            )
-            .unwrap();
-        let mut rows = stmt.query(params![char_id]).unwrap();
+//Filtered by platform the ranks count only that platform's players
+pub async fn top_char_inner(
+    conn: &RatingsDbConn,
+    char_id: i64,
+    page: i64,
+    page_size: i64,
+    platform: Option<i64>,
+) -> Vec<RankingPlayer> {
+    let page_size = page_size.clamp(1, TOP_CHAR_MAX_PAGE_SIZE);
+    let offset = page.max(0) * page_size;
+    cache::get_or_insert(
+        format!(
+            "top_char:{}:{}:{}:{:?}",
+            char_id, offset, page_size, platform
+        ),
+        || {
+            conn.run(move |c| {
+                let mut stmt = c
+                    .prepare(
+                        "SELECT 
+                        player_ratings.id as id, char_id, 
+                        wins, losses, 
+                        value, deviation, last_decay, 
+                        name, platform, vip_status, cheater_status, hidden_status,
+                        ROW_NUMBER() OVER (ORDER BY ranking_character.character_rank)
+                            AS character_rank
+                     FROM ranking_character
+                     NATURAL JOIN player_ratings
+                     NATURAL JOIN players
+                     LEFT JOIN vip_status ON vip_status.id = player_ratings.id
+                     LEFT JOIN cheater_status ON cheater_status.id = player_ratings.id
+                     LEFT JOIN hidden_status ON hidden_status.id = player_ratings.id
+                     WHERE char_id = ?1 AND (?2 IS NULL OR platform = ?2)
+                     ORDER BY ranking_character.character_rank
+                     LIMIT ?3 OFFSET ?4
+                     ",
+                    )
+                    .unwrap();
+                let mut rows = stmt
+                    .query(params![char_id, platform, page_size, offset])
+                    .unwrap();
 
-        let mut res = Vec::with_capacity(100);
-        let mut i = 1;
-        while let Some(row) = rows.next().unwrap() {
-            let name = row.get("name").unwrap();
-            let platform = row.get("platform").unwrap();
-            let vip_status = row.get("vip_status").unwrap();
-            let cheater_status = row.get("cheater_status").unwrap();
-            let hidden_status = row.get("hidden_status").unwrap();
-            res.push(RankingPlayer::from_db(
-                i,
-                name,
-                platform,
-                vip_status,
-                cheater_status,
-                hidden_status,
-                RatedPlayer::from_row(row),
-            ));
-            i += 1;
-        }
+                let mut res = Vec::with_capacity(page_size as usize);
+                while let Some(row) = rows.next().unwrap() {
+                    let name = row.get("name").unwrap();
+                    let platform = row.get("platform").unwrap();
+                    let vip_status = row.get("vip_status").unwrap();
+                    let cheater_status = row.get("cheater_status").unwrap();
+                    let hidden_status = row.get("hidden_status").unwrap();
+                    let character_rank = row.get("character_rank").unwrap();
+                    res.push(RankingPlayer::from_db(
+                        character_rank,
+                        name,
+                        platform,
+                        vip_status,
+                        cheater_status,
+                        hidden_status,
+                        RatedPlayer::from_row(row),
+                    ));
+                }
 
-        res
-    })
+                res
+            })
+        },
+    )
     .await
 }
 
@@ -842,6 +1682,7 @@ pub struct PlayerDataChar {
     cheater_status: Option<String>,
     other_names: Option<Vec<String>>,
     other_characters: Vec<OtherPlayerCharacter>,
+    claimed: Option<claims::ClaimedProfile>,
     data: PlayerCharacterData,
 }
 
@@ -861,6 +1702,7 @@ struct PlayerCharacterData {
     rating_deviation: i64,
     global_rank: Option<i32>,
     character_rank: Option<i32>,
+    top_percent: Option<f64>,
 
     top_rating_value: Option<i64>,
     top_rating_deviation: Option<i64>,
@@ -874,6 +1716,9 @@ struct PlayerCharacterData {
     top_defeated_floor: Option<String>,
     top_defeated_timestamp: Option<String>,
 
+    current_streak: i64,
+    longest_streak: i64,
+
     win_rate: f64,
     game_count: i32,
     matchups: Vec<PlayerMatchup>,
@@ -1122,7 +1967,7 @@ pub async fn get_player_data_char(
             info!(
                 "Loading data for {} ({})",
                 name,
-                website::CHAR_NAMES[char_id as usize].0
+                characters::all()[char_id as usize].0
             );
 
             if hidden_status.is_some() {
@@ -1135,6 +1980,8 @@ pub async fn get_player_data_char(
 
             let character_data = get_player_character_data(conn, id, char_id).unwrap()?;
 
+            let claimed = claims::get_claimed_profile(conn, id);
+
             Some(PlayerDataChar {
                 id: format!("{:X}", id),
                 name,
@@ -1143,6 +1990,7 @@ pub async fn get_player_data_char(
                 cheater_status,
                 other_characters,
                 other_names,
+                claimed,
                 data: character_data,
             })
         } else {
@@ -1191,8 +2039,8 @@ fn get_player_other_characters(conn: &Connection, id: i64) -> Vec<OtherPlayerCha
         let game_count: i32 = row.get::<_, i32>(1).unwrap() + row.get::<_, i32>(2).unwrap();
         let rating = Rating::new(row.get(3).unwrap(), row.get(4).unwrap());
 
-        let character_name = website::CHAR_NAMES[char_id].1.to_owned();
-        let character_shortname = website::CHAR_NAMES[char_id].0.to_owned();
+        let character_name = characters::all()[char_id].1.to_owned();
+        let character_shortname = characters::all()[char_id].0.to_owned();
         other_characters.push(OtherPlayerCharacter {
             character_name,
             character_shortname,
@@ -1229,6 +2077,9 @@ fn get_player_character_data(
         top_defeated_timestamp,
         global_rank,
         character_rank,
+        current_streak,
+        longest_streak,
+        top_percent,
     ) = match conn.query_row(
         "SELECT 
             wins, losses, value, deviation, 
@@ -1238,7 +2089,9 @@ fn get_player_character_data(
             top_defeated_value, top_defeated_deviation, top_defeated_floor,
             top_defeated_timestamp,
 
-            global_rank, character_rank
+            global_rank, character_rank,
+            current_streak, longest_streak,
+            top_percent
         FROM player_ratings
             LEFT JOIN ranking_global ON
                 ranking_global.id = player_ratings.id AND
@@ -1246,6 +2099,9 @@ fn get_player_character_data(
             LEFT JOIN ranking_character ON
                 ranking_character.id = player_ratings.id AND
                 ranking_character.char_id = player_ratings.char_id
+            LEFT JOIN player_percentiles ON
+                player_percentiles.id = player_ratings.id AND
+                player_percentiles.char_id = player_ratings.char_id
             WHERE player_ratings.id=? AND player_ratings.char_id=?",
         params![id, char_id],
         |row| {
@@ -1269,6 +2125,9 @@ fn get_player_character_data(
                 //rank
                 row.get::<_, Option<i32>>(14).unwrap(),
                 row.get::<_, Option<i32>>(15).unwrap(),
+                row.get::<_, i64>(16).unwrap(),
+                row.get::<_, i64>(17).unwrap(),
+                row.get::<_, Option<f64>>(18).unwrap(),
             ))
         },
     ) {
@@ -1277,7 +2136,7 @@ fn get_player_character_data(
         Err(err) => return Err(err.into()),
     };
     {
-        let character_name = website::CHAR_NAMES[char_id as usize].1.to_owned();
+        let character_name = characters::all()[char_id as usize].1.to_owned();
 
         let matchups = {
             let mut stmt = conn
@@ -1304,7 +2163,7 @@ fn get_player_character_data(
                 let losses: i64 = row.get(4).unwrap();
                 let rating_offset = rating_value - value;
                 matchups.push(PlayerMatchup {
-                    character: website::CHAR_NAMES[opp_char_id].1.to_owned(),
+                    character: characters::all()[opp_char_id].1.to_owned(),
                     game_count: wins + losses,
                     win_rate: (100.0 * wins as f64 / (wins + losses) as f64).round(),
                     rating_offset: format!("{:+.0} ±{:.0}", rating_offset, 2.0 * rating_deviation),
@@ -1341,7 +2200,7 @@ fn get_player_character_data(
                     .to_string()
             }),
             top_defeated_id: top_defeated_id.map(|id| format!("{:X}", id)),
-            top_defeated_char_id: top_defeated_char_id.map(|id| website::CHAR_NAMES[id as usize].0),
+            top_defeated_char_id: top_defeated_char_id.map(|id| characters::all()[id as usize].0),
             top_defeated_name,
             top_defeated_value: top_defeated_value.map(|r| r.round() as i64),
             top_defeated_deviation: top_defeated_deviation.map(|r| (2.0 * r).round() as i64),
@@ -1351,9 +2210,12 @@ fn get_player_character_data(
                     .format("%Y-%m-%d")
                     .to_string()
             }),
+            current_streak,
+            longest_streak,
             matchups,
             character_rank,
             global_rank,
+            top_percent: top_percent.map(|p| (p * 10.0).ceil() / 10.0),
         }))
     }
 }
@@ -1417,8 +2279,8 @@ impl RawPlayerSet {
             opponent_name: self.opponent_name,
             opponent_platform: self.opponent_platform,
             opponent_id: format!("{:X}", self.opponent_id),
-            opponent_character_short: website::CHAR_NAMES[self.opponent_char as usize].0,
-            opponent_character: website::CHAR_NAMES[self.opponent_char as usize].1,
+            opponent_character_short: characters::all()[self.opponent_char as usize].0,
+            opponent_character: characters::all()[self.opponent_char as usize].1,
 
             opponent_rating_value: self.opponent_value.round() as i64,
             opponent_rating_deviation: (2.0 * self.opponent_deviation).round() as i64,
@@ -1581,16 +2443,18 @@ fn add_ungrouped_set(
     });
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct CharacterMatchups {
     name: String,
     matchups: Vec<Matchup>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Matchup {
     matchup: String,
     win_rate: f64,
+    win_rate_low: f64,
+    win_rate_high: f64,
     game_count: i64,
     rating_delta: String,
     expected: f64,
@@ -1598,6 +2462,25 @@ pub struct Matchup {
     evaluation: &'static str,
 }
 
+//95% Wilson score interval for the win rate, clamped to [0, 1]
+fn wilson_interval(wins: i64, losses: i64) -> (f64, f64) {
+    const Z: f64 = 1.96;
+    let n = (wins + losses) as f64;
+    if n == 0.0 {
+        return (0.0, 1.0);
+    }
+
+    let p = wins as f64 / n;
+    let center = p + Z * Z / (2.0 * n);
+    let margin = Z * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt();
+    let denominator = 1.0 + Z * Z / n;
+
+    (
+        ((center - margin) / denominator).max(0.0),
+        ((center + margin) / denominator).min(1.0),
+    )
+}
+
 fn get_evaluation(r: f64, game_count: i64) -> &'static str {
     if game_count < MATCHUP_MIN_GAMES {
         return "none";
@@ -1621,68 +2504,121 @@ fn get_evaluation(r: f64, game_count: i64) -> &'static str {
 }
 
 pub async fn get_matchups(conn: &RatingsDbConn, table: &'static str) -> Vec<CharacterMatchups> {
-    conn.run(move |conn| {
-        let tx = conn.transaction().unwrap();
-        let mut all_matchups = FxHashMap::default();
+    cache::get_or_insert(format!("matchups:{}", table), || {
+        conn.run(move |conn| {
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT char_id, opp_char_id, rating_value, rating_deviation, wins, losses FROM {}",
+                    table
+                ))
+                .unwrap();
 
-        let mut stmt = tx
-            .prepare(&format!(
-                "SELECT char_id, opp_char_id, rating_value, rating_deviation, wins, losses FROM {}",
-                table
-            ))
-            .unwrap();
+            matchups_from_rows(stmt.query([]).unwrap())
+        })
+    })
+    .await
+}
 
-        let mut rows = stmt.query([]).unwrap();
+#[derive(Clone, Serialize)]
+pub struct FloorBandMatchups {
+    name: &'static str,
+    matchups: Vec<CharacterMatchups>,
+}
 
-        while let Some(row) = rows.next().unwrap() {
-            let char_id: i64 = row.get(0).unwrap();
-            let opp_char_id: i64 = row.get(1).unwrap();
-            let rating_value: f64 = row.get(2).unwrap();
-            let rating_deviation: f64 = row.get(3).unwrap();
-            let wins: i64 = row.get(4).unwrap();
-            let losses: i64 = row.get(5).unwrap();
-
-            all_matchups.insert(
-                (char_id, opp_char_id),
-                (rating_value, rating_deviation, wins, losses),
-            );
-        }
+pub async fn get_floor_band_matchups(conn: &RatingsDbConn) -> Vec<FloorBandMatchups> {
+    cache::get_or_insert("matchups:floor_bands".to_owned(), || {
+        conn.run(move |conn| {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT char_id, opp_char_id, rating_value, rating_deviation, wins, losses
+                    FROM floor_band_matchups
+                    WHERE band = ?",
+                )
+                .unwrap();
 
-        (0..website::CHAR_NAMES.len() as i64)
-            .map(|c| CharacterMatchups {
-                name: website::CHAR_NAMES[c as usize].1.to_owned(),
-                matchups: (0..website::CHAR_NAMES.len() as i64)
-                    .map(|o| {
-                        let (own_value, own_deviation, wins, losses) =
-                            *all_matchups.get(&(c, o)).unwrap_or(&(1500.0, 350.0, 0, 0));
-
-                        let (opp_value, opp_deviation, ..) =
-                            *all_matchups.get(&(o, c)).unwrap_or(&(1500.0, 350.0, 0, 0));
-
-                        let expected = Rating::new(own_value, own_deviation)
-                            .expected(Rating::new(opp_value, opp_deviation));
-
-                        Matchup {
-                            matchup: format!(
-                                "{} vs {}",
-                                website::CHAR_NAMES[c as usize].0,
-                                website::CHAR_NAMES[o as usize].0
-                            ),
-                            win_rate: (100.0 * wins as f64 / (wins + losses) as f64).round(),
-                            game_count: wins + losses,
-                            rating_delta: format!("{:+.0}", own_value - opp_value),
-                            expected: (100.0 * expected).round(),
-                            evaluation: get_evaluation(expected, wins + losses),
-                            suspicious: wins + losses < MATCHUP_MIN_GAMES,
-                        }
-                    })
-                    .collect(),
-            })
-            .collect()
+            rater::FLOOR_BANDS
+                .iter()
+                .map(|(band, name, ..)| FloorBandMatchups {
+                    name,
+                    matchups: matchups_from_rows(stmt.query(params![band]).unwrap()),
+                })
+                .collect()
+        })
     })
     .await
 }
 
+//Rows are (char_id, opp_char_id, rating_value, rating_deviation, wins, losses)
+fn matchups_from_rows(mut rows: rusqlite::Rows) -> Vec<CharacterMatchups> {
+    let mut all_matchups = FxHashMap::default();
+    while let Some(row) = rows.next().unwrap() {
+        let char_id: i64 = row.get(0).unwrap();
+        let opp_char_id: i64 = row.get(1).unwrap();
+        let rating_value: f64 = row.get(2).unwrap();
+        let rating_deviation: f64 = row.get(3).unwrap();
+        let wins: i64 = row.get(4).unwrap();
+        let losses: i64 = row.get(5).unwrap();
+
+        all_matchups.insert(
+            (char_id, opp_char_id),
+            (rating_value, rating_deviation, wins, losses),
+        );
+    }
+
+    (0..characters::all().len() as i64)
+        .map(|c| CharacterMatchups {
+            name: characters::all()[c as usize].1.to_owned(),
+            matchups: (0..characters::all().len() as i64)
+                .map(|o| {
+                    let (own_value, own_deviation, wins, losses) =
+                        *all_matchups.get(&(c, o)).unwrap_or(&(1500.0, 350.0, 0, 0));
+
+                    let (opp_value, opp_deviation, ..) =
+                        *all_matchups.get(&(o, c)).unwrap_or(&(1500.0, 350.0, 0, 0));
+
+                    let expected = Rating::new(own_value, own_deviation)
+                        .expected(Rating::new(opp_value, opp_deviation));
+                    let (win_rate_low, win_rate_high) = wilson_interval(wins, losses);
+
+                    Matchup {
+                        matchup: format!(
+                            "{} vs {}",
+                            characters::all()[c as usize].0,
+                            characters::all()[o as usize].0
+                        ),
+                        win_rate: (100.0 * wins as f64 / (wins + losses) as f64).round(),
+                        win_rate_low: (100.0 * win_rate_low).round(),
+                        win_rate_high: (100.0 * win_rate_high).round(),
+                        game_count: wins + losses,
+                        rating_delta: format!("{:+.0}", own_value - opp_value),
+                        expected: (100.0 * expected).round(),
+                        evaluation: get_evaluation(expected, wins + losses),
+                        suspicious: wins + losses < MATCHUP_MIN_GAMES,
+                    }
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+//Same charts as the matchups page, including the 95% win rate intervals
+#[get("/api/v1/matchups/<kind>")]
+pub async fn matchups_v1(conn: RatingsDbConn, kind: &str) -> Option<Json<Vec<CharacterMatchups>>> {
+    let table = match kind {
+        "global" => "global_matchups",
+        "top_1000" => "top_1000_matchups",
+        "proportional" => "proportional_matchups",
+        "top_100" => "top_100_matchups",
+        _ => return None,
+    };
+    Some(Json(get_matchups(&conn, table).await))
+}
+
+#[get("/api/v1/matchups/floors")]
+pub async fn matchups_floors_v1(conn: RatingsDbConn) -> Json<Vec<FloorBandMatchups>> {
+    Json(get_floor_band_matchups(&conn).await)
+}
+
 #[derive(Serialize)]
 pub struct FloorPlayers {
     floor: String,
@@ -1786,17 +2722,13 @@ pub async fn player_ratings_distribution(conn: &RatingsDbConn) -> Vec<RatingPlay
             let player_count: i64 = row.get(2).unwrap();
             let player_count_cum: i64 = row.get(3).unwrap();
 
-            res.push(RatingPlayers {
+            res.push(RatingPlayers::new(
                 min_rating,
                 max_rating,
                 player_count,
-                player_percentage: (1000.0 * player_count as f64 / total_players as f64).round()
-                    / 10.0,
                 player_count_cum,
-                player_percentage_cum: (1000.0 * player_count_cum as f64 / total_players as f64)
-                    .round()
-                    / 10.0,
-            });
+                total_players,
+            ));
         }
 
         res
@@ -1804,6 +2736,141 @@ pub async fn player_ratings_distribution(conn: &RatingsDbConn) -> Vec<RatingPlay
     .await
 }
 
+impl RatingPlayers {
+    fn new(
+        min_rating: i64,
+        max_rating: i64,
+        player_count: i64,
+        player_count_cum: i64,
+        total_players: i64,
+    ) -> Self {
+        Self {
+            min_rating,
+            max_rating,
+            player_count,
+            player_percentage: (1000.0 * player_count as f64 / total_players as f64).round() / 10.0,
+            player_count_cum,
+            player_percentage_cum: (1000.0 * player_count_cum as f64 / total_players as f64)
+                .round()
+                / 10.0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CharRatingDistribution {
+    character: &'static str,
+    character_short: &'static str,
+    player_count: i64,
+    ratings: Vec<RatingPlayers>,
+}
+
+#[get("/api/stats/rating_distribution")]
+pub async fn char_rating_distribution(conn: RatingsDbConn) -> Json<Vec<CharRatingDistribution>> {
+    Json(char_ratings_distribution(&conn).await)
+}
+
+//Same buckets as the overall distribution, with percentages of each character's own players
+pub async fn char_ratings_distribution(conn: &RatingsDbConn) -> Vec<CharRatingDistribution> {
+    conn.run(move |conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT
+                char_id, min_rating, max_rating, player_count, player_count_cum
+                FROM char_rating_distribution
+                ORDER BY char_id ASC, min_rating ASC",
+            )
+            .unwrap();
+
+        let mut buckets = FxHashMap::<usize, Vec<(i64, i64, i64, i64)>>::default();
+        let mut rows = stmt.query([]).unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            buckets.entry(row.get(0).unwrap()).or_default().push((
+                row.get(1).unwrap(),
+                row.get(2).unwrap(),
+                row.get(3).unwrap(),
+                row.get(4).unwrap(),
+            ));
+        }
+
+        characters::all()
+            .iter()
+            .enumerate()
+            .map(|(char_id, (short, name))| {
+                let buckets = buckets.remove(&char_id).unwrap_or_default();
+                //The cumulative count only grows, so the last bucket holds the total
+                let total_players = buckets.last().map(|b| b.3).unwrap_or(0);
+                CharRatingDistribution {
+                    character: name,
+                    character_short: short,
+                    player_count: total_players,
+                    ratings: buckets
+                        .into_iter()
+                        .map(|(min_rating, max_rating, player_count, player_count_cum)| {
+                            RatingPlayers::new(
+                                min_rating,
+                                max_rating,
+                                player_count,
+                                player_count_cum,
+                                total_players,
+                            )
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    })
+    .await
+}
+
+#[derive(Serialize)]
+pub struct ActivityHeatmap {
+    floor: Option<i64>,
+    utc_offset: i64,
+    //Seven rows from Sunday, each with 24 hourly game counts, in the requested UTC offset
+    game_counts: Vec<Vec<i64>>,
+}
+
+//When the game is busy, over the last two weeks. Without a floor every floor is summed up.
+#[get("/api/stats/activity?<floor>&<utc_offset>")]
+pub async fn activity_heatmap(
+    conn: RatingsDbConn,
+    floor: Option<i64>,
+    utc_offset: Option<i64>,
+) -> Option<Json<ActivityHeatmap>> {
+    let utc_offset = utc_offset.unwrap_or(0);
+    if floor.is_some_and(|f| !rater::FLOORS.contains(&f)) || !(-12..=14).contains(&utc_offset) {
+        return None;
+    }
+
+    conn.run(move |conn| {
+        let mut stmt = conn
+            .prepare(
+                "SELECT day_of_week, hour, SUM(game_count) FROM game_activity
+                WHERE ?1 IS NULL OR floor = ?1
+                GROUP BY day_of_week, hour",
+            )
+            .unwrap();
+        let mut rows = stmt.query(params![floor]).unwrap();
+
+        let mut game_counts = vec![vec![0; 24]; 7];
+        while let Some(row) = rows.next().unwrap() {
+            let day: i64 = row.get(0).unwrap();
+            let hour: i64 = row.get(1).unwrap();
+            //Shifting the hour can move a slot into the previous or next day
+            let slot = (day * 24 + hour + utc_offset).rem_euclid(7 * 24);
+            game_counts[(slot / 24) as usize][(slot % 24) as usize] = row.get(2).unwrap();
+        }
+
+        Some(Json(ActivityHeatmap {
+            floor,
+            utc_offset,
+            game_counts,
+        }))
+    })
+    .await
+}
+
 #[derive(Serialize)]
 pub struct RankCharacterPopularities {
     rating_min: usize,
@@ -1833,7 +2900,7 @@ pub async fn character_popularity(
                 .unwrap();
 
             let mut rows = stmt.query([]).unwrap();
-            let mut v = Vec::with_capacity(website::CHAR_NAMES.len());
+            let mut v = Vec::with_capacity(characters::all().len());
 
             while let Some(row) = rows.next().unwrap() {
                 let popularity: f64 = row.get(0).unwrap();
@@ -1866,7 +2933,7 @@ pub async fn character_popularity(
                     } else {
                         3000
                     },
-                    characters: Vec::with_capacity(website::CHAR_NAMES.len()),
+                    characters: Vec::with_capacity(characters::all().len()),
                 };
 
                 while let Some(row) = rows.next().unwrap() {
@@ -1925,7 +2992,7 @@ pub async fn get_fraud(conn: &RatingsDbConn) -> Vec<FraudStats> {
         let mut res = Vec::new();
         while let Some(row) = rows.next().unwrap() {
             res.push(FraudStats {
-                character_name: website::CHAR_NAMES[row.get::<_, usize>(0).unwrap()].1,
+                character_name: characters::all()[row.get::<_, usize>(0).unwrap()].1,
                 player_count: row.get(1).unwrap(),
                 average_offset: format!("{:+.1}", (row.get::<_, f64>(2).unwrap())),
             });
@@ -1947,7 +3014,7 @@ pub async fn get_fraud_higher_rated(conn: &RatingsDbConn) -> Vec<FraudStats> {
         let mut res = Vec::new();
         while let Some(row) = rows.next().unwrap() {
             res.push(FraudStats {
-                character_name: website::CHAR_NAMES[row.get::<_, usize>(0).unwrap()].1,
+                character_name: characters::all()[row.get::<_, usize>(0).unwrap()].1,
                 player_count: row.get(1).unwrap(),
                 average_offset: format!("{:+.1}", (row.get::<_, f64>(2).unwrap())),
             });
@@ -1969,7 +3036,7 @@ pub async fn get_fraud_highest_rated(conn: &RatingsDbConn) -> Vec<FraudStats> {
         let mut res = Vec::new();
         while let Some(row) = rows.next().unwrap() {
             res.push(FraudStats {
-                character_name: website::CHAR_NAMES[row.get::<_, usize>(0).unwrap()].1,
+                character_name: characters::all()[row.get::<_, usize>(0).unwrap()].1,
                 player_count: row.get(1).unwrap(),
                 average_offset: format!("{:+.1}", (row.get::<_, f64>(2).unwrap())),
             });
@@ -2031,9 +3098,11 @@ pub struct RatingDiffStats {
 #[get("/api/player_rating_experience/<player_id>")]
 pub async fn rating_experience_player(
     conn: RatingsDbConn,
+    config: &State<rater::Config>,
     player_id: &str,
 ) -> Json<RatingDiffStats> {
     let id = i64::from_str_radix(player_id, 16).unwrap();
+    let low_deviation = config.low_deviation;
 
     Json(
         conn.run(move |conn| {
@@ -2048,7 +3117,7 @@ pub async fn rating_experience_player(
                 .unwrap();
 
             let mut rows = stmt
-                .query(params![rater::LOW_DEVIATION, rater::LOW_DEVIATION, id, id,])
+                .query(params![low_deviation, low_deviation, id, id,])
                 .unwrap();
 
             let mut counts: FxHashMap<i64, i64> = Default::default();
@@ -2169,9 +3238,11 @@ pub async fn rating_experience_player(
 #[get("/api/rating_experience?<min_rating>&<max_rating>")]
 pub async fn rating_experience(
     conn: RatingsDbConn,
+    config: &State<rater::Config>,
     min_rating: i64,
     max_rating: i64,
 ) -> Json<RatingDiffStats> {
+    let low_deviation = config.low_deviation;
     Json(
         conn.run(move |conn| {
             let min_rating_glicko2 = (min_rating as f64 - 1500.0) / 173.718;
@@ -2189,8 +3260,8 @@ pub async fn rating_experience(
 
             let mut rows = stmt
                 .query(params![
-                    rater::LOW_DEVIATION,
-                    rater::LOW_DEVIATION,
+                    low_deviation,
+                    low_deviation,
                     min_rating_glicko2,
                     max_rating_glicko2,
                     min_rating_glicko2,
@@ -2321,7 +3392,11 @@ pub struct FloorRatingDistributions {
 }
 
 #[get("/api/floor_rating_distribution")]
-pub async fn floor_rating_distribution(conn: RatingsDbConn) -> Json<FloorRatingDistributions> {
+pub async fn floor_rating_distribution(
+    conn: RatingsDbConn,
+    config: &State<rater::Config>,
+) -> Json<FloorRatingDistributions> {
+    let low_deviation = config.low_deviation;
     Json(
         conn.run(move |conn| {
             let mut stmt = conn
@@ -2332,7 +3407,7 @@ pub async fn floor_rating_distribution(conn: RatingsDbConn) -> Json<FloorRatingD
                 )
                 .unwrap();
 
-            let mut rows = stmt.query(params![rater::LOW_DEVIATION]).unwrap();
+            let mut rows = stmt.query(params![low_deviation]).unwrap();
 
             let mut totals: FxHashMap<i64, FxHashMap<i64, i64>> = Default::default();
             let mut overall: FxHashMap<i64, i64> = Default::default();
@@ -0,0 +1,90 @@
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use std::{
+    any::Any,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+//Leaderboards and matchups only change when ratings or rankings do, which clears the cache.
+//The website can also run without the rater in the same process, then this is how stale
+//they can get.
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+//Keys come from request parameters like the page, so this keeps them from growing the cache
+//without bound. Past it, results are only cached again once older ones expire.
+const CACHE_MAX_ENTRIES: usize = 1000;
+
+lazy_static! {
+    static ref CACHE: Mutex<FxHashMap<String, Entry>> = Mutex::new(FxHashMap::default());
+}
+
+//Bumped on every invalidation, so a query that started before one doesn't put its now
+//outdated result back in
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    generation: u64,
+    stored: Instant,
+    value: Arc<dyn Any + Send + Sync>,
+}
+
+//Returns the cached result for `key`, or runs `query` and caches what it returns. Keys are
+//the route and its parameters, e.g. `top_char:3:0:100:None`.
+pub async fn get_or_insert<T, F, Fut>(key: String, query: F) -> T
+where
+    T: Clone + Send + Sync + 'static,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    let generation = GENERATION.load(Ordering::SeqCst);
+    if let Some(entry) = CACHE.lock().unwrap().get(&key) {
+        if entry.generation == generation && entry.stored.elapsed() < CACHE_TTL {
+            if let Some(value) = entry.value.downcast_ref::<T>() {
+                return value.clone();
+            }
+        }
+    }
+
+    let value = query().await;
+    if GENERATION.load(Ordering::SeqCst) == generation {
+        let mut cache = CACHE.lock().unwrap();
+        if cache.len() >= CACHE_MAX_ENTRIES && !cache.contains_key(&key) {
+            cache.retain(|_, e| e.stored.elapsed() < CACHE_TTL);
+        }
+        if cache.len() < CACHE_MAX_ENTRIES || cache.contains_key(&key) {
+            cache.insert(
+                key,
+                Entry {
+                    generation,
+                    stored: Instant::now(),
+                    value: Arc::new(value.clone()),
+                },
+            );
+        }
+    }
+    value
+}
+
+//Called whenever ratings, rankings or matchups were written
+pub fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    CACHE.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn invalidation() {
+        let key = || "test:invalidation".to_owned();
+        assert_eq!(get_or_insert(key(), || async { 1 }).await, 1);
+        assert_eq!(get_or_insert(key(), || async { 2 }).await, 1);
+        invalidate();
+        assert_eq!(get_or_insert(key(), || async { 3 }).await, 3);
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use rocket::serde::Deserialize;
+use rusqlite::{params, Connection};
+use std::sync::RwLock;
+
+//Characters as (short name, name), indexed by the character code the replay API uses. New
+//characters and renames can go in the `characters` config list without a new build.
+const BUILTIN: &[(&str, &str)] = &[
+    ("SO", "Sol"),
+    ("KY", "Ky"),
+    ("MA", "May"),
+    ("AX", "Axl"),
+    ("CH", "Chipp"),
+    ("PO", "Potemkin"),
+    ("FA", "Faust"),
+    ("MI", "Millia"),
+    ("ZA", "Zato-1"),
+    ("RA", "Ramlethal"),
+    ("LE", "Leo"),
+    ("NA", "Nagoriyuki"),
+    ("GI", "Giovanna"),
+    ("AN", "Anji"),
+    ("IN", "I-No"),
+    ("GO", "Goldlewis"),
+    ("JC", "Jack-O'"),
+    ("HA", "Happy Chaos"),
+    ("BA", "Baiken"),
+    ("TE", "Testament"),
+    ("BI", "Bridget"),
+    ("SI", "Sin"),
+];
+
+//One entry of the `characters` config list. An id that's already known renames that
+//character, the next id after the known ones adds a character.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct CharacterEntry {
+    pub id: i64,
+    pub short: String,
+    pub name: String,
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<&'static [(&'static str, &'static str)]> = RwLock::new(BUILTIN);
+}
+
+//Puts the configured characters on top of the built in ones for the rest of the process.
+//Everything before this, like tests, sees the built in characters.
+pub fn init(entries: &[CharacterEntry]) -> Result<()> {
+    let characters = build(entries)?
+        .into_iter()
+        .map(|(short, name)| {
+            let short: &'static str = Box::leak(short.into_boxed_str());
+            let name: &'static str = Box::leak(name.into_boxed_str());
+            (short, name)
+        })
+        .collect::<Vec<_>>();
+    *REGISTRY.write().unwrap() = Box::leak(characters.into_boxed_slice());
+    Ok(())
+}
+
+fn build(entries: &[CharacterEntry]) -> Result<Vec<(String, String)>> {
+    let mut characters = BUILTIN
+        .iter()
+        .map(|(short, name)| (short.to_string(), name.to_string()))
+        .collect::<Vec<_>>();
+
+    let mut entries = entries.to_vec();
+    entries.sort_by_key(|e| e.id);
+    for e in entries {
+        //Character codes index the matchup and distribution tables, so there can't be gaps
+        match usize::try_from(e.id) {
+            Ok(id) if id < characters.len() => characters[id] = (e.short, e.name),
+            Ok(id) if id == characters.len() => characters.push((e.short, e.name)),
+            _ => bail!(
+                "Character {} ({}) would leave a gap after the {} known characters",
+                e.id,
+                e.name,
+                characters.len()
+            ),
+        }
+    }
+
+    for (i, (short, _)) in characters.iter().enumerate() {
+        if characters[..i].iter().any(|(s, _)| s == short) {
+            bail!("Two characters are called {}", short);
+        }
+    }
+    Ok(characters)
+}
+
+pub fn all() -> &'static [(&'static str, &'static str)] {
+    *REGISTRY.read().unwrap()
+}
+
+pub fn count() -> usize {
+    all().len()
+}
+
+pub fn by_short(short: &str) -> Option<i64> {
+    all()
+        .iter()
+        .position(|(s, _)| *s == short)
+        .map(|id| id as i64)
+}
+
+//Mirrors the registry in the characters table, so the database and its snapshots can be
+//read without knowing the config that wrote them
+pub fn sync_table(conn: &mut Connection) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    for (id, (short, name)) in all().iter().enumerate() {
+        tx.execute(
+            "REPLACE INTO characters VALUES(?, ?, ?)",
+            params![id as i64, short, name],
+        )?;
+    }
+    tx.execute(
+        "DELETE FROM characters WHERE id >= ?",
+        params![count() as i64],
+    )?;
+    tx.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(id: i64, short: &str, name: &str) -> CharacterEntry {
+        CharacterEntry {
+            id,
+            short: short.to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn rename_and_add() {
+        let n = BUILTIN.len() as i64;
+        let characters = build(&[entry(n, "AS", "Asuka"), entry(0, "SO", "Sol Badguy")]).unwrap();
+        assert_eq!(characters.len(), BUILTIN.len() + 1);
+        assert_eq!(characters[0].1, "Sol Badguy");
+        assert_eq!(characters[n as usize].0, "AS");
+
+        assert!(build(&[entry(n + 1, "AS", "Asuka")]).is_err());
+        assert!(build(&[entry(n, "SO", "Sol again")]).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use rocket::serde::Serialize;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::cache;
+
+const MAX_DISPLAY_NAME: usize = 32;
+const MAX_PRONOUNS: usize = 16;
+const MAX_LINKS: usize = 3;
+
+//Player ids are shown in hex everywhere else, so the admin tools take them the same way
+pub fn parse_id(id: &str) -> Option<i64> {
+    i64::from_str_radix(id, 16).ok()
+}
+
+pub(crate) fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    getrandom::getrandom(&mut buf).unwrap();
+    hex::encode_upper(buf)
+}
+
+//Starts a claim, returning the code the player has to put in their R-code comment. A new
+//claim replaces a pending code, so nobody can hold a player's page by starting a claim for
+//it. Approved claims can't be restarted from the website.
+pub fn start_claim(conn: &Connection, id: i64) -> Option<String> {
+    let exists: bool = conn
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM players WHERE id = ?)",
+            params![id],
+            |r| r.get(0),
+        )
+        .unwrap();
+    if !exists || is_claimed(conn, id) {
+        return None;
+    }
+
+    let code = format!("RU-{}", random_hex(4));
+    conn.execute(
+        "REPLACE INTO claimed_players(id, code, requested) VALUES(?, ?, strftime('%s', 'now'))",
+        params![id, code],
+    )
+    .unwrap();
+
+    Some(code)
+}
+
+fn is_claimed(conn: &Connection, id: i64) -> bool {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM claimed_players WHERE id = ? AND token IS NOT NULL)",
+        params![id],
+        |r| r.get(0),
+    )
+    .unwrap()
+}
+
+pub fn pending_claims(conn: &Connection) -> Vec<PendingClaim> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT claimed_players.id, name, code, requested
+            FROM claimed_players JOIN players ON players.id = claimed_players.id
+            WHERE token IS NULL
+            ORDER BY requested ASC",
+        )
+        .unwrap();
+
+    stmt.query_map([], |r| {
+        Ok(PendingClaim {
+            id: format!("{:X}", r.get::<_, i64>(0)?),
+            name: r.get(1)?,
+            code: r.get(2)?,
+            requested: r.get(3)?,
+        })
+    })
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+//Run by an admin once they've checked the code is in the player's comment in game.
+//Returns the token the player uses to edit their profile. Only approves the code that was
+//checked, in case someone started a new claim for the player in between.
+pub fn approve_claim(conn: &Connection, id: i64, code: &str) -> Option<String> {
+    let token = random_hex(16);
+    let count = conn
+        .execute(
+            "UPDATE claimed_players SET token = ? WHERE id = ? AND code = ? AND token IS NULL",
+            params![token, id, code],
+        )
+        .unwrap();
+
+    if count == 1 {
+        Some(token)
+    } else {
+        None
+    }
+}
+
+//Checked by the player with the code they were given. Once the claim is approved the token
+//is handed out with the first check, which also retires the code so it can't be read again.
+pub fn claim_status(conn: &Connection, id: i64, code: &str) -> Option<ClaimStatus> {
+    if code.is_empty() {
+        return None;
+    }
+    let token: Option<String> = conn
+        .query_row(
+            "SELECT token FROM claimed_players WHERE id = ? AND code = ?",
+            params![id, code],
+            |r| r.get(0),
+        )
+        .optional()
+        .unwrap()?;
+
+    match token {
+        None => Some(ClaimStatus {
+            approved: false,
+            token: None,
+        }),
+        Some(token) => {
+            let retired = conn
+                .execute(
+                    "UPDATE claimed_players SET code = '' WHERE id = ? AND code = ?",
+                    params![id, code],
+                )
+                .unwrap();
+            //Someone else checked at the same moment and got it first
+            if retired == 0 {
+                return None;
+            }
+            Some(ClaimStatus {
+                approved: true,
+                token: Some(token),
+            })
+        }
+    }
+}
+
+pub fn revoke_claim(conn: &Connection, id: i64) {
+    conn.execute("DELETE FROM claimed_players WHERE id = ?", params![id])
+        .unwrap();
+}
+
+//Returns false if the token doesn't match an approved claim
+pub fn update_profile(
+    conn: &Connection,
+    id: i64,
+    token: &str,
+    display_name: Option<&str>,
+    pronouns: Option<&str>,
+    links: &[&str],
+) -> bool {
+    let display_name = display_name
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| n.chars().take(MAX_DISPLAY_NAME).collect::<String>());
+    let pronouns = pronouns
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| p.chars().take(MAX_PRONOUNS).collect::<String>());
+    //Only plain web links, anything else could end up as a script in the player page
+    let links = links
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| l.starts_with("https://") || l.starts_with("http://"))
+        .take(MAX_LINKS)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let count = conn
+        .execute(
+            "UPDATE claimed_players SET display_name = ?, pronouns = ?, links = ?
+            WHERE id = ? AND token = ?",
+            params![display_name, pronouns, links, id, token],
+        )
+        .unwrap();
+
+    count == 1
+}
+
+//Lets a player who has claimed their page hide it themselves
+pub fn hide_self(conn: &Connection, id: i64, token: &str) -> bool {
+    let owns: bool = conn
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM claimed_players WHERE id = ? AND token = ?)",
+            params![id, token],
+            |r| r.get(0),
+        )
+        .unwrap();
+    if owns {
+        conn.execute(
+            "INSERT OR IGNORE INTO hidden_status VALUES(?, 'hidden', 'Hidden by the player')",
+            params![id],
+        )
+        .unwrap();
+        //Leaderboards show hidden players without their name
+        cache::invalidate();
+    }
+
+    owns
+}
+
+pub fn get_claimed_profile(conn: &Connection, id: i64) -> Option<ClaimedProfile> {
+    conn.query_row(
+        "SELECT display_name, pronouns, links FROM claimed_players
+        WHERE id = ? AND token IS NOT NULL",
+        params![id],
+        |r| {
+            let links: Option<String> = r.get(2)?;
+            Ok(ClaimedProfile {
+                display_name: r.get(0)?,
+                pronouns: r.get(1)?,
+                links: links
+                    .map(|l| l.lines().map(|l| l.to_owned()).collect())
+                    .unwrap_or_default(),
+            })
+        },
+    )
+    .optional()
+    .unwrap()
+}
+
+#[derive(Debug, Serialize)]
+pub struct PendingClaim {
+    pub id: String,
+    pub name: String,
+    pub code: String,
+    pub requested: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClaimedProfile {
+    pub display_name: Option<String>,
+    pub pronouns: Option<String>,
+    pub links: Vec<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ClaimStatus {
+    pub approved: bool,
+    pub token: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db;
+
+    #[test]
+    fn claim_flow() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        conn.execute("INSERT INTO players VALUES(1, 10, 'a', 3)", [])
+            .unwrap();
+
+        let replaced = start_claim(&conn, 1).unwrap();
+        //Starting again replaces the pending code, the old one doesn't get approved
+        let code = start_claim(&conn, 1).unwrap();
+        assert_ne!(code, replaced);
+        assert_eq!(approve_claim(&conn, 1, &replaced), None);
+        assert_eq!(claim_status(&conn, 1, &replaced), None);
+        assert_eq!(claim_status(&conn, 1, "RU-WRONG"), None);
+        assert_eq!(
+            claim_status(&conn, 1, &code),
+            Some(ClaimStatus {
+                approved: false,
+                token: None
+            })
+        );
+
+        let token = approve_claim(&conn, 1, &code).unwrap();
+        assert_eq!(start_claim(&conn, 1), None);
+        assert_eq!(claim_status(&conn, 1, &code).unwrap().token, Some(token));
+        //Only handed out once
+        assert_eq!(claim_status(&conn, 1, &code), None);
+        assert_eq!(claim_status(&conn, 1, ""), None);
+    }
+}
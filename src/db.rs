@@ -0,0 +1,243 @@
+use chrono::Utc;
+use rusqlite::{params, Connection, Transaction, TransactionBehavior};
+use std::{path::Path, time::Duration};
+
+//Long enough to sit out the commit at the end of a rating update
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+//Everything that opens the ratings database goes through here. WAL lets readers carry on while
+//a write is in progress, and it's stored in the database file itself so the website's pooled
+//connections use it too once any connection has switched it on.
+pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    configure(&conn)?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+pub fn configure(conn: &Connection) -> rusqlite::Result<()> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+
+    //Answers with the mode it ended up in, in-memory databases stay in "memory"
+    let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |r| r.get(0))?;
+    if mode != "wal" && mode != "memory" {
+        warn!("Database is in {} journal mode instead of WAL", mode);
+    }
+
+    //With WAL a crash can lose the last few commits but never corrupt the database
+    conn.execute_batch("PRAGMA synchronous = NORMAL")?;
+
+    Ok(())
+}
+
+//Numbered schema changes, applied in order to every database that hasn't seen them yet. New
+//ones go at the end and are never edited once released.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, "initial", include_str!("../migrations/0001_initial.sql")),
+    (
+        2,
+        "game_activity",
+        include_str!("../migrations/0002_game_activity.sql"),
+    ),
+    (
+        3,
+        "opponent_indexes",
+        include_str!("../migrations/0003_opponent_indexes.sql"),
+    ),
+    (
+        4,
+        "peak_index",
+        include_str!("../migrations/0004_peak_index.sql"),
+    ),
+    (
+        5,
+        "api_keys",
+        include_str!("../migrations/0005_api_keys.sql"),
+    ),
+    (
+        6,
+        "characters",
+        include_str!("../migrations/0006_characters.sql"),
+    ),
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |(version, _, _)| *version)
+}
+
+//0 for a database that has never been migrated
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<i64> {
+    let tracked: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+        [],
+        |r| r.get(0),
+    )?;
+    if !tracked {
+        return Ok(0);
+    }
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |r| r.get(0),
+    )
+}
+
+//Applies whatever migrations the database is missing. Several connections can open the same
+//database at startup, so the work happens under a write lock and only the first one does it.
+pub fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    if schema_version(conn)? == latest_version() {
+        return Ok(());
+    }
+
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let current = schema_version(&tx)?;
+    if current > latest_version() {
+        warn!(
+            "Database is at schema version {}, newer than the {} this build knows",
+            current,
+            latest_version()
+        );
+        return Ok(());
+    }
+
+    //Databases made from init.sql before migrations existed have games but no version.
+    //The initial migration only fills in what they're missing.
+    if current == 0 {
+        let legacy: bool = tx.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'games')",
+            [],
+            |r| r.get(0),
+        )?;
+        if legacy {
+            info!("Adopting a database made before schema versions");
+            add_missing_columns(&tx)?;
+        }
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER NOT NULL PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied INTEGER NOT NULL
+        )",
+    )?;
+    for (version, name, sql) in MIGRATIONS.iter().filter(|(v, _, _)| *v > current) {
+        info!("Applying schema migration {} ({})", version, name);
+        tx.execute_batch(sql)?;
+        tx.execute(
+            "INSERT INTO schema_version VALUES(?, ?, ?)",
+            params![version, name, Utc::now().timestamp()],
+        )?;
+    }
+
+    tx.commit()
+}
+
+//Columns that were added to init.sql while databases were already being made from it, as
+//(table, column, type). Only needed to adopt those databases, new columns get a migration.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("games", "source", "TEXT NOT NULL DEFAULT ''"),
+    ("game_ratings", "new_value_a", "REAL"),
+    ("game_ratings", "new_deviation_a", "REAL"),
+    ("game_ratings", "new_value_b", "REAL"),
+    ("game_ratings", "new_deviation_b", "REAL"),
+    (
+        "player_ratings",
+        "current_streak",
+        "INTEGER NOT NULL DEFAULT 0",
+    ),
+    (
+        "player_ratings",
+        "longest_streak",
+        "INTEGER NOT NULL DEFAULT 0",
+    ),
+    ("config", "rating_system", "TEXT"),
+    ("fetch_failures", "source", "TEXT NOT NULL DEFAULT ''"),
+    ("pull_coverage", "source", "TEXT NOT NULL DEFAULT ''"),
+];
+
+fn add_missing_columns(conn: &Connection) -> rusqlite::Result<()> {
+    for (table, column, definition) in ADDED_COLUMNS {
+        let (exists, has_column): (bool, bool) = conn.query_row(
+            "SELECT
+                EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1),
+                EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+            params![table, column],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        if exists && !has_column {
+            info!("Adding {}.{}", table, column);
+            conn.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, definition
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fresh_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        //Nothing left to do the second time
+        migrate(&conn).unwrap();
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(applied, latest_version());
+    }
+
+    #[test]
+    fn adopt_unversioned_database() {
+        //A database made from init.sql as it was before migrations, with a game in it
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../testdata/legacy_init.sql"))
+            .unwrap();
+        conn.execute(
+            "INSERT INTO games VALUES(1, 1, 'a', 0, 3, 2, 'b', 3, 3, 1, 10)",
+            [],
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        let (source, configs, last_update, rating_system): (String, i64, i64, Option<String>) =
+            conn.query_row(
+                "SELECT source, (SELECT COUNT(*) FROM config), (SELECT last_update FROM config),
+                    (SELECT rating_system FROM config)
+                FROM games",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (source.as_str(), configs, last_update, rating_system),
+            ("", 1, 1675132574, None)
+        );
+        //Columns added to tables the old database already had
+        conn.query_row(
+            "SELECT COUNT(*) FROM player_ratings WHERE current_streak = longest_streak",
+            [],
+            |r| r.get::<_, i64>(0),
+        )
+        .unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) FROM game_ratings WHERE new_value_a IS NULL",
+            [],
+            |r| r.get::<_, i64>(0),
+        )
+        .unwrap();
+        //Tables the old database didn't have yet are there now
+        conn.query_row("SELECT COUNT(*) FROM player_merges", [], |r| {
+            r.get::<_, i64>(0)
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+use anyhow::{anyhow, Result};
+use arrow::{
+    array::{ArrayRef, Float64Builder, Int64Builder, StringBuilder},
+    datatypes::{DataType, Field, Schema},
+    record_batch::RecordBatch,
+};
+use chrono::{NaiveDate, Utc};
+use flate2::{write::GzEncoder, Compression};
+use parquet::arrow::ArrowWriter;
+use rusqlite::{named_params, params, types::ValueRef, Connection};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::rater::Config;
+
+//Rows per parquet row group, which is also about how much gets held in memory at once
+const BATCH_ROWS: usize = 65_536;
+
+#[derive(Clone, Copy)]
+enum ColumnType {
+    Int,
+    Real,
+    Text,
+}
+
+use ColumnType::*;
+
+const GAMES_COLUMNS: &[(&str, ColumnType)] = &[
+    ("timestamp", Int),
+    ("id_a", Int),
+    ("name_a", Text),
+    ("char_a", Int),
+    ("platform_a", Int),
+    ("id_b", Int),
+    ("name_b", Text),
+    ("char_b", Int),
+    ("platform_b", Int),
+    ("winner", Int),
+    ("game_floor", Int),
+];
+
+const GAME_RATINGS_COLUMNS: &[(&str, ColumnType)] = &[
+    ("timestamp", Int),
+    ("id_a", Int),
+    ("value_a", Real),
+    ("deviation_a", Real),
+    ("id_b", Int),
+    ("value_b", Real),
+    ("deviation_b", Real),
+    ("winner", Int),
+    ("valid", Int),
+    ("new_value_a", Real),
+    ("new_deviation_a", Real),
+    ("new_value_b", Real),
+    ("new_deviation_b", Real),
+];
+
+const PLAYER_RATINGS_COLUMNS: &[(&str, ColumnType)] = &[
+    ("id", Int),
+    ("char_id", Int),
+    ("wins", Int),
+    ("losses", Int),
+    ("value", Real),
+    ("deviation", Real),
+    ("last_decay", Int),
+    ("current_streak", Int),
+    ("longest_streak", Int),
+];
+
+//The only tables that can be exported. Everything else is either derived from these or
+//holds things like claims and moderation notes that shouldn't leave the server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportTable {
+    Games,
+    GameRatings,
+    PlayerRatings,
+}
+
+impl ExportTable {
+    fn columns(self) -> &'static [(&'static str, ColumnType)] {
+        match self {
+            ExportTable::Games => GAMES_COLUMNS,
+            ExportTable::GameRatings => GAME_RATINGS_COLUMNS,
+            ExportTable::PlayerRatings => PLAYER_RATINGS_COLUMNS,
+        }
+    }
+
+    //Hidden players are left out entirely. Player ratings are current values so the date
+    //range doesn't apply to them.
+    fn query(self) -> String {
+        let columns = self
+            .columns()
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        match self {
+            ExportTable::Games | ExportTable::GameRatings => {
+                let table = if self == ExportTable::Games {
+                    "games"
+                } else {
+                    "game_ratings"
+                };
+                format!(
+                    "SELECT {columns} FROM {table}
+                    WHERE (:from IS NULL OR timestamp >= :from)
+                        AND (:to IS NULL OR timestamp < :to)
+                        AND NOT EXISTS (
+                            SELECT 1 FROM hidden_status WHERE hidden_status.id IN (id_a, id_b)
+                        )
+                    ORDER BY timestamp ASC"
+                )
+            }
+            ExportTable::PlayerRatings => format!(
+                "SELECT {columns} FROM player_ratings
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM hidden_status WHERE hidden_status.id = player_ratings.id
+                )
+                ORDER BY id, char_id"
+            ),
+        }
+    }
+}
+
+impl FromStr for ExportTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "games" => Ok(ExportTable::Games),
+            "game_ratings" => Ok(ExportTable::GameRatings),
+            "player_ratings" => Ok(ExportTable::PlayerRatings),
+            _ => Err(format!("Can't export {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!("Unknown export format: {}", s)),
+        }
+    }
+}
+
+//Start of the given YYYY-MM-DD day as a timestamp
+pub fn parse_date(date: &str) -> Result<i64> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|e| anyhow!("Bad date {}: {}", date, e))?;
+    Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())
+}
+
+//Writes rows as they're read, returning how many went out. `to` is exclusive.
+pub fn export<W: Write + Send>(
+    conn: &Connection,
+    table: ExportTable,
+    format: ExportFormat,
+    from: Option<i64>,
+    to: Option<i64>,
+    out: W,
+) -> Result<usize> {
+    let mut stmt = conn.prepare(&table.query())?;
+    let mut rows = if table == ExportTable::PlayerRatings {
+        stmt.query([])?
+    } else {
+        stmt.query(named_params! {":from": from, ":to": to})?
+    };
+    let columns = table.columns();
+
+    let mut count = 0;
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(columns.iter().map(|(name, _)| *name))?;
+            while let Some(row) = rows.next()? {
+                for i in 0..columns.len() {
+                    match row.get_ref(i)? {
+                        ValueRef::Null => writer.write_field("")?,
+                        ValueRef::Integer(v) => writer.write_field(v.to_string())?,
+                        ValueRef::Real(v) => writer.write_field(v.to_string())?,
+                        ValueRef::Text(v) | ValueRef::Blob(v) => writer.write_field(v)?,
+                    }
+                }
+                writer.write_record(None::<&[u8]>)?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Parquet => {
+            let schema = Arc::new(Schema::new(
+                columns
+                    .iter()
+                    .map(|(name, column_type)| {
+                        let data_type = match column_type {
+                            Int => DataType::Int64,
+                            Real => DataType::Float64,
+                            Text => DataType::Utf8,
+                        };
+                        Field::new(*name, data_type, true)
+                    })
+                    .collect::<Vec<_>>(),
+            ));
+            let mut writer = ArrowWriter::try_new(out, schema.clone(), None)?;
+
+            let mut batch = Batch::new(columns);
+            while let Some(row) = rows.next()? {
+                batch.push(row)?;
+                count += 1;
+                if batch.len == BATCH_ROWS {
+                    writer.write(&batch.finish(schema.clone())?)?;
+                }
+            }
+            if batch.len > 0 {
+                writer.write(&batch.finish(schema.clone())?)?;
+            }
+            writer.close()?;
+        }
+    }
+
+    Ok(count)
+}
+
+//Tables keyed by player, with the columns holding player ids
+const PLAYER_TABLES: &[(&str, &[&str])] = &[
+    ("games", &["id_a", "id_b"]),
+    ("game_ratings", &["id_a", "id_b"]),
+    ("top_upsets", &["winner_id", "loser_id"]),
+    ("players", &["id"]),
+    ("player_names", &["id"]),
+    ("player_ratings", &["id"]),
+    ("daily_ratings", &["id"]),
+    ("player_rating_history", &["id"]),
+    ("player_matchups", &["id"]),
+    ("player_floor_matchups", &["id"]),
+    ("player_percentiles", &["id"]),
+    ("player_overall_ratings", &["id"]),
+    ("ranking_global", &["id"]),
+    ("ranking_character", &["id"]),
+    ("vip_status", &["id"]),
+    ("cheater_status", &["id"]),
+    ("claimed_players", &["id"]),
+];
+
+const SNAPSHOT_PREFIX: &str = "ratings-";
+const SNAPSHOT_SUFFIX: &str = ".sqlite.gz";
+
+//Copies the database with VACUUM INTO, strips hidden players (also as someone's best win),
+//claim codes, tokens, moderation notes, anomaly flags, merge logs, watchlists, webhooks and
+//API keys from the copy and gzips it into the snapshot directory, keeping the newest
+//`snapshot_keep` snapshots.
+pub fn write_snapshot(conn: &Connection, config: &Config) -> Result<PathBuf> {
+    let then = Utc::now();
+    let dir = Path::new(&config.snapshot_dir);
+    fs::create_dir_all(dir)?;
+
+    let copy_path = dir.join("snapshot.sqlite.tmp");
+    if copy_path.exists() {
+        fs::remove_file(&copy_path)?;
+    }
+    conn.execute(
+        "VACUUM INTO ?",
+        params![copy_path.to_string_lossy().as_ref()],
+    )?;
+
+    {
+        let mut copy = Connection::open(&copy_path)?;
+        let tx = copy.transaction()?;
+        for (table, columns) in PLAYER_TABLES {
+            for column in *columns {
+                tx.execute(
+                    &format!(
+                        "DELETE FROM {table} WHERE {column} IN (SELECT id FROM hidden_status)"
+                    ),
+                    [],
+                )?;
+            }
+        }
+        //Other players' best wins name who they beat
+        tx.execute(
+            "UPDATE player_ratings SET
+                top_defeated_id = NULL, top_defeated_char_id = NULL, top_defeated_name = NULL,
+                top_defeated_value = NULL, top_defeated_deviation = NULL,
+                top_defeated_floor = NULL, top_defeated_timestamp = NULL
+            WHERE top_defeated_id IN (SELECT id FROM hidden_status)",
+            [],
+        )?;
+        tx.execute("DELETE FROM hidden_status", [])?;
+        tx.execute("DELETE FROM claimed_players WHERE token IS NULL", [])?;
+        tx.execute("UPDATE claimed_players SET code = '', token = NULL", [])?;
+        tx.execute("UPDATE vip_status SET notes = ''", [])?;
+        tx.execute("UPDATE cheater_status SET notes = ''", [])?;
+        tx.execute("DELETE FROM watchlists", [])?;
+        tx.execute("DELETE FROM notifications", [])?;
+        tx.execute("DELETE FROM flagged_players", [])?;
+        tx.execute("DELETE FROM api_keys", [])?;
+        //Would still link a hidden player to the id they were merged into
+        tx.execute("DELETE FROM player_merges", [])?;
+        tx.commit()?;
+        //Deleted rows stay in the file's free pages until it's vacuumed
+        copy.execute_batch("VACUUM")?;
+    }
+
+    let name = format!(
+        "{}{}{}",
+        SNAPSHOT_PREFIX,
+        then.format("%Y-%m-%d"),
+        SNAPSHOT_SUFFIX
+    );
+    let path = dir.join(&name);
+    //Written next to the final name and renamed, so a half written file is never served
+    let partial_path = dir.join(format!("{}.tmp", name));
+    {
+        let mut encoder = GzEncoder::new(
+            BufWriter::new(File::create(&partial_path)?),
+            Compression::default(),
+        );
+        io::copy(&mut File::open(&copy_path)?, &mut encoder)?;
+        encoder.finish()?.flush()?;
+    }
+    fs::rename(&partial_path, &path)?;
+    fs::remove_file(&copy_path)?;
+
+    for old in list_snapshots(dir)?
+        .into_iter()
+        .rev()
+        .skip(config.snapshot_keep.max(1))
+    {
+        fs::remove_file(old)?;
+    }
+
+    info!(
+        "Wrote snapshot {} - {}ms",
+        path.display(),
+        (Utc::now() - then).num_milliseconds()
+    );
+    Ok(path)
+}
+
+pub fn latest_snapshot(config: &Config) -> Option<PathBuf> {
+    list_snapshots(Path::new(&config.snapshot_dir)).ok()?.pop()
+}
+
+//Oldest first, the dates in the names sort the same way as the files were written
+fn list_snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut snapshots = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX))
+                .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+enum ColumnBuilder {
+    Int(Int64Builder),
+    Real(Float64Builder),
+    Text(StringBuilder),
+}
+
+struct Batch {
+    builders: Vec<ColumnBuilder>,
+    len: usize,
+}
+
+impl Batch {
+    fn new(columns: &[(&str, ColumnType)]) -> Self {
+        Self {
+            builders: columns
+                .iter()
+                .map(|(_, column_type)| match column_type {
+                    Int => ColumnBuilder::Int(Int64Builder::with_capacity(BATCH_ROWS)),
+                    Real => ColumnBuilder::Real(Float64Builder::with_capacity(BATCH_ROWS)),
+                    Text => ColumnBuilder::Text(StringBuilder::new()),
+                })
+                .collect(),
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, row: &rusqlite::Row) -> Result<()> {
+        for (i, builder) in self.builders.iter_mut().enumerate() {
+            match builder {
+                ColumnBuilder::Int(b) => b.append_option(row.get::<_, Option<i64>>(i)?),
+                ColumnBuilder::Real(b) => b.append_option(row.get::<_, Option<f64>>(i)?),
+                ColumnBuilder::Text(b) => b.append_option(row.get::<_, Option<String>>(i)?),
+            }
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    //Hands back the rows so far and starts over
+    fn finish(&mut self, schema: Arc<Schema>) -> Result<RecordBatch> {
+        let arrays = self
+            .builders
+            .iter_mut()
+            .map(|builder| -> ArrayRef {
+                match builder {
+                    ColumnBuilder::Int(b) => Arc::new(b.finish()),
+                    ColumnBuilder::Real(b) => Arc::new(b.finish()),
+                    ColumnBuilder::Text(b) => Arc::new(b.finish()),
+                }
+            })
+            .collect();
+        self.len = 0;
+        Ok(RecordBatch::try_new(schema, arrays)?)
+    }
+}
@@ -9,33 +9,48 @@ use reqwest::header;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 
-pub async fn get_replays() -> Result<Vec<responses::Replay>, String> {
-    let token = std::fs::read_to_string("token.txt").unwrap();
-    let mut replays = Vec::new();
-    for i in 0..10 {
-        let request_data = requests::generate_replay_request(i, 127, &token);
-        let request_data = encrypt_data(&request_data);
-        let client = reqwest::Client::new();
-        let form = client
-            .post("https://ggst-game.guiltygear.com/api/catalog/get_replay")
-            .header(header::USER_AGENT, "GGST/Steam")
-            .header(header::CACHE_CONTROL, "no-store")
-            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .header("x-client-version", "1")
-            .form(&[("data", request_data)]);
-
-        let response = form.send().await.unwrap();
-
-        let response_bytes = response.bytes().await.unwrap();
-
-        if let Ok(r) = decrypt_response::<responses::Replays>(&response_bytes) {
-            replays.extend_from_slice(&r.replays);
-        } else {
-            return Err("Couldn't load replays".to_owned());
-        }
+pub async fn get_replay_page(
+    index: usize,
+    replays_per_page: usize,
+    min_floor: i64,
+    max_floor: i64,
+    platforms: i64,
+) -> Result<Vec<responses::Replay>, String> {
+    let token = std::fs::read_to_string("token.txt")
+        .map_err(|e| format!("Couldn't read token.txt: {}", e))?;
+    let request_data = requests::generate_replay_request(
+        index,
+        replays_per_page,
+        min_floor,
+        max_floor,
+        platforms,
+        &token,
+    );
+    let request_data = encrypt_data(&request_data);
+    let client = reqwest::Client::new();
+    let form = client
+        .post("https://ggst-game.guiltygear.com/api/catalog/get_replay")
+        .header(header::USER_AGENT, "GGST/Steam")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header("x-client-version", "1")
+        .form(&[("data", request_data)]);
+
+    let response = form
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    let response_bytes = response
+        .bytes()
+        .await
+        .map_err(|e| format!("Couldn't read response: {}", e))?;
+
+    if let Ok(r) = decrypt_response::<responses::Replays>(&response_bytes) {
+        Ok(r.replays)
+    } else {
+        Err("Couldn't load replays".to_owned())
     }
-
-    Ok(replays)
 }
 
 fn encrypt_data<T: Serialize>(data: &T) -> String {
@@ -65,6 +80,10 @@ fn decrypt_response<T: for<'a> Deserialize<'a>>(bytes: &[u8]) -> Result<T, Box<d
         hex::decode("EEBC1F57487F51921C0465665F8AE6D1658BB26DE6F8A069A3520293A572078F").unwrap();
     let aes_gcm = Aes256Gcm::new_from_slice(&key).unwrap();
 
+    if bytes.len() < 12 {
+        return Err("Response too short".into());
+    }
+
     let mut nonce = [0; 12];
     for i in 0..12 {
         nonce[i] = bytes[i];
@@ -76,7 +95,7 @@ fn decrypt_response<T: for<'a> Deserialize<'a>>(bytes: &[u8]) -> Result<T, Box<d
     let decrypted = match aes_gcm.decrypt(&nonce, &bytes[12..]) {
         Ok(decrypted) => decrypted,
         Err(e) => {
-            panic!("Error decrypting: {:?}", e);
+            return Err(format!("Error decrypting: {:?}", e).into());
         }
     };
 
@@ -45,8 +45,22 @@ impl Rating {
 
     #[must_use]
     pub fn update_with_min_dev(self, other: Rating, result: f64, min_deviation: f64) -> Rating {
+        self.update_with_weight(other, result, 1.0, min_deviation)
+    }
+
+    //A game with weight w counts as w of a game, both for the rating change and for how
+    //much certainty it adds
+    #[must_use]
+    pub fn update_with_weight(
+        self,
+        other: Rating,
+        result: f64,
+        weight: f64,
+        min_deviation: f64,
+    ) -> Rating {
         let d_2 = 1.0
-            / (Q.powf(2.0)
+            / (weight
+                * Q.powf(2.0)
                 * g(other.deviation).powf(2.0)
                 * e(self.value, other.value, other.deviation)
                 * (1.0 - e(self.value, other.value, other.deviation)));
@@ -54,6 +68,7 @@ impl Rating {
             value: self.value
                 + UPDATE_SPEED
                     * (Q / ((1.0 / self.deviation.powf(2.0)) + (1.0 / (d_2))))
+                    * weight
                     * g(other.deviation)
                     * (result - e(self.value, other.value, other.deviation)),
             deviation: (1.0 / (1.0 / self.deviation.powf(2.0) + 1.0 / d_2))
@@ -86,6 +101,13 @@ impl Rating {
         Self::update_with_min_dev(self, other, result, MIN_DEVIATION)
     }
 
+    //Standard glicko expected score, only the opponent's deviation is taken into account
+    pub fn expected_score(self, opponent: Rating) -> f64 {
+        1.0 / (1.0 + 10.0f64.powf(-g(opponent.deviation) * (self.value - opponent.value) / 400.0))
+    }
+
+    //Probability that self wins against other. Both deviations are folded into g() so
+    //uncertain ratings pull the prediction towards 50%, use this for any match prediction
     pub fn expected(self, other: Rating) -> f64 {
         1.0 / (1.0
             + 10.0f64.powf(
@@ -112,6 +134,17 @@ pub fn e(r: f64, r_j: f64, rd_j: f64) -> f64 {
 #[cfg(test)]
 mod test {
     use super::*;
+
+    #[test]
+    fn expected_score() {
+        let a = Rating::new(1700.0, 50.0);
+        assert_eq!(a.expected_score(Rating::new(1700.0, 50.0)), 0.5);
+
+        let certain = a.expected_score(Rating::new(1500.0, 50.0));
+        let uncertain = a.expected_score(Rating::new(1500.0, 300.0));
+        assert!(certain > uncertain && uncertain > 0.5);
+    }
+
     #[test]
     fn blah() {
         let mut a = Rating::new(1800.0, 100.0);
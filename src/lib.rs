@@ -3,10 +3,25 @@ extern crate rocket;
 #[macro_use]
 extern crate log;
 
+pub mod anomaly;
 mod api;
+mod cache;
+pub mod characters;
+pub mod claims;
+pub mod db;
+pub mod export;
 mod glicko;
 mod ggst_api;
+pub mod live;
+mod metrics;
+pub mod notifications;
 mod responses;
 mod requests;
 pub mod rater;
+mod rating_system;
+pub mod rate_limit;
+mod replay_source;
+pub mod simulation;
+pub mod storage;
+pub mod watchlists;
 pub mod website;
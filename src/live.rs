@@ -0,0 +1,57 @@
+use lazy_static::lazy_static;
+use rocket::serde::Serialize;
+use tokio::sync::broadcast;
+
+//Slow listeners skip ahead once they fall this far behind
+const FEED_BUFFER: usize = 4096;
+
+lazy_static! {
+    static ref FEED: broadcast::Sender<LiveEvent> = broadcast::channel(FEED_BUFFER).0;
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    Game(LiveGame),
+    RatingPeriod { timestamp: i64 },
+}
+
+impl LiveEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LiveEvent::Game(_) => "game",
+            LiveEvent::RatingPeriod { .. } => "rating_period",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LiveGame {
+    pub timestamp: i64,
+    pub floor: i64,
+    pub player_a: LivePlayer,
+    pub player_b: LivePlayer,
+    pub winner: i64,
+}
+
+//Hidden players come through without an id or name, like in opponents' match history
+#[derive(Clone, Debug, Serialize)]
+pub struct LivePlayer {
+    pub id: Option<String>,
+    pub name: String,
+    pub character_short: &'static str,
+}
+
+//Nothing happens if nobody is listening
+pub fn emit(event: LiveEvent) {
+    let _ = FEED.send(event);
+}
+
+//Lets the pull skip building events when nobody is listening
+pub fn has_listeners() -> bool {
+    FEED.receiver_count() > 0
+}
+
+pub fn subscribe() -> broadcast::Receiver<LiveEvent> {
+    FEED.subscribe()
+}
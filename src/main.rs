@@ -1,28 +1,44 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
+use clap::{Parser, Subcommand};
 use simplelog::*;
-use std::{fs::File, ops::Deref};
-use tokio::try_join;
+use std::fs::File;
+use tokio::{sync::watch, try_join};
 
-use rating_update::{rater, website};
+use rating_update::{
+    anomaly, characters, claims, db, export, notifications, rate_limit, rater, simulation, website,
+};
+
+fn init_logging(config: &rater::Config) {
+    //JSON lines go out bare so every line can be parsed on its own
+    let log_config = if config.json_logs {
+        ConfigBuilder::new()
+            .set_max_level(LevelFilter::Off)
+            .set_time_level(LevelFilter::Off)
+            .set_thread_level(LevelFilter::Off)
+            .set_target_level(LevelFilter::Off)
+            .set_location_level(LevelFilter::Off)
+            .build()
+    } else {
+        Config::default()
+    };
 
-fn init_logging() {
     if cfg!(debug_assertions) {
         CombinedLogger::init(vec![
-            TermLogger::new(LevelFilter::Debug, Config::default(), TerminalMode::Mixed),
+            TermLogger::new(LevelFilter::Debug, log_config.clone(), TerminalMode::Mixed),
             WriteLogger::new(
                 LevelFilter::Info,
-                Config::default(),
+                log_config,
                 File::create("output.log").unwrap(),
             ),
         ])
         .unwrap();
     } else {
         CombinedLogger::init(vec![
-            TermLogger::new(LevelFilter::Info, Config::default(), TerminalMode::Mixed),
+            TermLogger::new(LevelFilter::Info, log_config.clone(), TerminalMode::Mixed),
             WriteLogger::new(
                 LevelFilter::Info,
-                Config::default(),
+                log_config,
                 File::create("output.log").unwrap(),
             ),
         ])
@@ -30,72 +46,561 @@ fn init_logging() {
     }
 }
 
+//Resolves on ctrl-c, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+#[derive(Parser)]
+#[command(about = "Pulls Guilty Gear Strive replays and rates the players in them")]
+struct Cli {
+    /// Database to use instead of the configured db_path
+    #[arg(long, global = true)]
+    db_path: Option<String>,
+    //Runs the puller, rater and website together when left out
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Pull, rate and serve the website until stopped
+    Run,
+    /// Create the database or bring it up to the latest schema version
+    Init,
+    /// Clear every rating, keeping the games
+    Reset,
+    /// Rerate every game from scratch
+    Recalculate {
+        /// Continue an interrupted recalculation instead of clearing ratings first
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Pull replays once and rate them
+    Pull {
+        /// Pages to pull, defaults to the configured max pages
+        #[arg(long)]
+        pages: Option<usize>,
+    },
+    /// Rate any games that haven't been rated yet
+    Update,
+    /// Import replays from a JSON file in the replay API's format
+    #[command(alias = "import")]
+    LoadJson { path: String },
+    /// Serve the website without pulling or rating
+    #[command(alias = "nothoughts")]
+    Website,
+    /// Recompute the global and character rankings
+    Rankings,
+    /// Print the top 100 as a markdown table
+    #[command(alias = "print_rankings")]
+    PrintRankings,
+    /// Recompute the fraud index
+    Fraud,
+    /// Apply rating decay to every player now
+    Decay,
+    /// Apply decay to the matchup tables now
+    #[command(alias = "decay_matchups")]
+    DecayMatchups,
+    /// Discord webhook subscriptions
+    Notify {
+        #[command(subcommand)]
+        command: NotifyCommand,
+    },
+    /// Players flagged by the anomaly scan
+    Flags {
+        #[command(subcommand)]
+        command: FlagsCommand,
+    },
+    /// Keys that get API users past the per IP rate limit
+    ApiKeys {
+        #[command(subcommand)]
+        command: ApiKeysCommand,
+    },
+    /// Player name maintenance
+    Names {
+        #[command(subcommand)]
+        command: NamesCommand,
+    },
+    /// Recompute the player rating distribution
+    Distribution,
+    /// Print matchup rows whose wins and losses don't add up
+    #[command(alias = "audit_matchups")]
+    AuditMatchups,
+    /// Drop a character's matchup data
+    #[command(alias = "reset_matchups")]
+    ResetMatchups { char_id: i64 },
+    /// Recompute the matchups for a single floor
+    #[command(alias = "floor_matchups")]
+    FloorMatchups { floor: i64 },
+    /// Rebuild the floor band matchups from every game instead of just the new ones
+    BandMatchups,
+    /// Fold the games of one player id into another
+    #[command(alias = "merge_players")]
+    MergePlayers {
+        keep: String,
+        merge: String,
+        /// Only print what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Copy the database to another file
+    Backup { path: String },
+    /// Write a public snapshot of the database now, the same one made daily while running
+    Snapshot,
+    /// Dump games, game_ratings or player_ratings for sharing, hidden players are left out
+    Export {
+        table: export::ExportTable,
+        path: String,
+        /// csv or parquet
+        #[arg(long, default_value = "csv")]
+        format: export::ExportFormat,
+        /// First day to include, as YYYY-MM-DD
+        #[arg(long)]
+        from: Option<String>,
+        /// Last day to include, as YYYY-MM-DD
+        #[arg(long)]
+        to: Option<String>,
+    },
+    /// Rate a synthetic game history with known skills and report how close the ratings got
+    Simulate {
+        #[arg(long, default_value_t = 200)]
+        players: usize,
+        #[arg(long, default_value_t = 20_000)]
+        games: usize,
+        /// Rating periods the games are spread over
+        #[arg(long, default_value_t = 100)]
+        periods: i64,
+        /// Standard deviation of the true skills
+        #[arg(long, default_value_t = 300.0)]
+        skill_spread: f64,
+        /// Share of players on either side an opponent can come from
+        #[arg(long, default_value_t = 0.5)]
+        match_window: f64,
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+        /// Decay constants to compare, defaults to the configured one
+        #[arg(long)]
+        decay_constant: Vec<f64>,
+    },
+    /// Flag a player as a cheater, ids are in hex
+    #[command(alias = "mark_cheater")]
+    MarkCheater {
+        id: String,
+        cheater_type: Option<String>,
+        notes: Option<String>,
+    },
+    /// Give a player the VIP tag
+    #[command(alias = "mark_vip")]
+    MarkVip { id: String, notes: String },
+    /// Hide a player from the site and the API
+    #[command(alias = "mark_hidden")]
+    MarkHidden { id: String, notes: String },
+    /// Show a hidden player again
+    #[command(alias = "unmark_hidden")]
+    UnmarkHidden { id: String },
+    /// List profile claims waiting for review
+    Claims,
+    /// Approve a claim with the code seen in the player's comment and print their edit token
+    #[command(alias = "approve_claim")]
+    ApproveClaim { id: String, code: String },
+    /// Remove a claim, approved or not
+    #[command(alias = "revoke_claim")]
+    RevokeClaim { id: String },
+}
+
+#[derive(Subcommand)]
+enum NotifyCommand {
+    /// Send peak, matchup or stall events to a webhook
+    Add {
+        webhook_url: String,
+        kind: notifications::EventKind,
+        /// Player to follow for peak events, in hex
+        #[arg(long)]
+        player: Option<String>,
+        /// Only events for this character, by short name
+        #[arg(long)]
+        character: Option<String>,
+    },
+    /// List every subscription
+    List,
+    /// Stop a subscription by its id
+    Remove { id: i64 },
+}
+
+#[derive(Subcommand)]
+enum FlagsCommand {
+    /// List every finding, newest first
+    List,
+    /// Scan the last day of games now
+    Scan,
+    /// Leave a flagged player out of leaderboards and matchup stats, ids are in hex
+    Exclude { id: String },
+    /// Count an excluded player again
+    Include { id: String },
+}
+
+#[derive(Subcommand)]
+enum ApiKeysCommand {
+    /// Make a new key and print it
+    Mint {
+        /// Who the key is for
+        name: String,
+        #[arg(long, default_value_t = 600)]
+        requests_per_minute: u32,
+    },
+    /// Stop a key from working
+    Revoke { key: String },
+    /// List every key, revoked ones included
+    List,
+}
+
+#[derive(Subcommand)]
+enum NamesCommand {
+    /// Rebuild the player names from the games
+    Reset,
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() {
-    init_logging();
+    let mut config = rater::Config::load().unwrap();
+    init_logging(&config);
+    characters::init(&config.characters).unwrap();
 
-    let args = std::env::args().skip(1).collect::<Vec<_>>();
-    match args.get(0).map(|r| r.deref()) {
-        Some("init") => {
-            rater::init_database().unwrap();
+    let cli = Cli::parse();
+    if let Some(db_path) = cli.db_path {
+        config.db_path = db_path;
+    }
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Init => {
+            rater::init_database(&config).unwrap();
+        }
+        Command::Reset => {
+            rater::reset_database(&config).unwrap();
         }
-        Some("reset") => {
-            rater::reset_database().unwrap();
+        Command::Recalculate { resume } => {
+            rater::recalculate(&config, resume).unwrap();
         }
-        Some("update") => {
-            rater::update_once().await;
+        Command::Update => {
+            rater::update_once(&config).await;
         }
-        Some("rankings") => {
-            rater::update_rankings_once();
+        Command::Rankings => {
+            rater::update_rankings_once(&config);
         }
-        Some("fraud") => {
-            rater::update_fraud_once().await;
+        Command::Fraud => {
+            rater::update_fraud_once(&config).await;
         }
-        Some("mark_cheater") => {
+        Command::MarkCheater {
+            id,
+            cheater_type,
+            notes,
+        } => {
             rater::mark_cheater(
-                args.get(1).map(|r| r.deref()),
-                args.get(2).map(|r| r.deref()),
-                args.get(3).map(|r| r.deref()),
+                &config,
+                Some(&id),
+                cheater_type.as_deref(),
+                notes.as_deref(),
             )
             .await;
         }
-        Some("mark_vip") => {
-            rater::mark_vip(args.get(1).unwrap(), args.get(2).unwrap());
+        Command::MarkVip { id, notes } => {
+            rater::mark_vip(&config, &id, &notes);
+        }
+        Command::MarkHidden { id, notes } => {
+            rater::mark_hidden(&config, &id, &notes);
+        }
+        Command::Claims => {
+            let conn = db::open(&config.db_path).unwrap();
+            for claim in claims::pending_claims(&conn) {
+                println!("{} {} {}", claim.id, claim.code, claim.name);
+            }
+        }
+        Command::ApproveClaim { id, code } => {
+            let conn = db::open(&config.db_path).unwrap();
+            match claims::approve_claim(&conn, claims::parse_id(&id).unwrap(), &code) {
+                Some(token) => println!("Claim approved, token: {}", token),
+                None => println!("No pending claim for {} with code {}", id, code),
+            }
+        }
+        Command::RevokeClaim { id } => {
+            let conn = db::open(&config.db_path).unwrap();
+            claims::revoke_claim(&conn, claims::parse_id(&id).unwrap());
+        }
+        Command::UnmarkHidden { id } => {
+            rater::unmark_hidden(&config, &id);
+        }
+        Command::PrintRankings => {
+            rater::print_rankings(&config);
+        }
+        Command::Decay => {
+            rater::update_decay_once(&config).await;
+        }
+        Command::DecayMatchups => {
+            rater::test_decay_matchups(&config).await;
+        }
+        Command::Names {
+            command: NamesCommand::Reset,
+        } => {
+            rater::reset_names(&config).unwrap();
+        }
+        Command::Notify { command } => {
+            let conn = db::open(&config.db_path).unwrap();
+            match command {
+                NotifyCommand::Add {
+                    webhook_url,
+                    kind,
+                    player,
+                    character,
+                } => {
+                    let player_id = player.map(|p| claims::parse_id(&p).unwrap());
+                    let char_id = character.map(|c| characters::by_short(&c).unwrap());
+                    let id = notifications::add_subscription(
+                        &conn,
+                        &webhook_url,
+                        kind,
+                        player_id,
+                        char_id,
+                    )
+                    .unwrap();
+                    println!("Added subscription {}", id);
+                }
+                NotifyCommand::List => {
+                    for s in notifications::subscriptions(&conn).unwrap() {
+                        println!(
+                            "{} {} {} {} {}",
+                            s.id,
+                            s.kind,
+                            s.player_id
+                                .map(|id| format!("{:X}", id))
+                                .unwrap_or_default(),
+                            s.char_id
+                                .map(|c| characters::all()[c as usize].0)
+                                .unwrap_or_default(),
+                            s.webhook_url
+                        );
+                    }
+                }
+                NotifyCommand::Remove { id } => {
+                    if !notifications::remove_subscription(&conn, id).unwrap() {
+                        println!("No subscription {}", id);
+                    }
+                }
+            }
+        }
+        Command::Flags { command } => {
+            let mut conn = db::open(&config.db_path).unwrap();
+            match command {
+                FlagsCommand::List => {
+                    for f in anomaly::flagged_players(&conn).unwrap() {
+                        println!(
+                            "{} {} {}{} - {}",
+                            f.id,
+                            f.name.unwrap_or_default(),
+                            f.reason,
+                            if f.excluded { " (excluded)" } else { "" },
+                            f.details
+                        );
+                    }
+                }
+                FlagsCommand::Scan => {
+                    let count = anomaly::scan(&mut conn, chrono::Utc::now().timestamp()).unwrap();
+                    println!("{} findings", count);
+                }
+                FlagsCommand::Exclude { id } => {
+                    if !anomaly::set_excluded(&conn, claims::parse_id(&id).unwrap(), true).unwrap()
+                    {
+                        println!("{} hasn't been flagged", id);
+                    }
+                }
+                FlagsCommand::Include { id } => {
+                    if !anomaly::set_excluded(&conn, claims::parse_id(&id).unwrap(), false).unwrap()
+                    {
+                        println!("{} hasn't been flagged", id);
+                    }
+                }
+            }
+        }
+        Command::ApiKeys { command } => {
+            let conn = db::open(&config.db_path).unwrap();
+            match command {
+                ApiKeysCommand::Mint {
+                    name,
+                    requests_per_minute,
+                } => {
+                    let key = rate_limit::mint_key(&conn, &name, requests_per_minute).unwrap();
+                    println!("{}", key);
+                }
+                ApiKeysCommand::Revoke { key } => {
+                    if !rate_limit::revoke_key(&conn, &key).unwrap() {
+                        println!("No active key {}", key);
+                    }
+                }
+                ApiKeysCommand::List => {
+                    for k in rate_limit::api_keys(&conn).unwrap() {
+                        println!(
+                            "{} {} {}/min{}",
+                            k.key,
+                            k.name,
+                            k.requests_per_minute,
+                            if k.revoked.is_some() {
+                                " (revoked)"
+                            } else {
+                                ""
+                            }
+                        );
+                    }
+                }
+            }
+        }
+        Command::Distribution => {
+            rater::reset_distribution(&config).unwrap();
+        }
+        Command::AuditMatchups => {
+            let conn = db::open(&config.db_path).unwrap();
+            for inconsistency in rater::audit_matchups(&conn) {
+                println!("{:?}", inconsistency);
+            }
+        }
+        Command::MergePlayers {
+            keep,
+            merge,
+            dry_run,
+        } => {
+            let mut conn = db::open(&config.db_path).unwrap();
+            let report = rater::merge_players(
+                &mut conn,
+                claims::parse_id(&keep).unwrap(),
+                claims::parse_id(&merge).unwrap(),
+                dry_run,
+                "cli",
+            )
+            .unwrap();
+            for (table, rows) in report.rows {
+                println!("{} {}", table, rows);
+            }
+            if dry_run {
+                println!("Dry run, nothing was changed");
+            }
+        }
+        Command::ResetMatchups { char_id } => {
+            let mut conn = db::open(&config.db_path).unwrap();
+            rater::reset_matchups_for_char(&mut conn, char_id).unwrap();
         }
-        Some("mark_hidden") => {
-            rater::mark_hidden(args.get(1).unwrap(), args.get(2).unwrap());
+        Command::FloorMatchups { floor } => {
+            let mut conn = db::open(&config.db_path).unwrap();
+            rater::calc_floor_matchups(&mut conn, floor, &config).unwrap();
         }
-        Some("print_rankings") => {
-            rater::print_rankings();
+        Command::BandMatchups => {
+            let mut conn = db::open(&config.db_path).unwrap();
+            rater::calc_floor_band_matchups(&mut conn, &config, true).unwrap();
         }
-        Some("decay") => {
-            rater::update_decay_once().await;
+        Command::Backup { path } => {
+            let conn = db::open(&config.db_path).unwrap();
+            rater::backup_database(&conn, &path).unwrap();
         }
-        Some("decay_matchups") => {
-            rater::test_decay_matchups().await;
+        Command::Simulate {
+            players,
+            games,
+            periods,
+            skill_spread,
+            match_window,
+            seed,
+            decay_constant,
+        } => {
+            let simulation = simulation::Simulation {
+                players,
+                games,
+                periods,
+                skill_spread,
+                match_window,
+                seed,
+            };
+            let decay_constants = if decay_constant.is_empty() {
+                vec![config.decay_constant]
+            } else {
+                decay_constant
+            };
+            println!("| Decay constant | Games | Rank correlation | RMSE | Coverage | Brier | Log loss |");
+            println!("| --- | --- | --- | --- | --- | --- | --- |");
+            for c in decay_constants {
+                config.decay_constant = c;
+                let report = simulation.run(&config).unwrap();
+                println!(
+                    "| {} | {} | {:.3} | {:.1} | {:.2} | {:.4} | {:.4} |",
+                    c,
+                    report.games,
+                    report.rank_correlation,
+                    report.rmse,
+                    report.coverage,
+                    report.brier,
+                    report.log_loss
+                );
+            }
         }
-        Some("names") => {
-            rater::reset_names().unwrap();
+        Command::Snapshot => {
+            let conn = db::open(&config.db_path).unwrap();
+            let path = export::write_snapshot(&conn, &config).unwrap();
+            println!("Wrote {}", path.display());
         }
-        Some("distribution") => {
-            rater::reset_distribution().unwrap();
+        Command::Export {
+            table,
+            path,
+            format,
+            from,
+            to,
+        } => {
+            let conn = db::open(&config.db_path).unwrap();
+            let from = from.map(|d| export::parse_date(&d).unwrap());
+            let to = to.map(|d| export::parse_date(&d).unwrap() + 24 * 60 * 60);
+            let file = std::io::BufWriter::new(File::create(&path).unwrap());
+            let count = export::export(&conn, table, format, from, to, file).unwrap();
+            println!("Exported {} rows to {}", count, path);
         }
-        Some("pull") => {
-            rater::pull().await;
+        Command::LoadJson { path } => {
+            let mut conn = db::open(&config.db_path).unwrap();
+            //Rating into Postgres blocks on its own runtime
+            tokio::task::block_in_place(|| rater::import_replays(&mut conn, &path, &config))
+                .unwrap();
         }
-        Some("nothoughts") => {
-            website::run().await;
+        Command::Pull { pages } => {
+            if let Some(pages) = pages {
+                config.max_pages = pages;
+            }
+            rater::pull(&config).await;
         }
-        Some(x) => {
-            println!("Unrecognized argument: {}", x);
+        Command::Website => {
+            website::run(config).await;
         }
-        None => {
+        Command::Run => {
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                log::info!("Shutdown requested, finishing current work");
+                let _ = shutdown_tx.send(true);
+            });
+
+            let mut website_shutdown = shutdown_rx.clone();
+            let website_config = config.clone();
             if let Err(err) = try_join!(
                 async {
-                    tokio::spawn(website::run()).await?;
+                    //The website only reads, so it's fine to drop it once asked to stop
+                    tokio::select! {
+                        r = tokio::spawn(website::run(website_config)) => r?,
+                        _ = website_shutdown.changed() => {}
+                    }
                     Ok(())
                 },
-                rater::run()
+                rater::run(config, shutdown_rx)
             ) {
                 eprintln!("{:?}", err);
                 std::process::exit(1);
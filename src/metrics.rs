@@ -0,0 +1,67 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref GAMES_INGESTED: IntCounter = register_int_counter!(
+        "rating_update_games_ingested_total",
+        "New games added to the database"
+    )
+    .unwrap();
+    //Split by whether the replay was new or one we already had
+    pub static ref REPLAYS: IntCounterVec = register_int_counter_vec!(
+        "rating_update_replays_total",
+        "Replays received from the replay API",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref FETCH_ERRORS: IntCounter = register_int_counter!(
+        "rating_update_fetch_errors_total",
+        "Failed replay page fetches, counting every attempt"
+    )
+    .unwrap();
+    pub static ref PARSE_ERRORS: IntCounter = register_int_counter!(
+        "rating_update_parse_errors_total",
+        "Replays skipped because of character or floor codes we don't know"
+    )
+    .unwrap();
+    pub static ref PAGES_PULLED: IntGauge = register_int_gauge!(
+        "rating_update_pages_pulled",
+        "Pages fetched by the last pull"
+    )
+    .unwrap();
+    pub static ref PHASE_DURATION: HistogramVec = register_histogram_vec!(
+        "rating_update_phase_duration_seconds",
+        "Time taken by the periodic update phases",
+        &["phase"],
+        vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0]
+    )
+    .unwrap();
+    //Split by the background loop that had to be restarted
+    pub static ref TASK_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "rating_update_task_restarts_total",
+        "Background loops restarted after they panicked",
+        &["task"]
+    )
+    .unwrap();
+    static ref DB_SIZE: IntGauge = register_int_gauge!(
+        "rating_update_database_size_bytes",
+        "Size of the sqlite database file"
+    )
+    .unwrap();
+}
+
+//Everything registered above in the prometheus text format
+pub fn render(db_path: &str) -> String {
+    if let Ok(metadata) = std::fs::metadata(db_path) {
+        DB_SIZE.set(metadata.len() as i64);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
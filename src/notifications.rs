@@ -0,0 +1,326 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use fxhash::FxHashSet;
+use lazy_static::lazy_static;
+use reqwest::header::CONTENT_TYPE;
+use rocket::serde::json::serde_json::json;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{str::FromStr, time::Duration};
+use tokio::{
+    sync::{broadcast, watch},
+    time,
+};
+
+use crate::{api, characters, db, rater};
+
+//Events that can pile up before the notifier starts dropping the oldest ones
+const EVENT_BUFFER: usize = 1024;
+//Peaks from games older than this are history being rerated, not news
+pub const MAX_EVENT_AGE: i64 = 24 * 60 * 60;
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<Event> = broadcast::channel(EVENT_BUFFER).0;
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    NewPeak {
+        id: i64,
+        char_id: i64,
+        name: String,
+        value: f64,
+        deviation: f64,
+    },
+    MatchupFlipped {
+        char_id: i64,
+        opp_char_id: i64,
+        value: f64,
+    },
+    PullStalled {
+        last_pull: i64,
+    },
+}
+
+impl Event {
+    fn kind(&self) -> EventKind {
+        match self {
+            Event::NewPeak { .. } => EventKind::Peak,
+            Event::MatchupFlipped { .. } => EventKind::Matchup,
+            Event::PullStalled { .. } => EventKind::Stall,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Event::NewPeak {
+                char_id,
+                name,
+                value,
+                deviation,
+                ..
+            } => format!(
+                "{} ({}) reached a new peak rating of {:.0} ±{:.0}",
+                name,
+                char_name(*char_id),
+                value,
+                2.0 * deviation
+            ),
+            Event::MatchupFlipped {
+                char_id,
+                opp_char_id,
+                value,
+            } => format!(
+                "{} vs {} flipped, {} is now {} ({:+.0})",
+                char_name(*char_id),
+                char_name(*opp_char_id),
+                char_name(*char_id),
+                if *value > 1500.0 {
+                    "favored"
+                } else {
+                    "unfavored"
+                },
+                value - 1500.0
+            ),
+            Event::PullStalled { last_pull } => format!(
+                "No replays have been pulled since {} UTC",
+                DateTime::from_timestamp(*last_pull, 0)
+                    .unwrap()
+                    .format("%Y-%m-%d %H:%M")
+            ),
+        }
+    }
+}
+
+fn char_name(char_id: i64) -> &'static str {
+    characters::all()
+        .get(char_id as usize)
+        .map(|c| c.1)
+        .unwrap_or("Unknown")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Peak,
+    Matchup,
+    Stall,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Peak => "peak",
+            EventKind::Matchup => "matchup",
+            EventKind::Stall => "stall",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "peak" => Ok(EventKind::Peak),
+            "matchup" => Ok(EventKind::Matchup),
+            "stall" => Ok(EventKind::Stall),
+            _ => Err(format!("Unknown notification kind: {}", s)),
+        }
+    }
+}
+
+//Nothing happens if no notifier is running
+pub fn emit(event: Event) {
+    let _ = EVENTS.send(event);
+}
+
+//Players with a peak subscription, so the rater only raises events someone will get. Hidden
+//players are left out, their names and ratings don't go to webhooks.
+pub fn followed_players(conn: &Connection) -> FxHashSet<i64> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT DISTINCT player_id FROM notifications
+            WHERE kind = 'peak' AND player_id NOT IN (SELECT id FROM hidden_status)",
+        )
+        .unwrap();
+    stmt.query_map([], |r| r.get(0))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect()
+}
+
+pub fn add_subscription(
+    conn: &Connection,
+    webhook_url: &str,
+    kind: EventKind,
+    player_id: Option<i64>,
+    char_id: Option<i64>,
+) -> Result<i64> {
+    if kind == EventKind::Peak && player_id.is_none() {
+        anyhow::bail!("Peak notifications need a player to follow");
+    }
+    conn.execute(
+        "INSERT INTO notifications(webhook_url, kind, player_id, char_id) VALUES(?, ?, ?, ?)",
+        params![webhook_url, kind.as_str(), player_id, char_id],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn remove_subscription(conn: &Connection, id: i64) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM notifications WHERE id = ?", params![id])? == 1)
+}
+
+pub fn subscriptions(conn: &Connection) -> Result<Vec<Subscription>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, webhook_url, kind, player_id, char_id FROM notifications ORDER BY id",
+    )?;
+    let subscriptions = stmt
+        .query_map([], |r| {
+            Ok(Subscription {
+                id: r.get(0)?,
+                webhook_url: r.get(1)?,
+                kind: r.get(2)?,
+                player_id: r.get(3)?,
+                char_id: r.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(subscriptions)
+}
+
+fn webhooks_for(conn: &Connection, event: &Event) -> Result<Vec<String>> {
+    let (player_id, char_ids) = match event {
+        Event::NewPeak { id, char_id, .. } => (Some(*id), vec![*char_id]),
+        Event::MatchupFlipped {
+            char_id,
+            opp_char_id,
+            ..
+        } => (None, vec![*char_id, *opp_char_id]),
+        Event::PullStalled { .. } => (None, vec![]),
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT webhook_url, char_id FROM notifications
+        WHERE kind = ? AND (player_id IS NULL OR player_id = ?)",
+    )?;
+    let webhooks = stmt
+        .query_map(params![event.kind().as_str(), player_id], |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, Option<i64>>(1)?))
+        })?
+        .filter_map(|r| r.ok())
+        .filter(|(_, char_id)| char_id.map(|c| char_ids.contains(&c)).unwrap_or(true))
+        .map(|(webhook_url, _)| webhook_url)
+        .collect();
+    Ok(webhooks)
+}
+
+//Compares the global matchups against the last check and raises an event for each pair
+//that moved to the other side of even. The first check only records where things stand.
+pub fn check_matchup_flips(conn: &mut Connection, config: &rater::Config) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "SELECT char_id, opp_char_id, rating_value FROM global_matchups
+            WHERE char_id < opp_char_id AND wins + losses >= ?",
+        )?;
+        let mut rows = stmt.query(params![config.matchup_min_games])?;
+        while let Some(row) = rows.next()? {
+            let char_id: i64 = row.get(0)?;
+            let opp_char_id: i64 = row.get(1)?;
+            let value: f64 = row.get(2)?;
+            let favored = value > 1500.0;
+
+            let was_favored: Option<bool> = tx
+                .query_row(
+                    "SELECT favored FROM matchup_signs WHERE char_id = ? AND opp_char_id = ?",
+                    params![char_id, opp_char_id],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            if was_favored.map(|f| f != favored).unwrap_or(false) {
+                emit(Event::MatchupFlipped {
+                    char_id,
+                    opp_char_id,
+                    value,
+                });
+            }
+            tx.execute(
+                "REPLACE INTO matchup_signs VALUES(?, ?, ?)",
+                params![char_id, opp_char_id, favored],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+async fn post(client: &reqwest::Client, webhook_url: &str, message: &str) -> Result<()> {
+    client
+        .post(webhook_url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(json!({ "content": message }).to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+//Delivers events to the webhooks subscribed to them, and watches for the puller going quiet
+pub async fn run(config: rater::Config, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let conn = db::open(&config.db_path)?;
+    let client = reqwest::Client::new();
+    let mut events = EVENTS.subscribe();
+    let mut interval = time::interval(Duration::from_secs(60));
+    let mut stalled = false;
+
+    loop {
+        let event = tokio::select! {
+            _ = shutdown.changed() => break,
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Notifier fell behind, dropped {} events", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = interval.tick() => {
+                //Only raised once per stall, and not before the first pull is done
+                let last_pull = rater::get_runtime_stats().last_pull_time;
+                let is_stalled = last_pull
+                    .map(|t| Utc::now().timestamp() - t > api::PULL_STALE_SECONDS)
+                    .unwrap_or(false);
+                if is_stalled && !stalled {
+                    emit(Event::PullStalled { last_pull: last_pull.unwrap() });
+                }
+                stalled = is_stalled;
+                continue;
+            }
+        };
+
+        let webhooks = match webhooks_for(&conn, &event) {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                error!("Couldn't look up webhooks: {}", e);
+                continue;
+            }
+        };
+        let message = event.message();
+        for webhook_url in webhooks {
+            if let Err(e) = post(&client, &webhook_url, &message).await {
+                warn!("Couldn't post notification to a webhook: {}", e);
+            }
+        }
+    }
+
+    info!("Stopped sending notifications");
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    pub id: i64,
+    pub webhook_url: String,
+    pub kind: String,
+    pub player_id: Option<i64>,
+    pub char_id: Option<i64>,
+}
@@ -0,0 +1,264 @@
+use chrono::Utc;
+use fxhash::FxHashMap;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{uri::Origin, Header, Method},
+    serde::Serialize,
+    Data, Request, Response,
+};
+use rusqlite::{params, Connection};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{claims, website::RatingsDbConn};
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+//Requests over their limit are sent here instead of where they were going
+pub const RATE_LIMITED_PATH: &str = "/api/rate_limited";
+
+//Keys are read from the database again after this long, so minting or revoking one takes at
+//most this long to apply. Idle buckets are dropped just as often.
+const KEY_REFRESH: Duration = Duration::from_secs(60);
+
+//Returns the new key, `name` is only there to tell keys apart
+pub fn mint_key(
+    conn: &Connection,
+    name: &str,
+    requests_per_minute: u32,
+) -> rusqlite::Result<String> {
+    let key = claims::random_hex(16);
+    conn.execute(
+        "INSERT INTO api_keys VALUES(?, ?, ?, ?, NULL)",
+        params![key, name, requests_per_minute, Utc::now().timestamp()],
+    )?;
+    Ok(key)
+}
+
+//Returns false if there's no such key that's still in use
+pub fn revoke_key(conn: &Connection, key: &str) -> rusqlite::Result<bool> {
+    let changed = conn.execute(
+        "UPDATE api_keys SET revoked = ? WHERE key = ? AND revoked IS NULL",
+        params![Utc::now().timestamp(), key],
+    )?;
+    Ok(changed > 0)
+}
+
+pub fn api_keys(conn: &Connection) -> rusqlite::Result<Vec<ApiKey>> {
+    let mut stmt = conn.prepare(
+        "SELECT key, name, requests_per_minute, created, revoked FROM api_keys ORDER BY created",
+    )?;
+    let keys = stmt
+        .query_map([], |r| {
+            Ok(ApiKey {
+                key: r.get(0)?,
+                name: r.get(1)?,
+                requests_per_minute: r.get(2)?,
+                created: r.get(3)?,
+                revoked: r.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(keys)
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub key: String,
+    pub name: String,
+    pub requests_per_minute: u32,
+    pub created: i64,
+    pub revoked: Option<i64>,
+}
+
+//Holds up to a minute's worth of requests and refills at the same pace
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, per_minute: u32, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        self.updated = now;
+    }
+}
+
+//What a request was allowed, for the X-RateLimit headers
+#[derive(Clone, Copy)]
+struct Quota {
+    limit: u32,
+    remaining: u32,
+    //Seconds until the next request would get through, only set when this one didn't
+    retry_after: Option<u64>,
+}
+
+//Limits the JSON API to `per_ip` requests a minute for every client IP. Requests with a key
+//in the X-Api-Key header get that key's own limit instead, unknown and revoked keys count
+//against their IP. The admin routes have their own token and aren't limited.
+pub struct RateLimiter {
+    per_ip: u32,
+    buckets: Mutex<FxHashMap<String, Bucket>>,
+    last_prune: Mutex<Instant>,
+    //Active keys and their requests per minute, with when they were loaded
+    keys: Mutex<Option<(Instant, FxHashMap<String, u32>)>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_ip: u32) -> Self {
+        Self {
+            per_ip,
+            buckets: Mutex::new(FxHashMap::default()),
+            last_prune: Mutex::new(Instant::now()),
+            keys: Mutex::new(None),
+        }
+    }
+
+    async fn key_limit(&self, req: &Request<'_>, key: &str) -> Option<u32> {
+        let now = Instant::now();
+        let stale = match &*self.keys.lock().unwrap() {
+            Some((loaded, _)) => now.duration_since(*loaded) >= KEY_REFRESH,
+            None => true,
+        };
+        if stale {
+            let conn = RatingsDbConn::get_one(req.rocket()).await?;
+            let keys = conn
+                .run(|conn| {
+                    conn.prepare(
+                        "SELECT key, requests_per_minute FROM api_keys WHERE revoked IS NULL",
+                    )?
+                    .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+                    .collect::<rusqlite::Result<FxHashMap<String, u32>>>()
+                })
+                .await;
+            match keys {
+                Ok(keys) => *self.keys.lock().unwrap() = Some((now, keys)),
+                Err(e) => warn!("Couldn't load API keys: {}", e),
+            }
+        }
+
+        self.keys
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|(_, keys)| keys.get(key).copied())
+    }
+
+    fn take(&self, bucket: String, limit: u32) -> Quota {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if now.duration_since(*last_prune) >= KEY_REFRESH {
+            //Buckets that filled back up are the same as new ones
+            buckets.retain(|_, b| now.duration_since(b.updated) < KEY_REFRESH);
+            *last_prune = now;
+        }
+        drop(last_prune);
+
+        let bucket = buckets.entry(bucket).or_insert(Bucket {
+            tokens: limit as f64,
+            updated: now,
+        });
+        bucket.refill(limit, now);
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) * 60.0 / limit as f64).ceil() as u64)
+        };
+        Quota {
+            limit,
+            remaining: bucket.tokens as u32,
+            retry_after,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "API rate limit",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let path = req.uri().path();
+        if self.per_ip == 0 || !path.starts_with("/api/") || path.starts_with("/api/admin/") {
+            return;
+        }
+
+        let key = req.headers().get_one(API_KEY_HEADER).map(str::to_owned);
+        let key_limit = match &key {
+            Some(key) => self.key_limit(req, key).await,
+            None => None,
+        };
+        let quota = match (key, key_limit) {
+            (Some(key), Some(limit)) => self.take(format!("key:{}", key), limit),
+            _ => {
+                let ip = req.client_ip().map(|ip| ip.to_string()).unwrap_or_default();
+                self.take(format!("ip:{}", ip), self.per_ip)
+            }
+        };
+
+        req.local_cache(|| Some(quota));
+        if quota.retry_after.is_some() {
+            req.set_method(Method::Get);
+            req.set_uri(Origin::parse(RATE_LIMITED_PATH).unwrap());
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Some(quota) = req.local_cache(|| None::<Quota>) {
+            res.set_header(Header::new("X-RateLimit-Limit", quota.limit.to_string()));
+            res.set_header(Header::new(
+                "X-RateLimit-Remaining",
+                quota.remaining.to_string(),
+            ));
+            if let Some(retry_after) = quota.retry_after {
+                res.set_header(Header::new("Retry-After", retry_after.to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket() {
+        let limiter = RateLimiter::new(2);
+        assert!(limiter.take("ip:a".to_owned(), 2).retry_after.is_none());
+        assert!(limiter.take("ip:a".to_owned(), 2).retry_after.is_none());
+        //Refills at one request every 30 seconds
+        assert_eq!(limiter.take("ip:a".to_owned(), 2).retry_after, Some(30));
+        //Other clients have their own bucket
+        assert!(limiter.take("ip:b".to_owned(), 2).retry_after.is_none());
+    }
+
+    #[test]
+    fn prune_idle_buckets() {
+        let limiter = RateLimiter::new(2);
+        limiter.take("ip:a".to_owned(), 2);
+        let past = Instant::now() - KEY_REFRESH;
+        limiter
+            .buckets
+            .lock()
+            .unwrap()
+            .get_mut("ip:a")
+            .unwrap()
+            .updated = past;
+        *limiter.last_prune.lock().unwrap() = past;
+
+        limiter.take("ip:b".to_owned(), 2);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("ip:a"));
+        assert!(buckets.contains_key("ip:b"));
+    }
+}
@@ -1,13 +1,35 @@
-use crate::{ggst_api, glicko, glicko::Rating, responses, website};
+use crate::{
+    anomaly, cache,
+    characters::{self, CharacterEntry},
+    db, export, glicko,
+    glicko::Rating,
+    live, metrics, notifications,
+    rating_system::{RatingSystem, RatingSystemKind},
+    replay_source::{NamedSource, ReplaySource, ReplaySourceKind},
+    requests::ALL_PLATFORMS,
+    responses,
+    storage::{self, PostgresStorage, SqliteStorage, Storage, StorageKind},
+};
 use anyhow::Context;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use fxhash::{FxHashMap, FxHashSet};
 use lazy_static::lazy_static;
+use rocket::serde::{json::serde_json::json, Deserialize, Serialize};
 use rusqlite::{
-    functions::FunctionFlags, named_params, params, Connection, OptionalExtension, Row, Transaction,
+    backup::Backup, functions::FunctionFlags, named_params, params, Connection, OptionalExtension,
+    Row, Transaction,
+};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, VecDeque},
+    future::Future,
+    ops::Range,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task, time, try_join,
 };
-use std::{sync::Mutex, time::Duration};
-use tokio::{time, try_join};
 
 const DECAY_CONSTANT: f64 = 3.1;
 
@@ -15,40 +37,369 @@ pub const LOW_DEVIATION: f64 = 75.0;
 pub const HIGH_RATING: f64 = 1800.0;
 pub const DB_NAME: &str = "ratings.sqlite";
 
-const CHAR_COUNT: usize = website::CHAR_NAMES.len();
 pub const POP_RATING_BRACKETS: usize = 13;
 
 pub const RATING_PERIOD: i64 = 60 * 60;
 pub const RANKING_PERIOD: i64 = 1 * 60 * 60;
 pub const STATISTICS_PERIOD: i64 = 24 * 60 * 60;
 
+//Pages that can be queued for the writer before fetching waits on it
+const PULL_CHANNEL_PAGES: usize = 8;
+//Attempts per page before it's recorded in fetch_failures, waiting longer after each
+const FETCH_ATTEMPTS: usize = 4;
+const FETCH_BACKOFF: Duration = Duration::from_secs(2);
+//Pause before a background loop that panicked is started again
+const SUPERVISOR_BACKOFF: Duration = Duration::from_secs(30);
+
+//Upsets are looked for in games from this far back, keeping the most unlikely ones
+const UPSET_WINDOW: i64 = 7 * 24 * 60 * 60;
+const UPSET_COUNT: usize = 100;
+
+//Every floor code a game can have, 99 is celestial
+pub const FLOORS: [i64; 11] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 99];
+
+//Floor bands with their own matchup charts as (key, name, min floor, max floor).
+//Floors are 1-10 with 99 being celestial.
+pub const FLOOR_BANDS: &[(&str, &str, i64, i64)] = &[
+    ("low", "Floors 1-7", 1, 7),
+    ("high", "Floors 8-10", 8, 10),
+    ("celestial", "Celestial", 99, 99),
+];
+
 lazy_static! {
-    pub static ref RUNTIME_DATA: Mutex<RuntimeData> = Mutex::new(RuntimeData {});
+    pub static ref RUNTIME_DATA: Mutex<RuntimeData> = Mutex::new(RuntimeData::default());
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RuntimeData {
+    pub last_pull_time: Option<i64>,
+    pub last_rating_update_time: Option<i64>,
+    pub last_ranking_update_time: Option<i64>,
+    pub games_pulled_last_tick: usize,
+    pub pages_pulled_last_tick: usize,
+    pub total_pull_errors: usize,
+    //Replays skipped because of codes we don't recognize
+    pub total_parse_errors: usize,
+    //Background loops started again after a panic
+    pub task_restarts: usize,
+    //How long the periodic update phases take, keyed by function name
+    pub phase_timings: BTreeMap<&'static str, PhaseTiming>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PhaseTiming {
+    pub runs: u64,
+    pub total_ms: i64,
+    pub last_ms: i64,
 }
 
-pub struct RuntimeData {}
+impl PhaseTiming {
+    pub fn average_ms(&self) -> f64 {
+        if self.runs == 0 {
+            0.0
+        } else {
+            self.total_ms as f64 / self.runs as f64
+        }
+    }
+}
+
+pub fn get_runtime_stats() -> RuntimeData {
+    RUNTIME_DATA.lock().unwrap().clone()
+}
+
+fn record_phase_time(phase: &'static str, elapsed_ms: i64) {
+    let mut runtime_data = RUNTIME_DATA.lock().unwrap();
+    let timing = runtime_data.phase_timings.entry(phase).or_default();
+    timing.runs += 1;
+    timing.total_ms += elapsed_ms;
+    timing.last_ms = elapsed_ms;
+    metrics::PHASE_DURATION
+        .with_label_values(&[phase])
+        .observe(elapsed_ms as f64 / 1000.0);
+}
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
 
-pub fn init_database() -> Result<()> {
+//Loaded from config.toml when there is one, with RATING_* environment variables on top.
+//Every field is optional in the file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub db_path: String,
+    //Seconds between replay pulls
+    pub pull_interval_secs: u64,
+    //Length of a glicko rating period in seconds, idle players decay once per period
+    pub rating_period: i64,
+    //How much the deviation grows per idle rating period
+    pub decay_constant: f64,
+    //Characters rated above this count towards the high rated tables
+    pub high_rating: f64,
+    //Ratings with a lower deviation than this count as established for rankings,
+    //distributions and top ratings. Past top ratings only change on a full recompute.
+    pub low_deviation: f64,
+    //Only count players who played in the last this many days in distributions and rankings
+    pub active_days: Option<i64>,
+    //Characters with no games in this many rating periods drop off the rankings. Their
+    //deviation keeps growing while idle either way.
+    pub idle_periods: Option<i64>,
+    //Floor range requested from the replay API, 1-10 are the numbered floors and 99 is celestial
+    pub min_floor: i64,
+    pub max_floor: i64,
+    //How many replays the API returns per page, 127 is the most it will hand out at once
+    pub replays_per_page: usize,
+    //Pages pulled per tick normally, raised up to max_pages while most replays are new
+    pub pages: usize,
+    pub max_pages: usize,
+    //How far past the regular pages a backfill goes looking for the end of a gap
+    pub backfill_pages: usize,
+    //Pages pulled on startup against an empty database to seed some history
+    pub initial_pages: usize,
+    //Optional lower bound for rating values after a game, off by default to keep plain glicko
+    pub rating_floor: Option<f64>,
+    //Log pulls and rating updates as JSON lines instead of prose
+    pub json_logs: bool,
+    //Math used for player ratings, recorded in the database on the first update
+    pub rating_system: RatingSystemKind,
+    //Character pairs with fewer games than this are left out of the computed matchup tables
+    pub matchup_min_games: i64,
+    //Games between two players count 1/sqrt(n) towards the character matchups, n being how
+    //often they played each other in this many days, so a pair grinding hundreds of games
+    //can't drag a matchup around. 0 counts every game fully.
+    pub matchup_pair_window_days: i64,
+    //Bearer token for the admin routes, which are turned off while this is unset
+    pub admin_token: Option<String>,
+    //Requests a minute each client IP gets on the JSON API, 0 turns the limit off. API keys
+    //from `api-keys mint` come with their own limit.
+    pub api_rate_limit: u32,
+    //Where games and ratings are kept. Everything else stays in the SQLite database at db_path,
+    //which also still gets every pulled game.
+    pub storage: StorageKind,
+    //Where the daily public database snapshots go, and how many of them are kept
+    pub snapshot_dir: String,
+    pub snapshot_keep: usize,
+    //Where pulls get replays from, the game's own replay API unless set
+    pub replay_source: ReplaySourceKind,
+    //Named sources pulled side by side, e.g. one per platform. Replaces replay_source when set.
+    pub sources: Vec<NamedSource>,
+    //Renamed and newly released characters, on top of the ones this build knows
+    pub characters: Vec<CharacterEntry>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            db_path: DB_NAME.to_owned(),
+            pull_interval_secs: 60,
+            rating_period: RATING_PERIOD,
+            decay_constant: DECAY_CONSTANT,
+            high_rating: HIGH_RATING,
+            low_deviation: LOW_DEVIATION,
+            active_days: None,
+            idle_periods: None,
+            min_floor: 1,
+            max_floor: 99,
+            replays_per_page: 127,
+            pages: 10,
+            max_pages: 40,
+            backfill_pages: 100,
+            initial_pages: 200,
+            rating_floor: None,
+            json_logs: false,
+            rating_system: RatingSystemKind::Glicko,
+            matchup_min_games: 50,
+            matchup_pair_window_days: 7,
+            admin_token: None,
+            api_rate_limit: 120,
+            storage: StorageKind::Sqlite,
+            snapshot_dir: "snapshots".to_owned(),
+            snapshot_keep: 7,
+            replay_source: ReplaySourceKind::Ggst {
+                platforms: ALL_PLATFORMS,
+            },
+            sources: Vec::new(),
+            characters: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    //Reads the file named by RATING_CONFIG, config.toml by default, then the environment
+    pub fn load() -> Result<Self> {
+        let path = std::env::var("RATING_CONFIG").unwrap_or_else(|_| "config.toml".to_owned());
+        let config = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).with_context(|| format!("Couldn't parse {}", path))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e).with_context(|| format!("Couldn't read {}", path)),
+        };
+
+        Ok(config.with_env())
+    }
+
+    //Applies overrides from the environment, e.g. RATING_HIGH_RATING=1900
+    pub fn with_env(self) -> Self {
+        let mut config = self;
+        if let Some(db_path) = env_var("RATING_DB_PATH") {
+            config.db_path = db_path;
+        }
+        if let Some(pull_interval_secs) = env_var("RATING_PULL_INTERVAL_SECS") {
+            config.pull_interval_secs = pull_interval_secs;
+        }
+        if let Some(rating_period) = env_var("RATING_PERIOD") {
+            config.rating_period = rating_period;
+        }
+        if let Some(decay_constant) = env_var("RATING_DECAY_CONSTANT") {
+            config.decay_constant = decay_constant;
+        }
+        if let Some(high_rating) = env_var("RATING_HIGH_RATING") {
+            config.high_rating = high_rating;
+        }
+        if let Some(low_deviation) = env_var("RATING_LOW_DEVIATION") {
+            config.low_deviation = low_deviation;
+        }
+        if let Some(active_days) = env_var("RATING_ACTIVE_DAYS") {
+            config.active_days = Some(active_days);
+        }
+        if let Some(idle_periods) = env_var("RATING_IDLE_PERIODS") {
+            config.idle_periods = Some(idle_periods);
+        }
+        if let Some(min_floor) = env_var("RATING_MIN_FLOOR") {
+            config.min_floor = min_floor;
+        }
+        if let Some(max_floor) = env_var("RATING_MAX_FLOOR") {
+            config.max_floor = max_floor;
+        }
+        if let Some(replays_per_page) = env_var("RATING_REPLAYS_PER_PAGE") {
+            config.replays_per_page = replays_per_page;
+        }
+        if let Some(pages) = env_var("RATING_PAGES") {
+            config.pages = pages;
+        }
+        if let Some(max_pages) = env_var("RATING_MAX_PAGES") {
+            config.max_pages = max_pages;
+        }
+        if let Some(backfill_pages) = env_var("RATING_BACKFILL_PAGES") {
+            config.backfill_pages = backfill_pages;
+        }
+        if let Some(initial_pages) = env_var("RATING_INITIAL_PAGES") {
+            config.initial_pages = initial_pages;
+        }
+        if let Some(rating_floor) = env_var("RATING_FLOOR") {
+            config.rating_floor = Some(rating_floor);
+        }
+        if let Some(json_logs) = env_var("RATING_JSON_LOGS") {
+            config.json_logs = json_logs;
+        }
+        if let Some(rating_system) = env_var("RATING_SYSTEM") {
+            config.rating_system = rating_system;
+        }
+        if let Some(matchup_min_games) = env_var("RATING_MATCHUP_MIN_GAMES") {
+            config.matchup_min_games = matchup_min_games;
+        }
+        if let Some(matchup_pair_window_days) = env_var("RATING_MATCHUP_PAIR_WINDOW_DAYS") {
+            config.matchup_pair_window_days = matchup_pair_window_days;
+        }
+        if let Some(admin_token) = env_var("RATING_ADMIN_TOKEN") {
+            config.admin_token = Some(admin_token);
+        }
+        if let Some(api_rate_limit) = env_var("RATING_API_RATE_LIMIT") {
+            config.api_rate_limit = api_rate_limit;
+        }
+        if let Some(storage) = env_var("RATING_STORAGE") {
+            config.storage = storage;
+        }
+        if let Some(snapshot_dir) = env_var("RATING_SNAPSHOT_DIR") {
+            config.snapshot_dir = snapshot_dir;
+        }
+        if let Some(snapshot_keep) = env_var("RATING_SNAPSHOT_KEEP") {
+            config.snapshot_keep = snapshot_keep;
+        }
+        if let Some(replay_source) = env_var("RATING_REPLAY_SOURCE") {
+            config.replay_source = replay_source;
+        }
+        config
+    }
+
+    //The sources to pull from, replay_source under the name "default" if no list is set
+    pub fn pull_sources(&self) -> Vec<NamedSource> {
+        if self.sources.is_empty() {
+            vec![NamedSource {
+                name: "default".to_owned(),
+                kind: self.replay_source.clone(),
+            }]
+        } else {
+            self.sources.clone()
+        }
+    }
+
+    pub fn active_since(&self) -> Option<i64> {
+        self.active_days
+            .map(|days| Utc::now().timestamp() - days * 24 * 60 * 60)
+    }
+
+    pub fn idle_since(&self) -> Option<i64> {
+        self.idle_periods
+            .map(|periods| Utc::now().timestamp() - periods * self.rating_period)
+    }
+}
+
+//Matches rows of `table` whose player has a game since :active_since, or everything when
+//it's null. Split in two so each half can use the per-player game indices.
+fn active_filter(table: &str) -> String {
+    format!(
+        "(:active_since IS NULL
+            OR EXISTS (SELECT 1 FROM games WHERE id_a = {table}.id AND timestamp >= :active_since)
+            OR EXISTS (SELECT 1 FROM games WHERE id_b = {table}.id AND timestamp >= :active_since))"
+    )
+}
+
+//Same as active_filter but for the character in `table`, using :idle_since
+fn idle_filter(table: &str) -> String {
+    format!(
+        "(:idle_since IS NULL
+            OR EXISTS (SELECT 1 FROM games
+                WHERE id_a = {table}.id AND char_a = {table}.char_id AND timestamp >= :idle_since)
+            OR EXISTS (SELECT 1 FROM games
+                WHERE id_b = {table}.id AND char_b = {table}.char_id AND timestamp >= :idle_since))"
+    )
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            warn!("Ignoring invalid value for {}: {}", name, value);
+            None
+        }
+    }
+}
+
+//Opening the database creates it or applies whatever migrations it's missing
+pub fn init_database(config: &Config) -> Result<()> {
     info!("Intializing database");
 
-    let conn = Connection::open(DB_NAME)?;
-    conn.execute_batch(include_str!("../init.sql"))?;
+    let mut conn = db::open(&config.db_path)?;
+    info!(
+        "Database is at schema version {}",
+        db::schema_version(&conn)?
+    );
+    characters::sync_table(&mut conn)?;
 
     Ok(())
 }
 
-pub fn reset_database() -> Result<()> {
+pub fn reset_database(config: &Config) -> Result<()> {
     info!("Resetting database");
-    let conn = Connection::open(DB_NAME)?;
+    let conn = db::open(&config.db_path)?;
     conn.execute_batch(include_str!("../reset.sql"))?;
 
     Ok(())
 }
 
-pub fn reset_names() -> Result<()> {
-    let mut conn = Connection::open(DB_NAME)?;
+pub fn reset_names(config: &Config) -> Result<()> {
+    let mut conn = db::open(&config.db_path)?;
 
     let tx = conn.transaction()?;
 
@@ -70,202 +421,908 @@ pub fn reset_names() -> Result<()> {
         update_player(&tx, g.id_b, &g.name_b, g.game_floor, g.platform_b);
     }
 
+    //Also fills the name index for databases made before it existed
+    tx.execute(
+        "INSERT INTO player_names_fts(player_names_fts) VALUES('rebuild')",
+        [],
+    )?;
+
     tx.commit()?;
 
     Ok(())
 }
 
-pub fn reset_distribution() -> Result<()> {
-    let mut conn = Connection::open(DB_NAME)?;
+pub fn reset_distribution(config: &Config) -> Result<()> {
+    let mut conn = db::open(&config.db_path)?;
 
-    update_player_distribution(&mut conn);
+    update_player_distribution(&mut conn, config);
 
     Ok(())
 }
 
-pub async fn run() -> Result<()> {
-    try_join! {
-        async {
-            tokio::spawn(pull_continuous()).await?;
-            Ok(())
-        },
-        async {
-            tokio::spawn(
-                async {
-                    update_statistics_continuous()
-                    .await
-                    .context("Inside `update_rating_continuous`")
-                }).await?
-        },
-    }?;
+//Uses the online backup API so the copy is consistent even while other connections write
+pub fn backup_database(conn: &Connection, dest_path: &str) -> Result<()> {
+    let mut dest = Connection::open(dest_path)?;
+    let backup = Backup::new(conn, &mut dest)?;
+    backup.run_to_completion(100, Duration::from_millis(10), None)?;
 
-    Ok(())
-}
+    info!(
+        "Backed up {} pages to {}",
+        backup.progress().pagecount,
+        dest_path
+    );
 
-async fn pull_continuous() {
-    let mut conn = Connection::open(DB_NAME).unwrap();
-    grab_games(&mut conn, 100).await.unwrap();
-    let mut interval = time::interval(Duration::from_secs(60));
-    loop {
-        interval.tick().await;
-        if let Err(e) = grab_games(&mut conn, 10).await {
-            error!("grab_games failed: {}", e)
-        }
-    }
+    Ok(())
 }
 
-pub async fn update_statistics_continuous() -> Result<()> {
-    let mut conn = Connection::open(DB_NAME)?;
+pub fn reset_matchups_for_char(conn: &mut Connection, char_id: i64) -> Result<()> {
+    let tx = conn.transaction()?;
 
-    let mut last_ranking_update: i64 =
-        conn.query_row("SELECT last_update FROM config", [], |r| r.get(0))?;
-    let mut last_statistics_update = last_ranking_update;
+    for table in [
+        "player_matchups",
+        "player_floor_matchups",
+        "global_matchups",
+        "top_100_matchups",
+        "top_1000_matchups",
+        "proportional_matchups",
+        "floor_matchups",
+    ] {
+        let removed = tx.execute(
+            &format!(
+                "DELETE FROM {} WHERE char_id = ?1 OR opp_char_id = ?1",
+                table
+            ),
+            params![char_id],
+        )?;
+        info!(
+            "Removed {} rows from {} for character {}",
+            removed, table, char_id
+        );
+    }
 
-    let mut interval = time::interval(Duration::from_secs(60));
+    tx.commit()?;
 
-    loop {
-        interval.tick().await;
-        let now = Utc::now().timestamp();
-        if now - last_ranking_update > RANKING_PERIOD {
-            info!("New ranking period, updating decay and rankings");
+    Ok(())
+}
 
-            if last_ranking_update - last_statistics_update >= STATISTICS_PERIOD {
-                info!("New statistics period, updating statistics.");
-                last_statistics_update = last_ranking_update;
-                update_player_distribution(&mut conn);
-                //if let Err(e) = calc_versus_matchups(&mut conn) {
-                //    error!("calc_versus_matchups failed: {}", e);
-                //}
-                if let Err(e) = calc_fraud_index(&mut conn) {
-                    error!("calc_fraud_index failed: {}", e);
-                }
-                if let Err(e) = calc_character_popularity(&mut conn, last_ranking_update) {
-                    error!("calc_character_popularity failed: {}", e);
-                }
-            }
+//Folds merge_id into keep_id. Ratings aren't replayed here, so they stay stale until the
+//database is reset and rated again.
+//Every column that holds a player id, counted up front so a dry run can show what would change
+const MERGE_COLUMNS: &[(&str, &str)] = &[
+    ("games", "id_a"),
+    ("games", "id_b"),
+    ("game_ratings", "id_a"),
+    ("game_ratings", "id_b"),
+    ("players", "id"),
+    ("player_names", "id"),
+    ("player_ratings", "id"),
+    ("player_ratings", "top_defeated_id"),
+    ("player_matchups", "id"),
+    ("player_floor_matchups", "id"),
+    ("daily_ratings", "id"),
+    ("player_rating_history", "id"),
+    ("player_percentiles", "id"),
+    ("player_overall_ratings", "id"),
+    ("ranking_global", "id"),
+    ("ranking_character", "id"),
+    ("hidden_status", "id"),
+    ("cheater_status", "id"),
+    ("vip_status", "id"),
+    ("claimed_players", "id"),
+    ("flagged_players", "id"),
+    ("watchlists", "player_id"),
+    ("notifications", "player_id"),
+];
+
+//Everything happens in one transaction, a dry run rolls it back and leaves no audit entry
+pub fn merge_players(
+    conn: &mut Connection,
+    keep_id: i64,
+    merge_id: i64,
+    dry_run: bool,
+    source: &str,
+) -> Result<MergeReport> {
+    if keep_id == merge_id {
+        anyhow::bail!("Can't merge player {:X} into itself", keep_id);
+    }
 
-            if let Err(e) = update_decay(&mut conn, Utc::now().timestamp()) {
-                error!("update_decay failed: {}", e);
-            }
-            if let Err(e) = decay_matchups(&mut conn, Utc::now().timestamp()) {
-                error!("decay_matchups failed: {}", e);
-            }
-            if let Err(e) = update_rankings(&mut conn) {
-                error!("update_rankings failed: {}", e);
-            }
+    let tx = conn.transaction()?;
 
-            while now - last_ranking_update > RANKING_PERIOD {
-                last_ranking_update += RANKING_PERIOD;
-            }
+    let mut rows = BTreeMap::<&str, i64>::new();
+    for (table, column) in MERGE_COLUMNS {
+        let count: i64 = tx.query_row(
+            &format!("SELECT COUNT(*) FROM {table} WHERE {column} = ?"),
+            params![merge_id],
+            |r| r.get(0),
+        )?;
+        if count > 0 {
+            *rows.entry(*table).or_default() += count;
+        }
+    }
 
-            info!(
-                "Last ranking period: {}",
-                NaiveDateTime::from_timestamp(last_ranking_update, 0)
-            );
+    //Games between the two would turn into games against themselves
+    for table in ["games", "game_ratings"] {
+        tx.execute(
+            &format!(
+                "DELETE FROM {table}
+                WHERE (id_a = ?1 AND id_b = ?2) OR (id_a = ?2 AND id_b = ?1)"
+            ),
+            params![keep_id, merge_id],
+        )?;
+    }
 
-            conn.execute(
-                "UPDATE config SET last_update = ?",
-                params![last_ranking_update],
-            )
-            .unwrap();
+    for (table, columns) in [
+        ("games", ["id_a", "id_b"]),
+        ("game_ratings", ["id_a", "id_b"]),
+    ] {
+        for column in columns {
+            tx.execute(
+                &format!("UPDATE OR IGNORE {table} SET {column} = ?1 WHERE {column} = ?2"),
+                params![keep_id, merge_id],
+            )?;
+            //Anything left would have collided with a row the kept player already has
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE {column} = ?"),
+                params![merge_id],
+            )?;
         }
     }
-}
 
-pub async fn update_once() {
-    let mut conn = Connection::open(DB_NAME).unwrap();
+    //Characters only the merged id played move over as they are. Where both played the same
+    //character or matchup the kept rating stays and the records are added together.
+    for (table, key) in [
+        ("player_ratings", "char_id"),
+        ("player_matchups", "char_id, opp_char_id"),
+        ("player_floor_matchups", "char_id, opp_char_id, floor"),
+    ] {
+        tx.execute(
+            &format!("UPDATE OR IGNORE {table} SET id = ?1 WHERE id = ?2"),
+            params![keep_id, merge_id],
+        )?;
+        tx.execute(
+            &format!(
+                "UPDATE {table} SET
+                    wins = wins + (SELECT wins FROM {table} AS m
+                        WHERE m.id = ?2 AND ({key}) = ({table_key})),
+                    losses = losses + (SELECT losses FROM {table} AS m
+                        WHERE m.id = ?2 AND ({key}) = ({table_key}))
+                WHERE id = ?1 AND ({key}) IN (SELECT {key} FROM {table} WHERE id = ?2)",
+                table_key = key
+                    .split(", ")
+                    .map(|c| format!("{table}.{c}"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            params![keep_id, merge_id],
+        )?;
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE id = ?"),
+            params![merge_id],
+        )?;
+    }
+    tx.execute(
+        "UPDATE player_ratings SET top_defeated_id = ?1 WHERE top_defeated_id = ?2",
+        params![keep_id, merge_id],
+    )?;
 
-    while update_ratings(&mut conn, None) > 0 {
-        update_rankings(&mut conn).unwrap();
+    //Where both have a snapshot for the same day or period the kept one wins
+    for table in ["daily_ratings", "player_rating_history"] {
+        tx.execute(
+            &format!("UPDATE OR IGNORE {table} SET id = ?1 WHERE id = ?2"),
+            params![keep_id, merge_id],
+        )?;
     }
 
-    //let last_rating_timestamp: i64 = conn
-    //    .query_row("SELECT last_update FROM config", [], |r| r.get(0))
-    //    .unwrap();
-    update_player_distribution(&mut conn);
-    //if let Err(e) = calc_versus_matchups(&mut conn) {
-    //    error!("calc_versus_matchups failed: {}", e);
-    //}
-    if let Err(e) = calc_fraud_index(&mut conn) {
-        error!("calc_fraud_index failed: {}", e);
+    //Statuses come along so merging can't bring back a hidden player or clear a cheater flag.
+    //Where both have one the kept id's stays.
+    for table in [
+        "hidden_status",
+        "cheater_status",
+        "vip_status",
+        "claimed_players",
+    ] {
+        tx.execute(
+            &format!("UPDATE OR IGNORE {table} SET id = ?1 WHERE id = ?2"),
+            params![keep_id, merge_id],
+        )?;
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE id = ?"),
+            params![merge_id],
+        )?;
     }
+    tx.execute(
+        "UPDATE flagged_players SET excluded = 1
+        WHERE id = ?1 AND reason IN (SELECT reason FROM flagged_players WHERE id = ?2 AND excluded)",
+        params![keep_id, merge_id],
+    )?;
+    tx.execute(
+        "UPDATE OR IGNORE flagged_players SET id = ?1 WHERE id = ?2",
+        params![keep_id, merge_id],
+    )?;
+    tx.execute(
+        "DELETE FROM flagged_players WHERE id = ?",
+        params![merge_id],
+    )?;
+    tx.execute(
+        "UPDATE OR IGNORE watchlists SET player_id = ?1 WHERE player_id = ?2",
+        params![keep_id, merge_id],
+    )?;
+    tx.execute(
+        "DELETE FROM watchlists WHERE player_id = ?",
+        params![merge_id],
+    )?;
+    tx.execute(
+        "UPDATE notifications SET player_id = ?1 WHERE player_id = ?2",
+        params![keep_id, merge_id],
+    )?;
 
-    if let Err(e) = update_rankings(&mut conn) {
-        error!("update_rankings failed: {}", e);
+    tx.execute(
+        "INSERT OR IGNORE INTO player_names SELECT ?1, name FROM players WHERE id = ?2",
+        params![keep_id, merge_id],
+    )?;
+    tx.execute(
+        "UPDATE OR IGNORE player_names SET id = ?1 WHERE id = ?2",
+        params![keep_id, merge_id],
+    )?;
+
+    //Rankings and the rest are rebuilt with the next ranking update
+    for table in [
+        "player_names",
+        "players",
+        "daily_ratings",
+        "player_rating_history",
+        "player_percentiles",
+        "player_overall_ratings",
+        "ranking_global",
+        "ranking_character",
+    ] {
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE id = ?"),
+            params![merge_id],
+        )?;
     }
-    //if let Err(e) = calc_character_popularity(&mut conn, last_rating_timestamp) {
-    //    error!("calc_character_popularity failed: {}", e);
-    //}
-}
 
-pub fn print_rankings() {
-    let conn = Connection::open(DB_NAME).unwrap();
+    let report = MergeReport {
+        keep_id: format!("{:X}", keep_id),
+        merge_id: format!("{:X}", merge_id),
+        dry_run,
+        rows: rows.into_iter().map(|(t, c)| (t.to_owned(), c)).collect(),
+    };
 
-    println!("| Rank | Name | Character | Rating | Games |");
-    println!("|------|------|-----------|--------|-------|");
+    if dry_run {
+        tx.rollback()?;
+        return Ok(report);
+    }
 
-    let mut stmt = conn
-            .prepare(
-                "SELECT name, char_id, value, deviation, (wins + losses) as games, (100.0 * wins) / (wins + losses) as win_rate
-                FROM player_ratings NATURAL JOIN players
-                WHERE deviation < 75.0 
-                ORDER BY value - 2.0 * deviation DESC
-                LIMIT 100
-                ",
-            )
-            .unwrap();
+    tx.execute(
+        "INSERT INTO player_merges(timestamp, keep_id, merge_id, source, rows)
+        VALUES(strftime('%s', 'now'), ?, ?, ?, ?)",
+        params![keep_id, merge_id, source, json!(report.rows).to_string()],
+    )?;
+    tx.commit()?;
+    cache::invalidate();
 
-    let mut rank = 1;
-    let mut rows = stmt.query([]).unwrap();
+    warn!(
+        "Merged player {:X} into {:X}, ratings need a full recompute to be correct",
+        merge_id, keep_id
+    );
 
-    while let Some(row) = rows.next().unwrap() {
-        let name: String = row.get(0).unwrap();
-        let char_name = website::CHAR_NAMES[row.get::<_, usize>(1).unwrap()].1;
-        let value: f64 = row.get(2).unwrap();
-        let deviation: f64 = row.get(3).unwrap();
-        let games: i64 = row.get(4).unwrap();
-        let rate: f64 = row.get(5).unwrap();
-        println!(
-            "| {} | {} | {} | {:.0} ±{:.0} | {} ({:.0}%) |",
-            rank, name, char_name, value, deviation, games, rate
-        );
+    Ok(report)
+}
 
-        rank += 1;
+pub fn prune_empty_players(conn: &mut Connection) -> Result<usize> {
+    let tx = conn.transaction()?;
+
+    //Rows keyed on (id, char_id) go first, while we can still tell which ratings are empty
+    for table in [
+        "player_matchups",
+        "player_floor_matchups",
+        "daily_ratings",
+        "ranking_global",
+        "ranking_character",
+    ] {
+        tx.execute(
+            &format!(
+                "DELETE FROM {table}
+                WHERE EXISTS (
+                    SELECT 1 FROM player_ratings
+                    WHERE player_ratings.id = {table}.id
+                        AND player_ratings.char_id = {table}.char_id
+                        AND wins = 0 AND losses = 0)"
+            ),
+            [],
+        )?;
     }
-}
+    let ratings_removed = tx.execute(
+        "DELETE FROM player_ratings WHERE wins = 0 AND losses = 0",
+        [],
+    )?;
 
-pub fn mark_vip(vip_id: &str, notes: &str) {
-    let vip_id = i64::from_str_radix(vip_id, 16).unwrap();
+    let players_removed = tx.execute(
+        "DELETE FROM players
+        WHERE id NOT IN (SELECT id_a FROM games UNION SELECT id_b FROM games)",
+        [],
+    )?;
+    tx.execute(
+        "DELETE FROM player_names WHERE id NOT IN (SELECT id FROM players)",
+        [],
+    )?;
 
-    let conn = Connection::open(DB_NAME).unwrap();
-    conn.execute(
-        "INSERT INTO vip_status
-            VALUES(?, 'VIP', ?)",
-        params![vip_id, notes],
-    )
-    .unwrap();
+    tx.commit()?;
+
+    info!(
+        "Pruned {} empty ratings and {} players without games",
+        ratings_removed, players_removed
+    );
+
+    Ok(ratings_removed + players_removed)
 }
 
-pub fn mark_hidden(hidden_id: &str, notes: &str) {
-    let hidden_id = i64::from_str_radix(hidden_id, 16).unwrap();
+pub async fn run(config: Config, shutdown: watch::Receiver<bool>) -> Result<()> {
+    let pull_config = config.clone();
+    let pull_shutdown = shutdown.clone();
+    let notify_config = config.clone();
+    let notify_shutdown = shutdown.clone();
+    let statistics_shutdown = shutdown.clone();
+    try_join! {
+        supervise("pull_continuous", shutdown.clone(), move || {
+            let config = pull_config.clone();
+            let shutdown = pull_shutdown.clone();
+            async move {
+                pull_continuous(config, shutdown).await;
+                Ok(())
+            }
+        }),
+        supervise("notifications", shutdown.clone(), move || {
+            notifications::run(notify_config.clone(), notify_shutdown.clone())
+        }),
+        supervise("update_statistics_continuous", shutdown, move || {
+            let config = config.clone();
+            let shutdown = statistics_shutdown.clone();
+            async move {
+                update_statistics_continuous(&config, shutdown)
+                    .await
+                    .context("Inside `update_statistics_continuous`")
+            }
+        }),
+    }?;
 
-    let conn = Connection::open(DB_NAME).unwrap();
-    conn.execute(
-        "INSERT INTO hidden_status
-            VALUES(?, 'hidden', ?)",
-        params![hidden_id, notes],
-    )
-    .unwrap();
+    Ok(())
 }
 
-pub async fn mark_cheater(
+//Runs a background loop on its own task and starts it again if it panics, so one bad replay
+//or query doesn't take the whole process down. An open transaction is rolled back when the
+//panic drops it, so the restarted loop picks up from the last commit. Errors are returned
+//as they are.
+async fn supervise<F, Fut>(
+    name: &'static str,
+    mut shutdown: watch::Receiver<bool>,
+    start: F,
+) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        let panic = match tokio::spawn(start()).await {
+            Ok(result) => return result,
+            Err(e) if e.is_panic() => e.into_panic(),
+            Err(e) => return Err(e.into()),
+        };
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|m| m.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        metrics::TASK_RESTARTS.with_label_values(&[name]).inc();
+        RUNTIME_DATA.lock().unwrap().task_restarts += 1;
+        error!(
+            "{} panicked: {}, restarting it in {}s",
+            name,
+            message,
+            SUPERVISOR_BACKOFF.as_secs()
+        );
+
+        if *shutdown.borrow() {
+            return Ok(());
+        }
+        tokio::select! {
+            _ = time::sleep(SUPERVISOR_BACKOFF) => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+}
+
+//Runs work that would hold up one of the runtime's threads on the blocking pool. A panic in
+//it goes on as a panic of the calling task, so supervise restarts the loop instead of
+//getting an error back.
+async fn run_blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(value) => Ok(value),
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+//Waits for the next tick, returns false instead if a shutdown was requested
+async fn next_tick(interval: &mut time::Interval, shutdown: &mut watch::Receiver<bool>) -> bool {
+    if *shutdown.borrow() {
+        return false;
+    }
+    tokio::select! {
+        _ = interval.tick() => !*shutdown.borrow(),
+        _ = shutdown.changed() => false,
+    }
+}
+
+//Every source is pulled by its own task, a single writer puts all of their games in the database
+async fn pull_continuous(config: Config, shutdown: watch::Receiver<bool>) {
+    let (writer, events) = mpsc::channel(PULL_CHANNEL_PAGES);
+    let writer_config = config.clone();
+    let mut writer_task = task::spawn_blocking(move || write_games(events, &writer_config));
+
+    let mut pullers = task::JoinSet::new();
+    for source in config.pull_sources() {
+        pullers.spawn(pull_source(
+            source,
+            writer.clone(),
+            config.clone(),
+            shutdown.clone(),
+        ));
+    }
+    //Once every puller is done the channel closes, which lets the writer finish whatever
+    //pages are still queued
+    drop(writer);
+
+    let mut writer_done = false;
+    while !pullers.is_empty() {
+        tokio::select! {
+            //A panic anywhere takes the whole pull down so it's restarted in one piece, the
+            //writer finishes what was already queued on its own
+            Some(joined) = pullers.join_next() => {
+                if let Err(e) = joined {
+                    if e.is_panic() {
+                        pullers.abort_all();
+                        std::panic::resume_unwind(e.into_panic());
+                    }
+                    error!("Pulling a source failed: {}", e);
+                }
+            }
+            //The pullers hold the channel open, so the writer only stops before them when it
+            //panicked
+            written = &mut writer_task, if !writer_done => {
+                writer_done = true;
+                if let Err(e) = written {
+                    pullers.abort_all();
+                    if e.is_panic() {
+                        std::panic::resume_unwind(e.into_panic());
+                    }
+                }
+            }
+        }
+    }
+    if !writer_done {
+        writer_task.await.unwrap();
+    }
+    info!("Stopped pulling replays");
+}
+
+async fn pull_source(
+    named: NamedSource,
+    writer: mpsc::Sender<PullEvent>,
+    config: Config,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let name = named.name.as_str();
+    let source = named.kind.source();
+    info!("Pulling replays from {} as {}", named.kind, name);
+
+    let game_count: i64 = db::open(&config.db_path)
+        .unwrap()
+        .query_row(
+            "SELECT COUNT(*) FROM games WHERE source = ?",
+            params![name],
+            |r| r.get(0),
+        )
+        .unwrap();
+    let initial_pages = if game_count == 0 {
+        info!(
+            "No games from {} yet, seeding with {} pages",
+            name, config.initial_pages
+        );
+        config.initial_pages
+    } else {
+        info!("Catching up on {} pages from {}", config.max_pages, name);
+        config.max_pages
+    };
+    let mut unresolved_failures =
+        match grab_games(&*source, name, &writer, 0..initial_pages, &config).await {
+            Ok(outcome) => outcome.unresolved_failures,
+            Err(e) => {
+                error!("grab_games failed for {}: {}", name, e);
+                return;
+            }
+        };
+    let mut interval = time::interval(Duration::from_secs(config.pull_interval_secs));
+    let mut pages = config.pages;
+    while next_tick(&mut interval, &mut shutdown).await {
+        //Pages shift as new replays come in, so a failed page is retried by pulling as deep
+        //as we can until a pull gets through without failures
+        let tick_pages = if unresolved_failures > 0 {
+            info!(
+                "Retrying {} failed fetches from {} with {} pages",
+                unresolved_failures, name, config.max_pages
+            );
+            config.max_pages
+        } else {
+            pages
+        };
+        match grab_games(&*source, name, &writer, 0..tick_pages, &config).await {
+            Ok(PullOutcome {
+                new_ratio,
+                unresolved_failures: unresolved,
+                gap,
+            }) => {
+                unresolved_failures = unresolved;
+                if let Some(gap) = gap {
+                    if let Err(e) =
+                        backfill(&*source, name, &writer, gap, tick_pages, &config).await
+                    {
+                        error!("Backfill of {} failed: {}", name, e);
+                    }
+                }
+                //Most replays being new means we're likely missing some, so grab more next
+                //time. Ease back towards the baseline once things calm down.
+                if new_ratio > 0.5 && pages < config.max_pages {
+                    pages = (pages * 2).min(config.max_pages);
+                    info!("Increasing page count for {} to {}", name, pages);
+                } else if new_ratio < 0.25 && pages > config.pages {
+                    pages -= 1;
+                    info!("Decreasing page count for {} to {}", name, pages);
+                }
+            }
+            Err(e) => {
+                error!("grab_games failed for {}: {}", name, e);
+                RUNTIME_DATA.lock().unwrap().total_pull_errors += 1;
+            }
+        }
+    }
+}
+
+pub fn verify_config_consistency(conn: &Connection) -> Result<()> {
+    let (last_update, newest_game): (i64, Option<i64>) = conn.query_row(
+        "SELECT last_update, (SELECT MAX(timestamp) FROM games) FROM config",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+
+    if let Some(newest_game) = newest_game {
+        if last_update > newest_game {
+            anyhow::bail!(
+                "config.last_update ({}) is ahead of the newest game ({})",
+                last_update,
+                newest_game
+            );
+        }
+    }
+
+    Ok(())
+}
+
+//Seconds since the last finished ranking period, large while the loop is working through a backlog
+pub fn rating_lag_seconds(conn: &Connection) -> i64 {
+    let last_update: i64 = conn
+        .query_row("SELECT last_update FROM config", [], |r| r.get(0))
+        .unwrap();
+    Utc::now().timestamp() - last_update
+}
+
+//Same threshold update_statistics_continuous uses, plus a minute of slack for its tick interval
+pub fn is_caught_up(conn: &Connection) -> bool {
+    rating_lag_seconds(conn) <= RANKING_PERIOD + 60
+}
+
+pub async fn update_statistics_continuous(
+    config: &Config,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut conn = db::open(&config.db_path)?;
+    characters::sync_table(&mut conn)?;
+
+    //A last_update from the future would hold off rankings and decay until it's reached
+    if let Err(e) = verify_config_consistency(&conn) {
+        warn!("{}, clamping it to the newest game", e);
+        conn.execute(
+            "UPDATE config SET last_update = (SELECT MAX(timestamp) FROM games)",
+            [],
+        )?;
+    }
+
+    let mut last_ranking_update: i64 =
+        conn.query_row("SELECT last_update FROM config", [], |r| r.get(0))?;
+    let mut last_statistics_update = last_ranking_update;
+
+    let mut interval = time::interval(Duration::from_secs(60));
+
+    while next_tick(&mut interval, &mut shutdown).await {
+        let now = Utc::now().timestamp();
+        if now - last_ranking_update > RANKING_PERIOD {
+            info!("New ranking period, updating decay and rankings");
+
+            let statistics = last_ranking_update - last_statistics_update >= STATISTICS_PERIOD;
+            if statistics {
+                info!("New statistics period, updating statistics.");
+                last_statistics_update = last_ranking_update;
+            }
+
+            //This goes through every rated player and takes long enough that it would hold up
+            //one of the runtime's threads, and with it the website's requests on that thread
+            let period_config = config.clone();
+            conn = run_blocking(move || {
+                update_ranking_period(
+                    &mut conn,
+                    &period_config,
+                    now,
+                    last_ranking_update,
+                    statistics,
+                );
+                conn
+            })
+            .await?;
+
+            RUNTIME_DATA.lock().unwrap().last_ranking_update_time = Some(now);
+
+            while now - last_ranking_update > RANKING_PERIOD {
+                last_ranking_update += RANKING_PERIOD;
+            }
+
+            info!(
+                "Last ranking period: {}",
+                NaiveDateTime::from_timestamp(last_ranking_update, 0)
+            );
+
+            conn.execute(
+                "UPDATE config SET last_update = ?",
+                params![last_ranking_update],
+            )
+            .unwrap();
+            cache::invalidate();
+            live::emit(live::LiveEvent::RatingPeriod {
+                timestamp: last_ranking_update,
+            });
+        }
+    }
+
+    info!("Stopped updating statistics");
+    Ok(())
+}
+
+//Everything that's redone once per ranking period, plus the statistics every few periods.
+//Runs on a blocking thread.
+fn update_ranking_period(
+    conn: &mut Connection,
+    config: &Config,
+    now: i64,
+    last_ranking_update: i64,
+    statistics: bool,
+) {
+    if statistics {
+        update_player_distribution(conn, config);
+        warn_matchup_inconsistencies(conn);
+        //if let Err(e) = calc_versus_matchups(conn) {
+        //    error!("calc_versus_matchups failed: {}", e);
+        //}
+        if let Err(e) = calc_fraud_index(conn, config) {
+            error!("calc_fraud_index failed: {}", e);
+        }
+        if let Err(e) = calc_character_popularity(conn, config, last_ranking_update) {
+            error!("calc_character_popularity failed: {}", e);
+        }
+        if let Err(e) = notifications::check_matchup_flips(conn, config) {
+            error!("check_matchup_flips failed: {}", e);
+        }
+        if let Err(e) = export::write_snapshot(conn, config) {
+            error!("write_snapshot failed: {}", e);
+        }
+    }
+
+    if let Err(e) = update_decay(conn, Utc::now().timestamp(), config) {
+        error!("update_decay failed: {}", e);
+    }
+    if let Err(e) = decay_matchups(conn, Utc::now().timestamp(), config) {
+        error!("decay_matchups failed: {}", e);
+    }
+    if let Err(e) = anomaly::scan(conn, now) {
+        error!("anomaly scan failed: {}", e);
+    }
+    if let Err(e) = update_rankings(conn, config) {
+        error!("update_rankings failed: {}", e);
+    }
+    if let Err(e) = update_overall_ratings(conn, config) {
+        error!("update_overall_ratings failed: {}", e);
+    }
+    if let Err(e) = calc_top_upsets(conn, config) {
+        error!("calc_top_upsets failed: {}", e);
+    }
+    if let Err(e) = calc_floor_band_matchups(conn, config, false) {
+        error!("calc_floor_band_matchups failed: {}", e);
+    }
+}
+
+pub async fn update_once(config: &Config) {
+    let mut conn = db::open(&config.db_path).unwrap();
+
+    //Everything after rating reads the ratings from SQLite
+    if let StorageKind::Postgres { url } = &config.storage {
+        let rated = task::block_in_place(|| update_postgres_ratings(&conn, url, "", &[], config));
+        if let Err(e) = rated {
+            error!("Rating games in Postgres failed: {:#}", e);
+        }
+        return;
+    }
+
+    while update_ratings(&mut conn, None, config) > 0 {
+        update_rankings(&mut conn, config).unwrap();
+    }
+
+    //let last_rating_timestamp: i64 = conn
+    //    .query_row("SELECT last_update FROM config", [], |r| r.get(0))
+    //    .unwrap();
+    update_player_distribution(&mut conn, config);
+    //if let Err(e) = calc_versus_matchups(&mut conn) {
+    //    error!("calc_versus_matchups failed: {}", e);
+    //}
+    if let Err(e) = calc_fraud_index(&mut conn, config) {
+        error!("calc_fraud_index failed: {}", e);
+    }
+
+    if let Err(e) = update_rankings(&mut conn, config) {
+        error!("update_rankings failed: {}", e);
+    }
+    if let Err(e) = update_overall_ratings(&mut conn, config) {
+        error!("update_overall_ratings failed: {}", e);
+    }
+    //if let Err(e) = calc_character_popularity(&mut conn, config, last_rating_timestamp) {
+    //    error!("calc_character_popularity failed: {}", e);
+    //}
+}
+
+//Rates every game again from a clean slate. Each batch of games commits on its own, so an
+//interrupted run can be picked up again with resume instead of starting over.
+pub fn recalculate(config: &Config, resume: bool) -> Result<()> {
+    if config.storage != StorageKind::Sqlite {
+        anyhow::bail!("Recalculating only works on ratings stored in SQLite");
+    }
+    let mut conn = db::open(&config.db_path)?;
+
+    if resume {
+        info!("Resuming recalculation");
+    } else {
+        info!("Clearing ratings for recalculation");
+        conn.execute_batch(include_str!("../reset.sql"))?;
+    }
+
+    let total: i64 = conn.query_row("SELECT COUNT(*) FROM games", [], |r| r.get(0))?;
+    loop {
+        let remaining = update_ratings(&mut conn, None, config);
+        //Players only decay as they play, so catch everyone else up to the rated games too
+        let rated_until: Option<i64> =
+            conn.query_row("SELECT MAX(timestamp) FROM game_ratings", [], |r| r.get(0))?;
+        if let Some(rated_until) = rated_until {
+            update_decay(&mut conn, rated_until, config)?;
+        }
+        update_rankings(&mut conn, config)?;
+
+        let rated: i64 = conn.query_row("SELECT COUNT(*) FROM game_ratings", [], |r| r.get(0))?;
+        info!(
+            "Recalculated {} of {} games ({:.1}%)",
+            rated,
+            total,
+            rated as f64 / total.max(1) as f64 * 100.0
+        );
+
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    update_player_distribution(&mut conn, config);
+    calc_fraud_index(&mut conn, config)?;
+    info!("Recalculation done");
+
+    Ok(())
+}
+
+pub fn print_rankings(config: &Config) {
+    let conn = db::open(&config.db_path).unwrap();
+
+    println!("| Rank | Name | Character | Rating | Games |");
+    println!("|------|------|-----------|--------|-------|");
+
+    let mut stmt = conn
+            .prepare(
+                "SELECT name, char_id, value, deviation, (wins + losses) as games, (100.0 * wins) / (wins + losses) as win_rate
+                FROM player_ratings NATURAL JOIN players
+                WHERE deviation < ?
+                ORDER BY value - 2.0 * deviation DESC
+                LIMIT 100
+                ",
+            )
+            .unwrap();
+
+    let mut rank = 1;
+    let mut rows = stmt.query(params![config.low_deviation]).unwrap();
+
+    while let Some(row) = rows.next().unwrap() {
+        let name: String = row.get(0).unwrap();
+        let char_name = characters::all()[row.get::<_, usize>(1).unwrap()].1;
+        let value: f64 = row.get(2).unwrap();
+        let deviation: f64 = row.get(3).unwrap();
+        let games: i64 = row.get(4).unwrap();
+        let rate: f64 = row.get(5).unwrap();
+        println!(
+            "| {} | {} | {} | {:.0} ±{:.0} | {} ({:.0}%) |",
+            rank, name, char_name, value, deviation, games, rate
+        );
+
+        rank += 1;
+    }
+}
+
+pub fn mark_vip(config: &Config, vip_id: &str, notes: &str) {
+    let vip_id = i64::from_str_radix(vip_id, 16).unwrap();
+
+    let conn = db::open(&config.db_path).unwrap();
+    conn.execute(
+        "INSERT INTO vip_status
+            VALUES(?, 'VIP', ?)",
+        params![vip_id, notes],
+    )
+    .unwrap();
+}
+
+pub fn mark_hidden(config: &Config, hidden_id: &str, notes: &str) {
+    let hidden_id = i64::from_str_radix(hidden_id, 16).unwrap();
+
+    let conn = db::open(&config.db_path).unwrap();
+    conn.execute(
+        "INSERT INTO hidden_status
+            VALUES(?, 'hidden', ?)",
+        params![hidden_id, notes],
+    )
+    .unwrap();
+}
+
+pub fn unmark_hidden(config: &Config, hidden_id: &str) {
+    let hidden_id = i64::from_str_radix(hidden_id, 16).unwrap();
+
+    let conn = db::open(&config.db_path).unwrap();
+    conn.execute("DELETE FROM hidden_status WHERE id = ?", params![hidden_id])
+        .unwrap();
+}
+
+pub fn is_hidden(conn: &Connection, id: i64) -> bool {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM hidden_status WHERE id = ?)",
+        params![id],
+        |r| r.get(0),
+    )
+    .unwrap()
+}
+
+pub async fn mark_cheater(
+    config: &Config,
     cheater_id: Option<&str>,
     cheater_type: Option<&str>,
     notes: Option<&str>,
 ) {
     let cheater_id = i64::from_str_radix(cheater_id.unwrap(), 16).unwrap();
 
-    let conn = Connection::open(DB_NAME).unwrap();
+    let conn = db::open(&config.db_path).unwrap();
 
     struct Game {
         id_a: i64,
@@ -350,18 +1407,18 @@ pub async fn mark_cheater(
     }
 }
 
-pub async fn update_fraud_once() {
-    let mut conn = Connection::open(DB_NAME).unwrap();
+pub async fn update_fraud_once(config: &Config) {
+    let mut conn = db::open(&config.db_path).unwrap();
 
-    if let Err(e) = calc_fraud_index(&mut conn) {
+    if let Err(e) = calc_fraud_index(&mut conn, config) {
         error!("calc_fraud_index failed: {}", e);
     }
 }
 
-pub async fn update_decay_once() {
-    let mut conn = Connection::open(DB_NAME).unwrap();
+pub async fn update_decay_once(config: &Config) {
+    let mut conn = db::open(&config.db_path).unwrap();
 
-    update_decay(&mut conn, Utc::now().timestamp()).unwrap();
+    update_decay(&mut conn, Utc::now().timestamp(), config).unwrap();
 }
 
 pub fn get_average_rating(conn: &Transaction, id: i64) -> f64 {
@@ -374,67 +1431,1434 @@ pub fn get_average_rating(conn: &Transaction, id: i64) -> f64 {
     .unwrap_or_default()
 }
 
-pub async fn pull() {
-    let mut conn = Connection::open(DB_NAME).unwrap();
+pub fn get_head_to_head(conn: &Connection, id_a: i64, id_b: i64) -> HeadToHead {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id_a, char_a, char_b, winner
+            FROM games
+            WHERE (id_a = ?1 AND id_b = ?2) OR (id_a = ?2 AND id_b = ?1)",
+        )
+        .unwrap();
+    let mut rows = stmt.query(params![id_a, id_b]).unwrap();
+
+    //Keyed by (char of id_a, char of id_b), regardless of which slot they were stored in
+    let mut pairings = FxHashMap::<(i64, i64), (i64, i64)>::default();
+    while let Some(row) = rows.next().unwrap() {
+        let slot_a: i64 = row.get(0).unwrap();
+        let char_1: i64 = row.get(1).unwrap();
+        let char_2: i64 = row.get(2).unwrap();
+        let winner: i64 = row.get(3).unwrap();
+
+        let (chars, a_won) = if slot_a == id_a {
+            ((char_1, char_2), winner == 1)
+        } else {
+            ((char_2, char_1), winner == 2)
+        };
+
+        let record = pairings.entry(chars).or_default();
+        if a_won {
+            record.0 += 1;
+        } else {
+            record.1 += 1;
+        }
+    }
+
+    let mut characters = pairings
+        .into_iter()
+        .map(|((char_a, char_b), (wins_a, wins_b))| HeadToHeadPairing {
+            char_a,
+            char_b,
+            wins_a,
+            wins_b,
+        })
+        .collect::<Vec<_>>();
+    characters.sort_by_key(|p| -(p.wins_a + p.wins_b));
+
+    let wins_a = characters.iter().map(|p| p.wins_a).sum();
+    let wins_b = characters.iter().map(|p| p.wins_b).sum();
+
+    HeadToHead {
+        id_a,
+        id_b,
+        game_count: wins_a + wins_b,
+        wins_a,
+        wins_b,
+        characters,
+    }
+}
+
+pub fn get_top_opponents(conn: &Connection, player_id: i64, limit: usize) -> Vec<OpponentRecord> {
+    //winner is 1 when slot a won and 2 when slot b won, slot is the player's own slot
+    let mut stmt = conn
+        .prepare(
+            "SELECT opp_id, players.name,
+                COUNT(*) AS game_count,
+                COUNT(*) FILTER (WHERE winner = slot),
+                COUNT(*) FILTER (WHERE winner != slot)
+            FROM (
+                SELECT id_b AS opp_id, winner, 1 AS slot FROM games
+                WHERE id_a = :id AND id_b != :id
+                UNION ALL
+                SELECT id_a AS opp_id, winner, 2 AS slot FROM games
+                WHERE id_b = :id AND id_a != :id
+            )
+            JOIN players ON players.id = opp_id
+            LEFT JOIN hidden_status ON hidden_status.id = opp_id
+            WHERE hidden_status.id IS NULL
+            GROUP BY opp_id
+            ORDER BY game_count DESC
+            LIMIT :limit",
+        )
+        .unwrap();
+
+    stmt.query_map(
+        named_params! {":id": player_id, ":limit": limit as i64},
+        |r| {
+            Ok(OpponentRecord {
+                id: r.get(0)?,
+                name: r.get(1)?,
+                game_count: r.get(2)?,
+                wins: r.get(3)?,
+                losses: r.get(4)?,
+            })
+        },
+    )
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
 
-    grab_games(&mut conn, 100).await.unwrap();
+//Most played opponents across all characters, with the head to head record and how far above
+//them the player was rated on average going into their games
+pub fn get_opponent_breakdown(
+    conn: &Connection,
+    player_id: i64,
+    limit: usize,
+) -> Vec<OpponentBreakdown> {
+    //winner is 1 when slot a won and 2 when slot b won, slot is the player's own slot. Games
+    //that aren't rated yet count towards the record but not the rating difference.
+    let mut stmt = conn
+        .prepare(
+            "SELECT opp_id, players.name, hidden_status.id,
+                COUNT(*) AS game_count,
+                COUNT(*) FILTER (WHERE winner = slot),
+                COUNT(*) FILTER (WHERE winner != slot),
+                AVG(value - opp_value),
+                MAX(timestamp) AS last_played
+            FROM (
+                SELECT games.id_b AS opp_id, games.winner, 1 AS slot,
+                    value_a AS value, value_b AS opp_value, games.timestamp
+                FROM games LEFT JOIN game_ratings USING (timestamp, id_a, id_b)
+                WHERE games.id_a = :id AND games.id_b != :id
+                UNION ALL
+                SELECT games.id_a AS opp_id, games.winner, 2 AS slot,
+                    value_b AS value, value_a AS opp_value, games.timestamp
+                FROM games LEFT JOIN game_ratings USING (timestamp, id_a, id_b)
+                WHERE games.id_b = :id AND games.id_a != :id
+            )
+            LEFT JOIN players ON players.id = opp_id
+            LEFT JOIN hidden_status ON hidden_status.id = opp_id
+            GROUP BY opp_id
+            ORDER BY game_count DESC, last_played DESC
+            LIMIT :limit",
+        )
+        .unwrap();
+
+    stmt.query_map(
+        named_params! {":id": player_id, ":limit": limit as i64},
+        |r| {
+            //Hidden opponents still show up, just without anything that leads to them
+            let hidden = r.get::<_, Option<i64>>(2)?.is_some();
+            Ok(OpponentBreakdown {
+                id: if hidden { None } else { r.get(0)? },
+                name: if hidden {
+                    "Hidden".to_owned()
+                } else {
+                    r.get::<_, Option<String>>(1)?.unwrap_or_default()
+                },
+                game_count: r.get(3)?,
+                wins: r.get(4)?,
+                losses: r.get(5)?,
+                average_rating_difference: r.get(6)?,
+                last_played: r.get(7)?,
+            })
+        },
+    )
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+//Ranks against the stored percentiles, the same settled players update_player_distribution
+//counts. Players that aren't settled yet aren't in there and get None.
+pub fn get_player_rank(conn: &Connection, player_id: i64, char_id: i64) -> Option<PlayerRank> {
+    let top_percent: f64 = conn
+        .query_row(
+            "SELECT top_percent FROM player_percentiles WHERE id = ? AND char_id = ?",
+            params![player_id, char_id],
+            |r| r.get(0),
+        )
+        .optional()
+        .unwrap()?;
+
+    let (higher, total, character_higher, character_total): (i64, i64, i64, i64) = conn
+        .query_row(
+            "SELECT
+                COUNT(*) FILTER (WHERE top_percent < :top_percent),
+                COUNT(*),
+                COUNT(*) FILTER (WHERE top_percent < :top_percent AND char_id = :char_id),
+                COUNT(*) FILTER (WHERE char_id = :char_id)
+            FROM player_percentiles",
+            named_params! {
+                ":top_percent": top_percent,
+                ":char_id": char_id,
+            },
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap();
+
+    Some(PlayerRank {
+        rank: higher + 1,
+        percentile: 100.0 * (total - higher) as f64 / total as f64,
+        character_rank: character_higher + 1,
+        character_percentile: 100.0 * (character_total - character_higher) as f64
+            / character_total as f64,
+    })
+}
+
+pub fn search_players_by_name(
+    conn: &Connection,
+    query: &str,
+    limit: usize,
+) -> Vec<PlayerSearchResult> {
+    //Old names live in player_names, so players who renamed can still be found. The trigram
+    //index needs at least three characters, shorter searches fall back to a scan.
+    let use_index = query.chars().count() >= 3;
+    let name_filter = if use_index {
+        "players.id IN (SELECT player_names.id FROM player_names_fts
+            JOIN player_names ON player_names.rowid = player_names_fts.rowid
+            WHERE player_names_fts MATCH :match)"
+    } else {
+        "players.name LIKE :query
+            OR EXISTS (SELECT 1 FROM player_names
+                WHERE player_names.id = players.id AND name LIKE :query)"
+    };
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT
+                players.id,
+                players.name,
+                (SELECT name FROM player_names
+                    WHERE player_names.id = players.id
+                        AND name LIKE :query
+                        AND name != players.name
+                    LIMIT 1) AS alias,
+                (SELECT COALESCE(SUM(wins + losses), 0) FROM player_ratings
+                    WHERE player_ratings.id = players.id) AS game_count
+            FROM players
+            LEFT JOIN hidden_status ON hidden_status.id = players.id
+            WHERE ({}) AND hidden_status.id IS NULL
+            ORDER BY game_count DESC
+            LIMIT :limit",
+            name_filter
+        ))
+        .unwrap();
+
+    let like_query = format!("%{}%", query);
+    let mut rows = if use_index {
+        //Quoted as a single phrase so the search text can't be read as FTS syntax
+        let match_query = format!("\"{}\"", query.replace('"', "\"\""));
+        stmt.query(named_params! {
+            ":query": like_query,
+            ":match": match_query,
+            ":limit": limit,
+        })
+    } else {
+        stmt.query(named_params! {
+            ":query": like_query,
+            ":limit": limit,
+        })
+    }
+    .unwrap();
+
+    let mut res = Vec::new();
+    while let Some(row) = rows.next().unwrap() {
+        res.push(PlayerSearchResult {
+            id: row.get(0).unwrap(),
+            name: row.get(1).unwrap(),
+            matched_alias: row.get(2).unwrap(),
+            game_count: row.get(3).unwrap(),
+        });
+    }
+
+    res
+}
+
+pub fn get_player_profile(
+    conn: &Connection,
+    player_id: i64,
+    low_deviation: f64,
+) -> Option<PlayerProfile> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT name, floor, player_ratings.char_id, value, deviation, wins, losses, top_percent
+            FROM players
+            LEFT JOIN player_ratings ON player_ratings.id = players.id
+            LEFT JOIN player_percentiles ON
+                player_percentiles.id = player_ratings.id AND
+                player_percentiles.char_id = player_ratings.char_id
+            LEFT JOIN hidden_status ON hidden_status.id = players.id
+            WHERE players.id = ? AND hidden_status.id IS NULL
+            ORDER BY wins + losses DESC",
+        )
+        .unwrap();
+    let mut rows = stmt.query(params![player_id]).unwrap();
+
+    let mut profile: Option<PlayerProfile> = None;
+    while let Some(row) = rows.next().unwrap() {
+        let profile = profile.get_or_insert_with(|| PlayerProfile {
+            id: player_id,
+            name: row.get(0).unwrap(),
+            floor: row.get(1).unwrap(),
+            characters: Vec::new(),
+        });
+
+        //A player without any ratings still gets a single row from the left join
+        if let Some(char_id) = row.get::<_, Option<i64>>(2).unwrap() {
+            let deviation: f64 = row.get(4).unwrap();
+            profile.characters.push(PlayerProfileCharacter {
+                char_id,
+                char_name: characters::all()
+                    .get(char_id as usize)
+                    .map(|c| c.1)
+                    .unwrap_or("Unknown"),
+                value: row.get(3).unwrap(),
+                deviation,
+                wins: row.get(5).unwrap(),
+                losses: row.get(6).unwrap(),
+                established: deviation < low_deviation,
+                top_percent: row.get(7).unwrap(),
+            });
+        }
+    }
+
+    profile
+}
+
+//Most recent rated games across all of a player's characters, newest first
+pub fn get_recent_games(conn: &Connection, player_id: i64, limit: usize) -> Vec<RecentGame> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT timestamp, game_floor, char_a, id_b, name_b, char_b,
+                value_a, deviation_a, value_b, deviation_b, winner = 1, hidden_status.id,
+                new_value_a - value_a
+            FROM games NATURAL JOIN game_ratings
+            LEFT JOIN hidden_status ON hidden_status.id = games.id_b
+            WHERE games.id_a = :id
+            UNION ALL
+            SELECT timestamp, game_floor, char_b, id_a, name_a, char_a,
+                value_b, deviation_b, value_a, deviation_a, winner = 2, hidden_status.id,
+                new_value_b - value_b
+            FROM games NATURAL JOIN game_ratings
+            LEFT JOIN hidden_status ON hidden_status.id = games.id_a
+            WHERE games.id_b = :id
+            ORDER BY timestamp DESC
+            LIMIT :limit",
+        )
+        .unwrap();
+
+    stmt.query_map(
+        named_params! {":id": player_id, ":limit": limit as i64},
+        recent_game_from_row,
+    )
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+fn recent_game_from_row(r: &rusqlite::Row) -> rusqlite::Result<RecentGame> {
+    //Hidden opponents still show up, just without anything that leads to them
+    let hidden = r.get::<_, Option<i64>>(11)?.is_some();
+    Ok(RecentGame {
+        timestamp: r.get(0)?,
+        floor: r.get(1)?,
+        char_id: r.get(2)?,
+        opponent_id: if hidden { None } else { r.get(3)? },
+        opponent_name: if hidden {
+            "Hidden".to_owned()
+        } else {
+            r.get(4)?
+        },
+        opponent_char_id: r.get(5)?,
+        value: r.get(6)?,
+        deviation: r.get(7)?,
+        opponent_value: r.get(8)?,
+        opponent_deviation: r.get(9)?,
+        won: r.get(10)?,
+        rating_change: r.get(12)?,
+    })
+}
+
+//Recent games of everyone on a watchlist mixed together, newest first
+pub fn get_watchlist_feed(conn: &Connection, watchlist: &str, limit: usize) -> Vec<WatchlistGame> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT timestamp, game_floor, char_a, id_b, name_b, char_b,
+                value_a, deviation_a, value_b, deviation_b, winner = 1, hidden_status.id,
+                new_value_a - value_a, id_a, name_a
+            FROM games NATURAL JOIN game_ratings
+            LEFT JOIN hidden_status ON hidden_status.id = games.id_b
+            WHERE games.id_a IN (
+                SELECT player_id FROM watchlists WHERE watchlist_id = :watchlist
+                AND player_id NOT IN (SELECT id FROM hidden_status)
+            )
+            UNION ALL
+            SELECT timestamp, game_floor, char_b, id_a, name_a, char_a,
+                value_b, deviation_b, value_a, deviation_a, winner = 2, hidden_status.id,
+                new_value_b - value_b, id_b, name_b
+            FROM games NATURAL JOIN game_ratings
+            LEFT JOIN hidden_status ON hidden_status.id = games.id_a
+            WHERE games.id_b IN (
+                SELECT player_id FROM watchlists WHERE watchlist_id = :watchlist
+                AND player_id NOT IN (SELECT id FROM hidden_status)
+            )
+            ORDER BY timestamp DESC
+            LIMIT :limit",
+        )
+        .unwrap();
+
+    stmt.query_map(
+        named_params! {":watchlist": watchlist, ":limit": limit as i64},
+        |r| {
+            Ok(WatchlistGame {
+                player_id: r.get(13)?,
+                player_name: r.get(14)?,
+                game: recent_game_from_row(r)?,
+            })
+        },
+    )
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+//One snapshot per rating period the character played in, oldest first
+pub fn get_rating_history(conn: &Connection, player_id: i64, char_id: i64) -> Vec<RatingSnapshot> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT timestamp, value, deviation
+            FROM player_rating_history
+            WHERE id = ? AND char_id = ?
+            ORDER BY timestamp ASC",
+        )
+        .unwrap();
+
+    stmt.query_map(params![player_id, char_id], |r| {
+        Ok(RatingSnapshot {
+            timestamp: r.get(0)?,
+            value: r.get(1)?,
+            deviation: r.get(2)?,
+        })
+    })
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+//Share is the character's part of all the characters played in that period
+pub fn get_popularity_history(
+    conn: &Connection,
+    char_id: i64,
+    since: i64,
+) -> Vec<PopularitySnapshot> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT period, game_count, game_count * 1.0 / (
+                SELECT SUM(game_count) FROM character_popularity AS totals
+                WHERE totals.period = character_popularity.period
+            )
+            FROM character_popularity
+            WHERE char_id = ? AND period >= ?
+            ORDER BY period ASC",
+        )
+        .unwrap();
+
+    stmt.query_map(params![char_id, since], |r| {
+        Ok(PopularitySnapshot {
+            timestamp: r.get(0)?,
+            game_count: r.get(1)?,
+            share: r.get(2)?,
+        })
+    })
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+pub fn get_player_names(conn: &Connection, player_id: i64) -> Vec<String> {
+    let mut names = Vec::new();
+
+    if let Some(current) = conn
+        .query_row(
+            "SELECT name FROM players WHERE id = ?",
+            params![player_id],
+            |r| r.get::<_, String>(0),
+        )
+        .optional()
+        .unwrap()
+    {
+        names.push(current);
+    }
+
+    let mut stmt = conn
+        .prepare("SELECT name FROM player_names WHERE id = ?")
+        .unwrap();
+    let mut rows = stmt.query(params![player_id]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let name: String = row.get(0).unwrap();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names
+}
+
+//Blends a player's established character ratings into one, the same way as the stored
+//overall ratings
+pub fn get_combined_rating(
+    conn: &Connection,
+    player_id: i64,
+    low_deviation: f64,
+) -> Option<Rating> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT value, deviation, wins + losses
+            FROM player_ratings
+            WHERE id = ? AND deviation < ?",
+        )
+        .unwrap();
+    let ratings = stmt
+        .query_map(params![player_id, low_deviation], |r| {
+            Ok((Rating::new(r.get(0)?, r.get(1)?), r.get(2)?))
+        })
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect::<Vec<_>>();
+
+    combine_ratings(&ratings)
+}
+
+//Each character rating is weighted by its game count, so the result leans toward the
+//character played most. The value is the weighted mean of the character ratings, and the
+//deviation is the deviation of that weighted mean when each character rating is treated as
+//an independent estimate: sqrt(sum(w^2 * rd^2)) / sum(w).
+pub fn combine_ratings(ratings: &[(Rating, i64)]) -> Option<Rating> {
+    let mut weight_sum = 0.0;
+    let mut value_sum = 0.0;
+    let mut variance_sum = 0.0;
+    for (rating, games) in ratings {
+        let weight = *games as f64;
+        weight_sum += weight;
+        value_sum += weight * rating.value;
+        variance_sum += weight * weight * rating.deviation * rating.deviation;
+    }
+
+    if weight_sum == 0.0 {
+        return None;
+    }
+
+    Some(Rating::new(
+        value_sum / weight_sum,
+        variance_sum.sqrt() / weight_sum,
+    ))
+}
+
+pub fn get_global_stats(conn: &Connection, low_deviation: f64) -> GlobalStats {
+    let total_games: i64 = conn
+        .query_row("SELECT COUNT(*) FROM games", [], |r| r.get(0))
+        .unwrap();
+    let total_players: i64 = conn
+        .query_row("SELECT COUNT(*) FROM players", [], |r| r.get(0))
+        .unwrap();
+    let established_players: i64 = conn
+        .query_row(
+            "SELECT COUNT(DISTINCT id) FROM player_ratings WHERE deviation < ?",
+            params![low_deviation],
+            |r| r.get(0),
+        )
+        .unwrap();
+    let last_update: i64 = conn
+        .query_row("SELECT last_update FROM config", [], |r| r.get(0))
+        .unwrap();
+
+    let mut stmt = conn
+        .prepare("SELECT floor, game_count FROM player_floor_distribution ORDER BY floor")
+        .unwrap();
+    let floor_games = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+
+    GlobalStats {
+        total_games,
+        total_players,
+        established_players,
+        last_update,
+        floor_games,
+    }
+}
+
+pub fn get_most_active(conn: &Connection, limit: usize, since: Option<i64>) -> Vec<ActivePlayer> {
+    let mut stmt = match since {
+        None => conn
+            .prepare(
+                "SELECT players.id, players.name, SUM(wins + losses) AS game_count
+                FROM player_ratings
+                JOIN players ON players.id = player_ratings.id
+                LEFT JOIN hidden_status ON hidden_status.id = players.id
+                WHERE hidden_status.id IS NULL
+                GROUP BY players.id
+                ORDER BY game_count DESC
+                LIMIT :limit",
+            )
+            .unwrap(),
+        Some(_) => conn
+            .prepare(
+                "SELECT players.id, players.name, COUNT(*) AS game_count
+                FROM (
+                    SELECT id_a AS id FROM games WHERE timestamp >= :since
+                    UNION ALL
+                    SELECT id_b AS id FROM games WHERE timestamp >= :since
+                ) AS played
+                JOIN players ON players.id = played.id
+                LEFT JOIN hidden_status ON hidden_status.id = players.id
+                WHERE hidden_status.id IS NULL
+                GROUP BY players.id
+                ORDER BY game_count DESC
+                LIMIT :limit",
+            )
+            .unwrap(),
+    };
+
+    let mut rows = match since {
+        None => stmt.query(named_params! {":limit": limit}),
+        Some(since) => stmt.query(named_params! {":limit": limit, ":since": since}),
+    }
+    .unwrap();
+
+    let mut res = Vec::new();
+    while let Some(row) = rows.next().unwrap() {
+        res.push(ActivePlayer {
+            id: row.get(0).unwrap(),
+            name: row.get(1).unwrap(),
+            game_count: row.get(2).unwrap(),
+        });
+    }
+
+    res
+}
+
+pub fn get_recent_winrate(
+    conn: &Connection,
+    player_id: i64,
+    char_id: i64,
+    window_secs: i64,
+) -> Option<(i64, i64)> {
+    let since = Utc::now().timestamp() - window_secs;
+
+    //winner is 1 when slot a won and 2 when slot b won
+    let (wins, losses): (i64, i64) = conn
+        .query_row(
+            "SELECT
+                COUNT(*) FILTER (WHERE winner = slot),
+                COUNT(*) FILTER (WHERE winner != slot)
+            FROM (
+                SELECT winner, 1 AS slot FROM games
+                WHERE id_a = :id AND char_a = :char_id AND timestamp >= :since
+                UNION ALL
+                SELECT winner, 2 AS slot FROM games
+                WHERE id_b = :id AND char_b = :char_id AND timestamp >= :since
+            )",
+            named_params! {":id": player_id, ":char_id": char_id, ":since": since},
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+
+    if wins + losses == 0 {
+        None
+    } else {
+        Some((wins, losses))
+    }
+}
+
+pub fn get_floor_leaderboard(
+    conn: &Connection,
+    floor: i64,
+    limit: usize,
+    low_deviation: f64,
+) -> Vec<LeaderboardEntry> {
+    //Floors are 1-10, with 99 being celestial
+    if !matches!(floor, 1..=10 | 99) {
+        return Vec::new();
+    }
+
+    //Bare columns next to MAX() come from the row holding the maximum, giving each
+    //player's best established character
+    let mut stmt = conn
+        .prepare(
+            "SELECT players.id, players.name, char_id, MAX(value), deviation
+            FROM players
+            JOIN player_ratings ON player_ratings.id = players.id
+            LEFT JOIN hidden_status ON hidden_status.id = players.id
+            WHERE players.floor = ? AND deviation < ? AND hidden_status.id IS NULL
+            GROUP BY players.id
+            ORDER BY MAX(value) DESC
+            LIMIT ?",
+        )
+        .unwrap();
+    let mut rows = stmt.query(params![floor, low_deviation, limit]).unwrap();
+
+    let mut res = Vec::new();
+    while let Some(row) = rows.next().unwrap() {
+        res.push(LeaderboardEntry {
+            rank: res.len() + 1,
+            id: row.get(0).unwrap(),
+            name: row.get(1).unwrap(),
+            char_id: row.get(2).unwrap(),
+            rating: Rating::new(row.get(3).unwrap(), row.get(4).unwrap()),
+        });
+    }
+
+    res
+}
+
+pub fn get_character_stats(conn: &Connection) -> Vec<CharacterStat> {
+    let mut stats = characters::all()
+        .iter()
+        .enumerate()
+        .map(|(char_id, (_, name))| CharacterStat {
+            char_id: char_id as i64,
+            name: name.to_string(),
+            player_count: 0,
+            play_rate: 0.0,
+            game_count: 0,
+            win_rate: 0.0,
+        })
+        .collect::<Vec<_>>();
+
+    //A player's main is the character they've played the most games on
+    let mut stmt = conn
+        .prepare(
+            "SELECT char_id, COUNT(*) FROM (
+                SELECT char_id, MAX(wins + losses) FROM player_ratings GROUP BY id
+            )
+            GROUP BY char_id",
+        )
+        .unwrap();
+    let mut rows = stmt.query([]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let char_id: usize = row.get(0).unwrap();
+        if let Some(stat) = stats.get_mut(char_id) {
+            stat.player_count = row.get(1).unwrap();
+        }
+    }
+
+    //Mirrors add both a win and a loss, so they're left out of the win rate and counted once
+    let mut stmt = conn
+        .prepare(
+            "SELECT
+                char_id,
+                SUM(wins) FILTER (WHERE char_id != opp_char_id),
+                SUM(losses) FILTER (WHERE char_id != opp_char_id),
+                SUM(wins) FILTER (WHERE char_id = opp_char_id)
+            FROM global_matchups
+            GROUP BY char_id",
+        )
+        .unwrap();
+    let mut rows = stmt.query([]).unwrap();
+    while let Some(row) = rows.next().unwrap() {
+        let char_id: usize = row.get(0).unwrap();
+        let wins = row.get::<_, Option<i64>>(1).unwrap().unwrap_or(0);
+        let losses = row.get::<_, Option<i64>>(2).unwrap().unwrap_or(0);
+        let mirrors = row.get::<_, Option<i64>>(3).unwrap().unwrap_or(0);
+        if let Some(stat) = stats.get_mut(char_id) {
+            stat.game_count = wins + losses + mirrors;
+            if wins + losses > 0 {
+                stat.win_rate = wins as f64 / (wins + losses) as f64;
+            }
+        }
+    }
+
+    let total_players: i64 = stats.iter().map(|s| s.player_count).sum();
+    if total_players > 0 {
+        for stat in &mut stats {
+            stat.play_rate = stat.player_count as f64 / total_players as f64;
+        }
+    }
+
+    stats.sort_by(|a, b| b.play_rate.partial_cmp(&a.play_rate).unwrap());
+    stats
+}
+
+//The distribution's cumulative counts are from the bottom up, so the number of players
+//above a bucket is the established total minus its cumulative count
+fn rating_distribution(conn: &Connection, low_deviation: f64) -> (i64, Vec<(i64, i64, i64, i64)>) {
+    let total: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM player_ratings WHERE deviation < ?",
+            params![low_deviation],
+            |r| r.get(0),
+        )
+        .unwrap();
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT min_rating, max_rating, player_count, player_count_cum
+            FROM player_rating_distribution
+            ORDER BY min_rating",
+        )
+        .unwrap();
+    let buckets = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect();
+
+    (total, buckets)
+}
+
+//Approximate rating needed to be within the top target_rank players, interpolating within
+//the bucket the rank falls in
+pub fn get_rating_for_rank(conn: &Connection, target_rank: i64, low_deviation: f64) -> Option<i64> {
+    let (total, buckets) = rating_distribution(conn, low_deviation);
+    if target_rank < 1 || target_rank > total {
+        return None;
+    }
+
+    buckets
+        .into_iter()
+        .find(|(_, _, _, player_count_cum)| total - player_count_cum < target_rank)
+        .map(|(min_rating, max_rating, player_count, player_count_cum)| {
+            let players_above = total - player_count_cum;
+            let into_bucket = ((target_rank - players_above) as f64 / player_count as f64).min(1.0);
+            max_rating - (into_bucket * (max_rating - min_rating) as f64) as i64
+        })
+}
+
+//Approximate rank of an established rating, interpolating within its bucket
+pub fn get_rank_for_rating(conn: &Connection, rating: i64, low_deviation: f64) -> Option<i64> {
+    let (total, buckets) = rating_distribution(conn, low_deviation);
+    if total == 0 {
+        return None;
+    }
+
+    let mut players_below = 0.0;
+    for (min_rating, max_rating, player_count, player_count_cum) in buckets {
+        if rating >= max_rating {
+            players_below = player_count_cum as f64;
+        } else if rating >= min_rating {
+            players_below = (player_count_cum - player_count) as f64
+                + player_count as f64 * (rating - min_rating) as f64
+                    / (max_rating - min_rating) as f64;
+        }
+    }
+
+    Some((total - players_below as i64).max(1))
+}
+
+//Biggest rating changes since the given time, measured from the rating each character had
+//going into their first game after it
+pub fn get_rating_deltas(
+    conn: &Connection,
+    since: i64,
+    limit: usize,
+    low_deviation: f64,
+) -> Vec<RatingDelta> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT first.id, first.char_id, players.name, first.value, player_ratings.value
+            FROM (
+                SELECT id, char_id, value, MIN(timestamp) FROM (
+                    SELECT games.id_a AS id, games.char_a AS char_id,
+                        game_ratings.value_a AS value, games.timestamp AS timestamp
+                    FROM games JOIN game_ratings ON
+                        games.timestamp = game_ratings.timestamp
+                        AND games.id_a = game_ratings.id_a
+                        AND games.id_b = game_ratings.id_b
+                    WHERE games.timestamp >= :since
+                    UNION ALL
+                    SELECT games.id_b, games.char_b, game_ratings.value_b, games.timestamp
+                    FROM games JOIN game_ratings ON
+                        games.timestamp = game_ratings.timestamp
+                        AND games.id_a = game_ratings.id_a
+                        AND games.id_b = game_ratings.id_b
+                    WHERE games.timestamp >= :since
+                )
+                GROUP BY id, char_id
+            ) AS first
+            JOIN player_ratings ON
+                player_ratings.id = first.id AND player_ratings.char_id = first.char_id
+            JOIN players ON players.id = first.id
+            LEFT JOIN hidden_status ON hidden_status.id = first.id
+            WHERE player_ratings.deviation < :deviation AND hidden_status.id IS NULL
+            ORDER BY ABS(player_ratings.value - first.value) DESC
+            LIMIT :limit",
+        )
+        .unwrap();
+    let mut rows = stmt
+        .query(named_params! {
+            ":since": since,
+            ":deviation": low_deviation,
+            ":limit": limit,
+        })
+        .unwrap();
+
+    let mut res = Vec::new();
+    while let Some(row) = rows.next().unwrap() {
+        let old_value: f64 = row.get(3).unwrap();
+        let new_value: f64 = row.get(4).unwrap();
+        res.push(RatingDelta {
+            id: row.get(0).unwrap(),
+            char_id: row.get(1).unwrap(),
+            name: row.get(2).unwrap(),
+            old_value,
+            new_value,
+            delta: new_value - old_value,
+        });
+    }
+
+    res
+}
+
+//Stretches without any games, which usually means the puller was down or missed pages
+pub fn find_timeline_gaps(conn: &Connection, max_gap_secs: i64) -> Vec<(i64, i64)> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT previous, timestamp FROM (
+                SELECT timestamp, LAG(timestamp) OVER (ORDER BY timestamp) AS previous
+                FROM games
+            )
+            WHERE timestamp - previous > ?",
+        )
+        .unwrap();
+
+    stmt.query_map(params![max_gap_secs], |r| Ok((r.get(0)?, r.get(1)?)))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect()
+}
+
+pub async fn pull(config: &Config) {
+    let sources = config
+        .pull_sources()
+        .into_iter()
+        .map(|s| (s.name, s.kind.source()))
+        .collect();
+    pull_from(sources, config).await
+}
+
+async fn pull_from(sources: Vec<(String, Box<dyn ReplaySource>)>, config: &Config) {
+    let (writer, events) = mpsc::channel(PULL_CHANNEL_PAGES);
+    let writer_config = config.clone();
+    let writer_task = task::spawn_blocking(move || write_games(events, &writer_config));
+
+    let pulls = sources
+        .into_iter()
+        .map(|(name, source)| {
+            let writer = writer.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let outcome = grab_games(&*source, &name, &writer, 0..config.max_pages, &config)
+                    .await
+                    .unwrap();
+                if outcome.unresolved_failures > 0 {
+                    warn!(
+                        "{} failed fetches from {} haven't been covered by a complete pull yet",
+                        outcome.unresolved_failures, name
+                    );
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for pull in pulls {
+        pull.await.unwrap();
+    }
+
+    drop(writer);
+    writer_task.await.unwrap();
+}
+
+//Every event names the source it's from, the writer keeps their pulls apart
+struct PullEvent {
+    source: String,
+    kind: PullEventKind,
+}
+
+enum PullEventKind {
+    Page(Vec<responses::Replay>),
+    //A page that still failed after retrying, the pull stops there
+    Failed {
+        page: usize,
+        error: String,
+    },
+    //Sent after the last page of a pull, answered once everything is written and rated
+    Done {
+        pages: Range<usize>,
+        started: DateTime<Utc>,
+        complete: bool,
+        outcome: oneshot::Sender<PullOutcome>,
+    },
+}
+
+struct PullOutcome {
+    //Fraction of grabbed replays that were new
+    new_ratio: f64,
+    //Failed fetches that no complete pull has covered since
+    unresolved_failures: i64,
+    //Stretch between the previous pulls and this one that nothing has covered yet
+    gap: Option<(i64, i64)>,
+}
+
+//Fetches pages with retries and hands them to the writer
+async fn grab_games(
+    source: &dyn ReplaySource,
+    name: &str,
+    writer: &mpsc::Sender<PullEvent>,
+    pages: Range<usize>,
+    config: &Config,
+) -> Result<PullOutcome> {
+    let then = Utc::now();
+    info!("Grabbing replays from {}", name);
+
+    let send = |kind| {
+        writer.send(PullEvent {
+            source: name.to_owned(),
+            kind,
+        })
+    };
+    let mut complete = true;
+    for page in pages.clone() {
+        let event = match fetch_page(source, page, config).await {
+            Ok(replays) => PullEventKind::Page(replays),
+            Err(error) => {
+                complete = false;
+                PullEventKind::Failed { page, error }
+            }
+        };
+
+        send(event)
+            .await
+            .map_err(|_| anyhow::anyhow!("Replay writer stopped"))?;
+        if !complete {
+            break;
+        }
+    }
+
+    let (outcome, outcome_receiver) = oneshot::channel();
+    send(PullEventKind::Done {
+        pages,
+        started: then,
+        complete,
+        outcome,
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Replay writer stopped"))?;
+
+    Ok(outcome_receiver.await?)
+}
+
+//Keeps pulling past `from_page` until the pulls reach back to the start of the gap
+async fn backfill(
+    source: &dyn ReplaySource,
+    name: &str,
+    writer: &mpsc::Sender<PullEvent>,
+    mut gap: (i64, i64),
+    from_page: usize,
+    config: &Config,
+) -> Result<()> {
+    let mut page = from_page;
+    loop {
+        if page >= from_page + config.backfill_pages {
+            error!(
+                "Gave up backfilling {} - {} from {} after {} pages",
+                gap.0, gap.1, name, config.backfill_pages
+            );
+            return Ok(());
+        }
+
+        info!(
+            "Backfilling {} - {} from {} page {}",
+            gap.0, gap.1, name, page
+        );
+        let outcome = grab_games(source, name, writer, page..page + config.pages, config).await?;
+        match outcome.gap {
+            Some(remaining) => gap = remaining,
+            None => {
+                info!("Backfill of {} done", name);
+                return Ok(());
+            }
+        }
+        page += config.pages;
+    }
+}
+
+//Tries a page up to FETCH_ATTEMPTS times, doubling the wait between attempts
+async fn fetch_page(
+    source: &dyn ReplaySource,
+    page: usize,
+    config: &Config,
+) -> std::result::Result<Vec<responses::Replay>, String> {
+    let mut backoff = FETCH_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match source
+            .get_replay_page(
+                page,
+                config.replays_per_page,
+                config.min_floor,
+                config.max_floor,
+            )
+            .await
+        {
+            Ok(replays) => return Ok(replays),
+            Err(e) => {
+                if config.json_logs {
+                    error!(
+                        "{}",
+                        json!({ "event": "pull_error", "page": page, "attempt": attempt, "error": e })
+                    );
+                } else {
+                    error!(
+                        "Error fetching replays on page {} (attempt {}/{}): {}",
+                        page, attempt, FETCH_ATTEMPTS, e
+                    );
+                }
+                RUNTIME_DATA.lock().unwrap().total_pull_errors += 1;
+                metrics::FETCH_ERRORS.inc();
+
+                if attempt == FETCH_ATTEMPTS {
+                    return Err(e);
+                }
+            }
+        }
+
+        time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+//Owns the connection pulled games are written with. Runs on a blocking thread so inserts
+//and rating updates don't stall the fetching, and until every sending side is dropped.
+fn write_games(mut events: mpsc::Receiver<PullEvent>, config: &Config) {
+    let mut conn = db::open(&config.db_path).unwrap();
+    let mut pulls = FxHashMap::<String, SourcePull>::default();
+
+    while let Some(PullEvent { source, kind }) = events.blocking_recv() {
+        let pull = pulls.entry(source.clone()).or_default();
+        match kind {
+            PullEventKind::Page(replays) => {
+                pull.num_replays += replays.len();
+                for r in &replays {
+                    if let Ok(t) = NaiveDateTime::parse_from_str(&r.timestamp, "%Y-%m-%d %H:%M:%S")
+                    {
+                        let t = t.and_utc().timestamp();
+                        pull.seen = Some(pull.seen.map_or((t, t), |(o, n)| (o.min(t), n.max(t))));
+                    }
+                }
+                if let Err(e) = write_page(&mut conn, replays, &source, &mut pull.new_games) {
+                    error!("Writing replays failed: {}", e);
+                    RUNTIME_DATA.lock().unwrap().total_pull_errors += 1;
+                }
+            }
+            PullEventKind::Failed { page, error } => {
+                if let Err(e) = conn.execute(
+                    "INSERT INTO fetch_failures(timestamp, page, min_floor, max_floor, error, source)
+                    VALUES(?, ?, ?, ?, ?, ?)",
+                    params![
+                        Utc::now().timestamp(),
+                        page,
+                        config.min_floor,
+                        config.max_floor,
+                        error,
+                        source
+                    ],
+                ) {
+                    error!("Recording fetch failure failed: {}", e);
+                }
+            }
+            PullEventKind::Done {
+                pages,
+                started,
+                complete,
+                outcome,
+            } => {
+                let new_games = std::mem::take(&mut pull.new_games);
+                let new_count = new_games.len();
+                finish_pull(
+                    &mut conn,
+                    &source,
+                    new_games,
+                    pull.num_replays,
+                    pages.len(),
+                    started,
+                    config,
+                );
+                let ratio = if pull.num_replays > 0 {
+                    new_count as f64 / pull.num_replays as f64
+                } else {
+                    0.0
+                };
+                let unresolved_failures = resolve_fetch_failures(
+                    &conn,
+                    &source,
+                    complete && pages.start == 0 && pages.end >= config.max_pages,
+                )
+                .unwrap_or_else(|e| {
+                    error!("Checking fetch failures failed: {}", e);
+                    0
+                });
+                pull.open_gap = track_coverage(
+                    &conn,
+                    &source,
+                    started.timestamp(),
+                    &pages,
+                    pull.seen,
+                    pull.open_gap,
+                )
+                .unwrap_or_else(|e| {
+                    error!("Tracking pull coverage failed: {}", e);
+                    pull.open_gap
+                });
+                //Nobody waiting on the outcome just means the pull was abandoned
+                let _ = outcome.send(PullOutcome {
+                    new_ratio: ratio,
+                    unresolved_failures,
+                    gap: pull.open_gap,
+                });
+
+                pull.num_replays = 0;
+                pull.seen = None;
+            }
+        }
+    }
+}
+
+//What the writer keeps track of for each source's pulls
+#[derive(Default)]
+struct SourcePull {
+    new_games: Vec<Game>,
+    num_replays: usize,
+    seen: Option<(i64, i64)>,
+    open_gap: Option<(i64, i64)>,
+}
+
+//A pull as deep as we go that got through every page covers whatever failed before it.
+//Returns how many failures are still open.
+fn resolve_fetch_failures(conn: &Connection, source: &str, covered: bool) -> Result<i64> {
+    if covered {
+        let resolved = conn.execute(
+            "UPDATE fetch_failures SET resolved = ? WHERE resolved IS NULL AND source = ?",
+            params![Utc::now().timestamp(), source],
+        )?;
+        if resolved > 0 {
+            info!("Resolved {} failed fetches from {}", resolved, source);
+        }
+    }
+
+    Ok(conn.query_row(
+        "SELECT COUNT(*) FROM fetch_failures WHERE resolved IS NULL AND source = ?",
+        params![source],
+        |r| r.get(0),
+    )?)
+}
+
+//Records the stretch of time a pull saw and works out whether it left a gap behind the
+//earlier pulls. Deeper pulls reaching back past the start of an open gap close it.
+fn track_coverage(
+    conn: &Connection,
+    source: &str,
+    timestamp: i64,
+    pages: &Range<usize>,
+    seen: Option<(i64, i64)>,
+    open_gap: Option<(i64, i64)>,
+) -> Result<Option<(i64, i64)>> {
+    let (oldest, newest) = match seen {
+        Some(seen) => seen,
+        None => return Ok(open_gap),
+    };
+
+    let covered_until: Option<i64> = conn.query_row(
+        "SELECT MAX(newest) FROM pull_coverage WHERE source = ?",
+        params![source],
+        |r| r.get(0),
+    )?;
+    conn.execute(
+        "INSERT INTO pull_coverage(timestamp, first_page, last_page, oldest, newest, source)
+        VALUES(?, ?, ?, ?, ?, ?)",
+        params![timestamp, pages.start, pages.end, oldest, newest, source],
+    )?;
+
+    Ok(match (open_gap, covered_until) {
+        (Some((from, _)), _) if oldest <= from => None,
+        (Some((from, to)), _) => Some((from, to.min(oldest))),
+        (None, Some(until)) if oldest > until => {
+            warn!(
+                "Replays pulled from {} don't reach back to {}, found a gap",
+                source, until
+            );
+            Some((until, oldest))
+        }
+        (None, _) => None,
+    })
+}
+
+fn write_page(
+    conn: &mut Connection,
+    replays: Vec<responses::Replay>,
+    source: &str,
+    new_games: &mut Vec<Game>,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for r in replays {
+        new_games.extend(add_game(&tx, r, source));
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+//Rates the games of a finished pull, returns the new total game count
+fn finish_pull(
+    conn: &mut Connection,
+    source: &str,
+    new_games: Vec<Game>,
+    num_replays: usize,
+    pages: usize,
+    then: DateTime<Utc>,
+    config: &Config,
+) {
+    //Other sources' pages can land in between, so the new games are counted as they go in
+    let new_count = new_games.len();
+    let count: i64 = conn
+        .query_row("SELECT COUNT(*) FROM games", [], |r| r.get(0))
+        .unwrap();
+
+    let elapsed = (Utc::now() - then).num_milliseconds();
+
+    if config.json_logs {
+        info!(
+            "{}",
+            json!({
+                "event": "pull",
+                "source": source,
+                "replays": num_replays,
+                "new_games": new_count,
+                "total_games": count,
+                "pages": pages,
+                "elapsed_ms": elapsed,
+            })
+        );
+    } else {
+        info!(
+            "Grabbed {} games from {} -  new games: {} ({} total) - {}ms",
+            num_replays, source, new_count, count, elapsed,
+        );
+    }
+
+    {
+        let mut runtime_data = RUNTIME_DATA.lock().unwrap();
+        runtime_data.last_pull_time = Some(then.timestamp());
+        runtime_data.games_pulled_last_tick = new_count;
+        runtime_data.pages_pulled_last_tick = pages;
+    }
+    metrics::GAMES_INGESTED.inc_by(new_count as u64);
+    metrics::REPLAYS
+        .with_label_values(&["new"])
+        .inc_by(new_count as u64);
+    metrics::REPLAYS
+        .with_label_values(&["duplicate"])
+        .inc_by((num_replays - new_count) as u64);
+    metrics::PAGES_PULLED.set(pages as i64);
+
+    emit_live_games(conn, &new_games);
+    rate_new_games(conn, source, new_games, config);
+    RUNTIME_DATA.lock().unwrap().last_rating_update_time = Some(Utc::now().timestamp());
+
+    if new_count == num_replays {
+        if num_replays > 0 {
+            error!(
+                "Only new replays from {}! We're probably missing some, try increasing the page count.",
+                source
+            );
+        } else {
+            error!("No replays from {}! Maybe servers are down?", source);
+        }
+    } else if new_count > num_replays / 2 {
+        warn!(
+            "Over half the replays grabbed from {} are new, consider increasing page count.",
+            source
+        );
+    }
 }
 
-async fn grab_games(conn: &mut Connection, _pages: usize) -> Result<()> {
-    let then = Utc::now();
-    info!("Grabbing replays");
-    let replays = ggst_api::get_replays().await;
-
-    let replays = match replays {
-        Ok(replays) => replays,
-        Err(e) => {
-            error!("Error fetching replays: {e}");
-            return Ok(());
+fn emit_live_games(conn: &Connection, games: &[Game]) {
+    if !live::has_listeners() {
+        return;
+    }
+    let live_player = |id: i64, name: &str, char_id: i64| {
+        let hidden = is_hidden(conn, id);
+        live::LivePlayer {
+            id: if hidden {
+                None
+            } else {
+                Some(format!("{:X}", id))
+            },
+            name: if hidden { "Hidden" } else { name }.to_owned(),
+            character_short: characters::all()[char_id as usize].0,
         }
     };
+    for g in games {
+        live::emit(live::LiveEvent::Game(live::LiveGame {
+            timestamp: g.timestamp,
+            floor: g.game_floor,
+            player_a: live_player(g.id_a, &g.name_a, g.char_a),
+            player_b: live_player(g.id_b, &g.name_b, g.char_b),
+            winner: g.winner,
+        }));
+    }
+}
 
-    let old_count: i64 = conn.query_row("SELECT COUNT(*) FROM games", [], |r| r.get(0))?;
-
-    let tx = conn.transaction()?;
-
-    let mut new_games = Vec::new();
+//Imports a JSON array of replays in the same shape the replay API hands out, e.g. archived
+//pages, and rates the new games the same way a live pull would. Returns the new game count.
+pub fn import_replays(conn: &mut Connection, path: &str, config: &Config) -> Result<usize> {
+    info!("Importing replays from {}", path);
+    let file = std::fs::File::open(path).with_context(|| format!("Couldn't open {}", path))?;
+    let replays: Vec<responses::Replay> =
+        rocket::serde::json::serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Couldn't parse replays in {}", path))?;
 
     let num_replays = replays.len();
+    let mut new_games = Vec::new();
+    let tx = conn.transaction()?;
     for r in replays {
-        new_games.extend(add_game(&tx, r));
+        new_games.extend(add_game(&tx, r, "import"));
     }
     tx.commit()?;
 
-    let count: i64 = conn.query_row("SELECT COUNT(*) FROM games", [], |r| r.get(0))?;
-
-    let elapsed = (Utc::now() - then).num_milliseconds();
-
+    let new_count = new_games.len();
     info!(
-        "Grabbed {} games -  new games: {} ({} total) - {}ms",
-        num_replays,
-        count - old_count,
-        count,
-        elapsed,
+        "Imported {} replays - new games: {}",
+        num_replays, new_count
     );
 
-    assert_eq!(count - old_count, new_games.len() as i64);
-
-    update_ratings(conn, Some(new_games));
-
-    if count - old_count == num_replays as i64 {
-        if num_replays > 0 {
-            error!("Only new replays! We're probably missing some, try increasing the page count.");
-        } else {
-            error!("No replays! Maybe servers are down?");
-        }
-    } else if count - old_count > num_replays as i64 / 2 {
-        warn!("Over half the grabbed replays are new, consider increasing page count.");
-    }
+    rate_new_games(conn, "import", new_games, config);
 
-    Ok(())
+    Ok(new_count)
 }
 
-fn add_game(conn: &Transaction, game: responses::Replay) -> Option<Game> {
+fn add_game(conn: &Transaction, game: responses::Replay, source: &str) -> Option<Game> {
     //2023-01-30 01:52:15"
     let responses::Replay {
         timestamp,
@@ -446,53 +2870,48 @@ fn add_game(conn: &Transaction, game: responses::Replay) -> Option<Game> {
         winner,
         ..
     } = game;
+
+    //Games with codes from a newer patch than we know about are skipped rather than
+    //poisoning the tables that are indexed by character/floor
+    for character in [player1_character, player2_character] {
+        if character < 0 || character as usize >= characters::count() {
+            warn!(
+                "Skipping game with unknown character code {} - players {} and {}",
+                character, player1.id, player2.id
+            );
+            RUNTIME_DATA.lock().unwrap().total_parse_errors += 1;
+            metrics::PARSE_ERRORS.inc();
+            return None;
+        }
+    }
+    if !matches!(game_floor, 1..=10 | 99) {
+        warn!(
+            "Skipping game with unknown floor code {} - players {} and {}",
+            game_floor, player1.id, player2.id
+        );
+        RUNTIME_DATA.lock().unwrap().total_parse_errors += 1;
+        metrics::PARSE_ERRORS.inc();
+        return None;
+    }
+
     let timestamp = NaiveDateTime::parse_from_str(&timestamp, "%Y-%m-%d %H:%M:%S").unwrap();
 
-    let count = conn
-        .execute(
-            "INSERT OR IGNORE INTO games (
-            timestamp,
-            id_a,
-            name_a,
-            char_a,
-            platform_a,
-            id_b,
-            name_b,
-            char_b,
-            platform_b,
-            winner,
-            game_floor
-        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            params![
-                timestamp.timestamp(),
-                player1.id,
-                player1.name,
-                player1_character,
-                player1.platform,
-                player2.id,
-                player2.name,
-                player2_character,
-                player2.platform,
-                winner,
-                game_floor,
-            ],
-        )
-        .unwrap();
+    let game = Game {
+        timestamp: timestamp.and_utc().timestamp(),
+        id_a: player1.id.parse().unwrap(),
+        char_a: player1_character,
+        platform_a: player1.platform,
+        name_a: player1.name,
+        id_b: player2.id.parse().unwrap(),
+        char_b: player2_character,
+        name_b: player2.name,
+        platform_b: player2.platform,
+        winner,
+        game_floor,
+    };
 
-    if count == 1 {
-        Some(Game {
-            timestamp: timestamp.timestamp(),
-            id_a: player1.id.parse().unwrap(),
-            char_a: player1_character,
-            platform_a: player1.platform,
-            name_a: player1.name,
-            id_b: player2.id.parse().unwrap(),
-            char_b: player2_character,
-            name_b: player2.name,
-            platform_b: player2.platform,
-            winner,
-            game_floor,
-        })
+    if SqliteStorage::new(conn).add_game(&game, source).unwrap() {
+        Some(game)
     } else {
         None
     }
@@ -504,218 +2923,426 @@ fn add_game(conn: &Transaction, game: responses::Replay) -> Option<Game> {
 }
 
 fn update_player(conn: &Transaction, id: i64, name: &str, floor: i64, platform: i64) {
-    if let Err(e) = conn.execute(
-        "REPLACE INTO players(id, name, floor, platform) VALUES(?, ?, ?, ?)",
-        params![id, name, floor, platform],
-    ) {
+    if let Err(e) = conn
+        .prepare_cached("REPLACE INTO players(id, name, floor, platform) VALUES(?, ?, ?, ?)")
+        .and_then(|mut s| s.execute(params![id, name, floor, platform]))
+    {
         warn!("{}", e);
     }
 
-    if let Err(e) = conn.execute(
-        "INSERT OR IGNORE INTO player_names(id, name) VALUES(?, ?)",
-        params![id, name],
-    ) {
+    if let Err(e) = conn
+        .prepare_cached("INSERT OR IGNORE INTO player_names(id, name) VALUES(?, ?)")
+        .and_then(|mut s| s.execute(params![id, name]))
+    {
         warn!("{}", e);
     }
 }
 
-fn update_player_distribution(conn: &mut Connection) {
+fn update_player_distribution(conn: &mut Connection, config: &Config) {
     let then = Utc::now();
     let tx = conn.transaction().unwrap();
 
     let two_weeks_ago = then.timestamp() - 60 * 60 * 24 * 14;
 
-    tx.execute("DELETE FROM player_floor_distribution", [])
-        .unwrap();
-    tx.execute("DELETE FROM player_rating_distribution", [])
-        .unwrap();
+    //Rows are replaced in place rather than deleted and recomputed, so the tables the
+    //website reads are never empty
+    {
+        let mut player_counts = FxHashMap::<i64, i64>::default();
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT floor, COUNT(*) FROM players WHERE {} GROUP BY floor",
+                active_filter("players")
+            ))
+            .unwrap();
+        let mut rows = stmt
+            .query(named_params! {":active_since": config.active_since()})
+            .unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            player_counts.insert(row.get(0).unwrap(), row.get(1).unwrap());
+        }
 
-    for f in (1..=10).chain(std::iter::once(99)) {
-        let player_count: i64 = tx
-            .query_row(
-                "SELECT COUNT(*) FROM players WHERE floor = ?",
-                params![f],
-                |r| r.get(0),
+        let mut game_counts = FxHashMap::<i64, i64>::default();
+        let mut stmt = tx
+            .prepare(
+                "SELECT game_floor, COUNT(*) FROM games WHERE timestamp > ? GROUP BY game_floor",
             )
             .unwrap();
-        let game_count: i64 = tx
-            .query_row(
-                "SELECT COUNT(*) FROM games WHERE game_floor = ? AND timestamp > ?",
-                params![f, two_weeks_ago],
-                |r| r.get(0),
+        let mut rows = stmt.query(params![two_weeks_ago]).unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            game_counts.insert(row.get(0).unwrap(), row.get(1).unwrap());
+        }
+
+        for f in FLOORS {
+            tx.execute(
+                "REPLACE INTO
+                player_floor_distribution
+                (floor, player_count, game_count)
+                VALUES (?, ?, ?)",
+                params![
+                    f,
+                    player_counts.get(&f).copied().unwrap_or(0),
+                    game_counts.get(&f).copied().unwrap_or(0)
+                ],
             )
             .unwrap();
-
-        tx.execute(
-            "INSERT INTO
-            player_floor_distribution
-            (floor, player_count, game_count)
-            VALUES (?, ?, ?)",
-            params![f, player_count, game_count],
-        )
-        .unwrap();
+        }
     }
 
-    for r in 0..600 {
-        let r_min = r * 50;
-        let r_max = (r + 1) * 50;
+    {
+        const BUCKET_SIZE: i64 = 50;
+        const BUCKET_COUNT: usize = 600;
+
+        let mut below_zero = 0;
+        let mut bucket_counts = vec![0i64; BUCKET_COUNT];
+        let mut char_below_zero = vec![0i64; characters::all().len()];
+        let mut char_bucket_counts = vec![vec![0i64; BUCKET_COUNT]; characters::all().len()];
 
-        let player_count: i64 = tx
-            .query_row(
-                "SELECT COUNT(*)
-                FROM player_ratings
-                WHERE value >= ? AND value < ? AND deviation < ?",
-                params![r_min as f64, r_max as f64, LOW_DEVIATION],
-                |r| r.get(0),
+        let mut stmt = tx
+            .prepare(&format!(
+                "SELECT value, char_id FROM player_ratings WHERE deviation < :deviation AND {}",
+                active_filter("player_ratings")
+            ))
+            .unwrap();
+        let mut rows = stmt
+            .query(named_params! {
+                ":deviation": config.low_deviation,
+                ":active_since": config.active_since(),
+            })
+            .unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            let value: f64 = row.get(0).unwrap();
+            let char_id: usize = row.get(1).unwrap();
+            if value < 0.0 {
+                below_zero += 1;
+                if let Some(count) = char_below_zero.get_mut(char_id) {
+                    *count += 1;
+                }
+            } else {
+                let bucket = (value / BUCKET_SIZE as f64) as usize;
+                if let Some(count) = bucket_counts.get_mut(bucket) {
+                    *count += 1;
+                }
+                if let Some(count) = char_bucket_counts
+                    .get_mut(char_id)
+                    .and_then(|c| c.get_mut(bucket))
+                {
+                    *count += 1;
+                }
+            }
+        }
+
+        let mut replace_stmt = tx
+            .prepare(
+                "REPLACE INTO
+                player_rating_distribution
+                (min_rating, max_rating, player_count, player_count_cum)
+                VALUES (?, ?, ?, ?)",
             )
             .unwrap();
+        let mut delete_stmt = tx
+            .prepare("DELETE FROM player_rating_distribution WHERE min_rating = ?")
+            .unwrap();
 
-        if player_count < 10 {
-            continue;
+        let mut player_count_cum = below_zero;
+        for (r, player_count) in bucket_counts.into_iter().enumerate() {
+            let r_min = r as i64 * BUCKET_SIZE;
+            let r_max = (r as i64 + 1) * BUCKET_SIZE;
+            player_count_cum += player_count;
+
+            if player_count < 10 {
+                delete_stmt.execute(params![r_min]).unwrap();
+                continue;
+            }
+
+            replace_stmt
+                .execute(params![r_min, r_max, player_count, player_count_cum])
+                .unwrap();
         }
 
-        let player_count_cum: i64 = tx
-            .query_row(
-                "SELECT COUNT(*)
-                FROM player_ratings
-                WHERE value < ? AND deviation < ?",
-                params![r_max as f64, LOW_DEVIATION],
-                |r| r.get(0),
+        //Each character has far fewer players, so only empty buckets are left out
+        let mut replace_stmt = tx
+            .prepare(
+                "REPLACE INTO
+                char_rating_distribution
+                (char_id, min_rating, max_rating, player_count, player_count_cum)
+                VALUES (?, ?, ?, ?, ?)",
             )
             .unwrap();
+        let mut delete_stmt = tx
+            .prepare("DELETE FROM char_rating_distribution WHERE char_id = ? AND min_rating = ?")
+            .unwrap();
 
-        tx.execute(
-            "INSERT INTO
-            player_rating_distribution
-            (min_rating, max_rating, player_count, player_count_cum)
-            VALUES (?, ?, ?, ?)",
-            params![r_min, r_max, player_count, player_count_cum],
-        )
-        .unwrap();
-    }
+        for (char_id, bucket_counts) in char_bucket_counts.into_iter().enumerate() {
+            let mut player_count_cum = char_below_zero[char_id];
+            for (r, player_count) in bucket_counts.into_iter().enumerate() {
+                let r_min = r as i64 * BUCKET_SIZE;
+                let r_max = (r as i64 + 1) * BUCKET_SIZE;
+                player_count_cum += player_count;
 
-    tx.commit().unwrap();
+                if player_count == 0 {
+                    delete_stmt.execute(params![char_id, r_min]).unwrap();
+                    continue;
+                }
 
-    info!(
-        "Updated player distribution - {}ms",
-        (Utc::now() - then).num_milliseconds()
-    );
-}
+                replace_stmt
+                    .execute(params![
+                        char_id,
+                        r_min,
+                        r_max,
+                        player_count,
+                        player_count_cum
+                    ])
+                    .unwrap();
+            }
+        }
+    }
 
-fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
-    info!("Updating ratings");
-    let then = Utc::now();
+    update_game_activity(&tx, two_weeks_ago).unwrap();
 
-    let tx = conn.transaction().unwrap();
-    //Fetch the games from the rating period
-    let (games, remaining) = games.map(|g| (g, 0)).unwrap_or_else(|| {
+    //Same players as the rating distribution, best first
+    {
         let mut stmt = tx
-            .prepare(
-                "SELECT
-                    games.timestamp,
-                    games.id_a,
-                    games.name_a,
-                    games.char_a,
-                    games.platform_a,
-                    games.id_b,
-                    games.name_b,
-                    games.char_b,
-                    games.platform_b,
-                    games.winner,
-                    games.game_floor
-                FROM
-                    games LEFT JOIN game_ratings ON
-                    games.id_a == game_ratings.id_a
-                    AND games.id_b == game_ratings.id_b
-                    AND games.timestamp == game_ratings.timestamp
-                WHERE game_ratings.id_a IS NULL
-                ORDER BY games.timestamp ASC
-                LIMIT 250000",
+            .prepare(&format!(
+                "SELECT id, char_id FROM player_ratings
+                WHERE deviation < :deviation AND {}
+                ORDER BY value DESC",
+                active_filter("player_ratings")
+            ))
+            .unwrap();
+        let players = stmt
+            .query_map(
+                named_params! {
+                    ":deviation": config.low_deviation,
+                    ":active_since": config.active_since(),
+                },
+                |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?)),
             )
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+
+        tx.execute("DELETE FROM player_percentiles", []).unwrap();
+        let mut insert_stmt = tx
+            .prepare("INSERT INTO player_percentiles VALUES(?, ?, ?)")
             .unwrap();
+        for (pos, (id, char_id)) in players.iter().enumerate() {
+            let top_percent = 100.0 * (pos + 1) as f64 / players.len() as f64;
+            insert_stmt
+                .execute(params![id, char_id, top_percent])
+                .unwrap();
+        }
+    }
 
-        let mut rows = stmt.query([]).unwrap();
-        let mut games = Vec::new();
-        while let Some(row) = rows.next().unwrap() {
-            games.push(Game::from_row(row));
+    tx.commit().unwrap();
+
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("update_player_distribution", elapsed);
+    info!("Updated player distribution - {}ms", elapsed);
+}
+
+//Counts games since `since` by floor, UTC weekday and hour. Every slot gets a row, empty ones
+//with a count of 0, and rows are replaced in place like the other distributions.
+fn update_game_activity(tx: &Transaction, since: i64) -> Result<()> {
+    let mut counts = FxHashMap::<(i64, i64, i64), i64>::default();
+    {
+        let mut stmt = tx.prepare(
+            "SELECT game_floor,
+                CAST(strftime('%w', timestamp, 'unixepoch') AS INTEGER),
+                CAST(strftime('%H', timestamp, 'unixepoch') AS INTEGER),
+                COUNT(*)
+            FROM games WHERE timestamp > ?
+            GROUP BY 1, 2, 3",
+        )?;
+        let mut rows = stmt.query(params![since])?;
+        while let Some(row) = rows.next()? {
+            counts.insert((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?);
         }
+    }
 
-        let remaining = {
-            let mut stmt = tx
-                .prepare(
-                    "SELECT COUNT(*)
-                FROM
-                    games LEFT JOIN game_ratings ON
-                    games.id_a == game_ratings.id_a
-                    AND games.id_b == game_ratings.id_b
-                    AND games.timestamp == game_ratings.timestamp
-                WHERE game_ratings.id_a IS NULL",
-                )
-                .unwrap();
+    let mut stmt = tx.prepare(
+        "REPLACE INTO game_activity (floor, day_of_week, hour, game_count) VALUES (?, ?, ?, ?)",
+    )?;
+    for floor in FLOORS {
+        for day in 0..7 {
+            for hour in 0..24 {
+                let count = counts.get(&(floor, day, hour)).copied().unwrap_or(0);
+                stmt.execute(params![floor, day, hour, count])?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Records the configured rating system on first use, refusing to continue ratings that
+//were made with a different one
+fn check_rating_system(storage: &mut dyn Storage, system: &dyn RatingSystem) -> Result<bool> {
+    match storage.rating_system()? {
+        Some(stored) if stored != system.name() => {
+            error!(
+                "Database was rated with {} but {} is configured, reset before switching",
+                stored,
+                system.name()
+            );
+            Ok(false)
+        }
+        Some(_) => Ok(true),
+        None => {
+            storage.set_rating_system(system.name())?;
+            Ok(true)
+        }
+    }
+}
+
+pub(crate) fn update_ratings(
+    conn: &mut Connection,
+    games: Option<Vec<Game>>,
+    config: &Config,
+) -> i64 {
+    let then = Utc::now();
+    let tx = conn.transaction().unwrap();
+    let batch = match rate_batch(&mut SqliteStorage::new(&tx), &tx, true, games, config).unwrap() {
+        Some(batch) => batch,
+        None => return 0,
+    };
+    tx.commit().unwrap();
+    cache::invalidate();
+
+    log_rating_update(then, &batch, config);
+    for event in batch.new_peaks {
+        notifications::emit(event);
+    }
 
-            let count: i64 = stmt.query_row(params![], |r| r.get(0)).unwrap();
-            count
+    batch.remaining
+}
+
+//Stores the games in Postgres and rates them along with any that were left unrated there,
+//committing each batch on its own
+fn update_postgres_ratings(
+    conn: &Connection,
+    url: &str,
+    source: &str,
+    games: &[Game],
+    config: &Config,
+) -> Result<()> {
+    let mut client = storage::connect_postgres(url)?;
+    {
+        let mut storage = PostgresStorage::new(&mut client)?;
+        for g in games {
+            storage.add_game(g, source)?;
+        }
+        storage.commit()?;
+    }
+
+    loop {
+        let then = Utc::now();
+        let mut storage = PostgresStorage::new(&mut client)?;
+        let batch = match rate_batch(&mut storage, conn, false, None, config)? {
+            Some(batch) => batch,
+            None => return Ok(()),
         };
+        storage.commit()?;
+
+        log_rating_update(then, &batch, config);
+        for event in batch.new_peaks {
+            notifications::emit(event);
+        }
+        if batch.remaining == 0 {
+            return Ok(());
+        }
+    }
+}
+
+//Rates newly stored games wherever the config keeps ratings. In SQLite they were stored
+//already and are rated right away.
+fn rate_new_games(conn: &mut Connection, source: &str, games: Vec<Game>, config: &Config) {
+    match &config.storage {
+        StorageKind::Sqlite => {
+            update_ratings(conn, Some(games), config);
+        }
+        StorageKind::Postgres { url } => {
+            if let Err(e) = update_postgres_ratings(conn, url, source, &games, config) {
+                error!("Rating games in Postgres failed: {:#}", e);
+            }
+        }
+    }
+}
 
+fn log_rating_update(then: DateTime<Utc>, batch: &RatedBatch, config: &Config) {
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("update_ratings", elapsed);
+    if config.json_logs {
         info!(
-            "Fetched {} games to rate from {} remaining - {}ms",
-            games.len(),
-            remaining,
-            (Utc::now() - then).num_milliseconds(),
+            "{}",
+            json!({
+                "event": "rating_update",
+                "games": batch.game_count,
+                "players": batch.player_count,
+                "remaining": batch.remaining,
+                "elapsed_ms": elapsed,
+            })
         );
-        (games, remaining)
-    });
+    } else {
+        info!(
+            "Calculated ratings for {} games and {} players - {}ms",
+            batch.game_count, batch.player_count, elapsed
+        );
+    }
+}
+
+struct RatedBatch {
+    game_count: usize,
+    player_count: usize,
+    remaining: i64,
+    new_peaks: Vec<notifications::Event>,
+}
+
+//Rates the given games, or the oldest unrated ones in storage. The moderation lists and pair
+//weights are read from the SQLite database in conn. Matchups, rating history and popularity
+//only exist there, so they're written to it only when sqlite_tables is set, with conn being
+//the transaction the storage writes in. None if storage was rated with another system.
+fn rate_batch(
+    storage: &mut dyn Storage,
+    conn: &Connection,
+    sqlite_tables: bool,
+    games: Option<Vec<Game>>,
+    config: &Config,
+) -> Result<Option<RatedBatch>> {
+    info!("Updating ratings");
+    let then = Utc::now();
+    let system = config.rating_system.system();
+
+    if !check_rating_system(storage, &*system)? {
+        return Ok(None);
+    }
+    //Fetch the games from the rating period
+    let (games, remaining) = match games {
+        Some(games) => (games, 0),
+        None => {
+            let (games, remaining) = storage.unrated_games(250000)?;
+            info!(
+                "Fetched {} games to rate from {} remaining - {}ms",
+                games.len(),
+                remaining,
+                (Utc::now() - then).num_milliseconds(),
+            );
+            (games, remaining)
+        }
+    };
 
     //let popularities =
 
     //Fetch all the players in the games
     let mut players = FxHashMap::default();
     for g in &games {
-        if !players.contains_key(&(g.id_a, g.char_a)) {
-            players.insert(
-                (g.id_a, g.char_a),
-                tx.query_row(
-                    "SELECT 
-                        player_ratings.id, player_ratings.char_id, wins, losses, value, deviation, last_decay,
-                        top_rating_value, top_rating_deviation, top_rating_timestamp,
-                        top_defeated_id, top_defeated_char_id, top_defeated_name,
-                        top_defeated_value, top_defeated_deviation, top_defeated_floor,
-                        top_defeated_timestamp, character_rank
-                    FROM player_ratings LEFT JOIN ranking_character 
-                    ON 
-                        player_ratings.id = ranking_character.id AND 
-                        player_ratings.char_id = ranking_character.char_id
-                    WHERE player_ratings.id = ? AND player_ratings.char_id = ?",
-                    params![g.id_a, g.char_a],
-                    |r| Ok(RatedPlayer::from_row(r)),
-                )
-                .optional()
-                .unwrap()
-                .unwrap_or(RatedPlayer::new(g.id_a, g.char_a, g.timestamp)),
-            );
-        }
-        if !players.contains_key(&(g.id_b, g.char_b)) {
-            players.insert(
-                (g.id_b, g.char_b),
-                tx.query_row(
-                    "SELECT 
-                        player_ratings.id, player_ratings.char_id, wins, losses, value, deviation, last_decay,
-                        top_rating_value, top_rating_deviation, top_rating_timestamp,
-                        top_defeated_id, top_defeated_char_id, top_defeated_name,
-                        top_defeated_value, top_defeated_deviation, top_defeated_floor,
-                        top_defeated_timestamp, character_rank
-                    FROM player_ratings LEFT JOIN ranking_character 
-                    ON 
-                        player_ratings.id = ranking_character.id AND 
-                        player_ratings.char_id = ranking_character.char_id
-                    WHERE player_ratings.id = ? AND player_ratings.char_id = ?",
-                    params![g.id_b, g.char_b],
-                    |r| Ok(RatedPlayer::from_row(r)),
-                )
-                .optional()
-                .unwrap()
-                .unwrap_or(RatedPlayer::new(g.id_b, g.char_b, g.timestamp)),
-            );
+        for (id, char_id) in [(g.id_a, g.char_a), (g.id_b, g.char_b)] {
+            if let Entry::Vacant(entry) = players.entry((id, char_id)) {
+                entry.insert(
+                    storage
+                        .player_rating(id, char_id)?
+                        .unwrap_or_else(|| RatedPlayer::new(id, char_id, g.timestamp)),
+                );
+            }
         }
     }
 
@@ -725,7 +3352,7 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
     let cheaters = {
         let mut cheaters = FxHashSet::<i64>::default();
 
-        let mut stmt = tx
+        let mut stmt = conn
             .prepare(
                 "SELECT
                     id
@@ -740,12 +3367,22 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
         cheaters
     };
 
+    let excluded = {
+        let mut stmt = conn.prepare(anomaly::EXCLUDED_IDS).unwrap();
+        let excluded = stmt
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<FxHashSet<i64>>>()
+            .unwrap();
+        excluded
+    };
+
     let mut counter = 0;
 
     //let mut last_timestamp = 0;
 
     let popularities = {
-        let mut stmt = tx
+        let mut stmt = conn
             .prepare("SELECT char_id, popularity FROM character_popularity_global")
             .unwrap();
 
@@ -760,6 +3397,25 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
         popularities
     };
 
+    let mut period_counts = FxHashMap::<(i64, i64), i64>::default();
+
+    //Everything else the games touch is kept here and written once after the loop, rows are
+    //only read the first time the batch needs them
+    let mut player_details = FxHashMap::<i64, (String, i64, i64)>::default();
+    let mut player_names = FxHashSet::<(i64, String)>::default();
+    let mut player_matchups = FxHashMap::<(i64, i64, i64), PlayerMatchup>::default();
+    let mut floor_matchups = FxHashMap::<(i64, i64, i64, i64), (i64, i64)>::default();
+    let mut global_matchups = FxHashMap::<(&str, i64, i64), GlobalMatchup>::default();
+    let mut daily_ratings = FxHashMap::<(i64, i64, i64), Rating>::default();
+    let mut rating_history = FxHashMap::<(i64, i64, i64), Rating>::default();
+    let mut pair_weights = PairWeights::new(config);
+
+    //Only the last new peak of each followed character in this batch is announced
+    let followed = notifications::followed_players(conn);
+    let notify_since = Utc::now().timestamp() - notifications::MAX_EVENT_AGE;
+    let mut new_peaks = FxHashMap::<(i64, i64), notifications::Event>::default();
+
+    let game_count = games.len();
     for g in games {
         //This fails and I don't know why
         //assert_ge!(g.timestamp, last_timestamp);
@@ -770,10 +3426,25 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
             info!("On game {}...", counter);
         }
 
-        update_player(&tx, g.id_a, &g.name_a, g.game_floor, g.platform_a);
-        update_player(&tx, g.id_b, &g.name_b, g.game_floor, g.platform_b);
+        //Later games win, same as replacing the row every game
+        for (id, name, platform) in [
+            (g.id_a, &g.name_a, g.platform_a),
+            (g.id_b, &g.name_b, g.platform_b),
+        ] {
+            player_details.insert(id, (name.clone(), g.game_floor, platform));
+            player_names.insert((id, name.clone()));
+        }
+
+        let period_timestamp = g.timestamp - g.timestamp.rem_euclid(config.rating_period);
+        *period_counts
+            .entry((period_timestamp, g.char_a))
+            .or_default() += 1;
+        *period_counts
+            .entry((period_timestamp, g.char_b))
+            .or_default() += 1;
 
         let has_cheater = cheaters.contains(&g.id_a) || cheaters.contains(&g.id_b);
+        let pair_weight = pair_weights.weight(conn, g.id_a, g.id_b, g.timestamp);
 
         let old_rating_a = players.get(&(g.id_a, g.char_a)).unwrap().rating;
         let old_rating_b = players.get(&(g.id_b, g.char_b)).unwrap().rating;
@@ -781,11 +3452,11 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
         players
             .get_mut(&(g.id_a, g.char_a))
             .unwrap()
-            .decay(g.timestamp);
+            .decay(g.timestamp, config);
         players
             .get_mut(&(g.id_b, g.char_b))
             .unwrap()
-            .decay(g.timestamp);
+            .decay(g.timestamp, config);
 
         let (winner, loser) = match g.winner {
             1 => ((g.id_a, g.char_a), (g.id_b, g.char_b)),
@@ -804,7 +3475,7 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
         let loser_rank = players.get(&loser).unwrap().character_rank.unwrap_or(99999);
         let loser_char = players.get(&loser).unwrap().char_id;
 
-        let expected_outcome = winner_rating.expected(loser_rating);
+        let expected_outcome = system.expected(winner_rating, loser_rating);
 
         const MARGIN: f64 = 0.045;
         let rsm_deviation = (0.5 * winner_rating.deviation.powf(2.0)
@@ -816,260 +3487,144 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
 
         if valid {
             //Update ratings
-            players.get_mut(&winner).unwrap().rating = winner_rating.update(loser_rating, 1.0);
+            players.get_mut(&winner).unwrap().rating =
+                system.update(winner_rating, loser_rating, 1.0);
             players.get_mut(&winner).unwrap().win_count += 1;
+            players.get_mut(&winner).unwrap().add_win_to_streak();
 
-            players.get_mut(&loser).unwrap().rating = loser_rating.update(winner_rating, 0.0);
+            players.get_mut(&loser).unwrap().rating =
+                system.update(loser_rating, winner_rating, 0.0);
             players.get_mut(&loser).unwrap().loss_count += 1;
+            players.get_mut(&loser).unwrap().current_streak = 0;
+
+            if let Some(rating_floor) = config.rating_floor {
+                for id in [winner, loser] {
+                    let rating = &mut players.get_mut(&id).unwrap().rating;
+                    rating.value = rating.value.max(rating_floor);
+                }
+            }
 
             //Update top rating and top defeated
-            players
+            let new_peak = players
                 .get_mut(&winner)
                 .unwrap()
-                .update_top_rating(g.timestamp);
+                .update_top_rating(g.timestamp, config.low_deviation);
 
-            let loser_name = match g.winner {
-                1 => g.name_b,
-                2 => g.name_a,
+            let (winner_name, loser_name) = match g.winner {
+                1 => (g.name_a.clone(), g.name_b.clone()),
+                2 => (g.name_b.clone(), g.name_a.clone()),
                 _ => panic!("Bad winner"),
-            };
-            players.get_mut(&winner).unwrap().update_top_defeated(
-                loser.0,
-                loser.1,
-                loser_name.to_owned(),
-                loser_rating,
-                g.game_floor,
-                g.timestamp,
-            );
-            players
-                .get_mut(&loser)
-                .unwrap()
-                .update_top_rating(g.timestamp);
-
-            //Update player matchups
-            fn update_player_matchup(
-                tx: &Transaction,
-                player_id: i64,
-                char_id: i64,
-                player_rating: Rating,
-                opp_char_id: i64,
-                opp_rating: Rating,
-                result: f64,
-                game_timestamp: i64,
-            ) {
-                tx.execute(
-                    "INSERT OR IGNORE INTO player_matchups VALUES(?, ?, ?, ?, 350.0, ?, 0, 0)",
-                    params![
-                        player_id,
-                        char_id,
-                        opp_char_id,
-                        player_rating.value,
-                        game_timestamp
-                    ],
-                )
-                .unwrap();
-
-                let (value, deviation, mut last_decay_timestamp, mut wins, mut losses): (
-                    f64,
-                    f64,
-                    i64,
-                    i64,
-                    i64,
-                ) = tx
-                    .query_row(
-                        "SELECT 
-                            rating_value, 
-                            rating_deviation, 
-                            rating_timestamp, 
-                            wins,
-                            losses
-                        FROM player_matchups
-                        WHERE id=? AND char_id=? AND opp_char_id=?",
-                        params![player_id, char_id, opp_char_id],
-                        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
-                    )
-                    .unwrap();
-
-                let mut rating = Rating::new(value, deviation);
-
-                rating = rating.update(opp_rating, result);
-
-                while last_decay_timestamp + RATING_PERIOD < game_timestamp {
-                    rating.decay_deviation(1, DECAY_CONSTANT);
-                    last_decay_timestamp += RATING_PERIOD;
-                }
-
-                if result == 1.0 {
-                    wins += 1;
-                } else {
-                    losses += 1;
-                }
-
-                let Rating { value, deviation } = rating;
-
-                tx.execute(
-                    "UPDATE player_matchups
-                SET 
-                    rating_value = ?,
-                    rating_deviation = ?,
-                    rating_timestamp = ?,
-                    wins = ?,
-                    losses = ?
-                WHERE id=? AND char_id=? AND opp_char_id=?",
-                    params![
-                        value,
-                        deviation,
-                        last_decay_timestamp,
-                        wins,
-                        losses,
-                        player_id,
-                        char_id,
-                        opp_char_id
-                    ],
-                )
-                .unwrap();
+            };
+            if new_peak && followed.contains(&winner.0) && g.timestamp > notify_since {
+                let rating = players.get(&winner).unwrap().rating;
+                new_peaks.insert(
+                    winner,
+                    notifications::Event::NewPeak {
+                        id: winner.0,
+                        char_id: winner.1,
+                        name: winner_name,
+                        value: rating.value,
+                        deviation: rating.deviation,
+                    },
+                );
             }
-
-            update_player_matchup(
-                &tx,
-                winner.0,
-                winner.1,
-                winner_rating,
-                loser.1,
-                loser_rating,
-                1.0,
-                g.timestamp,
-            );
-            update_player_matchup(
-                &tx,
+            players.get_mut(&winner).unwrap().update_top_defeated(
                 loser.0,
                 loser.1,
+                loser_name.to_owned(),
                 loser_rating,
-                winner.1,
-                winner_rating,
-                0.0,
+                g.game_floor,
                 g.timestamp,
+                config.low_deviation,
             );
+            players
+                .get_mut(&loser)
+                .unwrap()
+                .update_top_rating(g.timestamp, config.low_deviation);
 
-            fn update_global_matchup(
-                tx: &Transaction,
-                table: &str,
-                winner_char: i64,
-                loser_char: i64,
-            ) {
-                tx.execute(
-                    &format!(
-                        "INSERT OR IGNORE INTO {} VALUES(?, ?, 1500.0, 350.0, 0, 0)",
-                        table
-                    ),
-                    params![winner_char, loser_char,],
-                )
-                .unwrap();
-                tx.execute(
-                    &format!(
-                        "INSERT OR IGNORE INTO {} VALUES(?, ?, 1500.0, 350.0, 0, 0)",
-                        table
-                    ),
-                    params![loser_char, winner_char],
-                )
-                .unwrap();
+            //Update player matchups
+            for ((id, char_id), player_rating, opp_char_id, opp_rating, result) in [
+                (winner, winner_rating, loser.1, loser_rating, 1.0),
+                (loser, loser_rating, winner.1, winner_rating, 0.0),
+            ] {
+                let matchup = player_matchups
+                    .entry((id, char_id, opp_char_id))
+                    .or_insert_with(|| {
+                        PlayerMatchup::load(conn, id, char_id, opp_char_id).unwrap_or(
+                            PlayerMatchup {
+                                rating: Rating::new(player_rating.value, 350.0),
+                                timestamp: g.timestamp,
+                                wins: 0,
+                                losses: 0,
+                            },
+                        )
+                    });
+
+                matchup.rating = matchup.rating.update(opp_rating, result);
+
+                while matchup.timestamp + config.rating_period < g.timestamp {
+                    matchup.rating.decay_deviation(1, config.decay_constant);
+                    matchup.timestamp += config.rating_period;
+                }
 
-                let (winner_value, winner_deviation, mut winner_wins): (f64, f64, i64) = tx
-                    .query_row(
-                        &format!(
-                            "SELECT 
-                        rating_value, rating_deviation, wins
-                    FROM {}
-                    WHERE char_id = ? AND opp_char_id = ?",
-                            table
-                        ),
-                        params![winner_char, loser_char],
-                        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
-                    )
-                    .unwrap();
+                if result == 1.0 {
+                    matchup.wins += 1;
+                } else {
+                    matchup.losses += 1;
+                }
+            }
 
-                let (loser_value, loser_deviation, mut loser_losses): (f64, f64, i64) = tx
-                    .query_row(
-                        &format!(
-                            "SELECT 
-                        rating_value, rating_deviation, losses
-                    FROM {}
-                    WHERE char_id = ? AND opp_char_id  = ?",
-                            table
-                        ),
-                        params![loser_char, winner_char],
-                        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
-                    )
+            //Plain win/loss tallies of the same matchups, split by the floor they were played on
+            floor_matchups
+                .entry((winner.0, winner.1, loser.1, g.game_floor))
+                .or_default()
+                .0 += 1;
+            floor_matchups
+                .entry((loser.0, loser.1, winner.1, g.game_floor))
+                .or_default()
+                .1 += 1;
+
+            let mut update_global_matchup = |table: &'static str| {
+                let [old_winner, old_loser] =
+                    [(winner.1, loser.1), (loser.1, winner.1)].map(|(char_id, opp_char_id)| {
+                        global_matchups
+                            .entry((table, char_id, opp_char_id))
+                            .or_insert_with(|| {
+                                GlobalMatchup::load(conn, table, char_id, opp_char_id)
+                            })
+                            .rating
+                    });
+
+                //Mirrors read and write the same row, the loser's update is the one that sticks
+                let winner_matchup = global_matchups
+                    .get_mut(&(table, winner.1, loser.1))
                     .unwrap();
+                winner_matchup.rating =
+                    old_winner.update_with_weight(old_loser, 1.0, pair_weight, 5.0);
+                winner_matchup.wins += 1;
 
-                let new_winner = Rating::new(winner_value, winner_deviation).update_with_min_dev(
-                    Rating::new(loser_value, loser_deviation),
-                    1.0,
-                    5.0,
-                );
-                let new_loser = Rating::new(loser_value, loser_deviation).update_with_min_dev(
-                    Rating::new(winner_value, winner_deviation),
-                    0.0,
-                    5.0,
-                );
-
-                winner_wins += 1;
-                loser_losses += 1;
-
-                tx.execute(
-                    &format!(
-                        "UPDATE {}
-                SET 
-                    rating_value=?,
-                    rating_deviation=?,
-                    wins=?
-                WHERE
-                    char_id = ? AND opp_char_id = ?",
-                        table
-                    ),
-                    params![
-                        new_winner.value,
-                        new_winner.deviation,
-                        winner_wins,
-                        winner_char,
-                        loser_char,
-                    ],
-                )
-                .unwrap();
-
-                tx.execute(
-                    &format!(
-                        "UPDATE {}
-                SET 
-                    rating_value=?,
-                    rating_deviation=?,
-                    losses=?
-                WHERE
-                    char_id = ? AND opp_char_id = ?",
-                        table
-                    ),
-                    params![
-                        new_loser.value,
-                        new_loser.deviation,
-                        loser_losses,
-                        loser_char,
-                        winner_char,
-                    ],
-                )
-                .unwrap();
-            }
+                let loser_matchup = global_matchups
+                    .get_mut(&(table, loser.1, winner.1))
+                    .unwrap();
+                loser_matchup.rating =
+                    old_loser.update_with_weight(old_winner, 0.0, pair_weight, 5.0);
+                loser_matchup.losses += 1;
+            };
 
-            update_global_matchup(&tx, "global_matchups", winner.1, loser.1);
-            if winner_rank <= 100 && loser_rank <= 100 {
-                update_global_matchup(&tx, "top_100_matchups", winner.1, loser.1);
-            }
-            if winner_rank <= 1000 && loser_rank <= 1000 {
-                update_global_matchup(&tx, "top_1000_matchups", winner.1, loser.1);
-            }
-            if winner_rank as f64 <= popularities.get(&winner_char).unwrap_or(&0.0) * 1000.0
-                && loser_rank as f64 <= popularities.get(&loser_char).unwrap_or(&0.0) * 1000.0
-            {
-                update_global_matchup(&tx, "proportional_matchups", winner.1, loser.1);
+            //Excluded players are still rated, they just don't count towards character stats
+            if !excluded.contains(&winner.0) && !excluded.contains(&loser.0) {
+                update_global_matchup("global_matchups");
+                if winner_rank <= 100 && loser_rank <= 100 {
+                    update_global_matchup("top_100_matchups");
+                }
+                if winner_rank <= 1000 && loser_rank <= 1000 {
+                    update_global_matchup("top_1000_matchups");
+                }
+                if winner_rank as f64 <= popularities.get(&winner_char).unwrap_or(&0.0) * 1000.0
+                    && loser_rank as f64 <= popularities.get(&loser_char).unwrap_or(&0.0) * 1000.0
+                {
+                    update_global_matchup("proportional_matchups");
+                }
             }
 
             //Update daily ratings
@@ -1079,102 +3634,165 @@ fn update_ratings(conn: &mut Connection, games: Option<Vec<Game>>) -> i64 {
                     .and_hms(0, 0, 0)
                     .timestamp();
 
-                let winner_new_rating = players.get(&winner).unwrap().rating;
-                let loser_new_rating = players.get(&loser).unwrap().rating;
-
-                if winner_new_rating.deviation < LOW_DEVIATION {
-                    tx.execute(
-                        "REPLACE INTO daily_ratings VALUES(?, ?, ?, ?, ?)",
-                        params![
-                            winner.0,
-                            winner.1,
-                            day_timestamp,
-                            winner_new_rating.value,
-                            winner_new_rating.deviation
-                        ],
-                    )
-                    .unwrap();
+                for id in [winner, loser] {
+                    let rating = players.get(&id).unwrap().rating;
+                    if rating.deviation < config.low_deviation {
+                        daily_ratings.insert((id.0, id.1, day_timestamp), rating);
+                    }
                 }
+            }
+
+            //Update rating history, the last game in a rating period leaves its snapshot
+            for id in [winner, loser] {
+                let rating = players.get(&id).unwrap().rating;
+                rating_history.insert((id.0, id.1, period_timestamp), rating);
+            }
+        }
+
+        let new_rating_a = players.get(&(g.id_a, g.char_a)).unwrap().rating;
+        let new_rating_b = players.get(&(g.id_b, g.char_b)).unwrap().rating;
+        storage.add_game_rating(
+            &g,
+            (old_rating_a, old_rating_b),
+            (new_rating_a, new_rating_b),
+            valid,
+        )?;
+    }
+
+    for (id, (name, floor, platform)) in player_details {
+        if let Err(e) = storage.update_player(id, &name, floor, platform) {
+            warn!("{}", e);
+        }
+    }
+
+    if sqlite_tables {
+        info!(
+            "Writing {} matchups and {} rating snapshots",
+            player_matchups.len() + floor_matchups.len() + global_matchups.len(),
+            daily_ratings.len() + rating_history.len()
+        );
+
+        {
+            let mut stmt = conn
+                .prepare("INSERT OR IGNORE INTO player_names(id, name) VALUES(?, ?)")
+                .unwrap();
+            for (id, name) in player_names {
+                stmt.execute(params![id, name]).unwrap();
+            }
+        }
+
+        {
+            let mut stmt = conn
+                .prepare("REPLACE INTO player_matchups VALUES(?, ?, ?, ?, ?, ?, ?, ?)")
+                .unwrap();
+            for ((id, char_id, opp_char_id), m) in player_matchups {
+                stmt.execute(params![
+                    id,
+                    char_id,
+                    opp_char_id,
+                    m.rating.value,
+                    m.rating.deviation,
+                    m.timestamp,
+                    m.wins,
+                    m.losses
+                ])
+                .unwrap();
+            }
+        }
 
-                if loser_new_rating.deviation < LOW_DEVIATION {
-                    tx.execute(
-                        "REPLACE INTO daily_ratings VALUES(?, ?, ?, ?, ?)",
-                        params![
-                            loser.0,
-                            loser.1,
-                            day_timestamp,
-                            loser_new_rating.value,
-                            loser_new_rating.deviation
-                        ],
-                    )
+        {
+            let mut insert = conn
+                .prepare("INSERT OR IGNORE INTO player_floor_matchups VALUES(?, ?, ?, ?, 0, 0)")
+                .unwrap();
+            let mut update = conn
+                .prepare(
+                    "UPDATE player_floor_matchups
+                    SET wins = wins + ?, losses = losses + ?
+                    WHERE id = ? AND char_id = ? AND opp_char_id = ? AND floor = ?",
+                )
+                .unwrap();
+            for ((id, char_id, opp_char_id, floor), (wins, losses)) in floor_matchups {
+                insert
+                    .execute(params![id, char_id, opp_char_id, floor])
+                    .unwrap();
+                update
+                    .execute(params![wins, losses, id, char_id, opp_char_id, floor])
                     .unwrap();
-                }
             }
         }
 
-        tx.execute(
-            "INSERT INTO game_ratings VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            params![
-                g.timestamp,
-                g.id_a,
-                old_rating_a.value,
-                old_rating_a.deviation,
-                g.id_b,
-                old_rating_b.value,
-                old_rating_b.deviation,
-                g.winner,
-                valid,
-            ],
-        )
-        .unwrap();
+        for ((table, char_id, opp_char_id), m) in global_matchups {
+            conn.prepare_cached(&format!("REPLACE INTO {} VALUES(?, ?, ?, ?, ?, ?)", table))
+                .unwrap()
+                .execute(params![
+                    char_id,
+                    opp_char_id,
+                    m.rating.value,
+                    m.rating.deviation,
+                    m.wins,
+                    m.losses
+                ])
+                .unwrap();
+        }
+
+        for (table, ratings) in [
+            ("daily_ratings", daily_ratings),
+            ("player_rating_history", rating_history),
+        ] {
+            let mut stmt = conn
+                .prepare(&format!("REPLACE INTO {} VALUES(?, ?, ?, ?, ?)", table))
+                .unwrap();
+            for ((id, char_id, timestamp), rating) in ratings {
+                stmt.execute(params![
+                    id,
+                    char_id,
+                    timestamp,
+                    rating.value,
+                    rating.deviation
+                ])
+                .unwrap();
+            }
+        }
+
+        {
+            let mut insert = conn
+                .prepare("INSERT OR IGNORE INTO character_popularity VALUES(?, ?, 0)")
+                .unwrap();
+            let mut update = conn
+                .prepare(
+                    "UPDATE character_popularity SET game_count = game_count + ?
+                    WHERE period = ? AND char_id = ?",
+                )
+                .unwrap();
+            for ((period, char_id), count) in period_counts {
+                insert.execute(params![period, char_id]).unwrap();
+                update.execute(params![count, period, char_id]).unwrap();
+            }
+        }
     }
 
+    let player_count = players.len();
     for (_, player) in players.into_iter() {
         if player.rating.deviation < 0.0 {
             error!("Negative rating deviation???");
         }
 
-        tx.execute(
-            "REPLACE INTO player_ratings VALUES(
-                ?, ?, ?, ?, ?, ?, ?,
-                ?, ?, ?, 
-                ?, ?, ?, ?, ?, ?, ?)",
-            params![
-                player.id,
-                player.char_id,
-                player.win_count,
-                player.loss_count,
-                player.rating.value,
-                player.rating.deviation,
-                player.last_decay,
-                //
-                player.top_rating.as_ref().map(|r| r.value),
-                player.top_rating.as_ref().map(|r| r.deviation),
-                player.top_rating.as_ref().map(|r| r.timestamp),
-                //
-                player.top_defeated.as_ref().map(|t| t.id),
-                player.top_defeated.as_ref().map(|t| t.char_id),
-                player.top_defeated.as_ref().map(|t| t.name.clone()),
-                player.top_defeated.as_ref().map(|t| t.value),
-                player.top_defeated.as_ref().map(|t| t.deviation),
-                player.top_defeated.as_ref().map(|t| t.floor),
-                player.top_defeated.as_ref().map(|t| t.timestamp),
-            ],
-        )
-        .unwrap();
+        storage.save_player_rating(&player)?;
     }
 
-    tx.commit().unwrap();
-
-    info!(
-        "Calculated ratings - {}ms",
-        (Utc::now() - then).num_milliseconds()
-    );
-
-    remaining
+    Ok(Some(RatedBatch {
+        game_count,
+        player_count,
+        remaining,
+        new_peaks: new_peaks.into_values().collect(),
+    }))
 }
 
-pub fn calc_character_popularity(conn: &mut Connection, last_timestamp: i64) -> Result<()> {
+pub fn calc_character_popularity(
+    conn: &mut Connection,
+    config: &Config,
+    last_timestamp: i64,
+) -> Result<()> {
     let then = Utc::now();
     info!("Calculating character popularity stats..");
     let one_week_ago = last_timestamp - 60 * 60 * 24 * 7;
@@ -1194,7 +3812,7 @@ pub fn calc_character_popularity(conn: &mut Connection, last_timestamp: i64) ->
         FROM
             games NATURAL JOIN game_ratings
         WHERE timestamp > ? AND (deviation_a < ? OR deviation_b < ?)",
-        params![one_week_ago, LOW_DEVIATION, LOW_DEVIATION],
+        params![one_week_ago, config.low_deviation, config.low_deviation],
     )?;
     info!("making indices");
     tx.execute("CREATE INDEX temp.i_char_a ON recent_games(char_a)", [])?;
@@ -1217,7 +3835,7 @@ pub fn calc_character_popularity(conn: &mut Connection, last_timestamp: i64) ->
         return Ok(());
     }
 
-    for c in 0..CHAR_COUNT {
+    for c in 0..characters::count() {
         let char_count: f64 = tx.query_row(
             "SELECT
                     (SELECT COUNT(*) FROM temp.recent_games
@@ -1254,15 +3872,15 @@ pub fn calc_character_popularity(conn: &mut Connection, last_timestamp: i64) ->
             params![
                 rating_min,
                 rating_max,
-                LOW_DEVIATION,
+                config.low_deviation,
                 rating_min,
                 rating_max,
-                LOW_DEVIATION
+                config.low_deviation
             ],
             |r| r.get(0),
         )?;
 
-        for c in 0..CHAR_COUNT {
+        for c in 0..characters::count() {
             let char_count: f64 = tx.query_row(
                 "SELECT
                     (SELECT COUNT(*) FROM temp.recent_games
@@ -1280,11 +3898,11 @@ pub fn calc_character_popularity(conn: &mut Connection, last_timestamp: i64) ->
                     c,
                     rating_min,
                     rating_max,
-                    LOW_DEVIATION,
+                    config.low_deviation,
                     c,
                     rating_min,
                     rating_max,
-                    LOW_DEVIATION
+                    config.low_deviation
                 ],
                 |r| r.get(0),
             )?;
@@ -1299,62 +3917,154 @@ pub fn calc_character_popularity(conn: &mut Connection, last_timestamp: i64) ->
     tx.execute("DROP TABLE temp.recent_games", [])?;
 
     tx.commit()?;
-    info!(
-        "Updated character popularity - {}ms",
-        (Utc::now() - then).num_milliseconds()
-    );
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("calc_character_popularity", elapsed);
+    info!("Updated character popularity - {}ms", elapsed);
     Ok(())
 }
 
-pub fn update_rankings_once() {
-    let mut conn = Connection::open(DB_NAME).unwrap();
-    update_rankings(&mut conn).unwrap();
+pub fn update_rankings_once(config: &Config) {
+    let mut conn = db::open(&config.db_path).unwrap();
+    update_rankings(&mut conn, config).unwrap();
 }
 
-pub fn update_rankings(conn: &mut Connection) -> Result<()> {
+pub fn update_rankings(conn: &mut Connection, config: &Config) -> Result<()> {
     info!("Updating rankings");
     let then = Utc::now();
     let tx = conn.transaction()?;
     tx.execute("DELETE FROM ranking_global", [])?;
     tx.execute("DELETE FROM ranking_character", [])?;
 
+    let active_since = config.active_since();
+    let idle_since = config.idle_since();
+
     tx.execute(
-        "INSERT INTO ranking_global (global_rank, id, char_id)
-         SELECT ROW_NUMBER()
-         OVER (ORDER BY value DESC) as global_rank, player_ratings.id, char_id
-         FROM player_ratings
-            LEFT JOIN cheater_status on player_ratings.id = cheater_status.id
-            LEFT JOIN hidden_status on player_ratings.id = hidden_status.id
-         WHERE deviation < ? AND cheater_status IS NULL AND hidden_status IS NULL
-         ORDER BY value DESC
-         LIMIT 1000",
-        params![LOW_DEVIATION],
+        &format!(
+            "INSERT INTO ranking_global (global_rank, id, char_id)
+            SELECT ROW_NUMBER()
+            OVER (ORDER BY value DESC) as global_rank, player_ratings.id, char_id
+            FROM player_ratings
+                LEFT JOIN cheater_status on player_ratings.id = cheater_status.id
+                LEFT JOIN hidden_status on player_ratings.id = hidden_status.id
+            WHERE deviation < :deviation AND cheater_status IS NULL AND hidden_status IS NULL
+                AND player_ratings.id NOT IN ({}) AND {} AND {}
+            ORDER BY value DESC
+            LIMIT 1000",
+            anomaly::EXCLUDED_IDS,
+            active_filter("player_ratings"),
+            idle_filter("player_ratings")
+        ),
+        named_params! {
+            ":deviation": config.low_deviation,
+            ":active_since": active_since,
+            ":idle_since": idle_since,
+        },
     )?;
 
-    for c in 0..CHAR_COUNT {
+    for c in 0..characters::count() {
         tx.execute(
-            "INSERT INTO ranking_character (character_rank, id, char_id)
-             SELECT ROW_NUMBER() 
-             OVER (ORDER BY value DESC) as character_rank, player_ratings.id, char_id
-             FROM player_ratings
+            &format!(
+                "INSERT INTO ranking_character (character_rank, id, char_id)
+                SELECT ROW_NUMBER()
+                OVER (ORDER BY value DESC) as character_rank, player_ratings.id, char_id
+                FROM player_ratings
+                    LEFT JOIN cheater_status on player_ratings.id = cheater_status.id
+                    LEFT JOIN hidden_status on player_ratings.id = hidden_status.id
+                WHERE deviation < :deviation AND char_id = :char_id
+                    AND cheater_status IS NULL AND hidden_status IS NULL
+                    AND player_ratings.id NOT IN ({}) AND {} AND {}
+                ORDER BY value DESC
+                LIMIT 1000",
+                anomaly::EXCLUDED_IDS,
+                active_filter("player_ratings"),
+                idle_filter("player_ratings")
+            ),
+            named_params! {
+                ":deviation": config.low_deviation,
+                ":char_id": c,
+                ":active_since": active_since,
+                ":idle_since": idle_since,
+            },
+        )?;
+    }
+
+    tx.commit()?;
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("update_rankings", elapsed);
+    info!("Updated rankings - {}ms", elapsed);
+    Ok(())
+}
+
+//Combines the characters that would be ranked into one rating per player. Both the value and
+//the variance are averaged with inverse variance weights, so a character the player barely
+//touches counts for less than their main.
+pub fn update_overall_ratings(conn: &mut Connection, config: &Config) -> Result<()> {
+    info!("Updating overall ratings");
+    let then = Utc::now();
+    let tx = conn.transaction()?;
+
+    let mut players = FxHashMap::<i64, Vec<(i64, Rating, i64)>>::default();
+    {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT player_ratings.id, char_id, value, deviation, wins + losses
+            FROM player_ratings
                 LEFT JOIN cheater_status on player_ratings.id = cheater_status.id
                 LEFT JOIN hidden_status on player_ratings.id = hidden_status.id
-             WHERE deviation < ? AND char_id = ? AND cheater_status IS NULL AND hidden_status IS NULL
-             ORDER BY value DESC
-             LIMIT 1000",
-            params![LOW_DEVIATION, c],
-        )?;
+            WHERE deviation < :deviation AND cheater_status IS NULL AND hidden_status IS NULL
+                AND player_ratings.id NOT IN ({}) AND {} AND {}",
+            anomaly::EXCLUDED_IDS,
+            active_filter("player_ratings"),
+            idle_filter("player_ratings")
+        ))?;
+        let mut rows = stmt.query(named_params! {
+            ":deviation": config.low_deviation,
+            ":active_since": config.active_since(),
+            ":idle_since": config.idle_since(),
+        })?;
+        while let Some(row) = rows.next()? {
+            players.entry(row.get(0)?).or_default().push((
+                row.get(1)?,
+                Rating::new(row.get(2)?, row.get(3)?),
+                row.get(4)?,
+            ));
+        }
     }
 
+    tx.execute("DELETE FROM player_overall_ratings", [])?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO player_overall_ratings VALUES(?, ?, ?, ?, ?)")?;
+        for (id, chars) in &players {
+            let ratings = chars.iter().map(|(_, r, g)| (*r, *g)).collect::<Vec<_>>();
+            let rating = match combine_ratings(&ratings) {
+                Some(rating) => rating,
+                None => continue,
+            };
+            let (best_char_id, _, _) = chars
+                .iter()
+                .max_by(|a, b| a.1.value.partial_cmp(&b.1.value).unwrap())
+                .unwrap();
+            stmt.execute(params![
+                id,
+                rating.value,
+                rating.deviation,
+                chars.len(),
+                best_char_id
+            ])?;
+        }
+    }
     tx.commit()?;
+
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("update_overall_ratings", elapsed);
     info!(
-        "Updated rankings - {}ms",
-        (Utc::now() - then).num_milliseconds()
+        "Updated overall ratings for {} players - {}ms",
+        players.len(),
+        elapsed
     );
     Ok(())
 }
 
-pub fn update_decay(conn: &mut Connection, timestamp: i64) -> Result<()> {
+pub fn update_decay(conn: &mut Connection, timestamp: i64, config: &Config) -> Result<()> {
     info!("Updating decay");
     let then = Utc::now();
 
@@ -1386,7 +4096,7 @@ pub fn update_decay(conn: &mut Connection, timestamp: i64) -> Result<()> {
 
     let mut total_decay = 0;
     for p in &mut players {
-        total_decay += p.1.decay(timestamp);
+        total_decay += p.1.decay(timestamp, config);
     }
 
     info!("Executed {} decay cycles.", total_decay);
@@ -1414,13 +4124,13 @@ pub fn update_decay(conn: &mut Connection, timestamp: i64) -> Result<()> {
     Ok(())
 }
 
-pub async fn test_decay_matchups() {
-    let mut conn = Connection::open(DB_NAME).unwrap();
+pub async fn test_decay_matchups(config: &Config) {
+    let mut conn = db::open(&config.db_path).unwrap();
 
-    decay_matchups(&mut conn, Utc::now().timestamp()).unwrap();
+    decay_matchups(&mut conn, Utc::now().timestamp(), config).unwrap();
 }
 
-fn decay_matchups(conn: &mut Connection, _timestamp: i64) -> Result<()> {
+fn decay_matchups(conn: &mut Connection, _timestamp: i64, config: &Config) -> Result<()> {
     conn.create_scalar_function("sqrt", 1, FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
         ctx.get::<f64>(0).map(f64::sqrt)
     })?;
@@ -1453,7 +4163,7 @@ fn decay_matchups(conn: &mut Connection, _timestamp: i64) -> Result<()> {
             rating_deviation < :initial_deviation",
         named_params! {
             ":initial_deviation": glicko::INITIAL_DEVIATION,
-            ":c": DECAY_CONSTANT,
+            ":c": config.decay_constant,
         },
     )?;
 
@@ -1466,7 +4176,7 @@ fn decay_matchups(conn: &mut Connection, _timestamp: i64) -> Result<()> {
             rating_deviation < :initial_deviation",
         named_params! {
             ":initial_deviation": glicko::INITIAL_DEVIATION,
-            ":c": DECAY_CONSTANT,
+            ":c": config.decay_constant,
         },
     )?;
     tx.execute(
@@ -1478,7 +4188,7 @@ fn decay_matchups(conn: &mut Connection, _timestamp: i64) -> Result<()> {
             rating_deviation < :initial_deviation",
         named_params! {
             ":initial_deviation": glicko::INITIAL_DEVIATION,
-            ":c": DECAY_CONSTANT,
+            ":c": config.decay_constant,
         },
     )?;
     tx.execute(
@@ -1490,7 +4200,7 @@ fn decay_matchups(conn: &mut Connection, _timestamp: i64) -> Result<()> {
             rating_deviation < :initial_deviation",
         named_params! {
             ":initial_deviation": glicko::INITIAL_DEVIATION,
-            ":c": DECAY_CONSTANT,
+            ":c": config.decay_constant,
         },
     )?;
 
@@ -1499,7 +4209,390 @@ fn decay_matchups(conn: &mut Connection, _timestamp: i64) -> Result<()> {
     Ok(())
 }
 
-pub fn calc_fraud_index(conn: &mut Connection) -> Result<()> {
+//Every win in a matchup table is a loss for the opposite pairing, so a[x][y].wins should
+//always equal a[y][x].losses. Anything that doesn't line up is reported.
+pub fn audit_matchups(conn: &Connection) -> Vec<MatchupInconsistency> {
+    let mut res = Vec::new();
+
+    for (table, floor, same_floor) in [
+        ("global_matchups", "NULL", ""),
+        ("top_100_matchups", "NULL", ""),
+        ("top_1000_matchups", "NULL", ""),
+        ("proportional_matchups", "NULL", ""),
+        ("floor_matchups", "a.floor", "AND b.floor = a.floor"),
+    ] {
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {floor}, a.char_id, a.opp_char_id, a.wins, b.losses
+                FROM {table} AS a
+                LEFT JOIN {table} AS b
+                    ON b.char_id = a.opp_char_id AND b.opp_char_id = a.char_id {same_floor}
+                WHERE b.losses IS NULL OR a.wins != b.losses"
+            ))
+            .unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            res.push(MatchupInconsistency {
+                table,
+                floor: row.get(0).unwrap(),
+                char_id: row.get(1).unwrap(),
+                opp_char_id: row.get(2).unwrap(),
+                wins: row.get(3).unwrap(),
+                opp_losses: row.get(4).unwrap(),
+            });
+        }
+    }
+
+    res
+}
+
+//Runs the matchup audit as part of the regular statistics update so a desynced mirror row
+//shows up in the logs without anyone having to go looking for it
+fn warn_matchup_inconsistencies(conn: &Connection) {
+    let inconsistencies = audit_matchups(conn);
+    for i in inconsistencies.iter().take(10) {
+        warn!(
+            "Matchup mismatch in {} (floor {:?}): {} vs {} has {} wins but the mirror has {:?} losses",
+            i.table, i.floor, i.char_id, i.opp_char_id, i.wins, i.opp_losses
+        );
+    }
+    if inconsistencies.len() > 10 {
+        warn!(
+            "...and {} more matchup mismatches",
+            inconsistencies.len() - 10
+        );
+    }
+}
+
+//How much each game counts towards character matchups, see matchup_pair_window_days. Games
+//have to be weighed in timestamp order, a pair's earlier games are looked up the first time
+//it comes up and tracked in memory after that.
+struct PairWeights {
+    window: i64,
+    recent: FxHashMap<(i64, i64), VecDeque<i64>>,
+}
+
+impl PairWeights {
+    fn new(config: &Config) -> Self {
+        Self {
+            window: config.matchup_pair_window_days * 24 * 60 * 60,
+            recent: FxHashMap::default(),
+        }
+    }
+
+    fn weight(&mut self, conn: &Connection, id_a: i64, id_b: i64, timestamp: i64) -> f64 {
+        if self.window <= 0 {
+            return 1.0;
+        }
+        let window_start = timestamp - self.window;
+        let recent = self
+            .recent
+            .entry((id_a.min(id_b), id_a.max(id_b)))
+            .or_insert_with(|| {
+                conn.prepare_cached(
+                    "SELECT timestamp FROM games
+                    WHERE ((id_a = ?1 AND id_b = ?2) OR (id_a = ?2 AND id_b = ?1))
+                        AND timestamp >= ?3 AND timestamp < ?4
+                    ORDER BY timestamp ASC",
+                )
+                .unwrap()
+                .query_map(params![id_a, id_b, window_start, timestamp], |r| r.get(0))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+            });
+        while recent.front().map(|&t| t < window_start).unwrap_or(false) {
+            recent.pop_front();
+        }
+        recent.push_back(timestamp);
+        1.0 / (recent.len() as f64).sqrt()
+    }
+}
+
+type FloorMatchups = FxHashMap<(i64, i64), (Rating, i64, i64)>;
+
+//Replays the same character vs character ratings as the global matchup tables, but only
+//over the valid games played between these floors
+fn floor_range_matchups(
+    tx: &Transaction,
+    min_floor: i64,
+    max_floor: i64,
+    config: &Config,
+) -> Result<FloorMatchups> {
+    let mut matchups = FloorMatchups::default();
+    fold_floor_range_matchups(tx, &mut matchups, min_floor, max_floor, 0..i64::MAX, config)?;
+    Ok(matchups)
+}
+
+//Adds the games whose game_ratings rowid falls in `rowids` on top of existing matchups
+fn fold_floor_range_matchups(
+    tx: &Transaction,
+    matchups: &mut FloorMatchups,
+    min_floor: i64,
+    max_floor: i64,
+    rowids: Range<i64>,
+    config: &Config,
+) -> Result<()> {
+    let mut weights = PairWeights::new(config);
+    let mut stmt = tx.prepare(&format!(
+        "SELECT games.char_a, games.char_b, games.winner, games.id_a, games.id_b, games.timestamp
+        FROM games JOIN game_ratings ON
+            games.timestamp = game_ratings.timestamp
+            AND games.id_a = game_ratings.id_a
+            AND games.id_b = game_ratings.id_b
+        WHERE games.game_floor BETWEEN ? AND ? AND game_ratings.valid
+            AND game_ratings.rowid >= ? AND game_ratings.rowid < ?
+            AND games.id_a NOT IN ({excluded}) AND games.id_b NOT IN ({excluded})
+        ORDER BY games.timestamp ASC",
+        excluded = anomaly::EXCLUDED_IDS
+    ))?;
+    let mut rows = stmt.query(params![min_floor, max_floor, rowids.start, rowids.end])?;
+
+    while let Some(row) = rows.next()? {
+        let char_a: i64 = row.get(0)?;
+        let char_b: i64 = row.get(1)?;
+        let (winner_char, loser_char) = match row.get(2)? {
+            1 => (char_a, char_b),
+            2 => (char_b, char_a),
+            _ => panic!("Bad winner"),
+        };
+        let weight = weights.weight(tx, row.get(3)?, row.get(4)?, row.get(5)?);
+
+        let empty = (Rating::default(), 0, 0);
+        let winner_rating = matchups.get(&(winner_char, loser_char)).unwrap_or(&empty).0;
+        let loser_rating = matchups.get(&(loser_char, winner_char)).unwrap_or(&empty).0;
+
+        let winner = matchups.entry((winner_char, loser_char)).or_insert(empty);
+        winner.0 = winner_rating.update_with_weight(loser_rating, 1.0, weight, 5.0);
+        winner.1 += 1;
+
+        let loser = matchups.entry((loser_char, winner_char)).or_insert(empty);
+        loser.0 = loser_rating.update_with_weight(winner_rating, 0.0, weight, 5.0);
+        loser.2 += 1;
+    }
+
+    Ok(())
+}
+
+pub fn calc_floor_matchups(conn: &mut Connection, floor: i64, config: &Config) -> Result<()> {
+    info!("Calculating matchups for floor {}", floor);
+    let then = Utc::now();
+
+    let tx = conn.transaction()?;
+    let matchups = floor_range_matchups(&tx, floor, floor, config)?;
+
+    tx.execute("DELETE FROM floor_matchups WHERE floor = ?", params![floor])?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO floor_matchups VALUES(?, ?, ?, ?, ?, ?, ?)")?;
+        for ((char_id, opp_char_id), (rating, wins, losses)) in &matchups {
+            //Both directions of a pair have the same game count, so they're dropped together
+            if wins + losses < config.matchup_min_games {
+                continue;
+            }
+            stmt.execute(params![
+                floor,
+                char_id,
+                opp_char_id,
+                rating.value,
+                rating.deviation,
+                wins,
+                losses
+            ])?;
+        }
+    }
+
+    tx.commit()?;
+
+    info!(
+        "Calculated {} matchups for floor {} - {}ms",
+        matchups.len(),
+        floor,
+        (Utc::now() - then).num_milliseconds()
+    );
+
+    Ok(())
+}
+
+//Folds the games rated since the last run into the running totals in
+//floor_band_matchup_totals and rebuilds floor_band_matchups from them. `full` throws the
+//totals away and starts over from every game, which repairs them if they ever drift.
+pub fn calc_floor_band_matchups(conn: &mut Connection, config: &Config, full: bool) -> Result<()> {
+    let then = Utc::now();
+
+    let tx = conn.transaction()?;
+    let next_rowid: i64 = tx.query_row(
+        "SELECT IFNULL(MAX(rowid), 0) + 1 FROM game_ratings",
+        [],
+        |r| r.get(0),
+    )?;
+    let mut from_rowid: i64 =
+        tx.query_row("SELECT next_rowid FROM floor_band_progress", [], |r| {
+            r.get(0)
+        })?;
+    //game_ratings was cleared since the last run, so the totals don't line up with it anymore
+    let full = full || from_rowid > next_rowid;
+    if full {
+        info!("Calculating floor band matchups from every game");
+        tx.execute("DELETE FROM floor_band_matchup_totals", [])?;
+        from_rowid = 0;
+    } else {
+        info!(
+            "Folding {} rated games into the floor band matchups",
+            next_rowid - from_rowid
+        );
+    }
+
+    for (band, _, min_floor, max_floor) in FLOOR_BANDS {
+        let mut matchups = FloorMatchups::default();
+        {
+            let mut stmt = tx.prepare(
+                "SELECT char_id, opp_char_id, rating_value, rating_deviation, wins, losses
+                FROM floor_band_matchup_totals WHERE band = ?",
+            )?;
+            let mut rows = stmt.query(params![band])?;
+            while let Some(row) = rows.next()? {
+                matchups.insert(
+                    (row.get(0)?, row.get(1)?),
+                    (
+                        Rating::new(row.get(2)?, row.get(3)?),
+                        row.get(4)?,
+                        row.get(5)?,
+                    ),
+                );
+            }
+        }
+        fold_floor_range_matchups(
+            &tx,
+            &mut matchups,
+            *min_floor,
+            *max_floor,
+            from_rowid..next_rowid,
+            config,
+        )?;
+
+        tx.execute(
+            "DELETE FROM floor_band_matchups WHERE band = ?",
+            params![band],
+        )?;
+        let mut totals_stmt =
+            tx.prepare("REPLACE INTO floor_band_matchup_totals VALUES(?, ?, ?, ?, ?, ?, ?)")?;
+        let mut stmt = tx.prepare("INSERT INTO floor_band_matchups VALUES(?, ?, ?, ?, ?, ?, ?)")?;
+        for ((char_id, opp_char_id), (rating, wins, losses)) in &matchups {
+            let row = params![
+                band,
+                char_id,
+                opp_char_id,
+                rating.value,
+                rating.deviation,
+                wins,
+                losses
+            ];
+            totals_stmt.execute(row)?;
+            if wins + losses >= config.matchup_min_games {
+                stmt.execute(row)?;
+            }
+        }
+    }
+    tx.execute(
+        "UPDATE floor_band_progress SET next_rowid = ?",
+        params![next_rowid],
+    )?;
+    tx.commit()?;
+
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("calc_floor_band_matchups", elapsed);
+    info!("Calculated floor band matchups - {}ms", elapsed);
+
+    Ok(())
+}
+
+pub fn calc_top_upsets(conn: &mut Connection, config: &Config) -> Result<()> {
+    info!("Calculating top upsets");
+    let then = Utc::now();
+    let since = Utc::now().timestamp() - UPSET_WINDOW;
+    let system = config.rating_system.system();
+
+    let tx = conn.transaction()?;
+    let mut upsets = Vec::new();
+    {
+        //Only settled ratings on both sides, and the winner has to have been the lower rated one
+        let mut stmt = tx.prepare(
+            "SELECT
+                timestamp, winner, game_floor,
+                id_a, char_a, name_a, value_a, deviation_a,
+                id_b, char_b, name_b, value_b, deviation_b
+            FROM games NATURAL JOIN game_ratings
+            LEFT JOIN cheater_status AS cheater_a ON cheater_a.id = id_a
+            LEFT JOIN cheater_status AS cheater_b ON cheater_b.id = id_b
+            WHERE timestamp > ? AND valid = 1
+                AND deviation_a < ? AND deviation_b < ?
+                AND ((winner = 1 AND value_a < value_b) OR (winner = 2 AND value_b < value_a))
+                AND cheater_a.id IS NULL AND cheater_b.id IS NULL",
+        )?;
+        let mut rows = stmt.query(params![since, config.low_deviation, config.low_deviation])?;
+
+        while let Some(row) = rows.next()? {
+            let winner: i64 = row.get(1)?;
+            let a = UpsetPlayer {
+                id: row.get(3)?,
+                char_id: row.get(4)?,
+                name: row.get(5)?,
+                rating: Rating::new(row.get(6)?, row.get(7)?),
+            };
+            let b = UpsetPlayer {
+                id: row.get(8)?,
+                char_id: row.get(9)?,
+                name: row.get(10)?,
+                rating: Rating::new(row.get(11)?, row.get(12)?),
+            };
+            let (w, l) = if winner == 1 { (a, b) } else { (b, a) };
+            let expected = system.expected(w.rating, l.rating);
+            upsets.push((row.get::<_, i64>(0)?, row.get::<_, i64>(2)?, w, l, expected));
+        }
+    }
+
+    upsets.sort_by(|a, b| a.4.partial_cmp(&b.4).unwrap());
+    upsets.truncate(UPSET_COUNT);
+
+    tx.execute("DELETE FROM top_upsets", [])?;
+    {
+        let mut stmt =
+            tx.prepare("INSERT INTO top_upsets VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+        for (timestamp, floor, w, l, expected) in &upsets {
+            stmt.execute(params![
+                timestamp,
+                w.id,
+                w.char_id,
+                w.name,
+                w.rating.value,
+                w.rating.deviation,
+                l.id,
+                l.char_id,
+                l.name,
+                l.rating.value,
+                l.rating.deviation,
+                expected,
+                floor
+            ])?;
+        }
+    }
+    tx.commit()?;
+
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("calc_top_upsets", elapsed);
+    info!("Calculated top upsets - {}ms", elapsed);
+
+    Ok(())
+}
+
+struct UpsetPlayer {
+    id: i64,
+    char_id: i64,
+    name: String,
+    rating: Rating,
+}
+
+pub fn calc_fraud_index(conn: &mut Connection, config: &Config) -> Result<()> {
     let then = Utc::now();
     info!("Calculating fraud index");
     let tx = conn.transaction()?;
@@ -1548,7 +4641,9 @@ pub fn calc_fraud_index(conn: &mut Connection) -> Result<()> {
             )
             .unwrap();
 
-        let mut rows = stmt.query(params![LOW_DEVIATION, LOW_DEVIATION]).unwrap();
+        let mut rows = stmt
+            .query(params![config.low_deviation, config.low_deviation])
+            .unwrap();
 
         while let Some(row) = rows.next().unwrap() {
             let char_id: i64 = row.get(0).unwrap();
@@ -1602,7 +4697,9 @@ pub fn calc_fraud_index(conn: &mut Connection) -> Result<()> {
             )
             .unwrap();
 
-        let mut rows = stmt.query(params![LOW_DEVIATION, LOW_DEVIATION]).unwrap();
+        let mut rows = stmt
+            .query(params![config.low_deviation, config.low_deviation])
+            .unwrap();
 
         while let Some(row) = rows.next().unwrap() {
             let char_id: i64 = row.get(0).unwrap();
@@ -1650,13 +4747,19 @@ pub fn calc_fraud_index(conn: &mut Connection) -> Result<()> {
                 on filtered_averages.id = char_ratings.id
 
                 where char_ratings.value > filtered_averages.avg_value 
-                    and char_ratings.value > 1800
+                    and char_ratings.value > ?
 
             group by char_id;",
             )
             .unwrap();
 
-        let mut rows = stmt.query(params![LOW_DEVIATION, LOW_DEVIATION]).unwrap();
+        let mut rows = stmt
+            .query(params![
+                config.low_deviation,
+                config.low_deviation,
+                config.high_rating
+            ])
+            .unwrap();
 
         while let Some(row) = rows.next().unwrap() {
             let char_id: i64 = row.get(0).unwrap();
@@ -1672,27 +4775,26 @@ pub fn calc_fraud_index(conn: &mut Connection) -> Result<()> {
 
     tx.commit()?;
 
-    info!(
-        "Updated fraud index - {}ms",
-        (Utc::now() - then).num_milliseconds()
-    );
+    let elapsed = (Utc::now() - then).num_milliseconds();
+    record_phase_time("calc_fraud_index", elapsed);
+    info!("Updated fraud index - {}ms", elapsed);
 
     Ok(())
 }
 
 #[derive(Debug)]
 pub struct Game {
-    timestamp: i64,
-    id_a: i64,
-    name_a: String,
-    char_a: i64,
-    platform_a: i64,
-    id_b: i64,
-    name_b: String,
-    char_b: i64,
-    platform_b: i64,
-    winner: i64,
-    game_floor: i64,
+    pub(crate) timestamp: i64,
+    pub(crate) id_a: i64,
+    pub(crate) name_a: String,
+    pub(crate) char_a: i64,
+    pub(crate) platform_a: i64,
+    pub(crate) id_b: i64,
+    pub(crate) name_b: String,
+    pub(crate) char_b: i64,
+    pub(crate) platform_b: i64,
+    pub(crate) winner: i64,
+    pub(crate) game_floor: i64,
 }
 
 impl Game {
@@ -1713,6 +4815,187 @@ impl Game {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct HeadToHead {
+    pub id_a: i64,
+    pub id_b: i64,
+    pub game_count: i64,
+    pub wins_a: i64,
+    pub wins_b: i64,
+    pub characters: Vec<HeadToHeadPairing>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeadToHeadPairing {
+    pub char_a: i64,
+    pub char_b: i64,
+    pub wins_a: i64,
+    pub wins_b: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpponentRecord {
+    pub id: i64,
+    pub name: String,
+    pub game_count: i64,
+    pub wins: i64,
+    pub losses: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpponentBreakdown {
+    pub id: Option<i64>,
+    pub name: String,
+    pub game_count: i64,
+    pub wins: i64,
+    pub losses: i64,
+    //The player's rating minus the opponent's, before each game
+    pub average_rating_difference: Option<f64>,
+    pub last_played: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlayerRank {
+    pub rank: i64,
+    pub percentile: f64,
+    pub character_rank: i64,
+    pub character_percentile: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlayerProfile {
+    pub id: i64,
+    pub name: String,
+    pub floor: i64,
+    pub characters: Vec<PlayerProfileCharacter>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlayerProfileCharacter {
+    pub char_id: i64,
+    pub char_name: &'static str,
+    pub value: f64,
+    pub deviation: f64,
+    pub wins: i64,
+    pub losses: i64,
+    pub established: bool,
+    //Missing for players that aren't active or settled enough to be placed
+    pub top_percent: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RatingSnapshot {
+    pub timestamp: i64,
+    pub value: f64,
+    pub deviation: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PopularitySnapshot {
+    pub timestamp: i64,
+    pub game_count: i64,
+    pub share: f64,
+}
+
+//Ratings are the ones going into the game
+#[derive(Debug, Serialize)]
+pub struct RecentGame {
+    pub timestamp: i64,
+    pub floor: i64,
+    pub char_id: i64,
+    pub opponent_id: Option<i64>,
+    pub opponent_name: String,
+    pub opponent_char_id: i64,
+    pub value: f64,
+    pub deviation: f64,
+    pub opponent_value: f64,
+    pub opponent_deviation: f64,
+    pub won: bool,
+    //Missing for games rated before the post-game values were stored
+    pub rating_change: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatchlistGame {
+    pub player_id: i64,
+    pub player_name: String,
+    #[serde(flatten)]
+    pub game: RecentGame,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlayerSearchResult {
+    pub id: i64,
+    pub name: String,
+    pub matched_alias: Option<String>,
+    pub game_count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GlobalStats {
+    pub total_games: i64,
+    pub total_players: i64,
+    pub established_players: i64,
+    pub last_update: i64,
+    pub floor_games: Vec<(i64, i64)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActivePlayer {
+    pub id: i64,
+    pub name: String,
+    pub game_count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CharacterStat {
+    pub char_id: i64,
+    pub name: String,
+    pub player_count: i64,
+    pub play_rate: f64,
+    pub game_count: i64,
+    pub win_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MatchupInconsistency {
+    pub table: &'static str,
+    pub floor: Option<i64>,
+    pub char_id: i64,
+    pub opp_char_id: i64,
+    pub wins: i64,
+    //None when the opposite pairing is missing entirely
+    pub opp_losses: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RatingDelta {
+    pub id: i64,
+    pub char_id: i64,
+    pub name: String,
+    pub old_value: f64,
+    pub new_value: f64,
+    pub delta: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub id: i64,
+    pub name: String,
+    pub char_id: i64,
+    pub rating: Rating,
+}
+
+//Rows that referenced the merged id, per table
+#[derive(Debug, Serialize)]
+pub struct MergeReport {
+    pub keep_id: String,
+    pub merge_id: String,
+    pub dry_run: bool,
+    pub rows: BTreeMap<String, i64>,
+}
+
 #[derive(Debug)]
 pub struct RatedPlayer {
     pub id: i64,
@@ -1727,24 +5010,28 @@ pub struct RatedPlayer {
     pub top_defeated: Option<TopDefeated>,
 
     pub character_rank: Option<i64>,
+
+    //Consecutive rated wins, reset by a loss
+    pub current_streak: i64,
+    pub longest_streak: i64,
 }
 
 #[derive(Debug)]
 pub struct TopRating {
-    value: f64,
-    deviation: f64,
-    timestamp: i64,
+    pub(crate) value: f64,
+    pub(crate) deviation: f64,
+    pub(crate) timestamp: i64,
 }
 
 #[derive(Debug)]
 pub struct TopDefeated {
-    id: i64,
-    char_id: i64,
-    name: String,
-    value: f64,
-    deviation: f64,
-    floor: i64,
-    timestamp: i64,
+    pub(crate) id: i64,
+    pub(crate) char_id: i64,
+    pub(crate) name: String,
+    pub(crate) value: f64,
+    pub(crate) deviation: f64,
+    pub(crate) floor: i64,
+    pub(crate) timestamp: i64,
 }
 
 impl RatedPlayer {
@@ -1761,6 +5048,9 @@ impl RatedPlayer {
             top_defeated: None,
 
             character_rank: None,
+
+            current_streak: 0,
+            longest_streak: 0,
         }
     }
     pub fn new_from_rating(id: i64, char_id: i64, timestamp: i64, rating: f64) -> Self {
@@ -1777,6 +5067,8 @@ impl RatedPlayer {
             top_rating: None,
             top_defeated: None,
             character_rank: None,
+            current_streak: 0,
+            longest_streak: 0,
         }
     }
     pub fn from_row(row: &Row) -> Self {
@@ -1815,30 +5107,39 @@ impl RatedPlayer {
                 .unwrap_or_default(),
 
             character_rank: row.get(17).unwrap_or(None),
+
+            current_streak: row.get(18).unwrap_or(0),
+            longest_streak: row.get(19).unwrap_or(0),
         }
     }
 
-    fn decay(&mut self, timestamp: i64) -> i64 {
+    fn add_win_to_streak(&mut self) {
+        self.current_streak += 1;
+        self.longest_streak = self.longest_streak.max(self.current_streak);
+    }
+
+    fn decay(&mut self, timestamp: i64, config: &Config) -> i64 {
         let delta = timestamp - self.last_decay;
         if delta < 0 {
             self.last_decay = timestamp;
             0
-        } else if delta > RATING_PERIOD {
+        } else if delta > config.rating_period {
             self.rating
-                .decay_deviation(delta / RATING_PERIOD, DECAY_CONSTANT);
+                .decay_deviation(delta / config.rating_period, config.decay_constant);
 
             //This is actually going to round some things off but I don't really mind
             //The difference should be extremely minor in any case
             self.last_decay = timestamp;
 
-            delta / RATING_PERIOD
+            delta / config.rating_period
         } else {
             0
         }
     }
 
-    fn update_top_rating(&mut self, timestamp: i64) {
-        if self.rating.deviation < LOW_DEVIATION
+    //Returns true if this is a new top rating
+    fn update_top_rating(&mut self, timestamp: i64, low_deviation: f64) -> bool {
+        if self.rating.deviation < low_deviation
             && self
                 .top_rating
                 .as_ref()
@@ -1850,6 +5151,9 @@ impl RatedPlayer {
                 deviation: self.rating.deviation,
                 timestamp,
             });
+            true
+        } else {
+            false
         }
     }
 
@@ -1861,8 +5165,9 @@ impl RatedPlayer {
         opponent_rating: Rating,
         floor: i64,
         timestamp: i64,
+        low_deviation: f64,
     ) {
-        if opponent_rating.deviation < LOW_DEVIATION
+        if opponent_rating.deviation < low_deviation
             && self
                 .top_defeated
                 .as_ref()
@@ -1881,3 +5186,631 @@ impl RatedPlayer {
         }
     }
 }
+
+//A player's rating against one opposing character, kept while update_ratings runs
+#[derive(Debug)]
+struct PlayerMatchup {
+    rating: Rating,
+    timestamp: i64,
+    wins: i64,
+    losses: i64,
+}
+
+impl PlayerMatchup {
+    fn load(conn: &Connection, id: i64, char_id: i64, opp_char_id: i64) -> Option<Self> {
+        conn.prepare_cached(
+            "SELECT rating_value, rating_deviation, rating_timestamp, wins, losses
+            FROM player_matchups
+            WHERE id = ? AND char_id = ? AND opp_char_id = ?",
+        )
+        .unwrap()
+        .query_row(params![id, char_id, opp_char_id], |r| {
+            Ok(Self {
+                rating: Rating::new(r.get(0)?, r.get(1)?),
+                timestamp: r.get(2)?,
+                wins: r.get(3)?,
+                losses: r.get(4)?,
+            })
+        })
+        .optional()
+        .unwrap()
+    }
+}
+
+//A row of global_matchups or one of the other character matchup tables
+#[derive(Debug)]
+struct GlobalMatchup {
+    rating: Rating,
+    wins: i64,
+    losses: i64,
+}
+
+impl GlobalMatchup {
+    fn load(conn: &Connection, table: &str, char_id: i64, opp_char_id: i64) -> Self {
+        conn.prepare_cached(&format!(
+            "SELECT rating_value, rating_deviation, wins, losses
+            FROM {}
+            WHERE char_id = ? AND opp_char_id = ?",
+            table
+        ))
+        .unwrap()
+        .query_row(params![char_id, opp_char_id], |r| {
+            Ok(Self {
+                rating: Rating::new(r.get(0)?, r.get(1)?),
+                wins: r.get(2)?,
+                losses: r.get(3)?,
+            })
+        })
+        .optional()
+        .unwrap()
+        .unwrap_or(Self {
+            rating: Rating::new(1500.0, 350.0),
+            wins: 0,
+            losses: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::{replay_source::MockSource, watchlists};
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        conn
+    }
+
+    fn add_test_game(
+        conn: &mut Connection,
+        timestamp: &str,
+        a: (i64, i64),
+        b: (i64, i64),
+        winner: i64,
+    ) {
+        let tx = conn.transaction().unwrap();
+        add_game(
+            &tx,
+            responses::Replay::new(
+                timestamp,
+                5,
+                responses::Player::new(a.0, "a"),
+                a.1,
+                responses::Player::new(b.0, "b"),
+                b.1,
+                winner,
+            ),
+            "test",
+        )
+        .unwrap();
+        tx.commit().unwrap();
+    }
+
+    fn rating(conn: &Connection, id: i64, char_id: i64) -> (Rating, i64, i64) {
+        conn.query_row(
+            "SELECT value, deviation, wins, losses FROM player_ratings WHERE id = ? AND char_id = ?",
+            params![id, char_id],
+            |r| Ok((Rating::new(r.get(0)?, r.get(1)?), r.get(2)?, r.get(3)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn single_win() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (2, 3), 1);
+        update_ratings(&mut conn, None, &Config::default());
+
+        let (winner, wins, losses) = rating(&conn, 1, 0);
+        assert!(winner.value > 1500.0);
+        assert!(winner.deviation < glicko::INITIAL_DEVIATION);
+        assert_eq!((wins, losses), (1, 0));
+
+        let (loser, wins, losses) = rating(&conn, 2, 3);
+        assert!(loser.value < 1500.0);
+        assert_eq!((wins, losses), (0, 1));
+
+        assert!((winner.value - 1500.0 - (1500.0 - loser.value)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn storage_only_batch() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (2, 3), 1);
+
+        let tx = conn.transaction().unwrap();
+        let mut storage = SqliteStorage::new(&tx);
+        let (games, remaining) = storage.unrated_games(10).unwrap();
+        assert_eq!((games.len(), remaining), (1, 1));
+        assert!(!storage.add_game(&games[0], "test").unwrap());
+        assert!(storage.player_rating(1, 0).unwrap().is_none());
+
+        let batch = rate_batch(&mut storage, &tx, false, None, &Config::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.game_count, 1);
+        assert_eq!(storage.unrated_games(10).unwrap().1, 0);
+        let player = storage.player_rating(1, 0).unwrap().unwrap();
+        assert_eq!((player.win_count, player.loss_count), (1, 0));
+
+        //The way a Postgres batch runs, nothing but the storage is written
+        let matchups: i64 = tx
+            .query_row("SELECT COUNT(*) FROM player_matchups", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(matchups, 0);
+    }
+
+    #[test]
+    fn merge_duplicate_player() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (3, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:55:15", (2, 0), (3, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:58:15", (3, 3), (2, 0), 1);
+        add_test_game(&mut conn, "2023-01-30 02:01:15", (2, 5), (3, 3), 1);
+        update_ratings(&mut conn, None, &Config::default());
+
+        let references = |conn: &Connection, id: i64| -> i64 {
+            conn.query_row(
+                "SELECT
+                    (SELECT COUNT(*) FROM games WHERE id_a = ?1 OR id_b = ?1)
+                    + (SELECT COUNT(*) FROM player_ratings WHERE id = ?1)
+                    + (SELECT COUNT(*) FROM player_matchups WHERE id = ?1)
+                    + (SELECT COUNT(*) FROM players WHERE id = ?1)",
+                params![id],
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+        conn.execute("INSERT INTO hidden_status VALUES(2, 'hidden', '')", [])
+            .unwrap();
+        let before = references(&conn, 2);
+
+        let report = merge_players(&mut conn, 1, 2, true, "test").unwrap();
+        assert_eq!(report.rows["games"], 3);
+        assert_eq!(report.rows["hidden_status"], 1);
+        assert_eq!(references(&conn, 2), before);
+
+        merge_players(&mut conn, 1, 2, false, "test").unwrap();
+        assert_eq!(references(&conn, 2), 0);
+        //The merged id was hidden, so its games don't show up again under the kept one
+        assert!(is_hidden(&conn, 1));
+        assert!(!is_hidden(&conn, 2));
+        assert_eq!(rating(&conn, 1, 0).1, 2);
+        assert_eq!(rating(&conn, 1, 0).2, 1);
+        assert_eq!(rating(&conn, 1, 5).1, 1);
+
+        let merges: i64 = conn
+            .query_row("SELECT COUNT(*) FROM player_merges", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(merges, 1);
+        assert!(merge_players(&mut conn, 1, 1, false, "test").is_err());
+    }
+
+    #[test]
+    fn pair_weighting() {
+        let matchup = |window_days: i64| {
+            let mut conn = test_db();
+            for i in 0..50 {
+                let timestamp = format!("2023-01-30 {:02}:{:02}:00", i / 60, i % 60);
+                add_test_game(&mut conn, &timestamp, (1, 0), (2, 3), 1);
+            }
+            let config = Config {
+                matchup_pair_window_days: window_days,
+                ..Config::default()
+            };
+            update_ratings(&mut conn, None, &config);
+            conn.query_row(
+                "SELECT rating_value, wins FROM global_matchups WHERE char_id = 0 AND opp_char_id = 3",
+                [],
+                |r| Ok((r.get::<_, f64>(0)?, r.get::<_, i64>(1)?)),
+            )
+            .unwrap()
+        };
+
+        let (damped, damped_wins) = matchup(7);
+        let (full, full_wins) = matchup(0);
+        assert!(damped > 1500.0 && damped < full);
+        //Only the ratings are weighed, the records stay plain game counts
+        assert_eq!((damped_wins, full_wins), (50, 50));
+    }
+
+    #[test]
+    fn matchup_counters() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:55:15", (2, 3), (1, 0), 1);
+        add_test_game(&mut conn, "2023-01-30 01:58:15", (1, 0), (2, 3), 1);
+        update_ratings(&mut conn, None, &Config::default());
+
+        let matchup = |table: &str, char_id: i64, opp_char_id: i64| -> (i64, i64) {
+            conn.query_row(
+                &format!(
+                    "SELECT wins, losses FROM {} WHERE char_id = ? AND opp_char_id = ?",
+                    table
+                ),
+                params![char_id, opp_char_id],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(matchup("global_matchups", 0, 3), (2, 1));
+        assert_eq!(matchup("global_matchups", 3, 0), (1, 2));
+        assert_eq!(matchup("player_matchups", 0, 3), (2, 1));
+        assert_eq!(matchup("player_matchups", 3, 0), (1, 2));
+        assert_eq!(matchup("player_floor_matchups", 0, 3), (2, 1));
+
+        assert!(audit_matchups(&conn).is_empty());
+    }
+
+    #[test]
+    fn win_streaks() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:55:15", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:58:15", (1, 0), (2, 3), 2);
+        add_test_game(&mut conn, "2023-01-30 02:01:15", (1, 0), (2, 3), 1);
+        update_ratings(&mut conn, None, &Config::default());
+
+        let streaks = |id: i64, char_id: i64| -> (i64, i64) {
+            conn.query_row(
+                "SELECT current_streak, longest_streak FROM player_ratings
+                WHERE id = ? AND char_id = ?",
+                params![id, char_id],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(streaks(1, 0), (1, 2));
+        assert_eq!(streaks(2, 3), (0, 1));
+    }
+
+    #[test]
+    fn incremental_floor_band_matchups() {
+        let mut conn = test_db();
+        let config = Config {
+            matchup_min_games: 0,
+            ..Config::default()
+        };
+        let totals = |conn: &Connection| -> Vec<(String, i64, i64, f64, i64, i64)> {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT band, char_id, opp_char_id, rating_value, wins, losses
+                    FROM floor_band_matchups ORDER BY band, char_id, opp_char_id",
+                )
+                .unwrap();
+            stmt.query_map([], |r| {
+                Ok((
+                    r.get(0)?,
+                    r.get(1)?,
+                    r.get(2)?,
+                    r.get(3)?,
+                    r.get(4)?,
+                    r.get(5)?,
+                ))
+            })
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+        };
+
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:55:15", (2, 3), (1, 0), 1);
+        update_ratings(&mut conn, None, &config);
+        calc_floor_band_matchups(&mut conn, &config, false).unwrap();
+        add_test_game(&mut conn, "2023-01-30 02:58:15", (1, 0), (2, 3), 1);
+        update_ratings(&mut conn, None, &config);
+        calc_floor_band_matchups(&mut conn, &config, false).unwrap();
+        //Nothing new to fold in
+        calc_floor_band_matchups(&mut conn, &config, false).unwrap();
+        let incremental = totals(&conn);
+
+        calc_floor_band_matchups(&mut conn, &config, true).unwrap();
+        assert_eq!(incremental, totals(&conn));
+        assert_eq!(incremental[0].4 + incremental[0].5, 3);
+    }
+
+    #[test]
+    fn popularity_per_period() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:55:15", (1, 0), (3, 0), 2);
+        add_test_game(&mut conn, "2023-01-30 02:01:15", (1, 0), (2, 3), 1);
+        update_ratings(&mut conn, None, &Config::default());
+
+        let history = get_popularity_history(&conn, 0, 0);
+        let counts: Vec<_> = history.iter().map(|s| s.game_count).collect();
+        assert_eq!(counts, vec![3, 1]);
+        assert!((history[0].share - 0.75).abs() < 1e-9);
+        assert!((history[1].share - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut conn = test_db();
+        for (id, value, deviation) in [(1, 1600.0, 50.0), (2, 1500.0, 50.0), (3, 1700.0, 200.0)] {
+            conn.execute(
+                "INSERT INTO player_ratings(id, char_id, wins, losses, value, deviation, last_decay)
+                VALUES(?, 0, 0, 0, ?, ?, 0)",
+                params![id, value, deviation],
+            )
+            .unwrap();
+        }
+        update_player_distribution(&mut conn, &Config::default());
+
+        let top_percent = |id: i64| -> Option<f64> {
+            conn.query_row(
+                "SELECT top_percent FROM player_percentiles WHERE id = ?",
+                params![id],
+                |r| r.get(0),
+            )
+            .optional()
+            .unwrap()
+        };
+
+        assert_eq!(top_percent(1), Some(50.0));
+        assert_eq!(top_percent(2), Some(100.0));
+        assert_eq!(top_percent(3), None);
+
+        let rank = get_player_rank(&conn, 1, 0).unwrap();
+        assert_eq!((rank.rank, rank.percentile), (1, 100.0));
+        assert_eq!(get_player_rank(&conn, 2, 0).unwrap().rank, 2);
+        assert!(get_player_rank(&conn, 3, 0).is_none());
+    }
+
+    #[test]
+    fn combined_rating() {
+        assert_eq!(combine_ratings(&[]), None);
+        //The main with three times the games counts three times as much
+        let combined = combine_ratings(&[
+            (Rating::new(1600.0, 50.0), 30),
+            (Rating::new(1400.0, 100.0), 10),
+        ])
+        .unwrap();
+        assert!((combined.value - 1550.0).abs() < 1e-9);
+        let deviation = (30.0_f64.powi(2) * 2500.0 + 10.0_f64.powi(2) * 10000.0).sqrt() / 40.0;
+        assert!((combined.deviation - deviation).abs() < 1e-9);
+    }
+
+    #[test]
+    fn opponents() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:00:00", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:10:00", (2, 3), (1, 0), 1);
+        add_test_game(&mut conn, "2023-01-30 01:20:00", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:30:00", (3, 5), (1, 0), 2);
+        update_ratings(&mut conn, None, &Config::default());
+
+        conn.execute("INSERT INTO hidden_status VALUES(3, 'hidden', '')", [])
+            .unwrap();
+
+        let opponents = get_opponent_breakdown(&conn, 1, 10);
+        let records = opponents
+            .iter()
+            .map(|o| (o.id, o.game_count, o.wins, o.losses))
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![(Some(2), 3, 2, 1), (None, 1, 1, 0)]);
+        assert_eq!(opponents[1].name, "Hidden");
+        assert!(opponents[0].average_rating_difference.is_some());
+
+        let top = get_top_opponents(&conn, 1, 10);
+        let records = top
+            .iter()
+            .map(|o| (o.id, o.game_count, o.wins, o.losses))
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![(2, 3, 2, 1)]);
+    }
+
+    #[test]
+    fn game_activity() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-29 23:10:00", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:10:00", (1, 0), (2, 3), 2);
+        add_test_game(&mut conn, "2023-01-30 01:40:00", (1, 0), (2, 3), 1);
+
+        let tx = conn.transaction().unwrap();
+        update_game_activity(&tx, 0).unwrap();
+        tx.commit().unwrap();
+
+        let slots = conn
+            .prepare(
+                "SELECT floor, day_of_week, hour, game_count FROM game_activity
+                WHERE game_count > 0 ORDER BY day_of_week",
+            )
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect::<Vec<(i64, i64, i64, i64)>>();
+        //Sunday 23:00 and Monday 01:00 on the test games' floor
+        assert_eq!(slots, vec![(5, 0, 23, 1), (5, 1, 1, 2)]);
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM game_activity", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, FLOORS.len() as i64 * 7 * 24);
+    }
+
+    #[test]
+    fn char_distribution() {
+        let mut conn = test_db();
+        for (id, char_id, value) in [(1, 0, 1510.0), (2, 0, 1620.0), (3, 1, 1520.0)] {
+            conn.execute(
+                "INSERT INTO player_ratings(id, char_id, wins, losses, value, deviation, last_decay)
+                VALUES(?, ?, 0, 0, ?, 50.0, 0)",
+                params![id, char_id, value],
+            )
+            .unwrap();
+        }
+        update_player_distribution(&mut conn, &Config::default());
+
+        let buckets = |char_id: i64| -> Vec<(i64, i64, i64)> {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT min_rating, player_count, player_count_cum
+                    FROM char_rating_distribution WHERE char_id = ? ORDER BY min_rating",
+                )
+                .unwrap();
+            stmt.query_map(params![char_id], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        };
+
+        assert_eq!(buckets(0), vec![(1500, 1, 1), (1600, 1, 2)]);
+        assert_eq!(buckets(1), vec![(1500, 1, 1)]);
+        assert!(buckets(2).is_empty());
+    }
+
+    #[test]
+    fn overall_ratings() {
+        let mut conn = test_db();
+        for (char_id, games, value, deviation) in [
+            (0, 30, 1600.0, 30.0),
+            (1, 10, 1400.0, 60.0),
+            (2, 5, 2000.0, 300.0),
+        ] {
+            conn.execute(
+                "INSERT INTO player_ratings(id, char_id, wins, losses, value, deviation, last_decay)
+                VALUES(1, ?, ?, 0, ?, ?, 0)",
+                params![char_id, games, value, deviation],
+            )
+            .unwrap();
+        }
+        update_overall_ratings(&mut conn, &Config::default()).unwrap();
+
+        let (value, deviation, char_count, best_char_id): (f64, f64, i64, i64) = conn
+            .query_row(
+                "SELECT value, deviation, char_count, best_char_id FROM player_overall_ratings",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+            )
+            .unwrap();
+        //Weighted by games, the unsettled character doesn't count
+        assert!((value - 1550.0).abs() < 1e-6);
+        let expected = (30.0_f64.powi(2) * 900.0 + 10.0_f64.powi(2) * 3600.0).sqrt() / 40.0;
+        assert!((deviation - expected).abs() < 1e-6);
+        assert_eq!((char_count, best_char_id), (2, 0));
+    }
+
+    #[test]
+    fn watchlist_feed() {
+        let mut conn = test_db();
+        add_test_game(&mut conn, "2023-01-30 01:52:15", (1, 0), (2, 3), 1);
+        add_test_game(&mut conn, "2023-01-30 01:55:15", (3, 1), (2, 3), 2);
+        add_test_game(&mut conn, "2023-01-30 01:58:15", (4, 2), (5, 4), 1);
+        update_ratings(&mut conn, None, &Config::default());
+
+        assert!(watchlists::add_player(&conn, "list", 1));
+        assert!(watchlists::add_player(&conn, "list", 2));
+        assert!(!watchlists::add_player(&conn, "list", 99));
+
+        //Games between two watched players show up once for each side
+        let feed = get_watchlist_feed(&conn, "list", 10);
+        let mut players = feed.iter().map(|g| g.player_id).collect::<Vec<_>>();
+        assert_eq!(players.len(), 3);
+        assert_eq!(players[0], 2);
+        assert_eq!(feed[1].game.timestamp, feed[2].game.timestamp);
+        players[1..].sort();
+        assert_eq!(players[1..], [1, 2]);
+        assert!(feed.iter().all(|g| g.game.rating_change.is_some()));
+
+        watchlists::remove_player(&conn, "list", 2);
+        assert_eq!(get_watchlist_feed(&conn, "list", 10).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn supervise_blocking_panic() {
+        let restarts = || {
+            metrics::TASK_RESTARTS
+                .with_label_values(&["blocking_panic"])
+                .get()
+        };
+        let before = restarts();
+        //Shut down already, so it doesn't wait out the backoff before restarting
+        let (_shutdown_tx, shutdown) = watch::channel(true);
+        let result = supervise("blocking_panic", shutdown, || async {
+            run_blocking::<(), _>(|| panic!("panic in a blocking section")).await
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(restarts(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn pull_from_mock_source() {
+        let path = std::env::temp_dir().join(format!("pull_test_{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config {
+            db_path: path.to_string_lossy().into_owned(),
+            replays_per_page: 2,
+            max_pages: 3,
+            ..Config::default()
+        };
+        init_database(&config).unwrap();
+
+        let replay = |timestamp: &str, floor: i64| {
+            responses::Replay::new(
+                timestamp,
+                floor,
+                responses::Player::new(1, "a"),
+                0,
+                responses::Player::new(2, "b"),
+                3,
+                1,
+            )
+        };
+        let replays = [
+            replay("2023-01-30 01:52:15", 10),
+            replay("2023-01-30 01:55:15", 99),
+            replay("2023-01-30 01:58:15", 10),
+            replay("2023-01-30 02:01:15", 10),
+            replay("2023-01-30 02:04:15", 10),
+        ];
+        //Two sources that overlap on one game, like a cross platform game seen from both sides
+        let sources = || -> Vec<(String, Box<dyn ReplaySource>)> {
+            vec![
+                (
+                    "pc".to_owned(),
+                    Box::new(MockSource {
+                        replays: replays[..3].to_vec(),
+                    }),
+                ),
+                (
+                    "ps".to_owned(),
+                    Box::new(MockSource {
+                        replays: replays[2..].to_vec(),
+                    }),
+                ),
+            ]
+        };
+        pull_from(sources(), &config).await;
+        //Pulling the same pages again doesn't add anything
+        pull_from(sources(), &config).await;
+
+        let conn = Connection::open(&path).unwrap();
+        let (games, rated): (i64, i64) = conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM games), (SELECT COUNT(*) FROM game_ratings)",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((games, rated), (5, 5));
+        let from_pc: i64 = conn
+            .query_row("SELECT COUNT(*) FROM games WHERE source = 'pc'", [], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert!((2..=3).contains(&from_pc));
+        //Set by the rater's connections and kept in the file
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use crate::glicko::{Rating, INITIAL_DEVIATION, MIN_DEVIATION};
+use rocket::serde::Deserialize;
+use std::str::FromStr;
+
+pub trait RatingSystem {
+    //Stored in the config table so ratings from different systems don't get mixed
+    fn name(&self) -> &'static str;
+    //Probability that rating wins against opponent
+    fn expected(&self, rating: Rating, opponent: Rating) -> f64;
+    #[must_use]
+    fn update(&self, rating: Rating, opponent: Rating, result: f64) -> Rating;
+}
+
+pub struct Glicko;
+
+impl RatingSystem for Glicko {
+    fn name(&self) -> &'static str {
+        "glicko"
+    }
+
+    fn expected(&self, rating: Rating, opponent: Rating) -> f64 {
+        rating.expected(opponent)
+    }
+
+    fn update(&self, rating: Rating, opponent: Rating, result: f64) -> Rating {
+        rating.update(opponent, result)
+    }
+}
+
+//Fixed step Elo on the value. Elo has no deviation of its own, so it shrinks by a fixed share
+//every game, whoever the opponent was. That way the established cutoffs used for rankings and
+//distributions still mean a number of games played.
+pub struct Elo {
+    pub k: f64,
+}
+
+const ELO_DEVIATION_FACTOR: f64 = 0.95;
+
+impl RatingSystem for Elo {
+    fn name(&self) -> &'static str {
+        "elo"
+    }
+
+    fn expected(&self, rating: Rating, opponent: Rating) -> f64 {
+        1.0 / (1.0 + 10.0f64.powf((opponent.value - rating.value) / 400.0))
+    }
+
+    fn update(&self, rating: Rating, opponent: Rating, result: f64) -> Rating {
+        Rating {
+            value: rating.value + self.k * (result - self.expected(rating, opponent)),
+            deviation: (rating.deviation * ELO_DEVIATION_FACTOR).max(MIN_DEVIATION),
+        }
+    }
+}
+
+//Bradley-Terry with the Bayesian approximation from Weng and Lin (2011): both deviations
+//widen the logistic curve, and the deviation shrinks by how much the game told us.
+pub struct BradleyTerry;
+
+//Spread of performances within a single game
+const BT_BETA: f64 = INITIAL_DEVIATION / 2.0;
+//Smallest share of the variance a game can leave
+const BT_KAPPA: f64 = 0.0001;
+
+impl BradleyTerry {
+    fn spread(rating: Rating, opponent: Rating) -> f64 {
+        (rating.deviation.powi(2) + opponent.deviation.powi(2) + 2.0 * BT_BETA.powi(2)).sqrt()
+    }
+}
+
+impl RatingSystem for BradleyTerry {
+    fn name(&self) -> &'static str {
+        "bradley_terry"
+    }
+
+    fn expected(&self, rating: Rating, opponent: Rating) -> f64 {
+        let c = Self::spread(rating, opponent);
+        1.0 / (1.0 + ((opponent.value - rating.value) / c).exp())
+    }
+
+    fn update(&self, rating: Rating, opponent: Rating, result: f64) -> Rating {
+        let c = Self::spread(rating, opponent);
+        let p = self.expected(rating, opponent);
+        let variance = rating.deviation.powi(2);
+        let eta = (rating.deviation / c) * variance / c.powi(2) * p * (1.0 - p);
+        Rating {
+            value: rating.value + variance / c * (result - p),
+            deviation: (variance * (1.0 - eta).max(BT_KAPPA))
+                .sqrt()
+                .max(MIN_DEVIATION),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RatingSystemKind {
+    Glicko,
+    Elo,
+    #[serde(rename = "bradley_terry")]
+    BradleyTerry,
+}
+
+impl RatingSystemKind {
+    pub fn system(self) -> Box<dyn RatingSystem> {
+        match self {
+            RatingSystemKind::Glicko => Box::new(Glicko),
+            RatingSystemKind::Elo => Box::new(Elo { k: 32.0 }),
+            RatingSystemKind::BradleyTerry => Box::new(BradleyTerry),
+        }
+    }
+}
+
+impl FromStr for RatingSystemKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "glicko" => Ok(RatingSystemKind::Glicko),
+            "elo" => Ok(RatingSystemKind::Elo),
+            "bradley_terry" => Ok(RatingSystemKind::BradleyTerry),
+            _ => Err(format!("Unknown rating system: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn winner_goes_up() {
+        for kind in [
+            RatingSystemKind::Glicko,
+            RatingSystemKind::Elo,
+            RatingSystemKind::BradleyTerry,
+        ] {
+            let system = kind.system();
+            let (a, b) = (Rating::new(1500.0, 200.0), Rating::new(1600.0, 100.0));
+            assert!(system.expected(a, b) < 0.5);
+
+            let won = system.update(a, b, 1.0);
+            let lost = system.update(b, a, 0.0);
+            assert!(won.value > a.value, "{}", system.name());
+            assert!(lost.value < b.value, "{}", system.name());
+            assert!(won.deviation < a.deviation, "{}", system.name());
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use rocket::serde::{json::serde_json, Deserialize};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{ggst_api, requests::ALL_PLATFORMS, responses::Replay};
+
+//Where pulls get their replays from. Pages are numbered from 0 and newest first, the same
+//way the game's replay API hands them out.
+#[rocket::async_trait]
+pub trait ReplaySource: Send + Sync {
+    async fn get_replay_page(
+        &self,
+        index: usize,
+        replays_per_page: usize,
+        min_floor: i64,
+        max_floor: i64,
+    ) -> Result<Vec<Replay>, String>;
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ReplaySourceKind {
+    //The platform filter uses the same codes as players' platforms, 1 for PS and 3 for PC
+    Ggst {
+        #[serde(default = "all_platforms")]
+        platforms: i64,
+    },
+    Folder {
+        path: String,
+    },
+    Json {
+        url: String,
+    },
+}
+
+fn all_platforms() -> i64 {
+    ALL_PLATFORMS
+}
+
+//One entry of the `sources` list in the config. Every game remembers the name of the source
+//it was first pulled from.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct NamedSource {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ReplaySourceKind,
+}
+
+impl ReplaySourceKind {
+    pub fn source(&self) -> Box<dyn ReplaySource> {
+        match self {
+            ReplaySourceKind::Ggst { platforms } => Box::new(GgstApi {
+                platforms: *platforms,
+            }),
+            ReplaySourceKind::Folder { path } => Box::new(ReplayFolder {
+                path: PathBuf::from(path),
+            }),
+            ReplaySourceKind::Json { url } => Box::new(JsonFeed {
+                url: url.clone(),
+                client: reqwest::Client::new(),
+            }),
+        }
+    }
+}
+
+//`ggst`, `ggst:<platforms>`, `folder:<path>` or `json:<url>`, for setting the source from
+//the environment
+impl FromStr for ReplaySourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "ggst" => Ok(ReplaySourceKind::Ggst {
+                platforms: ALL_PLATFORMS,
+            }),
+            Some(("ggst", platforms)) => Ok(ReplaySourceKind::Ggst {
+                platforms: platforms
+                    .parse()
+                    .map_err(|_| format!("Unknown platform filter: {}", platforms))?,
+            }),
+            Some(("folder", path)) => Ok(ReplaySourceKind::Folder {
+                path: path.to_owned(),
+            }),
+            Some(("json", url)) => Ok(ReplaySourceKind::Json {
+                url: url.to_owned(),
+            }),
+            _ => Err(format!("Unknown replay source: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReplaySourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplaySourceKind::Ggst { platforms } if *platforms == ALL_PLATFORMS => {
+                write!(f, "the GGST replay API")
+            }
+            ReplaySourceKind::Ggst { platforms } => {
+                write!(f, "the GGST replay API for platforms {}", platforms)
+            }
+            ReplaySourceKind::Folder { path } => write!(f, "replay folder {}", path),
+            ReplaySourceKind::Json { url } => write!(f, "JSON feed {}", url),
+        }
+    }
+}
+
+pub struct GgstApi {
+    pub platforms: i64,
+}
+
+#[rocket::async_trait]
+impl ReplaySource for GgstApi {
+    async fn get_replay_page(
+        &self,
+        index: usize,
+        replays_per_page: usize,
+        min_floor: i64,
+        max_floor: i64,
+    ) -> Result<Vec<Replay>, String> {
+        ggst_api::get_replay_page(
+            index,
+            replays_per_page,
+            min_floor,
+            max_floor,
+            self.platforms,
+        )
+        .await
+    }
+}
+
+//A folder of .json files, each an array of replays in the shape `import` takes. The folder is
+//read again for every page so files can be dropped in while the puller runs.
+pub struct ReplayFolder {
+    pub path: PathBuf,
+}
+
+impl ReplayFolder {
+    fn read_replays(path: &Path) -> Result<Vec<Replay>, String> {
+        let mut files = fs::read_dir(path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let mut replays = Vec::new();
+        for file in files {
+            let contents =
+                fs::read(&file).map_err(|e| format!("Couldn't read {}: {}", file.display(), e))?;
+            let file_replays: Vec<Replay> = serde_json::from_slice(&contents)
+                .map_err(|e| format!("Couldn't parse {}: {}", file.display(), e))?;
+            replays.extend(file_replays);
+        }
+        Ok(replays)
+    }
+}
+
+#[rocket::async_trait]
+impl ReplaySource for ReplayFolder {
+    async fn get_replay_page(
+        &self,
+        index: usize,
+        replays_per_page: usize,
+        min_floor: i64,
+        max_floor: i64,
+    ) -> Result<Vec<Replay>, String> {
+        let path = self.path.clone();
+        let replays = tokio::task::spawn_blocking(move || Self::read_replays(&path))
+            .await
+            .map_err(|e| format!("Reading replays panicked: {}", e))??;
+        Ok(page_of(
+            replays,
+            index,
+            replays_per_page,
+            min_floor,
+            max_floor,
+        ))
+    }
+}
+
+//Replays as JSON over HTTP, e.g. from a mirror. The page and floor range are passed along
+//as query parameters and the response is a plain array of replays.
+pub struct JsonFeed {
+    pub url: String,
+    client: reqwest::Client,
+}
+
+#[rocket::async_trait]
+impl ReplaySource for JsonFeed {
+    async fn get_replay_page(
+        &self,
+        index: usize,
+        replays_per_page: usize,
+        min_floor: i64,
+        max_floor: i64,
+    ) -> Result<Vec<Replay>, String> {
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&[
+                ("page", index as i64),
+                ("replays_per_page", replays_per_page as i64),
+                ("min_floor", min_floor),
+                ("max_floor", max_floor),
+            ])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Request failed: {}", e))?;
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| format!("Couldn't read response: {}", e))?;
+        serde_json::from_slice(&bytes).map_err(|e| format!("Couldn't parse replays: {}", e))
+    }
+}
+
+//Hands out the same fixed replays every time
+#[cfg(test)]
+pub struct MockSource {
+    pub replays: Vec<Replay>,
+}
+
+#[cfg(test)]
+#[rocket::async_trait]
+impl ReplaySource for MockSource {
+    async fn get_replay_page(
+        &self,
+        index: usize,
+        replays_per_page: usize,
+        min_floor: i64,
+        max_floor: i64,
+    ) -> Result<Vec<Replay>, String> {
+        Ok(page_of(
+            self.replays.clone(),
+            index,
+            replays_per_page,
+            min_floor,
+            max_floor,
+        ))
+    }
+}
+
+//Pages through replays the way the replay API does, newest first within the floor range
+fn page_of(
+    mut replays: Vec<Replay>,
+    index: usize,
+    replays_per_page: usize,
+    min_floor: i64,
+    max_floor: i64,
+) -> Vec<Replay> {
+    replays.retain(|r| (min_floor..=max_floor).contains(&r.floor));
+    //Timestamps are zero padded, so they sort the same as strings
+    replays.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    replays
+        .into_iter()
+        .skip(index * replays_per_page)
+        .take(replays_per_page)
+        .collect()
+}
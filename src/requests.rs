@@ -1,6 +1,8 @@
 use serde_derive::{Deserialize, Serialize};
 
 const VERSION: &str = "0.1.7";
+//Replay query platform filter that lets every platform through
+pub const ALL_PLATFORMS: i64 = 6;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request<T> {
@@ -45,6 +47,9 @@ struct ReplayQuery {
 pub fn generate_replay_request(
     index: usize,
     replays_per_page: usize,
+    min_floor: i64,
+    max_floor: i64,
+    platforms: i64,
     token: &str,
 ) -> Request<ReplayRequest> {
     Request {
@@ -62,8 +67,8 @@ pub fn generate_replay_request(
             query: ReplayQuery {
                 int1: -1,
                 player_search: 0,
-                min_floor: 1,
-                max_floor: 99,
+                min_floor,
+                max_floor,
                 seq1: vec![],
                 char_1: -1,
                 char_2: -1,
@@ -71,7 +76,7 @@ pub fn generate_replay_request(
                 prioritize_best_bout: 0,
                 int2: 1,
             },
-            platforms: 6, //All
+            platforms,
         },
     }
 }
@@ -52,3 +52,46 @@ pub struct Player {
     pub platform: i64,
     _int1: i64,
 }
+
+#[cfg(test)]
+impl Replay {
+    pub fn new(
+        timestamp: &str,
+        floor: i64,
+        player1: Player,
+        player1_character: i64,
+        player2: Player,
+        player2_character: i64,
+        winner: i64,
+    ) -> Self {
+        Self {
+            _int1: 0,
+            _int2: 0,
+            floor,
+            player1_character,
+            player2_character,
+            player1,
+            player2,
+            winner,
+            timestamp: timestamp.to_owned(),
+            _int7: 0,
+            _views: 0,
+            _int8: 0,
+            _likes: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Player {
+    pub fn new(id: i64, name: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_owned(),
+            _string1: String::new(),
+            _string2: String::new(),
+            platform: 3,
+            _int1: 0,
+        }
+    }
+}
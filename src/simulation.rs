@@ -0,0 +1,282 @@
+use anyhow::Result;
+use rocket::serde::Serialize;
+use rusqlite::{params, Connection};
+use std::f64::consts::PI;
+
+use crate::{characters, db, glicko::Rating, rater};
+
+//Monday 2023-01-02, so every run has the same timestamps
+const START_TIMESTAMP: i64 = 1672617600;
+//Predictions from the first part of a run mostly measure how fast new players settle
+const BURN_IN: f64 = 0.5;
+
+//A synthetic game history. Every player gets a fixed true skill, games are played between
+//players close in skill and the winner is drawn from the elo win chance of the true skills.
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    pub players: usize,
+    //Games to generate, a few less end up played since pairings past either end are dropped
+    pub games: usize,
+    //Rating periods the games are spread over
+    pub periods: i64,
+    //Standard deviation of the true skills around 1500
+    pub skill_spread: f64,
+    //How far apart in the skill order opponents can be, as a share of all players. Narrow
+    //windows are closer to floor matchmaking and take the ratings much longer to spread out.
+    pub match_window: f64,
+    pub seed: u64,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self {
+            players: 200,
+            games: 20_000,
+            periods: 100,
+            skill_spread: 300.0,
+            match_window: 0.5,
+            seed: 1,
+        }
+    }
+}
+
+//SplitMix64, plenty for picking games and small enough that runs never change under us
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    //Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    //Box-Muller
+    fn normal(&mut self) -> f64 {
+        let u = 1.0 - self.next_f64();
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+    }
+}
+
+impl Simulation {
+    //Rates the generated games in a fresh in-memory database with the given config, which is
+    //where the rating system, decay constant and rating floor come from
+    pub fn run(&self, config: &rater::Config) -> Result<SimulationReport> {
+        let mut conn = Connection::open_in_memory()?;
+        db::migrate(&conn)?;
+
+        let mut rng = Rng(self.seed);
+        //Sorted, so players near each other in the list are near each other in skill
+        let mut skills = (0..self.players)
+            .map(|_| 1500.0 + self.skill_spread * rng.normal())
+            .collect::<Vec<_>>();
+        skills.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let window = ((self.players as f64 * self.match_window) as usize).max(1);
+
+        let span = self.periods * config.rating_period;
+        {
+            let tx = conn.transaction()?;
+            let mut stmt = tx.prepare(
+                "INSERT INTO games VALUES(?, ?, ?, ?, 3, ?, ?, ?, 3, ?, 10, 'simulation')",
+            )?;
+            for i in 0..self.games {
+                let a = rng.below(self.players);
+                let offset = 1 + rng.below(window);
+                let b = if rng.below(2) == 0 {
+                    a.checked_sub(offset)
+                } else {
+                    Some(a + offset).filter(|&b| b < self.players)
+                };
+                //Skipped rather than bunched up at the ends, which would skew the extremes
+                let b = match b {
+                    Some(b) => b,
+                    None => continue,
+                };
+
+                let win_chance = 1.0 / (1.0 + 10f64.powf((skills[b] - skills[a]) / 400.0));
+                let winner = if rng.next_f64() < win_chance { 1 } else { 2 };
+                let timestamp = START_TIMESTAMP + i as i64 * span / self.games as i64;
+                stmt.execute(params![
+                    timestamp,
+                    player_id(a),
+                    format!("sim{}", a),
+                    char_id(a),
+                    player_id(b),
+                    format!("sim{}", b),
+                    char_id(b),
+                    winner,
+                ])?;
+            }
+            drop(stmt);
+            tx.commit()?;
+        }
+
+        while rater::update_ratings(&mut conn, None, config) > 0 {}
+
+        self.report(&conn, &skills, config)
+    }
+
+    fn report(
+        &self,
+        conn: &Connection,
+        skills: &[f64],
+        config: &rater::Config,
+    ) -> Result<SimulationReport> {
+        let mut ratings = vec![None; self.players];
+        {
+            let mut stmt = conn.prepare("SELECT id, value, deviation FROM player_ratings")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let index = (row.get::<_, i64>(0)? - 1) as usize;
+                ratings[index] = Some(Rating::new(row.get(1)?, row.get(2)?));
+            }
+        }
+        let rated = ratings
+            .iter()
+            .zip(skills)
+            .filter_map(|(r, s)| r.map(|r| (r, *s)))
+            .collect::<Vec<_>>();
+        let n = rated.len() as f64;
+
+        //Ratings only mean something relative to each other, so compare them around their mean
+        let rating_mean = rated.iter().map(|(r, _)| r.value).sum::<f64>() / n;
+        let skill_mean = rated.iter().map(|(_, s)| s).sum::<f64>() / n;
+        let rmse = (rated
+            .iter()
+            .map(|(r, s)| ((r.value - rating_mean) - (s - skill_mean)).powi(2))
+            .sum::<f64>()
+            / n)
+            .sqrt();
+        let coverage = rated
+            .iter()
+            .filter(|(r, s)| {
+                ((r.value - rating_mean) - (s - skill_mean)).abs() <= 2.0 * r.deviation
+            })
+            .count() as f64
+            / n;
+        let rank_correlation = spearman(
+            &rated.iter().map(|(r, _)| r.value).collect::<Vec<_>>(),
+            &rated.iter().map(|(_, s)| *s).collect::<Vec<_>>(),
+        );
+
+        //How well the ratings before each game predicted it, past the burn-in
+        let system = config.rating_system.system();
+        let mut stmt = conn.prepare(
+            "SELECT value_a, deviation_a, value_b, deviation_b, winner
+            FROM game_ratings ORDER BY timestamp ASC",
+        )?;
+        let predictions = stmt
+            .query_map([], |r| {
+                let expected = system.expected(
+                    Rating::new(r.get(0)?, r.get(1)?),
+                    Rating::new(r.get(2)?, r.get(3)?),
+                );
+                let outcome = if r.get::<_, i64>(4)? == 1 { 1.0 } else { 0.0 };
+                Ok((expected, outcome))
+            })?
+            .collect::<rusqlite::Result<Vec<(f64, f64)>>>()?;
+        let scored = &predictions[(predictions.len() as f64 * BURN_IN) as usize..];
+        let m = scored.len().max(1) as f64;
+        let brier = scored.iter().map(|(e, o)| (e - o).powi(2)).sum::<f64>() / m;
+        let log_loss = scored
+            .iter()
+            .map(|(e, o)| {
+                let e = e.clamp(1e-9, 1.0 - 1e-9);
+                -(o * e.ln() + (1.0 - o) * (1.0 - e).ln())
+            })
+            .sum::<f64>()
+            / m;
+
+        Ok(SimulationReport {
+            games: predictions.len(),
+            rank_correlation,
+            rmse,
+            coverage,
+            brier,
+            log_loss,
+        })
+    }
+}
+
+fn player_id(index: usize) -> i64 {
+    index as i64 + 1
+}
+
+fn char_id(index: usize) -> i64 {
+    (index % characters::all().len()) as i64
+}
+
+//Rank correlation, ties are rare enough with float ratings to ignore
+fn spearman(a: &[f64], b: &[f64]) -> f64 {
+    let ranks = |values: &[f64]| {
+        let mut order = (0..values.len()).collect::<Vec<_>>();
+        order.sort_by(|&i, &j| values[i].partial_cmp(&values[j]).unwrap());
+        let mut ranks = vec![0.0; values.len()];
+        for (rank, i) in order.into_iter().enumerate() {
+            ranks[i] = rank as f64;
+        }
+        ranks
+    };
+    let (ra, rb) = (ranks(a), ranks(b));
+    let n = a.len() as f64;
+    let d2 = ra
+        .iter()
+        .zip(&rb)
+        .map(|(x, y)| (x - y).powi(2))
+        .sum::<f64>();
+    1.0 - 6.0 * d2 / (n * (n * n - 1.0))
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SimulationReport {
+    pub games: usize,
+    //Between the final ratings and the true skills, 1 is a perfect ordering
+    pub rank_correlation: f64,
+    //Rating error in rating points, both sides centered on their mean
+    pub rmse: f64,
+    //Share of players whose true skill is within two deviations of their rating
+    pub coverage: f64,
+    //Prediction error of the pre-game ratings, 0.25 is a coin flip for brier
+    pub brier: f64,
+    pub log_loss: f64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn small() -> Simulation {
+        Simulation {
+            players: 60,
+            games: 6_000,
+            periods: 30,
+            ..Simulation::default()
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let config = rater::Config::default();
+        assert_eq!(small().run(&config).unwrap(), small().run(&config).unwrap());
+    }
+
+    #[test]
+    fn converges() {
+        let report = small().run(&rater::Config::default()).unwrap();
+        assert!(report.rank_correlation > 0.9, "{:?}", report);
+        assert!(report.rmse < 150.0, "{:?}", report);
+        assert!(report.coverage > 0.8, "{:?}", report);
+        assert!(report.brier < 0.25, "{:?}", report);
+    }
+}
@@ -0,0 +1,568 @@
+use anyhow::Context;
+use fxhash::FxHashMap;
+use rocket::serde::Deserialize;
+use rusqlite::{params, OptionalExtension, Transaction};
+use std::{fmt, str::FromStr};
+
+use crate::{
+    glicko::Rating,
+    rater::{Game, RatedPlayer, TopDefeated, TopRating},
+};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+//Where the rater keeps the games it rates and the ratings it works out from them. Matchups,
+//rankings, statistics, moderation and the website always use the SQLite database.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum StorageKind {
+    Sqlite,
+    Postgres { url: String },
+}
+
+//`sqlite` or a `postgres://` connection url, for picking the storage from the environment
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "sqlite" {
+            Ok(StorageKind::Sqlite)
+        } else if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+            Ok(StorageKind::Postgres { url: s.to_owned() })
+        } else {
+            Err(format!("Unknown storage: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageKind::Sqlite => write!(f, "SQLite"),
+            //The url can carry a password
+            StorageKind::Postgres { .. } => write!(f, "Postgres"),
+        }
+    }
+}
+
+//Everything rating a batch of games reads and writes: the games, the players in them, each
+//game's ratings and every character's current rating
+pub trait Storage {
+    //Name of the rating system the stored ratings were made with, None before the first update
+    fn rating_system(&mut self) -> Result<Option<String>>;
+    fn set_rating_system(&mut self, name: &str) -> Result<()>;
+
+    //False if the game was stored already
+    fn add_game(&mut self, game: &Game, source: &str) -> Result<bool>;
+    //The oldest games that haven't been rated, at most limit of them, and how many there are
+    fn unrated_games(&mut self, limit: i64) -> Result<(Vec<Game>, i64)>;
+
+    fn player_rating(&mut self, id: i64, char_id: i64) -> Result<Option<RatedPlayer>>;
+    fn update_player(&mut self, id: i64, name: &str, floor: i64, platform: i64) -> Result<()>;
+    fn add_game_rating(
+        &mut self,
+        game: &Game,
+        before: (Rating, Rating),
+        after: (Rating, Rating),
+        valid: bool,
+    ) -> Result<()>;
+    fn save_player_rating(&mut self, player: &RatedPlayer) -> Result<()>;
+}
+
+//Works inside the caller's transaction, which also gets the SQLite only tables written to it
+pub struct SqliteStorage<'a> {
+    tx: &'a Transaction<'a>,
+}
+
+impl<'a> SqliteStorage<'a> {
+    pub fn new(tx: &'a Transaction<'a>) -> Self {
+        Self { tx }
+    }
+}
+
+impl Storage for SqliteStorage<'_> {
+    fn rating_system(&mut self) -> Result<Option<String>> {
+        Ok(self
+            .tx
+            .query_row("SELECT rating_system FROM config", [], |r| r.get(0))?)
+    }
+
+    fn set_rating_system(&mut self, name: &str) -> Result<()> {
+        self.tx
+            .execute("UPDATE config SET rating_system = ?", params![name])?;
+        Ok(())
+    }
+
+    fn add_game(&mut self, game: &Game, source: &str) -> Result<bool> {
+        let count = self
+            .tx
+            .prepare_cached(
+                "INSERT OR IGNORE INTO games (
+                    timestamp,
+                    id_a,
+                    name_a,
+                    char_a,
+                    platform_a,
+                    id_b,
+                    name_b,
+                    char_b,
+                    platform_b,
+                    winner,
+                    game_floor,
+                    source
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                game.timestamp,
+                game.id_a,
+                game.name_a,
+                game.char_a,
+                game.platform_a,
+                game.id_b,
+                game.name_b,
+                game.char_b,
+                game.platform_b,
+                game.winner,
+                game.game_floor,
+                source,
+            ])?;
+        Ok(count == 1)
+    }
+
+    fn unrated_games(&mut self, limit: i64) -> Result<(Vec<Game>, i64)> {
+        let mut stmt = self.tx.prepare(
+            "SELECT
+                games.timestamp,
+                games.id_a,
+                games.name_a,
+                games.char_a,
+                games.platform_a,
+                games.id_b,
+                games.name_b,
+                games.char_b,
+                games.platform_b,
+                games.winner,
+                games.game_floor
+            FROM
+                games LEFT JOIN game_ratings ON
+                games.id_a == game_ratings.id_a
+                AND games.id_b == game_ratings.id_b
+                AND games.timestamp == game_ratings.timestamp
+            WHERE game_ratings.id_a IS NULL
+            ORDER BY games.timestamp ASC
+            LIMIT ?",
+        )?;
+        let games = stmt
+            .query_map(params![limit], |r| Ok(Game::from_row(r)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let remaining = self.tx.query_row(
+            "SELECT COUNT(*)
+            FROM
+                games LEFT JOIN game_ratings ON
+                games.id_a == game_ratings.id_a
+                AND games.id_b == game_ratings.id_b
+                AND games.timestamp == game_ratings.timestamp
+            WHERE game_ratings.id_a IS NULL",
+            [],
+            |r| r.get(0),
+        )?;
+
+        Ok((games, remaining))
+    }
+
+    fn player_rating(&mut self, id: i64, char_id: i64) -> Result<Option<RatedPlayer>> {
+        Ok(self
+            .tx
+            .prepare_cached(
+                "SELECT
+                    player_ratings.id, player_ratings.char_id, wins, losses, value, deviation, last_decay,
+                    top_rating_value, top_rating_deviation, top_rating_timestamp,
+                    top_defeated_id, top_defeated_char_id, top_defeated_name,
+                    top_defeated_value, top_defeated_deviation, top_defeated_floor,
+                    top_defeated_timestamp, character_rank,
+                    current_streak, longest_streak
+                FROM player_ratings LEFT JOIN ranking_character
+                ON
+                    player_ratings.id = ranking_character.id AND
+                    player_ratings.char_id = ranking_character.char_id
+                WHERE player_ratings.id = ? AND player_ratings.char_id = ?",
+            )?
+            .query_row(params![id, char_id], |r| Ok(RatedPlayer::from_row(r)))
+            .optional()?)
+    }
+
+    fn update_player(&mut self, id: i64, name: &str, floor: i64, platform: i64) -> Result<()> {
+        self.tx
+            .prepare_cached("REPLACE INTO players(id, name, floor, platform) VALUES(?, ?, ?, ?)")?
+            .execute(params![id, name, floor, platform])?;
+        self.tx
+            .prepare_cached("INSERT OR IGNORE INTO player_names(id, name) VALUES(?, ?)")?
+            .execute(params![id, name])?;
+        Ok(())
+    }
+
+    fn add_game_rating(
+        &mut self,
+        game: &Game,
+        before: (Rating, Rating),
+        after: (Rating, Rating),
+        valid: bool,
+    ) -> Result<()> {
+        self.tx
+            .prepare_cached(
+                "INSERT INTO game_ratings VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                game.timestamp,
+                game.id_a,
+                before.0.value,
+                before.0.deviation,
+                game.id_b,
+                before.1.value,
+                before.1.deviation,
+                game.winner,
+                valid,
+                after.0.value,
+                after.0.deviation,
+                after.1.value,
+                after.1.deviation,
+            ])?;
+        Ok(())
+    }
+
+    fn save_player_rating(&mut self, player: &RatedPlayer) -> Result<()> {
+        self.tx
+            .prepare_cached(
+                "REPLACE INTO player_ratings VALUES(
+                    ?, ?, ?, ?, ?, ?, ?,
+                    ?, ?, ?,
+                    ?, ?, ?, ?, ?, ?, ?,
+                    ?, ?)",
+            )?
+            .execute(params![
+                player.id,
+                player.char_id,
+                player.win_count,
+                player.loss_count,
+                player.rating.value,
+                player.rating.deviation,
+                player.last_decay,
+                //
+                player.top_rating.as_ref().map(|r| r.value),
+                player.top_rating.as_ref().map(|r| r.deviation),
+                player.top_rating.as_ref().map(|r| r.timestamp),
+                //
+                player.top_defeated.as_ref().map(|t| t.id),
+                player.top_defeated.as_ref().map(|t| t.char_id),
+                player.top_defeated.as_ref().map(|t| t.name.clone()),
+                player.top_defeated.as_ref().map(|t| t.value),
+                player.top_defeated.as_ref().map(|t| t.deviation),
+                player.top_defeated.as_ref().map(|t| t.floor),
+                player.top_defeated.as_ref().map(|t| t.timestamp),
+                //
+                player.current_streak,
+                player.longest_streak,
+            ])?;
+        Ok(())
+    }
+}
+
+//Creates whatever tables are missing. The client is synchronous, so it has to be used from a
+//blocking thread rather than inside the async runtime.
+pub fn connect_postgres(url: &str) -> Result<postgres::Client> {
+    let mut client =
+        postgres::Client::connect(url, postgres::NoTls).context("Couldn't connect to Postgres")?;
+    client.batch_execute(include_str!("../migrations/postgres.sql"))?;
+    Ok(client)
+}
+
+//Everything goes through one transaction, nothing is stored until commit
+pub struct PostgresStorage<'a> {
+    tx: postgres::Transaction<'a>,
+    statements: FxHashMap<&'static str, postgres::Statement>,
+}
+
+impl<'a> PostgresStorage<'a> {
+    pub fn new(client: &'a mut postgres::Client) -> Result<Self> {
+        Ok(Self {
+            tx: client.transaction()?,
+            statements: FxHashMap::default(),
+        })
+    }
+
+    pub fn commit(self) -> Result<()> {
+        self.tx.commit()?;
+        Ok(())
+    }
+
+    //Statements are prepared once per transaction, the same as prepare_cached on SQLite
+    fn statement(&mut self, query: &'static str) -> Result<postgres::Statement> {
+        if let Some(statement) = self.statements.get(query) {
+            return Ok(statement.clone());
+        }
+        let statement = self.tx.prepare(query)?;
+        self.statements.insert(query, statement.clone());
+        Ok(statement)
+    }
+}
+
+impl Storage for PostgresStorage<'_> {
+    fn rating_system(&mut self) -> Result<Option<String>> {
+        Ok(self
+            .tx
+            .query_one("SELECT rating_system FROM config", &[])?
+            .get(0))
+    }
+
+    fn set_rating_system(&mut self, name: &str) -> Result<()> {
+        self.tx
+            .execute("UPDATE config SET rating_system = $1", &[&name])?;
+        Ok(())
+    }
+
+    fn add_game(&mut self, game: &Game, source: &str) -> Result<bool> {
+        let statement = self.statement(
+            "INSERT INTO games (
+                timestamp,
+                id_a,
+                name_a,
+                char_a,
+                platform_a,
+                id_b,
+                name_b,
+                char_b,
+                platform_b,
+                winner,
+                game_floor,
+                source
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            ON CONFLICT DO NOTHING",
+        )?;
+        let count = self.tx.execute(
+            &statement,
+            &[
+                &game.timestamp,
+                &game.id_a,
+                &game.name_a,
+                &game.char_a,
+                &game.platform_a,
+                &game.id_b,
+                &game.name_b,
+                &game.char_b,
+                &game.platform_b,
+                &game.winner,
+                &game.game_floor,
+                &source,
+            ],
+        )?;
+        Ok(count == 1)
+    }
+
+    fn unrated_games(&mut self, limit: i64) -> Result<(Vec<Game>, i64)> {
+        let games = self
+            .tx
+            .query(
+                "SELECT
+                    games.timestamp,
+                    games.id_a,
+                    games.name_a,
+                    games.char_a,
+                    games.platform_a,
+                    games.id_b,
+                    games.name_b,
+                    games.char_b,
+                    games.platform_b,
+                    games.winner,
+                    games.game_floor
+                FROM
+                    games LEFT JOIN game_ratings ON
+                    games.id_a = game_ratings.id_a
+                    AND games.id_b = game_ratings.id_b
+                    AND games.timestamp = game_ratings.timestamp
+                WHERE game_ratings.id_a IS NULL
+                ORDER BY games.timestamp ASC
+                LIMIT $1",
+                &[&limit],
+            )?
+            .into_iter()
+            .map(|r| Game {
+                timestamp: r.get(0),
+                id_a: r.get(1),
+                name_a: r.get(2),
+                char_a: r.get(3),
+                platform_a: r.get(4),
+                id_b: r.get(5),
+                name_b: r.get(6),
+                char_b: r.get(7),
+                platform_b: r.get(8),
+                winner: r.get(9),
+                game_floor: r.get(10),
+            })
+            .collect();
+
+        let remaining = self
+            .tx
+            .query_one(
+                "SELECT COUNT(*)
+                FROM
+                    games LEFT JOIN game_ratings ON
+                    games.id_a = game_ratings.id_a
+                    AND games.id_b = game_ratings.id_b
+                    AND games.timestamp = game_ratings.timestamp
+                WHERE game_ratings.id_a IS NULL",
+                &[],
+            )?
+            .get(0);
+
+        Ok((games, remaining))
+    }
+
+    fn player_rating(&mut self, id: i64, char_id: i64) -> Result<Option<RatedPlayer>> {
+        let statement = self.statement(
+            "SELECT
+                id, char_id, wins, losses, value, deviation, last_decay,
+                top_rating_value, top_rating_deviation, top_rating_timestamp,
+                top_defeated_id, top_defeated_char_id, top_defeated_name,
+                top_defeated_value, top_defeated_deviation, top_defeated_floor,
+                top_defeated_timestamp,
+                current_streak, longest_streak
+            FROM player_ratings
+            WHERE id = $1 AND char_id = $2",
+        )?;
+        Ok(self
+            .tx
+            .query_opt(&statement, &[&id, &char_id])?
+            .map(|r| RatedPlayer {
+                id: r.get(0),
+                char_id: r.get(1),
+                win_count: r.get(2),
+                loss_count: r.get(3),
+                rating: Rating::new(r.get(4), r.get(5)),
+                last_decay: r.get(6),
+
+                top_rating: r.get::<_, Option<f64>>(7).map(|value| TopRating {
+                    value,
+                    deviation: r.get(8),
+                    timestamp: r.get(9),
+                }),
+
+                top_defeated: r.get::<_, Option<i64>>(10).map(|id| TopDefeated {
+                    id,
+                    char_id: r.get(11),
+                    name: r.get(12),
+                    value: r.get(13),
+                    deviation: r.get(14),
+                    floor: r.get(15),
+                    timestamp: r.get(16),
+                }),
+
+                //Rankings are only worked out in SQLite
+                character_rank: None,
+
+                current_streak: r.get(17),
+                longest_streak: r.get(18),
+            }))
+    }
+
+    fn update_player(&mut self, id: i64, name: &str, floor: i64, platform: i64) -> Result<()> {
+        let statement = self.statement(
+            "INSERT INTO players(id, name, floor, platform) VALUES($1, $2, $3, $4)
+            ON CONFLICT (id) DO UPDATE
+            SET name = EXCLUDED.name, floor = EXCLUDED.floor, platform = EXCLUDED.platform",
+        )?;
+        self.tx
+            .execute(&statement, &[&id, &name, &floor, &platform])?;
+        Ok(())
+    }
+
+    fn add_game_rating(
+        &mut self,
+        game: &Game,
+        before: (Rating, Rating),
+        after: (Rating, Rating),
+        valid: bool,
+    ) -> Result<()> {
+        let statement = self.statement(
+            "INSERT INTO game_ratings
+            VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+        )?;
+        self.tx.execute(
+            &statement,
+            &[
+                &game.timestamp,
+                &game.id_a,
+                &before.0.value,
+                &before.0.deviation,
+                &game.id_b,
+                &before.1.value,
+                &before.1.deviation,
+                &game.winner,
+                &valid,
+                &after.0.value,
+                &after.0.deviation,
+                &after.1.value,
+                &after.1.deviation,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn save_player_rating(&mut self, player: &RatedPlayer) -> Result<()> {
+        let statement = self.statement(
+            "INSERT INTO player_ratings VALUES(
+                $1, $2, $3, $4, $5, $6, $7,
+                $8, $9, $10,
+                $11, $12, $13, $14, $15, $16, $17,
+                $18, $19)
+            ON CONFLICT (id, char_id) DO UPDATE SET
+                wins = EXCLUDED.wins,
+                losses = EXCLUDED.losses,
+                value = EXCLUDED.value,
+                deviation = EXCLUDED.deviation,
+                last_decay = EXCLUDED.last_decay,
+                top_rating_value = EXCLUDED.top_rating_value,
+                top_rating_deviation = EXCLUDED.top_rating_deviation,
+                top_rating_timestamp = EXCLUDED.top_rating_timestamp,
+                top_defeated_id = EXCLUDED.top_defeated_id,
+                top_defeated_char_id = EXCLUDED.top_defeated_char_id,
+                top_defeated_name = EXCLUDED.top_defeated_name,
+                top_defeated_value = EXCLUDED.top_defeated_value,
+                top_defeated_deviation = EXCLUDED.top_defeated_deviation,
+                top_defeated_floor = EXCLUDED.top_defeated_floor,
+                top_defeated_timestamp = EXCLUDED.top_defeated_timestamp,
+                current_streak = EXCLUDED.current_streak,
+                longest_streak = EXCLUDED.longest_streak",
+        )?;
+        let top_rating = player.top_rating.as_ref();
+        let top_defeated = player.top_defeated.as_ref();
+        self.tx.execute(
+            &statement,
+            &[
+                &player.id,
+                &player.char_id,
+                &player.win_count,
+                &player.loss_count,
+                &player.rating.value,
+                &player.rating.deviation,
+                &player.last_decay,
+                //
+                &top_rating.map(|r| r.value),
+                &top_rating.map(|r| r.deviation),
+                &top_rating.map(|r| r.timestamp),
+                //
+                &top_defeated.map(|t| t.id),
+                &top_defeated.map(|t| t.char_id),
+                &top_defeated.map(|t| t.name.as_str()),
+                &top_defeated.map(|t| t.value),
+                &top_defeated.map(|t| t.deviation),
+                &top_defeated.map(|t| t.floor),
+                &top_defeated.map(|t| t.timestamp),
+                //
+                &player.current_streak,
+                &player.longest_streak,
+            ],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use rocket::serde::Serialize;
+use rusqlite::{params, Connection};
+
+use crate::claims;
+
+//Plenty for a tournament pool, and keeps the feed query cheap
+pub const MAX_WATCHED_PLAYERS: usize = 50;
+
+//Watchlists are anonymous, the id only lives in the visitor's cookie
+pub fn new_watchlist_id() -> String {
+    claims::random_hex(16)
+}
+
+pub fn is_valid_id(watchlist: &str) -> bool {
+    watchlist.len() == 32 && watchlist.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+//Returns false if the player doesn't exist or the watchlist is full
+pub fn add_player(conn: &Connection, watchlist: &str, player_id: i64) -> bool {
+    let exists: bool = conn
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM players WHERE id = ?)",
+            params![player_id],
+            |r| r.get(0),
+        )
+        .unwrap();
+    if !exists {
+        return false;
+    }
+
+    let count: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM watchlists WHERE watchlist_id = ?",
+            params![watchlist],
+            |r| r.get(0),
+        )
+        .unwrap();
+    if count >= MAX_WATCHED_PLAYERS as i64 {
+        return false;
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO watchlists(watchlist_id, player_id, added)
+        VALUES(?, ?, strftime('%s', 'now'))",
+        params![watchlist, player_id],
+    )
+    .unwrap();
+    true
+}
+
+pub fn remove_player(conn: &Connection, watchlist: &str, player_id: i64) {
+    conn.execute(
+        "DELETE FROM watchlists WHERE watchlist_id = ? AND player_id = ?",
+        params![watchlist, player_id],
+    )
+    .unwrap();
+}
+
+//Hidden players drop off the list shown to the visitor but stay stored in case they come back
+pub fn watched_players(conn: &Connection, watchlist: &str) -> Vec<WatchedPlayer> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT players.id, players.name
+            FROM watchlists JOIN players ON players.id = watchlists.player_id
+            WHERE watchlist_id = ? AND players.id NOT IN (SELECT id FROM hidden_status)
+            ORDER BY watchlists.added ASC",
+        )
+        .unwrap();
+    stmt.query_map(params![watchlist], |r| {
+        Ok(WatchedPlayer {
+            id: format!("{:X}", r.get::<_, i64>(0)?),
+            name: r.get(1)?,
+        })
+    })
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatchedPlayer {
+    pub id: String,
+    pub name: String,
+}
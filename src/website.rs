@@ -1,51 +1,47 @@
-use crate::api;
+use crate::{api, characters, claims, db, export, rate_limit::RateLimiter, rater, watchlists};
 use rocket::{
+    form::Form,
     fs::NamedFile,
-    http::{hyper::header::CACHE_CONTROL, Header},
+    http::{hyper::header::CACHE_CONTROL, Cookie, CookieJar, Header, Status},
     response::{self, Redirect, Responder},
-    serde::Serialize,
-    Request,
+    serde::{json::Json, Serialize},
+    Request, State,
 };
 use rocket_dyn_templates::Template;
 use rocket_sync_db_pools::database;
 use rusqlite::Connection;
 use std::path::{Path, PathBuf};
 
-pub const CHAR_NAMES: &[(&str, &str)] = &[
-    ("SO", "Sol"),
-    ("KY", "Ky"),
-    ("MA", "May"),
-    ("AX", "Axl"),
-    ("CH", "Chipp"),
-    ("PO", "Potemkin"),
-    ("FA", "Faust"),
-    ("MI", "Millia"),
-    ("ZA", "Zato-1"),
-    ("RA", "Ramlethal"),
-    ("LE", "Leo"),
-    ("NA", "Nagoriyuki"),
-    ("GI", "Giovanna"),
-    ("AN", "Anji"),
-    ("IN", "I-No"),
-    ("GO", "Goldlewis"),
-    ("JC", "Jack-O'"),
-    ("HA", "Happy Chaos"),
-    ("BA", "Baiken"),
-    ("TE", "Testament"),
-    ("BI", "Bridget"),
-    ("SI", "Sin"),
-];
-
-pub async fn run() {
-    let _ = rocket::build()
+//Upsets listed under the top 100
+const FRONT_PAGE_UPSETS: usize = 10;
+//Games shown on the watchlist page
+const WATCHLIST_FEED_GAMES: usize = 100;
+const WATCHLIST_COOKIE: &str = "watchlist";
+
+pub async fn run(config: rater::Config) {
+    //The pool opens its connections itself, but WAL is kept in the database file so switching
+    //it on here is enough for them to read alongside the rater's writes
+    if let Err(e) = db::open(&config.db_path) {
+        warn!("Couldn't set up {}: {}", config.db_path, e);
+    }
+
+    //The database path comes from our own config so the website and rater can't disagree
+    let figment = rocket::Config::figment().merge(("databases.ratings.url", &config.db_path));
+    let api_rate_limit = config.api_rate_limit;
+    let _ = rocket::custom(figment)
+        .manage(config)
         .attach(RatingsDbConn::fairing())
         .attach(Template::fairing())
+        .attach(RateLimiter::new(api_rate_limit))
         .mount(
             "/",
             routes![
                 index,
                 files,
                 top_all,
+                top_streaks,
+                top_overall,
+                top_peaks,
                 top_char,
                 matchups,
                 character_popularity,
@@ -54,19 +50,50 @@ pub async fn run() {
                 player,
                 player_char,
                 player_char_history,
+                player_claim,
+                player_claim_status,
+                player_profile,
+                player_hide,
+                player_watch,
+                player_unwatch,
+                watchlist,
                 search,
                 about,
                 stats,
                 supporters,
                 rating_calculator,
+                metrics,
+                latest_snapshot,
                 api::stats,
+                api::char_rating_distribution,
+                api::activity_heatmap,
+                api::health,
+                api::rate_limited,
+                api::admin_export,
+                api::admin_merge,
+                api::admin_flags,
+                api::admin_flag_exclude,
                 api::player_rating,
                 api::player_rating_all,
                 api::player_rating_accuracy,
+                api::player_v1,
+                api::player_recent_v1,
+                api::player_opponents_v1,
+                api::player_history_v1,
+                api::popularity_history_v1,
+                api::watchlist_feed_v1,
+                api::live_feed,
                 api::top_all,
                 api::top_char,
+                api::top_streaks,
+                api::top_overall,
+                api::top_peaks,
+                api::upsets_v1,
                 api::search,
                 api::search_exact,
+                api::search_v1,
+                api::matchups_v1,
+                api::matchups_floors_v1,
                 api::outcomes,
                 api::outcomes_delta,
                 api::floor_rating_distribution,
@@ -93,7 +120,7 @@ pub struct RatingsDbConn(Connection);
 
 #[get("/")]
 async fn index() -> Redirect {
-    Redirect::to(uri!(top_all))
+    Redirect::to(uri!(top_all(_)))
 }
 
 #[get("/about")]
@@ -105,7 +132,7 @@ async fn about(conn: RatingsDbConn) -> Cached<Template> {
         all_characters: &'static [(&'static str, &'static str)],
     }
     let context = Context {
-        all_characters: CHAR_NAMES,
+        all_characters: characters::all(),
     };
 
     Cached::new(Template::render("about", &context), 999)
@@ -120,7 +147,7 @@ async fn rating_calculator(conn: RatingsDbConn) -> Cached<Template> {
         all_characters: &'static [(&'static str, &'static str)],
     }
     let context = Context {
-        all_characters: CHAR_NAMES,
+        all_characters: characters::all(),
     };
 
     Cached::new(Template::render("rating_calculator", &context), 999)
@@ -137,12 +164,26 @@ async fn stats(conn: RatingsDbConn) -> Cached<Template> {
     }
     let context = Context {
         stats: api::stats_inner(&conn).await,
-        all_characters: CHAR_NAMES,
+        all_characters: characters::all(),
     };
 
     Cached::new(Template::render("stats", &context), 999)
 }
 
+#[get("/data/latest.sqlite.gz")]
+async fn latest_snapshot(config: &State<rater::Config>) -> Cached<Option<NamedFile>> {
+    let file = match export::latest_snapshot(config) {
+        Some(path) => NamedFile::open(path).await.ok(),
+        None => None,
+    };
+    Cached::new(file, 3600)
+}
+
+#[get("/metrics")]
+fn metrics(config: &State<rater::Config>) -> String {
+    crate::metrics::render(&config.db_path)
+}
+
 #[get("/supporters")]
 async fn supporters(conn: RatingsDbConn) -> Cached<Template> {
     api::add_hit(&conn, format!("supporters")).await;
@@ -157,34 +198,103 @@ async fn supporters(conn: RatingsDbConn) -> Cached<Template> {
             "supporters",
             &Context {
                 players: api::get_supporters(&conn).await,
-                all_characters: CHAR_NAMES,
+                all_characters: characters::all(),
             },
         ),
         999,
     )
 }
 
-#[get("/top/all")]
-async fn top_all(conn: RatingsDbConn) -> Cached<Template> {
+#[get("/top/all?<platform>")]
+async fn top_all(conn: RatingsDbConn, platform: Option<&str>) -> Cached<Template> {
     api::add_hit(&conn, format!("top/all")).await;
 
     #[derive(Serialize)]
     struct Context {
         players: Vec<api::RankingPlayer>,
+        upsets: Vec<api::Upset>,
+        platform: Option<String>,
         all_characters: &'static [(&'static str, &'static str)],
     }
 
-    let players = api::top_all_inner(&conn).await;
+    let platform_code = platform.and_then(api::from_platform_string);
+    let players = api::top_all_inner(&conn, platform_code).await;
+    let upsets = api::top_upsets_inner(&conn, FRONT_PAGE_UPSETS).await;
     let context = Context {
         players,
-        all_characters: CHAR_NAMES,
+        upsets,
+        platform: platform_code.map(|_| platform.unwrap().to_lowercase()),
+        all_characters: characters::all(),
     };
 
     Cached::new(Template::render("top_100", &context), 999)
 }
 
-#[get("/top/<character_short>")]
-async fn top_char(conn: RatingsDbConn, character_short: &str) -> Option<Cached<Template>> {
+#[get("/top/streaks")]
+async fn top_streaks(conn: RatingsDbConn) -> Cached<Template> {
+    api::add_hit(&conn, "top/streaks".to_owned()).await;
+
+    #[derive(Serialize)]
+    struct Context {
+        players: Vec<api::StreakPlayer>,
+        all_characters: &'static [(&'static str, &'static str)],
+    }
+
+    let players = api::top_streaks_inner(&conn).await;
+    let context = Context {
+        players,
+        all_characters: characters::all(),
+    };
+
+    Cached::new(Template::render("top_streaks", &context), 999)
+}
+
+#[get("/top/overall")]
+async fn top_overall(conn: RatingsDbConn) -> Cached<Template> {
+    api::add_hit(&conn, "top/overall".to_owned()).await;
+
+    #[derive(Serialize)]
+    struct Context {
+        players: Vec<api::OverallPlayer>,
+        all_characters: &'static [(&'static str, &'static str)],
+    }
+
+    let players = api::top_overall_inner(&conn).await;
+    let context = Context {
+        players,
+        all_characters: characters::all(),
+    };
+
+    Cached::new(Template::render("top_overall", &context), 999)
+}
+
+#[get("/top/peaks")]
+async fn top_peaks(conn: RatingsDbConn) -> Cached<Template> {
+    api::add_hit(&conn, "top/peaks".to_owned()).await;
+
+    #[derive(Serialize)]
+    struct Context {
+        players: Vec<api::PeakPlayer>,
+        all_characters: &'static [(&'static str, &'static str)],
+    }
+
+    let players = api::top_peaks_inner(&conn).await;
+    let context = Context {
+        players,
+        all_characters: characters::all(),
+    };
+
+    Cached::new(Template::render("top_peaks", &context), 999)
+}
+
+#[get("/top/<character_short>?<page>&<page_size>&<platform>")]
+async fn top_char(
+    conn: RatingsDbConn,
+    character_short: &str,
+    page: Option<i64>,
+    page_size: Option<i64>,
+    platform: Option<&str>,
+) -> Option<Cached<Template>> {
     api::add_hit(&conn, format!("top/{}", character_short)).await;
 
     #[derive(Serialize)]
@@ -192,18 +302,37 @@ async fn top_char(conn: RatingsDbConn, character_short: &str) -> Option<Cached<T
         players: Vec<api::RankingPlayer>,
         character: &'static str,
         character_short: &'static str,
+        page: i64,
+        page_size: i64,
+        prev_page: Option<i64>,
+        next_page: Option<i64>,
+        platform: Option<String>,
         all_characters: &'static [(&'static str, &'static str)],
     }
 
-    if let Some(char_code) = CHAR_NAMES.iter().position(|(c, _)| *c == character_short) {
-        let (character_short, character) = CHAR_NAMES[char_code];
-
-        let players = api::top_char_inner(&conn, char_code as i64).await;
+    if let Some(char_code) = characters::all()
+        .iter()
+        .position(|(c, _)| *c == character_short)
+    {
+        let (character_short, character) = characters::all()[char_code];
+        let page = page.unwrap_or(0).max(0);
+        let page_size = page_size
+            .unwrap_or(100)
+            .clamp(1, api::TOP_CHAR_MAX_PAGE_SIZE);
+
+        let platform_code = platform.and_then(api::from_platform_string);
+        let players =
+            api::top_char_inner(&conn, char_code as i64, page, page_size, platform_code).await;
         let context = Context {
+            prev_page: (page > 0).then(|| page - 1),
+            next_page: (players.len() as i64 == page_size).then(|| page + 1),
             players,
             character,
             character_short,
-            all_characters: CHAR_NAMES,
+            page,
+            page_size,
+            platform: platform_code.map(|_| platform.unwrap().to_lowercase()),
+            all_characters: characters::all(),
         };
 
         Some(Cached::new(Template::render("top_100_char", &context), 999))
@@ -223,23 +352,32 @@ async fn matchups(conn: RatingsDbConn) -> Cached<Template> {
         matchups_top_1000: Vec<api::CharacterMatchups>,
         matchups_proportional: Vec<api::CharacterMatchups>,
         matchups_top_100: Vec<api::CharacterMatchups>,
+        matchups_floor_bands: Vec<api::FloorBandMatchups>,
         all_characters: &'static [(&'static str, &'static str)],
     }
 
-    let (matchups_global, matchups_top_1000, matchups_proportional, matchups_top_100) = tokio::join!(
+    let (
+        matchups_global,
+        matchups_top_1000,
+        matchups_proportional,
+        matchups_top_100,
+        matchups_floor_bands,
+    ) = tokio::join!(
         api::get_matchups(&conn, "global_matchups"),
         api::get_matchups(&conn, "top_1000_matchups"),
         api::get_matchups(&conn, "proportional_matchups"),
         api::get_matchups(&conn, "top_100_matchups"),
+        api::get_floor_band_matchups(&conn),
     );
 
     let context = Context {
-        character_shortnames: CHAR_NAMES.iter().map(|c| c.0).collect(),
+        character_shortnames: characters::all().iter().map(|c| c.0).collect(),
         matchups_global,
         matchups_top_1000,
         matchups_proportional,
         matchups_top_100,
-        all_characters: CHAR_NAMES,
+        matchups_floor_bands,
+        all_characters: characters::all(),
     };
 
     Cached::new(Template::render("matchups", &context), 999)
@@ -273,13 +411,13 @@ async fn character_popularity(conn: RatingsDbConn) -> Cached<Template> {
     );
 
     let context = Context {
-        character_shortnames: CHAR_NAMES.iter().map(|c| c.0).collect(),
+        character_shortnames: characters::all().iter().map(|c| c.0).collect(),
         global_character_popularity,
         rank_character_popularity,
         fraud_stats,
         fraud_stats_higher_rated,
         fraud_stats_highest_rated,
-        all_characters: CHAR_NAMES,
+        all_characters: characters::all(),
     };
 
     Cached::new(Template::render("character_popularity", &context), 999)
@@ -308,7 +446,7 @@ async fn player_distribution(conn: RatingsDbConn) -> Cached<Template> {
     let context = Context {
         floors,
         ratings,
-        all_characters: CHAR_NAMES,
+        all_characters: characters::all(),
     };
 
     Cached::new(Template::render("player_distribution", &context), 999)
@@ -321,7 +459,7 @@ async fn player(conn: RatingsDbConn, player_id: &str) -> Option<Redirect> {
     let id = i64::from_str_radix(player_id, 16).unwrap();
 
     if let Some(char_id) = api::get_player_highest_rated_character(&conn, id).await {
-        let char_short = CHAR_NAMES[char_id as usize].0;
+        let char_short = characters::all()[char_id as usize].0;
         Some(Redirect::to(uri!(player_char(
             player_id = player_id,
             char_id = char_short,
@@ -342,7 +480,7 @@ async fn player_char_history(
     api::add_hit(&conn, format!("player/{}/{}/history", player_id, char_id)).await;
 
     let id = i64::from_str_radix(player_id, 16).unwrap();
-    let char_id = CHAR_NAMES.iter().position(|(c, _)| *c == char_id)? as i64;
+    let char_id = characters::by_short(char_id)?;
     let game_count = game_count.unwrap_or(100);
     let offset = offset.unwrap_or(0);
     let group_games = group_games.unwrap_or(true);
@@ -369,7 +507,7 @@ async fn player_char(
 
     let id = i64::from_str_radix(player_id, 16).unwrap();
 
-    let char_id_i64 = CHAR_NAMES.iter().position(|(c, _)| *c == char_id)? as i64;
+    let char_id_i64 = characters::by_short(char_id)?;
 
     #[derive(Serialize)]
     struct Context {
@@ -384,7 +522,7 @@ async fn player_char(
             player_id: player_id.to_owned(),
             char_id: char_id.to_owned(),
             player,
-            all_characters: CHAR_NAMES,
+            all_characters: characters::all(),
         };
         Some(Cached::new(Template::render("player_char", &context), 999))
     } else {
@@ -392,6 +530,168 @@ async fn player_char(
     }
 }
 
+#[derive(Serialize)]
+struct ClaimCode {
+    code: String,
+}
+
+#[post("/player/<player_id>/claim")]
+async fn player_claim(conn: RatingsDbConn, player_id: &str) -> Option<Json<ClaimCode>> {
+    let id = claims::parse_id(player_id)?;
+    let code = conn.run(move |conn| claims::start_claim(conn, id)).await?;
+    Some(Json(ClaimCode { code }))
+}
+
+#[derive(FromForm)]
+struct ClaimStatusForm {
+    code: String,
+}
+
+#[post("/player/<player_id>/claim/status", data = "<form>")]
+async fn player_claim_status(
+    conn: RatingsDbConn,
+    player_id: &str,
+    form: Form<ClaimStatusForm>,
+) -> Option<Json<claims::ClaimStatus>> {
+    let id = claims::parse_id(player_id)?;
+    let code = form.into_inner().code;
+    conn.run(move |conn| claims::claim_status(conn, id, &code))
+        .await
+        .map(Json)
+}
+
+#[derive(FromForm)]
+struct ProfileForm {
+    token: String,
+    display_name: Option<String>,
+    pronouns: Option<String>,
+    links: Vec<String>,
+}
+
+#[post("/player/<player_id>/profile", data = "<form>")]
+async fn player_profile(conn: RatingsDbConn, player_id: &str, form: Form<ProfileForm>) -> Status {
+    let id = match claims::parse_id(player_id) {
+        Some(id) => id,
+        None => return Status::NotFound,
+    };
+    let form = form.into_inner();
+    let updated = conn
+        .run(move |conn| {
+            let links = form.links.iter().map(|l| l.as_str()).collect::<Vec<_>>();
+            claims::update_profile(
+                conn,
+                id,
+                &form.token,
+                form.display_name.as_deref(),
+                form.pronouns.as_deref(),
+                &links,
+            )
+        })
+        .await;
+
+    if updated {
+        Status::Ok
+    } else {
+        Status::Forbidden
+    }
+}
+
+#[derive(FromForm)]
+struct HideForm {
+    token: String,
+}
+
+#[post("/player/<player_id>/hide", data = "<form>")]
+async fn player_hide(conn: RatingsDbConn, player_id: &str, form: Form<HideForm>) -> Status {
+    let id = match claims::parse_id(player_id) {
+        Some(id) => id,
+        None => return Status::NotFound,
+    };
+    let token = form.into_inner().token;
+    if conn
+        .run(move |conn| claims::hide_self(conn, id, &token))
+        .await
+    {
+        Status::Ok
+    } else {
+        Status::Forbidden
+    }
+}
+
+fn watchlist_id(cookies: &CookieJar<'_>) -> Option<String> {
+    cookies
+        .get(WATCHLIST_COOKIE)
+        .map(|c| c.value().to_owned())
+        .filter(|id| watchlists::is_valid_id(id))
+}
+
+#[post("/player/<player_id>/watch")]
+async fn player_watch(
+    conn: RatingsDbConn,
+    cookies: &CookieJar<'_>,
+    player_id: &str,
+) -> Option<Redirect> {
+    let id = claims::parse_id(player_id)?;
+    let watchlist = watchlist_id(cookies).unwrap_or_else(watchlists::new_watchlist_id);
+    let mut cookie = Cookie::new(WATCHLIST_COOKIE, watchlist.clone());
+    cookie.make_permanent();
+    cookies.add(cookie);
+
+    conn.run(move |conn| watchlists::add_player(conn, &watchlist, id))
+        .await;
+    Some(Redirect::to(uri!(watchlist)))
+}
+
+#[post("/player/<player_id>/unwatch")]
+async fn player_unwatch(
+    conn: RatingsDbConn,
+    cookies: &CookieJar<'_>,
+    player_id: &str,
+) -> Option<Redirect> {
+    let id = claims::parse_id(player_id)?;
+    if let Some(watchlist) = watchlist_id(cookies) {
+        conn.run(move |conn| watchlists::remove_player(conn, &watchlist, id))
+            .await;
+    }
+    Some(Redirect::to(uri!(watchlist)))
+}
+
+#[get("/watchlist")]
+async fn watchlist(conn: RatingsDbConn, cookies: &CookieJar<'_>) -> Template {
+    #[derive(Serialize)]
+    struct Context {
+        watchlist_id: Option<String>,
+        players: Vec<watchlists::WatchedPlayer>,
+        games: Vec<api::FeedGame>,
+        max_players: usize,
+        all_characters: &'static [(&'static str, &'static str)],
+    }
+
+    let watchlist = watchlist_id(cookies);
+    let (players, games) = match watchlist.clone() {
+        Some(watchlist) => (
+            conn.run({
+                let watchlist = watchlist.clone();
+                move |conn| watchlists::watched_players(conn, &watchlist)
+            })
+            .await,
+            api::watchlist_feed_inner(&conn, watchlist, WATCHLIST_FEED_GAMES).await,
+        ),
+        None => (Vec::new(), Vec::new()),
+    };
+
+    Template::render(
+        "watchlist",
+        &Context {
+            watchlist_id: watchlist,
+            players,
+            games,
+            max_players: watchlists::MAX_WATCHED_PLAYERS,
+            all_characters: characters::all(),
+        },
+    )
+}
+
 #[get("/?<name>")]
 async fn search(conn: RatingsDbConn, name: String) -> Template {
     api::add_hit(&conn, format!("search/{}", name)).await;
@@ -409,7 +709,7 @@ async fn search(conn: RatingsDbConn, name: String) -> Template {
         &Context {
             players,
             search_string: name,
-            all_characters: CHAR_NAMES,
+            all_characters: characters::all(),
         },
     )
 }